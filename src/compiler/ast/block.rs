@@ -4,5 +4,6 @@ use super::statement::Statement;
 #[derive(Debug)]
 pub struct Block {
     pub statements: Vec<Statement>,
-    pub return_expression: Vec<Expression>,
+    /// `None` if the block does not end with a return statement.
+    pub return_expression: Option<Vec<Expression>>,
 }
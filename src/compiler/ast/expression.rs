@@ -2,6 +2,7 @@ use std::fmt::Debug;
 
 use super::block::Block;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Expression {
     EmptyExpression,
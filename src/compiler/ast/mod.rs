@@ -1,4 +1,3 @@
 pub mod block;
 pub mod expression;
-mod node;
 pub mod statement;
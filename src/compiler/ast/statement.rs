@@ -2,16 +2,17 @@ use std::fmt::Debug;
 
 use super::{
     block::Block,
-    expression::{Expression, FunctionDefinedExpression},
+    expression::{Expression, FunctionCallExpression},
 };
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Statement {
     EmptyStatement,
     BreakStatement,
     LabelStatement(String),
     GotoStatement(String),
-    DotStatement,
+    DoStatement(Block),
     WhileStatement(WhileStatement),
     RepeatStatement(RepeatStatement),
     /// https://snacky.blog/en/recursive-rust.html
@@ -20,6 +21,7 @@ pub enum Statement {
     LocalVarDeclareStatement(LocalVarDeclareStatement),
     AssignStatement(AssignStatement),
     LocalFunctionDefinedStatement(LocalFunctionDefinedStatement),
+    FunctionCallStatement(FunctionCallExpression),
 }
 
 impl Statement {
@@ -97,23 +99,12 @@ impl Statement {
 //     }
 // }
 
-// #[derive(Debug)]
-// pub struct DotStatement {}
-
-// impl DotStatement {}
-
 #[derive(Debug)]
 pub struct WhileStatement {
     pub condition: Expression,
     pub block: Block,
 }
 
-impl WhileStatement {
-    fn new(condition: Expression, block: Block) -> WhileStatement {
-        WhileStatement { condition, block }
-    }
-}
-
 #[derive(Debug)]
 pub struct RepeatStatement {
     pub condition: Expression,
@@ -0,0 +1,56 @@
+use crate::{
+    compiler::ast::{block::Block, expression::Expression},
+    vm::{lua_state::LUA_MULTRET, op_code::OpCodeEnum},
+};
+
+use super::{
+    cg_expression::{cg_expression, is_multret},
+    cg_statement::cg_statement,
+    func_info::FuncInfo,
+};
+
+pub fn cg_block(fi: &mut FuncInfo, block: &Block) {
+    for statement in &block.statements {
+        cg_statement(fi, statement);
+    }
+    if let Some(exps) = &block.return_expression {
+        cg_return_statement(fi, exps);
+    }
+}
+
+/// The parameters are the first locals of a function, it always ends with
+/// a return.
+pub fn cg_function_body(fi: &mut FuncInfo, param_list: &[String], block: &Block) {
+    fi.enter_scope(false);
+    for param in param_list {
+        fi.add_local_var(param);
+    }
+    if fi.is_vararg {
+        fi.emit_abc(OpCodeEnum::OpVarArgPrep, fi.num_params, 0, 0, false);
+    }
+    cg_block(fi, block);
+    fi.emit_return(0, Some(0));
+    fi.exit_scope();
+}
+
+fn cg_return_statement(fi: &mut FuncInfo, exps: &[Expression]) {
+    if let [Expression::NameString(name)] = exps {
+        if let Some(slot) = fi.slot_of_local_var(name) {
+            fi.emit_return(slot, Some(1));
+            return;
+        }
+    }
+
+    let multret = exps.last().is_some_and(is_multret);
+    let a = fi.used_regs();
+    for (i, exp) in exps.iter().enumerate() {
+        let tmp = fi.alloc_reg();
+        if i == exps.len() - 1 && multret {
+            cg_expression(fi, exp, tmp, LUA_MULTRET);
+        } else {
+            cg_expression(fi, exp, tmp, 1);
+        }
+    }
+    fi.free_regs(exps.len());
+    fi.emit_return(a, if multret { None } else { Some(exps.len()) });
+}
@@ -0,0 +1,334 @@
+use crate::{
+    compiler::ast::expression::*,
+    vm::{lua_state::LUA_MULTRET, lua_value::LuaValue, op_code::OpCodeEnum, tag_method::TagMethod},
+};
+
+use super::{
+    cg_block::cg_function_body,
+    func_info::{FuncInfo, MAXARG_C},
+};
+
+/// Number of array items stored by one SETLIST.
+const LFIELDS_PER_FLUSH: usize = 50;
+
+/// A call or `...`, whose number of values depends on where it is used.
+pub fn is_multret(exp: &Expression) -> bool {
+    matches!(
+        exp,
+        Expression::FunctionCallExpression(_) | Expression::VarargExpression
+    )
+}
+
+/// Put `n` values of `exp` into the registers from `a` on, `a` is the last
+/// allocated register. `n` only matters for calls and `...`, where
+/// `LUA_MULTRET` keeps all values and leaves the top after the last one.
+pub fn cg_expression(fi: &mut FuncInfo, exp: &Expression, a: usize, n: i32) {
+    match exp {
+        Expression::EmptyExpression | Expression::NilExpression => fi.emit_load_nil(a, 1),
+        Expression::TrueExpression => {
+            fi.emit_abc(OpCodeEnum::OpLoadTrue, a, 0, 0, false);
+        }
+        Expression::FalseExpression => {
+            fi.emit_abc(OpCodeEnum::OpLoadFalse, a, 0, 0, false);
+        }
+        Expression::VarargExpression => {
+            if !fi.is_vararg {
+                panic!("cannot use '...' outside a vararg function");
+            }
+            fi.emit_abc(OpCodeEnum::OpVararg, a, 0, (n + 1) as usize, false);
+        }
+        Expression::IntegerExpression(i) => fi.emit_load_integer(a, *i),
+        Expression::FloatExpresion(f) => {
+            let idx = fi.index_of_constant(LuaValue::Number(*f));
+            fi.emit_load_k(a, idx);
+        }
+        Expression::StringExpression(s) => {
+            let idx = fi.index_of_string(s);
+            fi.emit_load_k(a, idx);
+        }
+        Expression::NameString(name) => cg_name_expression(fi, name, a),
+        Expression::UnaryExpression(exp) => cg_unary_expression(fi, exp, a),
+        Expression::BinaryExpression(exp) => cg_binary_expression(fi, exp, a),
+        Expression::ConcatExpression(exp) => cg_concat_expression(fi, exp, a),
+        Expression::TableConstructorExpression(exp) => cg_table_constructor_expression(fi, exp, a),
+        Expression::FunctionDefinedExpression(exp) => cg_function_defined_expression(fi, exp, a),
+        // parentheses keep only the first value of a call or `...`
+        Expression::ParenthesisExpression(exp) => cg_expression(fi, &exp.exp, a, 1),
+        Expression::TableAccessExpression(exp) => {
+            cg_table_access_expression(fi, &exp.prefix_exp, &exp.key_exp, a)
+        }
+        Expression::FunctionCallExpression(exp) => cg_function_call_expression(fi, exp, a, n),
+    }
+}
+
+/// A local, an upvalue or else a field of `_ENV`.
+fn cg_name_expression(fi: &mut FuncInfo, name: &str, a: usize) {
+    if let Some(slot) = fi.slot_of_local_var(name) {
+        if slot != a {
+            fi.emit_abc(OpCodeEnum::OpMove, a, slot, 0, false);
+        }
+    } else if let Some(idx) = fi.index_of_upvalue(name) {
+        fi.emit_abc(OpCodeEnum::OpGetUpval, a, idx, 0, false);
+    } else {
+        let k = fi.index_of_string(name);
+        match env_upvalue(fi) {
+            Some(env) if k <= MAXARG_C => {
+                fi.emit_abc(OpCodeEnum::OpGetTabUp, a, env, k, false);
+            }
+            _ => cg_table_access_expression(
+                fi,
+                &Expression::NameString("_ENV".to_string()),
+                &Expression::StringExpression(name.to_string()),
+                a,
+            ),
+        }
+    }
+}
+
+/// Upvalue holding `_ENV`, unless a local `_ENV` shadows it.
+pub fn env_upvalue(fi: &mut FuncInfo) -> Option<usize> {
+    if fi.slot_of_local_var("_ENV").is_some() {
+        return None;
+    }
+    fi.index_of_upvalue("_ENV")
+}
+
+fn cg_table_access_expression(fi: &mut FuncInfo, prefix: &Expression, key: &Expression, a: usize) {
+    cg_expression(fi, prefix, a, 1);
+    match key {
+        Expression::StringExpression(s) if fi.index_of_string(s) <= MAXARG_C => {
+            let k = fi.index_of_string(s);
+            fi.emit_abc(OpCodeEnum::OpGetField, a, a, k, false);
+        }
+        _ => {
+            let b = fi.alloc_reg();
+            cg_expression(fi, key, b, 1);
+            fi.free_reg();
+            fi.emit_abc(OpCodeEnum::OpGetTable, a, a, b, false);
+        }
+    }
+}
+
+fn cg_unary_expression(fi: &mut FuncInfo, exp: &UnaryExpression, a: usize) {
+    let op = match &exp.operator[..] {
+        "-" => OpCodeEnum::OpUNM,
+        "~" => OpCodeEnum::OpBNOT,
+        "not" => OpCodeEnum::OpNOT,
+        "#" => OpCodeEnum::OpLEN,
+        op => unreachable!("unary operator {}", op),
+    };
+    let b = fi.alloc_reg();
+    cg_expression(fi, &exp.exp, b, 1);
+    fi.free_reg();
+    fi.emit_abc(op, a, b, 0, false);
+}
+
+fn cg_binary_expression(fi: &mut FuncInfo, exp: &BinaryExpression, a: usize) {
+    match &exp.operator[..] {
+        op @ ("and" | "or") => {
+            // keep the left operand if it decides the result
+            cg_expression(fi, &exp.exp_l, a, 1);
+            fi.emit_abc(OpCodeEnum::OpTest, a, 0, 0, op == "or");
+            let jump = fi.emit_jump(0);
+            cg_expression(fi, &exp.exp_r, a, 1);
+            let end = fi.next_pc();
+            fi.fix_sj(jump, end);
+        }
+        op => {
+            let b = fi.alloc_reg();
+            cg_expression(fi, &exp.exp_l, b, 1);
+            let c = fi.alloc_reg();
+            cg_expression(fi, &exp.exp_r, c, 1);
+            fi.free_regs(2);
+            if let Some((op, event)) = arith_operator(op) {
+                fi.emit_abc(op, a, b, c, false);
+                fi.emit_abc(OpCodeEnum::OpMmbin, b, c, event as usize, false);
+            } else {
+                cg_comparison(fi, op, a, b, c);
+            }
+        }
+    }
+}
+
+/// Opcode of an arithmetic or bitwise operator and the event of the
+/// metamethod its MMBIN calls.
+fn arith_operator(op: &str) -> Option<(OpCodeEnum, TagMethod)> {
+    Some(match op {
+        "+" => (OpCodeEnum::OpAdd, TagMethod::Add),
+        "-" => (OpCodeEnum::OpSub, TagMethod::Sub),
+        "*" => (OpCodeEnum::OpMul, TagMethod::Mul),
+        "%" => (OpCodeEnum::OpMod, TagMethod::Mod),
+        "^" => (OpCodeEnum::OpPow, TagMethod::Pow),
+        "/" => (OpCodeEnum::OpDiv, TagMethod::Div),
+        "//" => (OpCodeEnum::OpIdiv, TagMethod::IDiv),
+        "&" => (OpCodeEnum::OpBAND, TagMethod::BAnd),
+        "|" => (OpCodeEnum::OpBOR, TagMethod::BOr),
+        "~" => (OpCodeEnum::OpBXOR, TagMethod::BXor),
+        "<<" => (OpCodeEnum::OpSHL, TagMethod::Shl),
+        ">>" => (OpCodeEnum::OpSHR, TagMethod::Shr),
+        _ => return None,
+    })
+}
+
+/// R[a] := R[b] op R[c] as a boolean: the test skips the jump to LOADTRUE
+/// if it fails.
+fn cg_comparison(fi: &mut FuncInfo, op: &str, a: usize, b: usize, c: usize) {
+    let (op, b, c, k) = match op {
+        "==" => (OpCodeEnum::OpEq, b, c, true),
+        "~=" => (OpCodeEnum::OpEq, b, c, false),
+        "<" => (OpCodeEnum::OpLt, b, c, true),
+        "<=" => (OpCodeEnum::OpLe, b, c, true),
+        ">" => (OpCodeEnum::OpLt, c, b, true),
+        ">=" => (OpCodeEnum::OpLe, c, b, true),
+        op => unreachable!("binary operator {}", op),
+    };
+    fi.emit_abc(op, b, c, 0, k);
+    let load_true = fi.pc() + 3;
+    fi.emit_jump(load_true);
+    fi.emit_abc(OpCodeEnum::OpLFalseSkip, a, 0, 0, false);
+    fi.emit_abc(OpCodeEnum::OpLoadTrue, a, 0, 0, false);
+}
+
+fn cg_concat_expression(fi: &mut FuncInfo, exp: &ConcatExpression, a: usize) {
+    cg_expression(fi, &exp.exps[0], a, 1);
+    for exp in &exp.exps[1..] {
+        let b = fi.alloc_reg();
+        cg_expression(fi, exp, b, 1);
+    }
+    let n = exp.exps.len();
+    fi.free_regs(n - 1);
+    fi.emit_abc(OpCodeEnum::OpCONCAT, a, n, 0, false);
+}
+
+/// Positional items are collected above the table and stored by SETLIST,
+/// `LFIELDS_PER_FLUSH` at a time. Record fields are set one by one.
+fn cg_table_constructor_expression(fi: &mut FuncInfo, exp: &TableConstructorExpression, a: usize) {
+    let n_arr = exp
+        .key_exps
+        .iter()
+        .filter(|key| matches!(key, Expression::NilExpression))
+        .count();
+    let n_hash = exp.key_exps.len() - n_arr;
+    let multret = matches!(exp.key_exps.last(), Some(Expression::NilExpression))
+        && exp.value_exps.last().is_some_and(is_multret);
+
+    // B is the log2 of the hash size plus one, C the array size, with its
+    // high bits in the EXTRAARG if k is set
+    let b = if n_hash > 0 {
+        n_hash.next_power_of_two().trailing_zeros() as usize + 1
+    } else {
+        0
+    };
+    fi.emit_abc(
+        OpCodeEnum::OpNEWTABLE,
+        a,
+        b,
+        n_arr % (MAXARG_C + 1),
+        n_arr > MAXARG_C,
+    );
+    fi.emit_ax(OpCodeEnum::OpExtraArg, n_arr / (MAXARG_C + 1));
+
+    let mut arr_idx = 0;
+    let last = exp.key_exps.len().saturating_sub(1);
+    for (i, (key, value)) in exp.key_exps.iter().zip(&exp.value_exps).enumerate() {
+        if let Expression::NilExpression = key {
+            arr_idx += 1;
+            let tmp = fi.alloc_reg();
+            let open = i == last && multret;
+            cg_expression(fi, value, tmp, if open { LUA_MULTRET } else { 1 });
+
+            if arr_idx % LFIELDS_PER_FLUSH == 0 || arr_idx == n_arr {
+                let n = match arr_idx % LFIELDS_PER_FLUSH {
+                    0 => LFIELDS_PER_FLUSH,
+                    n => n,
+                };
+                fi.free_regs(n);
+                let stored = arr_idx - n;
+                fi.emit_abc(
+                    OpCodeEnum::OpSetList,
+                    a,
+                    if open { 0 } else { n },
+                    stored % (MAXARG_C + 1),
+                    stored > MAXARG_C,
+                );
+                if stored > MAXARG_C {
+                    fi.emit_ax(OpCodeEnum::OpExtraArg, stored / (MAXARG_C + 1));
+                }
+            }
+        } else {
+            let b = fi.alloc_reg();
+            cg_expression(fi, key, b, 1);
+            let c = fi.alloc_reg();
+            cg_expression(fi, value, c, 1);
+            fi.free_regs(2);
+            fi.emit_abc(OpCodeEnum::OpSetTable, a, b, c, false);
+        }
+    }
+}
+
+fn cg_function_defined_expression(fi: &mut FuncInfo, exp: &FunctionDefinedExpression, a: usize) {
+    // the enclosing function is the parent of the new one while it is
+    // compiled, so that its locals can be captured
+    let parent = std::mem::take(fi);
+    let mut sub = FuncInfo::new(parent, exp.param_list.len(), exp.is_vararg);
+    cg_function_body(&mut sub, &exp.param_list, &exp.block);
+    *fi = *sub.parent.take().expect("parent of a nested function");
+
+    let idx = fi.add_sub_func(sub.into_prototype());
+    fi.emit_abx(OpCodeEnum::OpClosure, a, idx);
+}
+
+/// Evaluate the function and its arguments above it, from `a` on. Returns
+/// the number of arguments, `LUA_MULTRET` if the last one is a call or `...`
+/// whose values go up to the top.
+pub fn prep_function_call(fi: &mut FuncInfo, exp: &FunctionCallExpression, a: usize) -> i32 {
+    cg_expression(fi, &exp.prefix_exp, a, 1);
+    let mut n_regs = 0;
+    if let Expression::StringExpression(name) = &*exp.name_exp {
+        if !name.is_empty() {
+            // obj:name(...) passes obj as the first argument
+            fi.alloc_reg();
+            n_regs += 1;
+            let k = fi.index_of_string(name);
+            if k <= MAXARG_C {
+                fi.emit_abc(OpCodeEnum::OpSELF, a, a, k, true);
+            } else {
+                let c = fi.alloc_reg();
+                fi.emit_load_k(c, k);
+                fi.free_reg();
+                fi.emit_abc(OpCodeEnum::OpSELF, a, a, c, false);
+            }
+        }
+    }
+
+    let mut n_args = n_regs + exp.args.len() as i32;
+    for (i, arg) in exp.args.iter().enumerate() {
+        let tmp = fi.alloc_reg();
+        n_regs += 1;
+        if i == exp.args.len() - 1 && is_multret(arg) {
+            cg_expression(fi, arg, tmp, LUA_MULTRET);
+            n_args = LUA_MULTRET;
+        } else {
+            cg_expression(fi, arg, tmp, 1);
+        }
+    }
+    fi.free_regs(n_regs as usize);
+    n_args
+}
+
+/// R[a], ..., R[a+n-1] := R[a](args)
+pub fn cg_function_call_expression(
+    fi: &mut FuncInfo,
+    exp: &FunctionCallExpression,
+    a: usize,
+    n: i32,
+) {
+    let n_args = prep_function_call(fi, exp, a);
+    fi.emit_abc(
+        OpCodeEnum::OpCall,
+        a,
+        (n_args + 1) as usize,
+        (n + 1) as usize,
+        false,
+    );
+}
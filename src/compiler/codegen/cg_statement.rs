@@ -0,0 +1,203 @@
+use crate::{
+    compiler::ast::{block::Block, expression::Expression, statement::*},
+    vm::op_code::OpCodeEnum,
+};
+
+use super::{
+    cg_block::cg_block,
+    cg_expression::{cg_expression, cg_function_call_expression, env_upvalue, is_multret},
+    func_info::{FuncInfo, MAXARG_C},
+};
+
+pub fn cg_statement(fi: &mut FuncInfo, statement: &Statement) {
+    match statement {
+        Statement::EmptyStatement => {}
+        Statement::BreakStatement => {
+            let pc = fi.emit_jump(0);
+            fi.add_break_jump(pc);
+        }
+        Statement::LabelStatement(_) | Statement::GotoStatement(_) => {
+            panic!("goto is not supported")
+        }
+        Statement::ForStatement(_) => panic!("for loops are not supported"),
+        Statement::DoStatement(block) => cg_do_statement(fi, block),
+        Statement::WhileStatement(statement) => cg_while_statement(fi, statement),
+        Statement::RepeatStatement(statement) => cg_repeat_statement(fi, statement),
+        Statement::IfStatement(statement) => cg_if_statement(fi, statement),
+        Statement::LocalVarDeclareStatement(statement) => {
+            cg_local_var_declare_statement(fi, statement)
+        }
+        Statement::AssignStatement(statement) => cg_assign_statement(fi, statement),
+        Statement::LocalFunctionDefinedStatement(statement) => {
+            // the function is in scope in its own body
+            let slot = fi.add_local_var(&statement.name);
+            cg_expression(fi, &statement.exp, slot, 1);
+        }
+        Statement::FunctionCallStatement(call) => {
+            let a = fi.alloc_reg();
+            cg_function_call_expression(fi, call, a, 0);
+            fi.free_reg();
+        }
+    }
+}
+
+fn cg_do_statement(fi: &mut FuncInfo, block: &Block) {
+    fi.enter_scope(false);
+    cg_block(fi, block);
+    fi.exit_scope();
+}
+
+/// Evaluate the condition and jump if it is false, returns the pending jump.
+fn cg_condition_jump(fi: &mut FuncInfo, condition: &Expression) -> usize {
+    let a = fi.alloc_reg();
+    cg_expression(fi, condition, a, 1);
+    fi.free_reg();
+    fi.emit_abc(OpCodeEnum::OpTest, a, 0, 0, false);
+    fi.emit_jump(0)
+}
+
+fn cg_while_statement(fi: &mut FuncInfo, statement: &WhileStatement) {
+    let start = fi.next_pc();
+    let exit = cg_condition_jump(fi, &statement.condition);
+
+    fi.enter_scope(true);
+    cg_block(fi, &statement.block);
+    fi.close_open_upvalues();
+    fi.emit_jump(start);
+    let end = fi.next_pc();
+    fi.exit_scope();
+    fi.fix_sj(exit, end);
+}
+
+/// The condition is inside the scope of the body.
+fn cg_repeat_statement(fi: &mut FuncInfo, statement: &RepeatStatement) {
+    let start = fi.next_pc();
+    fi.enter_scope(true);
+    cg_block(fi, &statement.block);
+
+    let a = fi.alloc_reg();
+    cg_expression(fi, &statement.condition, a, 1);
+    fi.free_reg();
+    if fi.block_has_upvalue() {
+        // close the locals of this iteration before going back
+        fi.emit_abc(OpCodeEnum::OpTest, a, 0, 0, true);
+        let exit = fi.emit_jump(0);
+        fi.close_open_upvalues();
+        fi.emit_jump(start);
+        let end = fi.next_pc();
+        fi.exit_scope();
+        fi.fix_sj(exit, end);
+    } else {
+        fi.emit_abc(OpCodeEnum::OpTest, a, 0, 0, false);
+        fi.emit_jump(start);
+        fi.exit_scope();
+    }
+}
+
+fn cg_if_statement(fi: &mut FuncInfo, statement: &IfStatement) {
+    let else_jump = cg_condition_jump(fi, &statement.condition);
+    cg_do_statement(fi, &statement.then_block);
+
+    let else_block = &statement.else_block;
+    if else_block.statements.is_empty() && else_block.return_expression.is_none() {
+        let end = fi.next_pc();
+        fi.fix_sj(else_jump, end);
+    } else {
+        let end_jump = fi.emit_jump(0);
+        let else_start = fi.next_pc();
+        fi.fix_sj(else_jump, else_start);
+        cg_do_statement(fi, else_block);
+        let end = fi.next_pc();
+        fi.fix_sj(end_jump, end);
+    }
+}
+
+/// Put `n_vars` values of `exps` into new registers, padded with nil or
+/// from the open results of a last call or `...`. Extra expressions are
+/// evaluated for their side effects. Returns the first register.
+fn cg_expression_list(fi: &mut FuncInfo, exps: &[Expression], n_vars: usize) -> usize {
+    let first = fi.used_regs();
+    for (i, exp) in exps.iter().enumerate() {
+        let a = fi.alloc_reg();
+        if i == exps.len() - 1 && is_multret(exp) && i < n_vars {
+            let n = n_vars - i;
+            cg_expression(fi, exp, a, n as i32);
+            fi.alloc_regs(n - 1);
+        } else if i >= n_vars {
+            cg_expression(fi, exp, a, 0);
+        } else {
+            cg_expression(fi, exp, a, 1);
+        }
+    }
+    if exps.len() < n_vars && !exps.last().is_some_and(is_multret) {
+        let n = n_vars - exps.len();
+        let a = fi.alloc_regs(n);
+        fi.emit_load_nil(a, n);
+    }
+    first
+}
+
+/// The names are in scope after the declaration only.
+fn cg_local_var_declare_statement(fi: &mut FuncInfo, statement: &LocalVarDeclareStatement) {
+    let used_regs = fi.used_regs();
+    cg_expression_list(fi, &statement.exp_list, statement.name_list.len());
+    fi.set_used_regs(used_regs);
+    for name in &statement.name_list {
+        fi.add_local_var(name);
+    }
+}
+
+/// The tables and keys of all variables and then all values are evaluated
+/// before any variable is assigned.
+fn cg_assign_statement(fi: &mut FuncInfo, statement: &AssignStatement) {
+    let used_regs = fi.used_regs();
+    let mut fields = Vec::new();
+    for var in &statement.var_list {
+        if let Expression::TableAccessExpression(access) = var {
+            let t = fi.alloc_reg();
+            cg_expression(fi, &access.prefix_exp, t, 1);
+            let k = fi.alloc_reg();
+            cg_expression(fi, &access.key_exp, k, 1);
+            fields.push(Some((t, k)));
+        } else {
+            fields.push(None);
+        }
+    }
+    let values = cg_expression_list(fi, &statement.exp_list, statement.var_list.len());
+
+    for (i, (var, field)) in statement.var_list.iter().zip(fields).enumerate() {
+        let v = values + i;
+        match (var, field) {
+            (_, Some((t, k))) => {
+                fi.emit_abc(OpCodeEnum::OpSetTable, t, k, v, false);
+            }
+            (Expression::NameString(name), None) => cg_set_name(fi, name, v),
+            (var, None) => unreachable!("cannot assign to {:?}", var),
+        }
+    }
+    fi.set_used_regs(used_regs);
+}
+
+/// Set the local, upvalue or else the field of `_ENV` `name` to R[v].
+fn cg_set_name(fi: &mut FuncInfo, name: &str, v: usize) {
+    if let Some(slot) = fi.slot_of_local_var(name) {
+        fi.emit_abc(OpCodeEnum::OpMove, slot, v, 0, false);
+    } else if let Some(idx) = fi.index_of_upvalue(name) {
+        fi.emit_abc(OpCodeEnum::OpSetUpval, v, idx, 0, false);
+    } else {
+        let k = fi.index_of_string(name);
+        match env_upvalue(fi) {
+            Some(env) if k <= MAXARG_C => {
+                fi.emit_abc(OpCodeEnum::OpSetTabUp, env, k, v, false);
+            }
+            _ => {
+                let t = fi.alloc_reg();
+                cg_expression(fi, &Expression::NameString("_ENV".to_string()), t, 1);
+                let key = fi.alloc_reg();
+                fi.emit_load_k(key, k);
+                fi.free_regs(2);
+                fi.emit_abc(OpCodeEnum::OpSetTable, t, key, v, false);
+            }
+        }
+    }
+}
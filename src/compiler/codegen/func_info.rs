@@ -0,0 +1,402 @@
+use std::rc::Rc;
+
+use crate::vm::{
+    binary_chunk::{LocalVariable, Prototype, Upvalue},
+    instruction::{Instruction, InstructionOperation},
+    lua_string::LuaString,
+    lua_value::LuaValue,
+    op_code::OpCodeEnum,
+};
+
+const MAXARG_BX: usize = (1 << 17) - 1;
+const MAXARG_SBX: i64 = (MAXARG_BX >> 1) as i64;
+const MAXARG_SJ: i64 = ((1 << 25) - 1) >> 1;
+pub const MAXARG_C: usize = (1 << 8) - 1;
+const MAXREGS: usize = 255;
+
+/// A local variable in scope.
+struct LocalVarInfo {
+    name: String,
+    slot: usize,
+    /// Index of its debug info in `FuncInfo::local_vars`.
+    debug_index: usize,
+    captured: bool,
+}
+
+/// A block that declares local variables.
+struct BlockInfo {
+    is_loop: bool,
+    /// Number of registers in use when the block was entered.
+    level: usize,
+    /// Number of active locals declared outside the block.
+    first_local: usize,
+    /// Pending jumps of the `break` statements of a loop.
+    breaks: Vec<usize>,
+    /// Some local declared in the block or a nested one is an upvalue.
+    has_upvalue: bool,
+}
+
+struct UpvalueInfo {
+    name: String,
+    instack: bool,
+    index: usize,
+}
+
+/// State of the function being compiled, its parent is the enclosing
+/// function whose locals it can capture.
+#[derive(Default)]
+pub struct FuncInfo {
+    pub parent: Option<Box<FuncInfo>>,
+    source: String,
+    constants: Vec<LuaValue>,
+    used_regs: usize,
+    max_regs: usize,
+    active_locals: Vec<LocalVarInfo>,
+    blocks: Vec<BlockInfo>,
+    upvalues: Vec<UpvalueInfo>,
+    insts: Vec<Instruction>,
+    sub_funcs: Vec<Rc<Prototype>>,
+    local_vars: Vec<LocalVariable>,
+    pub num_params: usize,
+    pub is_vararg: bool,
+    /// Some local of the function is captured, returns must close it.
+    need_close: bool,
+}
+
+impl FuncInfo {
+    /// The main function of a chunk, a vararg function with `_ENV` as its
+    /// only upvalue.
+    pub fn main(source: &str) -> FuncInfo {
+        FuncInfo {
+            source: source.to_string(),
+            upvalues: vec![UpvalueInfo {
+                name: "_ENV".to_string(),
+                instack: true,
+                index: 0,
+            }],
+            is_vararg: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn new(parent: FuncInfo, num_params: usize, is_vararg: bool) -> FuncInfo {
+        FuncInfo {
+            source: parent.source.clone(),
+            parent: Some(Box::new(parent)),
+            num_params,
+            is_vararg,
+            ..Default::default()
+        }
+    }
+
+    /* constants */
+
+    /// Index of the constant `k`, added if it is new. Integers and floats
+    /// are different constants even if they are equal numbers.
+    pub fn index_of_constant(&mut self, k: LuaValue) -> usize {
+        let same = |c: &LuaValue| match (c, &k) {
+            (LuaValue::Integer(a), LuaValue::Integer(b)) => a == b,
+            (LuaValue::Number(a), LuaValue::Number(b)) => a.to_bits() == b.to_bits(),
+            (LuaValue::String(a), LuaValue::String(b)) => a == b,
+            _ => false,
+        };
+        if let Some(idx) = self.constants.iter().position(same) {
+            return idx;
+        }
+        self.constants.push(k);
+        self.constants.len() - 1
+    }
+
+    pub fn index_of_string(&mut self, s: &str) -> usize {
+        self.index_of_constant(LuaValue::String(LuaString::from(s)))
+    }
+
+    /* registers */
+
+    pub fn alloc_reg(&mut self) -> usize {
+        self.used_regs += 1;
+        if self.used_regs > MAXREGS {
+            panic!("function or expression needs too many registers");
+        }
+        self.max_regs = self.max_regs.max(self.used_regs);
+        self.used_regs - 1
+    }
+
+    /// Allocate `n` consecutive registers, returns the first one.
+    pub fn alloc_regs(&mut self, n: usize) -> usize {
+        let first = self.used_regs;
+        for _ in 0..n {
+            self.alloc_reg();
+        }
+        first
+    }
+
+    pub fn free_reg(&mut self) {
+        self.used_regs -= 1;
+    }
+
+    pub fn free_regs(&mut self, n: usize) {
+        self.used_regs -= n;
+    }
+
+    pub fn used_regs(&self) -> usize {
+        self.used_regs
+    }
+
+    pub fn set_used_regs(&mut self, n: usize) {
+        self.used_regs = n;
+    }
+
+    /* scopes */
+
+    pub fn enter_scope(&mut self, is_loop: bool) {
+        self.blocks.push(BlockInfo {
+            is_loop,
+            level: self.used_regs,
+            first_local: self.active_locals.len(),
+            breaks: Vec::new(),
+            has_upvalue: false,
+        });
+    }
+
+    /// Leave the innermost block, its locals go out of scope. Captured
+    /// locals are closed here, which is also where the `break`s of a loop
+    /// jump to.
+    pub fn exit_scope(&mut self) {
+        let end_pc = self.insts.len();
+        let block = self.blocks.pop().expect("no block to exit");
+        let locals = self.active_locals.split_off(block.first_local);
+        for local in &locals {
+            self.local_vars[local.debug_index].end_pc = end_pc as i32;
+        }
+        self.used_regs = block.level;
+
+        // a break may leave nested blocks without closing them, the loop
+        // closes everything above its level
+        let captured = locals.iter().any(|local| local.captured);
+        if let Some(parent) = self.blocks.last_mut() {
+            parent.has_upvalue |= captured || block.has_upvalue;
+            // the function's own block is closed by its returns
+            if captured || (block.is_loop && block.has_upvalue) {
+                self.emit_abc(OpCodeEnum::OpClose, block.level, 0, 0, false);
+            }
+        }
+        for pc in block.breaks {
+            self.fix_sj(pc, end_pc);
+        }
+    }
+
+    /// Close the captured locals of the innermost block before jumping back
+    /// to the start of a loop, each iteration gets fresh ones.
+    pub fn close_open_upvalues(&mut self) {
+        let block = self.blocks.last().expect("not in a block");
+        let captured = self.active_locals[block.first_local..]
+            .iter()
+            .find(|local| local.captured);
+        if let Some(local) = captured {
+            let slot = local.slot;
+            self.emit_abc(OpCodeEnum::OpClose, slot, 0, 0, false);
+        }
+    }
+
+    /// Some local of the innermost block is captured.
+    pub fn block_has_upvalue(&self) -> bool {
+        let block = self.blocks.last().expect("not in a block");
+        self.active_locals[block.first_local..]
+            .iter()
+            .any(|local| local.captured)
+    }
+
+    /// Record a pending jump out of the innermost loop.
+    pub fn add_break_jump(&mut self, pc: usize) {
+        match self.blocks.iter_mut().rev().find(|block| block.is_loop) {
+            Some(block) => block.breaks.push(pc),
+            None => panic!("break outside a loop"),
+        }
+    }
+
+    /* local variables and upvalues */
+
+    /// Declare a local in the next free register, it is visible from the
+    /// next instruction on.
+    pub fn add_local_var(&mut self, name: &str) -> usize {
+        let slot = self.alloc_reg();
+        self.local_vars.push(LocalVariable {
+            var_name: name.to_string(),
+            start_pc: self.insts.len() as i32,
+            end_pc: 0,
+        });
+        self.active_locals.push(LocalVarInfo {
+            name: name.to_string(),
+            slot,
+            debug_index: self.local_vars.len() - 1,
+            captured: false,
+        });
+        slot
+    }
+
+    /// Register of the local `name`, the innermost one if it is shadowed.
+    pub fn slot_of_local_var(&self, name: &str) -> Option<usize> {
+        self.active_locals
+            .iter()
+            .rfind(|local| local.name == name)
+            .map(|local| local.slot)
+    }
+
+    /// Index of the upvalue `name`, captured from the enclosing functions if
+    /// it is not an upvalue yet. `None` if no enclosing function has it.
+    pub fn index_of_upvalue(&mut self, name: &str) -> Option<usize> {
+        if let Some(idx) = self.upvalues.iter().position(|up| up.name == name) {
+            return Some(idx);
+        }
+        let parent = self.parent.as_mut()?;
+        let (instack, index) = match parent.capture_local_var(name) {
+            Some(slot) => (true, slot),
+            None => (false, parent.index_of_upvalue(name)?),
+        };
+        self.upvalues.push(UpvalueInfo {
+            name: name.to_string(),
+            instack,
+            index,
+        });
+        Some(self.upvalues.len() - 1)
+    }
+
+    /// Mark the local `name` as captured by a closure, returns its register.
+    fn capture_local_var(&mut self, name: &str) -> Option<usize> {
+        let local = self
+            .active_locals
+            .iter_mut()
+            .rfind(|local| local.name == name)?;
+        local.captured = true;
+        self.need_close = true;
+        Some(local.slot)
+    }
+
+    /* code */
+
+    /// Index of the last instruction.
+    pub fn pc(&self) -> usize {
+        self.insts.len() - 1
+    }
+
+    /// Index of the next instruction.
+    pub fn next_pc(&self) -> usize {
+        self.insts.len()
+    }
+
+    pub fn emit_abc(&mut self, op: OpCodeEnum, a: usize, b: usize, c: usize, k: bool) -> usize {
+        let i =
+            op as u32 | (a as u32) << 7 | (k as u32) << 15 | (b as u32) << 16 | (c as u32) << 24;
+        self.insts.push(i);
+        self.pc()
+    }
+
+    pub fn emit_abx(&mut self, op: OpCodeEnum, a: usize, bx: usize) -> usize {
+        self.insts
+            .push(op as u32 | (a as u32) << 7 | (bx as u32) << 15);
+        self.pc()
+    }
+
+    fn emit_asbx(&mut self, op: OpCodeEnum, a: usize, sbx: i64) -> usize {
+        self.emit_abx(op, a, (sbx + MAXARG_SBX) as usize)
+    }
+
+    pub fn emit_ax(&mut self, op: OpCodeEnum, ax: usize) -> usize {
+        self.insts.push(op as u32 | (ax as u32) << 7);
+        self.pc()
+    }
+
+    /// Jump to the instruction at `target`, `fix_sj` sets it later for
+    /// forward jumps.
+    pub fn emit_jump(&mut self, target: usize) -> usize {
+        self.insts.push(OpCodeEnum::OpJmp as u32);
+        let pc = self.pc();
+        self.fix_sj(pc, target);
+        pc
+    }
+
+    /// Make the jump at `pc` go to the instruction at `target`.
+    pub fn fix_sj(&mut self, pc: usize, target: usize) {
+        let sj = target as i64 - pc as i64 - 1;
+        if sj.abs() > MAXARG_SJ {
+            panic!("control structure too long");
+        }
+        self.insts[pc] = OpCodeEnum::OpJmp as u32 | ((sj + MAXARG_SJ) as u32) << 7;
+    }
+
+    /// R[a] := K[idx], through an EXTRAARG if the index does not fit in Bx.
+    pub fn emit_load_k(&mut self, a: usize, idx: usize) {
+        if idx <= MAXARG_BX {
+            self.emit_abx(OpCodeEnum::OpLOADK, a, idx);
+        } else {
+            self.emit_abx(OpCodeEnum::OpLOADKX, a, 0);
+            self.emit_ax(OpCodeEnum::OpExtraArg, idx);
+        }
+    }
+
+    pub fn emit_load_integer(&mut self, a: usize, i: i64) {
+        if (-MAXARG_SBX..=MAXARG_BX as i64 - MAXARG_SBX).contains(&i) {
+            self.emit_asbx(OpCodeEnum::OpLOADI, a, i);
+        } else {
+            let idx = self.index_of_constant(LuaValue::Integer(i));
+            self.emit_load_k(a, idx);
+        }
+    }
+
+    /// R[a], ..., R[a+n-1] := nil
+    pub fn emit_load_nil(&mut self, a: usize, n: usize) {
+        self.emit_abc(OpCodeEnum::OpLOADNIL, a, n - 1, 0, false);
+    }
+
+    /// Return the `n` values from R[a] on, all values up to the top if `n`
+    /// is `None`.
+    pub fn emit_return(&mut self, a: usize, n: Option<usize>) {
+        let b = n.map_or(0, |n| n + 1);
+        self.emit_abc(OpCodeEnum::OpReturn, a, b, 0, false);
+    }
+
+    pub fn add_sub_func(&mut self, prototype: Prototype) -> usize {
+        self.sub_funcs.push(Rc::new(prototype));
+        self.sub_funcs.len() - 1
+    }
+
+    pub fn into_prototype(mut self) -> Prototype {
+        // a return closes the captured locals (k), and in a vararg function
+        // it gives the number of fixed parameters to restore the frame (C)
+        for i in self.insts.iter_mut() {
+            if i.op_code() == OpCodeEnum::OpReturn as usize {
+                if self.need_close {
+                    *i |= 1 << 15;
+                }
+                if self.is_vararg {
+                    *i |= ((self.num_params + 1) as u32) << 24;
+                }
+            }
+        }
+        Prototype {
+            source: self.source,
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: self.num_params as u8,
+            is_vararg: self.is_vararg as u8,
+            max_statck_size: self.max_regs.max(2) as u8,
+            code: self.insts,
+            constants: self.constants,
+            upvalue_names: self.upvalues.iter().map(|up| up.name.clone()).collect(),
+            upvalues: self
+                .upvalues
+                .iter()
+                .map(|up| Upvalue {
+                    instack: up.instack as u8,
+                    index: up.index as u8,
+                    kind: 0,
+                })
+                .collect(),
+            prototypes: self.sub_funcs,
+            line_info: vec![],
+            abs_line_list: vec![],
+            local_variable: self.local_vars,
+        }
+    }
+}
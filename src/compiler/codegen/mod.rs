@@ -0,0 +1,32 @@
+mod cg_block;
+mod cg_expression;
+mod cg_statement;
+mod func_info;
+
+use crate::{compiler::ast::block::Block, vm::binary_chunk::Prototype};
+
+use self::{cg_block::cg_function_body, func_info::FuncInfo};
+
+/// Generate the prototype of the main function of a chunk, `source` is its
+/// name as given to `load`.
+pub fn gen_prototype(block: &Block, source: &str) -> Prototype {
+    let mut fi = FuncInfo::main(source);
+    cg_function_body(&mut fi, &[], block);
+    fi.into_prototype()
+}
+
+#[test]
+fn test_gen_method_call() {
+    use crate::{compiler::parser::parse, vm::instruction::InstructionOperation};
+
+    let block = parse("test.lua", "acc:deposit(10)");
+    let prototype = gen_prototype(&block, "@test.lua");
+    let op_names: Vec<_> = prototype.code.iter().map(|i| i.op_name()).collect();
+    assert_eq!(
+        op_names,
+        ["VARARGPREP", "GETTABUP", "SELF", "LOADI", "CALL", "RETURN"]
+    );
+    // acc:deposit(10) is acc.deposit(acc, 10), called with 2 arguments
+    assert_eq!(prototype.code[2].abc(), (0, 0, 1));
+    assert_eq!(prototype.code[4].abc(), (0, 3, 1));
+}
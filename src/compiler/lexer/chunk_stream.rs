@@ -17,6 +17,7 @@ impl ChunkStream {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> char {
         if self.eof() {
             return '\0';
@@ -42,11 +43,7 @@ impl ChunkStream {
     }
 
     pub fn peek2(&self) -> char {
-        if self.eof() {
-            '\0'
-        } else {
-            self.chunk[self.index + 1]
-        }
+        self.chunk.get(self.index + 1).copied().unwrap_or('\0')
     }
 
     pub fn eof(&self) -> bool {
@@ -76,7 +73,7 @@ fn test_chuck_steam() {
     assert_eq!(chunk_stream.line, 2);
     assert_eq!(chunk_stream.column, 1);
     assert_eq!(chunk_stream.index, 7);
-    assert_eq!(chunk_stream.eof(), false);
+    assert!(!chunk_stream.eof());
 
     chunk_stream.next(); // eat i
     chunk_stream.next(); // eat n
@@ -84,5 +81,5 @@ fn test_chuck_steam() {
     chunk_stream.next(); // eat 2
 
     assert_eq!(chunk_stream.next(), '\0');
-    assert_eq!(chunk_stream.eof(), true);
+    assert!(chunk_stream.eof());
 }
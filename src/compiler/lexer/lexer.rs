@@ -1,3 +1,5 @@
+use crate::vm::number::str_to_number;
+
 use super::chunk_stream::ChunkStream;
 use super::token::{Token, TokenType};
 use super::utils::{is_digit, is_hex_digit, is_letter, is_whitespace};
@@ -59,6 +61,7 @@ impl Lexer {
         self.is_parsing_token = true;
         self.skip_white_space();
         if self.eof() {
+            self.current_token = Token::eof_token();
             return Token::eof_token();
         }
 
@@ -202,8 +205,9 @@ impl Lexer {
                     } else {
                         Token::concat_token()
                     }
+                } else if is_digit(next_char) {
+                    self.parse_number(String::from("."))
                 } else {
-                    // TODO: next can't be digit
                     Token::dot_token()
                 }
             }
@@ -223,8 +227,8 @@ impl Lexer {
                 // self.stream.next();
                 self.parse_short_string()
             }
-            c if is_digit(c) => self.parse_number(),
-            c if is_letter(c) => self.parse_identifier(),
+            c if is_digit(c) => self.parse_number(String::new()),
+            c if is_letter(c) || c == '_' => self.parse_identifier(),
             _ => todo!(),
         };
 
@@ -295,50 +299,55 @@ impl Lexer {
      * 3.0     3.1416     314.16e-2     0.31416E1     34e1
      * 0x0.1E  0xA23p-4   0X1.921FB54442D18P+1
      */
-    fn parse_number(&mut self) -> Token {
-        let mut number_string = String::new();
-
-        let mut first_char = self.stream.next();
-        number_string.push(first_char);
-        let second_char = self.stream.peek();
-        match second_char {
-            c if is_digit(c) || c == '.' => {
-                while is_digit(self.stream.peek()) {
-                    number_string.push(self.stream.next());
-                }
-                if self.stream.peek() == '.' {
-                    number_string.push(self.stream.next()); // eat .
-
-                    while is_digit(self.stream.peek()) {
-                        number_string.push(self.stream.next());
-                    }
-                    Token::number_token(&number_string)
-                } else {
-                    Token::number_token(&number_string)
-                }
-            }
-            c if c == 'x' && first_char == '0' => {
-                number_string.push(self.stream.next()); // eat x
-                while is_hex_digit(self.stream.peek()) {
+    fn parse_number(&mut self, mut number_string: String) -> Token {
+        let mut exponent = ['e', 'E'];
+        if number_string.is_empty()
+            && self.stream.peek() == '0'
+            && matches!(self.stream.peek2(), 'x' | 'X')
+        {
+            number_string.push(self.stream.next());
+            number_string.push(self.stream.next());
+            exponent = ['p', 'P'];
+        }
+        loop {
+            let c = self.stream.peek();
+            if exponent.contains(&c) {
+                number_string.push(self.stream.next());
+                if matches!(self.stream.peek(), '+' | '-') {
                     number_string.push(self.stream.next());
                 }
-                Token::number_token(&number_string)
+            } else if is_hex_digit(c) || c == '.' {
+                number_string.push(self.stream.next());
+            } else {
+                break;
             }
-            _ => Token::number_token(&number_string),
         }
+        // a numeral touching a letter is malformed
+        if is_letter(self.stream.peek()) {
+            number_string.push(self.stream.next());
+        }
+        if str_to_number(number_string.as_bytes()).is_none() {
+            panic!(
+                "malformed number near '{}' at {}",
+                number_string,
+                self.stream.get_position()
+            );
+        }
+        Token::number_token(&number_string)
     }
 
     fn parse_identifier(&mut self) -> Token {
         let mut identifier_string = String::new();
         identifier_string.push(self.stream.next());
         let mut letter = self.stream.peek();
-        while is_letter(letter) || letter == '-' || is_digit(letter) {
+        while is_letter(letter) || letter == '_' || is_digit(letter) {
             self.stream.next();
             identifier_string.push(letter);
             letter = self.stream.peek();
         }
 
         match &identifier_string[..] {
+            "and" => Token::and_token(),
             "break" => Token::break_token(),
             "do" => Token::do_token(),
             "else" => Token::else_token(),
@@ -352,6 +361,8 @@ impl Lexer {
             "in" => Token::in_token(),
             "local" => Token::local_token(),
             "nil" => Token::nil_token(),
+            "not" => Token::not_token(),
+            "or" => Token::or_token(),
             "repeat" => Token::repeat_token(),
             "return" => Token::return_token(),
             "then" => Token::then_token(),
@@ -362,7 +373,7 @@ impl Lexer {
         }
     }
 
-    fn skip_specific_char(&mut self, target_char: char) -> () {
+    fn skip_specific_char(&mut self, target_char: char) {
         let char_should_skip = self.stream.peek();
         if char_should_skip == target_char {
             self.stream.next();
@@ -376,7 +387,7 @@ impl Lexer {
         }
     }
 
-    fn skip_white_space(&mut self) -> () {
+    fn skip_white_space(&mut self) {
         loop {
             if self.eof() {
                 break;
@@ -391,7 +402,7 @@ impl Lexer {
     }
 
     fn eof(&self) -> bool {
-        return self.stream.eof();
+        self.stream.eof()
     }
 }
 
@@ -472,13 +483,39 @@ fn test_parse_digit() {
     assert_eq!(lexer.next_token(), Token::number_token("0xBEBADA"));
     assert_eq!(lexer.next_token(), Token::number_token("3.0"));
     assert_eq!(lexer.next_token(), Token::number_token("3.1416"));
+
+    let mut lexer = Lexer::create(
+        "test.lua",
+        "314.16e-2 0.31416E1 34e1 0x0.1E 0xA23p-4 0X1.921FB54442D18P+1 .5 a.b",
+    );
+    for numeral in [
+        "314.16e-2",
+        "0.31416E1",
+        "34e1",
+        "0x0.1E",
+        "0xA23p-4",
+        "0X1.921FB54442D18P+1",
+        ".5",
+    ] {
+        assert_eq!(lexer.next_token(), Token::number_token(numeral));
+    }
+    assert_eq!(lexer.next_token(), Token::identifier_token("a"));
+    assert_eq!(lexer.next_token(), Token::dot_token());
+    assert_eq!(lexer.next_token(), Token::identifier_token("b"));
+}
+
+#[test]
+#[should_panic(expected = "malformed number near '3..x'")]
+fn test_malformed_number() {
+    let mut lexer = Lexer::create("test.lua", "3..x");
+    lexer.next_token();
 }
 
 #[test]
 fn test_parse_identifier() {
     let mut lexer = Lexer::new(ChunkStream {
         chunk_name: String::from("test.lua"),
-        chunk: String::from("if true then else end function() end param1")
+        chunk: String::from("if true then else end function() end param1 _param")
             .chars()
             .collect(),
         line: 1,
@@ -496,5 +533,21 @@ fn test_parse_identifier() {
     assert_eq!(lexer.next_token(), Token::close_paren_token());
     assert_eq!(lexer.next_token(), Token::end_token());
     assert_eq!(lexer.next_token(), Token::identifier_token("param1"));
-    // assert_eq!(lexer.next_token(), Token::identifier_token("_param"));
+    assert_eq!(lexer.next_token(), Token::identifier_token("_param"));
+}
+
+#[test]
+fn test_parse_keyword_operator() {
+    let mut lexer = Lexer::create("test.lua", "a and b or not c == d");
+
+    assert_eq!(lexer.next_token(), Token::identifier_token("a"));
+    assert_eq!(lexer.next_token(), Token::and_token());
+    assert_eq!(lexer.next_token(), Token::identifier_token("b"));
+    assert_eq!(lexer.next_token(), Token::or_token());
+    assert_eq!(lexer.next_token(), Token::not_token());
+    assert_eq!(lexer.next_token(), Token::identifier_token("c"));
+    assert_eq!(lexer.next_token(), Token::equal_token());
+    assert_eq!(lexer.next_token(), Token::identifier_token("d"));
+    assert_eq!(lexer.next_token(), Token::eof_token());
+    assert_eq!(lexer.peek_token(), Token::eof_token());
 }
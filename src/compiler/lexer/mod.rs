@@ -1,4 +1,5 @@
 pub mod chunk_stream;
+#[allow(clippy::module_inception)]
 pub mod lexer;
 pub mod token;
 mod utils;
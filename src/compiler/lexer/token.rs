@@ -189,7 +189,7 @@ impl Token {
 
     pub fn equal_token() -> Token {
         Token {
-            kind: TokenType::OperatorAssign,
+            kind: TokenType::OperatorEq,
             value: String::from("=="),
         }
     }
@@ -476,4 +476,25 @@ impl Token {
             value: String::from("while"),
         }
     }
+
+    pub fn and_token() -> Token {
+        Token {
+            kind: TokenType::OperatorAnd,
+            value: String::from("and"),
+        }
+    }
+
+    pub fn or_token() -> Token {
+        Token {
+            kind: TokenType::OperatorOr,
+            value: String::from("or"),
+        }
+    }
+
+    pub fn not_token() -> Token {
+        Token {
+            kind: TokenType::OperatorNot,
+            value: String::from("not"),
+        }
+    }
 }
@@ -1,17 +1,17 @@
 pub fn is_whitespace(c: char) -> bool {
-    c == '\n' || c == '\r' || c == ' '
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0b' | '\x0c')
 }
 
 pub fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 pub fn is_hex_digit(c: char) -> bool {
-    is_digit(c) || (c >= 'a' && c <= 'f') || (c >= 'A' && c <= 'F')
+    c.is_ascii_hexdigit()
 }
 
 pub fn is_letter(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z')
+    c.is_ascii_alphabetic()
 }
 
 #[test]
@@ -1,3 +1,14 @@
-mod ast;
-mod lexer;
-mod parser;
+pub mod ast;
+pub mod codegen;
+pub mod lexer;
+pub mod parser;
+
+use crate::vm::binary_chunk::Prototype;
+
+/// Compile the Lua source `chunk` into the prototype of its main function.
+///
+/// Syntax errors panic, as the parser does not recover from them.
+pub fn compile(chunk: &str, chunk_name: &str) -> Prototype {
+    let block = parser::parse(chunk_name, chunk);
+    codegen::gen_prototype(&block, chunk_name)
+}
@@ -2,4 +2,7 @@ pub mod parse_expression;
 pub mod parse_table_constructor_expression;
 
 pub mod parse_statement;
+#[allow(clippy::module_inception)]
 mod parser;
+
+pub use parser::parse;
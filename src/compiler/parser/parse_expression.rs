@@ -1,6 +1,9 @@
-use crate::compiler::{
-    ast::{expression::*, statement::Statement},
-    lexer::{chunk_stream::ChunkStream, lexer::Lexer, token::TokenType},
+use crate::{
+    compiler::{
+        ast::expression::*,
+        lexer::{lexer::Lexer, token::TokenType},
+    },
+    vm::{lua_value::LuaValue, number::str_to_number},
 };
 
 use super::{
@@ -8,7 +11,7 @@ use super::{
 };
 
 pub fn parse_expression_list(lexer: &mut Lexer) -> Vec<Expression> {
-    let mut exp_list: Vec<Expression> = Vec::new();
+    let mut exp_list: Vec<Expression> = vec![parse_expression(lexer)];
 
     while lexer.peek_token().kind == TokenType::SeparetorComma {
         lexer.next_token(); // eat ,
@@ -101,13 +104,13 @@ fn parse_expression_6(lexer: &mut Lexer) -> Expression {
 }
 
 fn parse_expression_5(lexer: &mut Lexer) -> Expression {
-    let mut exp = parse_expression_4(lexer);
+    let exp = parse_expression_4(lexer);
 
     if lexer.peek_token().kind != TokenType::OperatorConcat {
         return exp;
     }
 
-    let mut exps = Vec::new();
+    let mut exps = vec![exp];
     while lexer.peek_token().kind == TokenType::OperatorConcat {
         lexer.next_token();
         exps.push(parse_expression_4(lexer));
@@ -146,9 +149,8 @@ fn parse_expression_2(lexer: &mut Lexer) -> Expression {
     match lexer.peek_token().kind {
         TokenType::OperatorLen
         | TokenType::OperatorNot
-        | TokenType::OperatorLen
-        | TokenType::OperatorBnot
-        | TokenType::OperatorUnm => {
+        | TokenType::OperatorWave
+        | TokenType::OperatorMinus => {
             let operator = lexer.peek_token();
             lexer.next_token();
             Expression::unary_expression(operator.value, parse_expression_2(lexer))
@@ -162,7 +164,8 @@ fn parse_expression_1(lexer: &mut Lexer) -> Expression {
     if lexer.peek_token().kind == TokenType::OperatorPow {
         let operator = lexer.peek_token();
         lexer.next_token();
-        exp_l = Expression::binary_expression(operator.value, exp_l, parse_expression_0(lexer))
+        // ^ is right associative and its exponent may be a unary expression
+        exp_l = Expression::binary_expression(operator.value, exp_l, parse_expression_2(lexer))
     }
     exp_l
 }
@@ -202,12 +205,12 @@ fn parse_expression_0(lexer: &mut Lexer) -> Expression {
 
 fn parse_number_expression(lexer: &mut Lexer) -> Expression {
     let token = lexer.peek_token();
-    if token.value.contains('.') {
-        lexer.next_token();
-        Expression::FloatExpresion(token.value.parse::<f64>().unwrap())
-    } else {
-        lexer.next_token();
-        Expression::IntegerExpression(token.value.parse::<i64>().unwrap())
+    lexer.next_token();
+    // the lexer only produces well-formed numerals
+    match str_to_number(token.value.as_bytes()) {
+        Some(LuaValue::Integer(i)) => Expression::IntegerExpression(i),
+        Some(LuaValue::Number(n)) => Expression::FloatExpresion(n),
+        _ => unreachable!("malformed number {}", token.value),
     }
 }
 
@@ -229,7 +232,7 @@ fn parse_param_list(lexer: &mut Lexer) -> (bool, Vec<String>) {
         TokenType::SeparatorCloseParenthesis => (false, Vec::new()),
         TokenType::Vararg => {
             lexer.next_token();
-            (false, Vec::new())
+            (true, Vec::new())
         }
         _ => {
             let mut is_vararg = false;
@@ -254,12 +257,14 @@ fn parse_param_list(lexer: &mut Lexer) -> (bool, Vec<String>) {
 }
 
 pub fn parse_prefix_expression(lexer: &mut Lexer) -> Expression {
-    if lexer.peek_token().kind == TokenType::Identifier {
-        Expression::NameString(lexer.should_be_identifier_token().value)
+    let exp = if lexer.peek_token().kind == TokenType::Identifier {
+        let name = lexer.should_be_identifier_token();
+        lexer.next_token();
+        Expression::NameString(name.value)
     } else {
-        let exp = parse_parenthesis_expression(lexer);
-        _parse_prefix_expression(lexer, exp)
-    }
+        parse_parenthesis_expression(lexer)
+    };
+    _parse_prefix_expression(lexer, exp)
 }
 
 fn _parse_prefix_expression(lexer: &mut Lexer, mut exp: Expression) -> Expression {
@@ -274,6 +279,7 @@ fn _parse_prefix_expression(lexer: &mut Lexer, mut exp: Expression) -> Expressio
             TokenType::SeparatorDot => {
                 lexer.next_token();
                 let name = lexer.should_be_identifier_token();
+                lexer.next_token();
                 let key_exp = Expression::StringExpression(name.value);
                 Expression::table_access_expression(exp, key_exp)
             }
@@ -310,6 +316,7 @@ fn parse_name_expression(lexer: &mut Lexer) -> Expression {
     if lexer.peek_token().kind == TokenType::SeparatorColon {
         lexer.next_token();
         let token = lexer.should_be_identifier_token();
+        lexer.next_token();
         Expression::StringExpression(token.value)
     } else {
         Expression::StringExpression(String::from(""))
@@ -327,17 +334,18 @@ fn parse_args(lexer: &mut Lexer) -> Vec<Expression> {
             lexer.next_if_special_token(TokenType::SeparatorCloseParenthesis);
             args
         }
-        TokenType::SeparatorOpenBrace => {
-            todo!()
-        }
+        TokenType::SeparatorOpenBrace => vec![parse_table_constructor_expression(lexer)],
         _ => {
-            lexer.next_if_special_token(TokenType::String);
-            let string = lexer.peek_token();
+            let string = lexer.should_be_special_token(TokenType::String);
+            lexer.next_token();
             vec![Expression::StringExpression(string.value)]
         }
     }
 }
 
+#[cfg(test)]
+use crate::compiler::lexer::chunk_stream::ChunkStream;
+
 #[test]
 fn test_simple_expression() {
     let exp = parse_expression(&mut Lexer::new(ChunkStream::new("test.lua", "1 + 2 * 3")));
@@ -360,13 +368,13 @@ fn test_function_expression() {
     )));
 
     match exp {
-        Expression::FunctionDefinedExpression(fnDef) => {
-            assert_eq!(fnDef.is_vararg, false);
+        Expression::FunctionDefinedExpression(fn_def) => {
+            assert!(!fn_def.is_vararg);
             assert_eq!(
-                fnDef.param_list,
+                fn_def.param_list,
                 vec!["param1".to_string(), "param2".to_string()]
             );
-            // assert_eq!(fnDef.block.statements[0], Statement::BreakStatement);
+            // assert_eq!(fn_def.block.statements[0], Statement::BreakStatement);
         }
         _ => panic!("{:#?}", exp),
     }
@@ -380,18 +388,41 @@ fn test_vararg_function_expression() {
     )));
 
     match exp {
-        Expression::FunctionDefinedExpression(fnDef) => {
-            assert_eq!(fnDef.is_vararg, true);
+        Expression::FunctionDefinedExpression(fn_def) => {
+            assert!(fn_def.is_vararg);
             assert_eq!(
-                fnDef.param_list,
+                fn_def.param_list,
                 vec!["param1".to_string(), "param2".to_string()]
             );
-            // assert_eq!(fnDef.block.statements[0], Statement::BreakStatement);
+            // assert_eq!(fn_def.block.statements[0], Statement::BreakStatement);
         }
         _ => panic!("{:#?}", exp),
     }
 }
 
+#[test]
+fn test_vararg_only_function_expression() {
+    let exp = parse_expression(&mut Lexer::create("test.lua", "function (...) end"));
+
+    assert!(matches!(exp, Expression::FunctionDefinedExpression(ref fn_def) if fn_def.is_vararg));
+}
+
+#[test]
+fn test_unary_expression() {
+    // -x ^ 2 is -(x ^ 2)
+    let exp = parse_expression(&mut Lexer::create("test.lua", "-x ^ -2"));
+
+    let Expression::UnaryExpression(unary) = exp else {
+        panic!("{:#?}", exp)
+    };
+    assert_eq!(unary.operator, "-");
+    let Expression::BinaryExpression(pow) = *unary.exp else {
+        panic!("{:#?}", unary.exp)
+    };
+    assert_eq!(pow.operator, "^");
+    assert!(matches!(*pow.exp_r, Expression::UnaryExpression(_)));
+}
+
 #[test]
 fn test_function_call_expression() {
     let exp = parse_expression(&mut Lexer::new(ChunkStream::new(
@@ -401,3 +432,28 @@ fn test_function_call_expression() {
 
     print!("{:#?}", exp);
 }
+
+#[test]
+fn test_method_call_expression() {
+    let exp = parse_expression(&mut Lexer::new(ChunkStream::new(
+        "test.lua",
+        "acc:deposit(10, 20)",
+    )));
+
+    match exp {
+        Expression::FunctionCallExpression(call) => {
+            assert!(matches!(*call.prefix_exp, Expression::NameString(ref name) if name == "acc"));
+            assert!(
+                matches!(*call.name_exp, Expression::StringExpression(ref name) if name == "deposit")
+            );
+            assert!(matches!(
+                call.args[..],
+                [
+                    Expression::IntegerExpression(10),
+                    Expression::IntegerExpression(20)
+                ]
+            ));
+        }
+        _ => panic!("{:#?}", exp),
+    }
+}
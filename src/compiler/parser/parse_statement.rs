@@ -1,15 +1,6 @@
-use std::ptr::null;
-
 use crate::compiler::{
-    ast::{
-        block::Block,
-        expression::{Expression, TableAccessExpression},
-        statement::{
-            AssignStatement, ForStatement, IfStatement, LocalFunctionDefinedStatement,
-            LocalVarDeclareStatement, RepeatStatement, Statement, WhileStatement,
-        },
-    },
-    lexer::{chunk_stream::ChunkStream, lexer::Lexer, token::TokenType},
+    ast::{block::Block, expression::Expression, statement::Statement},
+    lexer::{lexer::Lexer, token::TokenType},
     parser::{
         parse_expression::{
             parse_expression, parse_expression_list, parse_function_defined_expression,
@@ -65,7 +56,7 @@ fn parse_do_statement(lexer: &mut Lexer) -> Statement {
     let block = parse_block(lexer);
 
     lexer.next_if_special_token(TokenType::KeywrodEnd);
-    Statement::DotStatement
+    Statement::DoStatement(block)
 }
 
 fn parse_while_statement(lexer: &mut Lexer) -> Statement {
@@ -88,7 +79,7 @@ fn parse_repeat_statement(lexer: &mut Lexer) -> Statement {
 }
 
 fn parse_if_statement(lexer: &mut Lexer, is_if: bool) -> Statement {
-    if is_if == true {
+    if is_if {
         lexer.next_if_special_token(TokenType::KeywrodIf);
     } else {
         lexer.next_if_special_token(TokenType::KeywrodElseIf);
@@ -102,27 +93,29 @@ fn parse_if_statement(lexer: &mut Lexer, is_if: bool) -> Statement {
         let else_statement = parse_if_statement(lexer, false);
         let else_block = Block {
             statements: vec![else_statement],
-            return_expression: vec![],
+            return_expression: None,
         };
         Statement::if_statement(condition, then_block, else_block)
     } else if lexer.peek_token().kind == TokenType::KeywrodElse {
         lexer.next_token();
         let else_block = parse_block(lexer);
+        lexer.next_if_special_token(TokenType::KeywrodEnd);
         Statement::if_statement(condition, then_block, else_block)
     } else {
+        lexer.next_if_special_token(TokenType::KeywrodEnd);
         Statement::if_statement(
             condition,
             then_block,
             Block {
                 statements: vec![],
-                return_expression: vec![],
+                return_expression: None,
             },
         )
     }
 }
 
-fn parse_for_statement(lexer: &mut Lexer) -> Statement {
-    Statement::for_statement(todo!(), todo!(), todo!(), todo!())
+fn parse_for_statement(_lexer: &mut Lexer) -> Statement {
+    todo!()
 }
 
 /**
@@ -130,30 +123,36 @@ fn parse_for_statement(lexer: &mut Lexer) -> Statement {
  */
 fn parse_function_defined_statement(lexer: &mut Lexer) -> Statement {
     lexer.next_if_special_token(TokenType::KeywrodFunction);
-    let (has_colol, fn_name_exp) = parse_function_name(lexer);
-    let fn_body_exp = parse_function_defined_expression(lexer);
-    // TODO: has colon case
+    let (has_colon, fn_name_exp) = parse_function_name(lexer);
+    let mut fn_body_exp = parse_function_defined_expression(lexer);
+    // `function t:m(...)` is `t.m = function(self, ...)`
+    if let (true, Expression::FunctionDefinedExpression(fn_def)) = (has_colon, &mut fn_body_exp) {
+        fn_def.param_list.insert(0, "self".to_string());
+    }
     Statement::assign_statement(vec![fn_name_exp], vec![fn_body_exp])
 }
 
+/// funcname ::= Name {'.' Name} [':' Name]
 fn parse_function_name(lexer: &mut Lexer) -> (bool, Expression) {
     let fn_name = lexer.should_be_identifier_token();
+    lexer.next_token();
     let mut exp: Expression = Expression::NameString(fn_name.value);
 
     while lexer.peek_token().kind == TokenType::SeparatorDot {
         lexer.next_token(); // eat .
         let name = lexer.should_be_identifier_token();
+        lexer.next_token();
         let key_exp = Expression::StringExpression(name.value);
 
         exp = Expression::table_access_expression(exp, key_exp);
     }
-    let mut has_colon = false;
-    while lexer.peek_token().kind == TokenType::SeparatorColon {
+    let has_colon = lexer.peek_token().kind == TokenType::SeparatorColon;
+    if has_colon {
         lexer.next_token(); // eat :
         let name = lexer.should_be_identifier_token();
+        lexer.next_token();
         let key_exp = Expression::StringExpression(name.value);
         exp = Expression::table_access_expression(exp, key_exp);
-        let has_colon = true;
     }
 
     (has_colon, exp)
@@ -171,14 +170,13 @@ fn parse_local_assign_or_function_defined_statement(lexer: &mut Lexer) -> Statem
 fn _parse_local_function_defined_statement(lexer: &mut Lexer) -> Statement {
     lexer.next_if_special_token(TokenType::KeywrodFunction);
     let name = lexer.should_be_identifier_token();
+    lexer.next_token();
     let fn_body_exp = parse_function_defined_expression(lexer);
 
     Statement::local_function_defined_statement(name.value, fn_body_exp)
 }
 
 fn _parse_local_var_defined_statement(lexer: &mut Lexer) -> Statement {
-    let var_name = lexer.should_be_identifier_token();
-
     let name_list = _parse_name_list(lexer);
 
     let mut exp_list: Vec<Expression> = Vec::new();
@@ -190,43 +188,66 @@ fn _parse_local_var_defined_statement(lexer: &mut Lexer) -> Statement {
     Statement::local_var_declare_statement(name_list, exp_list)
 }
 
+/// namelist ::= Name {',' Name}
 fn _parse_name_list(lexer: &mut Lexer) -> Vec<String> {
-    let mut name_list = Vec::new();
+    let mut name_list = vec![lexer.should_be_identifier_token().value];
+    lexer.next_token();
     while lexer.peek_token().kind == TokenType::SeparetorComma {
         lexer.next_token();
         let token = lexer.should_be_identifier_token();
+        lexer.next_token();
         name_list.push(token.value);
     }
     name_list
 }
 
+/// A prefix expression is a function call statement if it ends with a
+/// call, otherwise it is the first variable of an assignment.
 fn parse_assign_or_function_call_statement(lexer: &mut Lexer) -> Statement {
     let prefix_exp = parse_prefix_expression(lexer);
 
-    if true {
-        todo!()
-    } else {
-        parse_assign_statement(lexer)
+    match prefix_exp {
+        Expression::FunctionCallExpression(call)
+            if !matches!(
+                lexer.peek_token().kind,
+                TokenType::OperatorAssign | TokenType::SeparetorComma
+            ) =>
+        {
+            Statement::FunctionCallStatement(call)
+        }
+        var => parse_assign_statement(lexer, var),
     }
 }
 
-fn parse_assign_statement(lexer: &mut Lexer) -> Statement {
-    let var_list = parse_var_list(lexer);
+fn parse_assign_statement(lexer: &mut Lexer, var: Expression) -> Statement {
+    let var_list = parse_var_list(lexer, var);
     lexer.next_if_special_token(TokenType::OperatorAssign); // eat =
     let exp_list = parse_expression_list(lexer);
     Statement::assign_statement(var_list, exp_list)
 }
 
-fn parse_var_list(lexer: &mut Lexer) -> Vec<Expression> {
-    let mut var_list = Vec::new();
+/// varlist ::= var {',' var}
+fn parse_var_list(lexer: &mut Lexer, var: Expression) -> Vec<Expression> {
+    let mut var_list = vec![check_var(var)];
     while lexer.peek_token().kind == TokenType::SeparetorComma {
         lexer.next_token();
         let exp = parse_prefix_expression(lexer);
-        var_list.push(exp);
+        var_list.push(check_var(exp));
     }
     var_list
 }
 
+/// Only names and table fields can be assigned to.
+fn check_var(exp: Expression) -> Expression {
+    match exp {
+        Expression::NameString(_) | Expression::TableAccessExpression(_) => exp,
+        _ => panic!("syntax error, cannot assign to {:?}", exp),
+    }
+}
+
+#[cfg(test)]
+use crate::compiler::lexer::chunk_stream::ChunkStream;
+
 #[test]
 fn test_parse_simple_while_statement() {
     let stmt = parse_statement(&mut Lexer::new(ChunkStream::new(
@@ -244,3 +265,106 @@ fn test_parse_simple_if_statement() {
     )));
     print!("statement {:?}", stmt)
 }
+
+#[test]
+fn test_parse_method_defined_statement() {
+    let stmt = parse_statement(&mut Lexer::new(ChunkStream::new(
+        "test.lua",
+        "function Account:deposit(v) break end",
+    )));
+    let Statement::AssignStatement(assign) = stmt else {
+        panic!("{:?}", stmt)
+    };
+    match (&assign.var_list[..], &assign.exp_list[..]) {
+        (
+            [Expression::TableAccessExpression(access)],
+            [Expression::FunctionDefinedExpression(fn_def)],
+        ) => {
+            assert!(
+                matches!(&*access.prefix_exp, Expression::NameString(name) if name == "Account")
+            );
+            assert!(
+                matches!(&*access.key_exp, Expression::StringExpression(key) if key == "deposit")
+            );
+            assert_eq!(fn_def.param_list, vec!["self".to_string(), "v".to_string()]);
+        }
+        _ => panic!("{:?}", assign),
+    }
+}
+
+#[test]
+fn test_parse_function_defined_statement() {
+    let stmt = parse_statement(&mut Lexer::new(ChunkStream::new(
+        "test.lua",
+        "function a.b.c(v) break end",
+    )));
+    let Statement::AssignStatement(assign) = stmt else {
+        panic!("{:?}", stmt)
+    };
+    match &assign.exp_list[..] {
+        [Expression::FunctionDefinedExpression(fn_def)] => {
+            assert_eq!(fn_def.param_list, vec!["v".to_string()]);
+        }
+        _ => panic!("{:?}", assign),
+    }
+}
+
+#[test]
+fn test_parse_function_call_statement() {
+    let stmt = parse_statement(&mut Lexer::create("test.lua", "acc:deposit(10) a.b = 1"));
+    let Statement::FunctionCallStatement(call) = stmt else {
+        panic!("{:?}", stmt)
+    };
+    assert!(matches!(*call.prefix_exp, Expression::NameString(ref name) if name == "acc"));
+    assert!(matches!(call.args[..], [Expression::IntegerExpression(10)]));
+}
+
+#[test]
+fn test_parse_assign_statement() {
+    let stmt = parse_statement(&mut Lexer::create("test.lua", "a, t.b, f().c = 1, 2"));
+    let Statement::AssignStatement(assign) = stmt else {
+        panic!("{:?}", stmt)
+    };
+    assert!(matches!(
+        assign.var_list[..],
+        [
+            Expression::NameString(_),
+            Expression::TableAccessExpression(_),
+            Expression::TableAccessExpression(_)
+        ]
+    ));
+    assert_eq!(assign.exp_list.len(), 2);
+}
+
+#[test]
+#[should_panic(expected = "cannot assign")]
+fn test_parse_assign_to_call() {
+    parse_statement(&mut Lexer::create("test.lua", "f(), a = 1"));
+}
+
+#[test]
+fn test_parse_local_statement() {
+    let mut lexer = Lexer::create("test.lua", "local a, b = 1 local function f() end");
+    let stmt = parse_statement(&mut lexer);
+    let Statement::LocalVarDeclareStatement(local) = stmt else {
+        panic!("{:?}", stmt)
+    };
+    assert_eq!(local.name_list, vec!["a".to_string(), "b".to_string()]);
+    assert!(matches!(
+        local.exp_list[..],
+        [Expression::IntegerExpression(1)]
+    ));
+
+    let stmt = parse_statement(&mut lexer);
+    assert!(matches!(stmt, Statement::LocalFunctionDefinedStatement(ref f) if f.name == "f"));
+}
+
+#[test]
+fn test_parse_if_statement_end() {
+    let mut lexer = Lexer::create("test.lua", "if a then elseif b then else end x = 1");
+    let stmt = parse_statement(&mut lexer);
+    assert!(matches!(stmt, Statement::IfStatement(_)));
+    let stmt = parse_statement(&mut lexer);
+    assert!(matches!(stmt, Statement::AssignStatement(_)));
+    assert_eq!(lexer.peek_token().kind, TokenType::Eof);
+}
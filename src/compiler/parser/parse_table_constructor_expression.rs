@@ -53,23 +53,24 @@ pub fn parse_field(lexer: &mut Lexer) -> (Expression, Expression) {
     } else {
         let exp = parse_expression(lexer);
         match exp {
-            Expression::NameString(name) => {
-                lexer.next_token();
-                lexer.next_if_special_token(TokenType::OperatorAssign);
+            Expression::NameString(name)
+                if lexer.peek_token().kind == TokenType::OperatorAssign =>
+            {
+                lexer.next_token(); // eat =
                 let key = Expression::StringExpression(name);
                 let value = parse_expression(lexer);
                 (key, value)
             }
-            otherExp => (Expression::NilExpression, otherExp),
+            other_exp => (Expression::NilExpression, other_exp),
         }
     }
 }
 
 pub fn is_field_separator(token: Token) -> bool {
-    match token.kind {
-        TokenType::SeparetorComma | TokenType::SeparatorSemicolon => true,
-        _ => false,
-    }
+    matches!(
+        token.kind,
+        TokenType::SeparetorComma | TokenType::SeparatorSemicolon
+    )
 }
 
 #[test]
@@ -81,3 +82,31 @@ fn test_simple_table_constructor() {
 
     println!("{:#?}", &exp)
 }
+
+#[test]
+fn test_name_fields() {
+    let parse =
+        |chunk| match parse_table_constructor_expression(&mut Lexer::create("test.lua", chunk)) {
+            Expression::TableConstructorExpression(table) => table,
+            exp => panic!("{:#?}", exp),
+        };
+
+    // a bare name is a list item
+    let table = parse("{x}");
+    assert!(matches!(table.key_exps[..], [Expression::NilExpression]));
+    assert!(matches!(&table.value_exps[..], [Expression::NameString(name)] if name == "x"));
+
+    // `name = exp` is a record field
+    let table = parse("{x = 1}");
+    assert!(matches!(&table.key_exps[..], [Expression::StringExpression(key)] if key == "x"));
+    assert!(matches!(
+        table.value_exps[..],
+        [Expression::IntegerExpression(1)]
+    ));
+
+    let table = parse("{x, y = x}");
+    assert!(matches!(
+        table.key_exps[..],
+        [Expression::NilExpression, Expression::StringExpression(_)]
+    ));
+}
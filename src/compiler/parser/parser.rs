@@ -8,6 +8,14 @@ use super::super::lexer::token::Token;
 use super::parse_expression::parse_expression_list;
 use super::parse_statement::parse_statement;
 
+/// chunk ::= block
+pub fn parse(chunk_name: &str, chunk: &str) -> Block {
+    let mut lexer = Lexer::create(chunk_name, chunk);
+    let block = parse_block(&mut lexer);
+    lexer.should_be_special_token(TokenType::Eof);
+    block
+}
+
 pub fn parse_block(lexer: &mut Lexer) -> Block {
     Block {
         statements: parse_statements(lexer),
@@ -27,37 +35,36 @@ fn parse_statements(lexer: &mut Lexer) -> Vec<Statement> {
     statements
 }
 
-fn parse_return_expression(lexer: &mut Lexer) -> Vec<Expression> {
-    let expressions = Vec::new();
+fn parse_return_expression(lexer: &mut Lexer) -> Option<Vec<Expression>> {
     let token = lexer.peek_token();
     if token.kind != TokenType::KeywrodReturn {
-        return expressions;
+        return None;
     }
 
     lexer.next_token(); // eat return keyword
-    match lexer.peek_token().kind {
+    let expressions = match lexer.peek_token().kind {
         TokenType::Eof
         | TokenType::KeywrodEnd
         | TokenType::KeywrodElse
-        | TokenType::KeywrodElse
-        | TokenType::KeywrodUntil => expressions,
-        TokenType::SeparatorSemicolon => expressions,
-        _ => {
-            let exps = parse_expression_list(lexer);
-
-            exps
-        }
+        | TokenType::KeywrodElseIf
+        | TokenType::KeywrodUntil
+        | TokenType::SeparatorSemicolon => Vec::new(),
+        _ => parse_expression_list(lexer),
+    };
+    if lexer.peek_token().kind == TokenType::SeparatorSemicolon {
+        lexer.next_token();
     }
+    Some(expressions)
 }
 
 fn is_return_or_block_end(token: Token) -> bool {
-    match token.kind {
+    matches!(
+        token.kind,
         TokenType::KeywrodReturn
-        | TokenType::Eof
-        | TokenType::KeywrodEnd
-        | TokenType::KeywrodElse
-        | TokenType::KeywrodElseIf
-        | TokenType::KeywrodUntil => true,
-        _ => false,
-    }
+            | TokenType::Eof
+            | TokenType::KeywrodEnd
+            | TokenType::KeywrodElse
+            | TokenType::KeywrodElseIf
+            | TokenType::KeywrodUntil
+    )
 }
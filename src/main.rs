@@ -1,6 +1,44 @@
-pub mod compiler;
-pub mod vm;
+use crescent::vm::{
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState, LUA_MULTRET},
+};
+
+/// Run the script, source or binary chunk, its arguments are passed to the
+/// main chunk as varargs and are in the global `arg` table, with the script
+/// at index 0.
+fn run(script: &str, args: &[String]) -> Result<(), LuaError> {
+    let mut state = LuaState::new();
+    state.open_base()?;
+    state.open_coroutine()?;
+    state.open_string()?;
+    state.open_table()?;
+
+    state.create_table(args.len(), 1);
+    state.push_string(script);
+    state.set_i(-2, 0)?;
+    for (i, arg) in args.iter().enumerate() {
+        state.push_string(arg.as_str());
+        state.set_i(-2, i as i64 + 1)?;
+    }
+    state.set_global("arg")?;
+
+    let chunk = std::fs::read(script)
+        .map_err(|err| LuaError::new(format!("cannot open {}: {}", script, err)))?;
+    state.load(chunk, &format!("@{}", script), None)?;
+    for arg in args {
+        state.push_string(arg.as_str());
+    }
+    state.call(args.len(), LUA_MULTRET)
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().collect();
+    let Some(script) = args.get(1) else {
+        eprintln!("usage: {} script [args]", args[0]);
+        std::process::exit(1);
+    };
+    if let Err(err) = run(script, &args[2..]) {
+        eprintln!("{}: {}", args[0], err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,734 @@
+use std::io::Write;
+
+use crate::vm::{
+    gc::{GcOption, LUA_GCGEN},
+    lua_closure::RustFn,
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState, LUA_MULTRET},
+    lua_value::LuaValue,
+    number::{str_to_integer_base, str_to_number},
+};
+
+/// Functions of the base library, set as globals.
+const FUNCS: [(&str, RustFn); 19] = [
+    ("assert", assert),
+    ("collectgarbage", collectgarbage),
+    ("error", error),
+    ("getmetatable", getmetatable),
+    ("ipairs", ipairs),
+    ("next", next),
+    ("pairs", pairs),
+    ("pcall", pcall),
+    ("print", print),
+    ("rawequal", rawequal),
+    ("rawget", rawget),
+    ("rawlen", rawlen),
+    ("rawset", rawset),
+    ("select", select),
+    ("setmetatable", setmetatable),
+    ("tonumber", tonumber),
+    ("tostring", tostring),
+    ("type", type_),
+    ("xpcall", xpcall),
+];
+
+impl LuaState {
+    /// Set the base functions as globals, with `_G` the global table itself
+    /// and `_VERSION` the language version.
+    pub fn open_base(&mut self) -> Result<(), LuaError> {
+        for (name, f) in FUNCS {
+            self.push_rust_function(f);
+            self.set_global(name)?;
+        }
+        self.push_global_table();
+        self.set_global("_G")?;
+        self.push_string("Lua 5.4");
+        self.set_global("_VERSION")
+    }
+}
+
+/// Error unless there is an argument `arg`, possibly nil.
+fn check_any(state: &mut LuaState, arg: usize, fname: &str) -> Result<(), LuaError> {
+    if state.type_name(arg as i32 - 1) == "no value" {
+        return Err(state.arg_error(arg, fname, "value expected"));
+    }
+    Ok(())
+}
+
+/// Error unless the argument `arg` is a table.
+fn check_table(state: &mut LuaState, arg: usize, fname: &str) -> Result<(), LuaError> {
+    if state.type_name(arg as i32 - 1) != "table" {
+        return Err(state.type_error(arg, fname, "table"));
+    }
+    Ok(())
+}
+
+/// `assert(v [, message])`
+///
+/// All arguments when `v` is true, otherwise raise `message` like `error`,
+/// by default "assertion failed!".
+pub fn assert(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.to_boolean(0) {
+        return Ok(state.get_top());
+    }
+    check_any(state, 1, "assert")?;
+    let msg = match state.get_top() {
+        1 => LuaValue::String("assertion failed!".into()),
+        _ => state.stack.get(1),
+    };
+    state.set_top(0);
+    state.stack.push(msg);
+    error(state)
+}
+
+/// `error(message [, level])`
+///
+/// Raise `message` as error. A string message gets the position of the
+/// function at `level` prepended: 1, the default, is the function calling
+/// `error`, 2 its caller and 0 adds no position.
+pub fn error(state: &mut LuaState) -> Result<usize, LuaError> {
+    let level = state.opt_integer(2, "error", 1)?;
+    state.set_top(1);
+    if let (Some(msg), true) = (state.to_string(0), level > 0) {
+        let msg = format!("{}{}", state.location(level as usize), msg);
+        state.push_string(msg);
+        state.replace(0);
+    }
+    Err(state.error())
+}
+
+/// `pcall(f, ...)`
+///
+/// Call `f` in protected mode: `true` and the results of `f`, or `false` and
+/// the error object.
+pub fn pcall(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.get_top() == 0 {
+        return Err(state.arg_error(1, "pcall", "value expected"));
+    }
+    let ok = state.pcallk(state.get_top() - 1, LUA_MULTRET, None, 0, finish_pcall)?;
+    finish_pcall(state, ok, 0)
+}
+
+/// Rest of `pcall` once `f` returned, possibly after yields.
+fn finish_pcall(state: &mut LuaState, ok: bool, _ctx: i64) -> Result<usize, LuaError> {
+    state.push_boolean(ok);
+    state.insert(0);
+    Ok(state.get_top())
+}
+
+/// `xpcall(f, msgh, ...)`
+///
+/// Like `pcall`, but on error `msgh` is called with the error object and its
+/// result is returned instead.
+pub fn xpcall(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.get_top() < 2 {
+        return Err(state.arg_error(2, "xpcall", "value expected"));
+    }
+    let n_args = state.get_top() - 2;
+    // f, msgh, true, f, args...
+    state.push_boolean(true);
+    state.push_value(0);
+    state.rotate(2, 2);
+    let ok = state.pcallk(n_args, LUA_MULTRET, Some(1), 0, finish_xpcall)?;
+    finish_xpcall(state, ok, 0)
+}
+
+/// Rest of `xpcall`, the results or the error object follow `true`.
+fn finish_xpcall(state: &mut LuaState, ok: bool, _ctx: i64) -> Result<usize, LuaError> {
+    if !ok {
+        state.push_boolean(false);
+        state.replace(2);
+    }
+    Ok(state.get_top() - 2)
+}
+
+/// `select(index, ...)`
+///
+/// The arguments after argument number `index`, a negative index counts from
+/// the end. With `index` "#" the number of extra arguments.
+pub fn select(state: &mut LuaState) -> Result<usize, LuaError> {
+    let n = state.get_top() as i64;
+    if let LuaValue::String(s) = state.stack.get(0) {
+        if s.as_bytes().first() == Some(&b'#') {
+            state.push_integer(n - 1);
+            return Ok(1);
+        }
+    }
+    let i = match state.check_integer(1, "select")? {
+        i if i < 0 => n + i,
+        i => i.min(n),
+    };
+    if i < 1 {
+        return Err(state.arg_error(1, "select", "index out of range"));
+    }
+    Ok((n - i) as usize)
+}
+
+/// `print(...)`
+///
+/// Write the arguments converted by `tostring` to the standard output,
+/// separated by tabs and followed by a newline.
+pub fn print(state: &mut LuaState) -> Result<usize, LuaError> {
+    let mut out = Vec::new();
+    for i in 0..state.get_top() {
+        if i > 0 {
+            out.push(b'\t');
+        }
+        out.extend_from_slice(state.to_lstring(i as i32)?.as_bytes());
+    }
+    out.push(b'\n');
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&out)
+        .and_then(|_| stdout.flush())
+        .map_err(|err| state.lib_error(err.to_string()))?;
+    Ok(0)
+}
+
+/// `tostring(v)`
+///
+/// `v` converted to a string, see `LuaState::to_lstring`.
+pub fn tostring(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_any(state, 1, "tostring")?;
+    let s = state.to_lstring(0)?;
+    state.push_string(s);
+    Ok(1)
+}
+
+/// `tonumber(e [, base])`
+///
+/// The number `e` converts to, or nil. With `base`, `e` must be a string
+/// holding an integer numeral in that base, letters are digits from 10 on.
+pub fn tonumber(state: &mut LuaState) -> Result<usize, LuaError> {
+    let val = state.stack.get(0);
+    let result = if matches!(state.type_name(1), "no value" | "nil") {
+        match &val {
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(val.clone()),
+            LuaValue::String(s) => str_to_number(s.as_bytes()),
+            _ if state.get_top() == 0 => {
+                return Err(state.arg_error(1, "tonumber", "value expected"));
+            }
+            _ => None,
+        }
+    } else {
+        let base = state.check_integer(2, "tonumber")?;
+        let LuaValue::String(s) = &val else {
+            let msg = format!("string expected, got {}", state.type_name(0));
+            return Err(state.arg_error(1, "tonumber", &msg));
+        };
+        if !(2..=36).contains(&base) {
+            return Err(state.arg_error(2, "tonumber", "base out of range"));
+        }
+        str_to_integer_base(s.as_bytes(), base as u32).map(LuaValue::Integer)
+    };
+    state.stack.push(result.unwrap_or(LuaValue::Nil));
+    Ok(1)
+}
+
+/// `rawlen(v)`
+///
+/// Length of the table or string `v` without calling `__len`.
+pub fn rawlen(state: &mut LuaState) -> Result<usize, LuaError> {
+    if !matches!(state.type_name(0), "table" | "string") {
+        return Err(state.arg_error(1, "rawlen", "table or string expected"));
+    }
+    let len = state.raw_len(0);
+    state.push_integer(len as i64);
+    Ok(1)
+}
+
+/// `type(v)`
+///
+/// Name of the type of `v`.
+pub fn type_(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_any(state, 1, "type")?;
+    let name = state.type_name(0);
+    state.push_string(name);
+    Ok(1)
+}
+
+/// `next(table [, index])`
+///
+/// The entry of `table` following `index`, or nil at the end of the
+/// traversal. Without `index` the first entry.
+pub fn next(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_table(state, 1, "next")?;
+    state.set_top(2);
+    if state.next(0)? {
+        Ok(2)
+    } else {
+        state.push_nil();
+        Ok(1)
+    }
+}
+
+/// `pairs(t)`
+///
+/// `next`, `t` and nil to traverse `t` in a generic for, unless the
+/// metatable of `t` has a `__pairs` field: its first three results then.
+pub fn pairs(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_any(state, 1, "pairs")?;
+    let val = state.stack.get(0);
+    let mm = match state.get_metatable_of(&val) {
+        Some(mt) => mt.borrow().get_str("__pairs"),
+        None => LuaValue::Nil,
+    };
+    if mm.is_nil() {
+        state.push_rust_function(next);
+        state.push_value(0);
+        state.push_nil();
+    } else {
+        state.stack.push(mm);
+        state.push_value(0);
+        state.call(1, 3)?;
+    }
+    Ok(3)
+}
+
+/// `ipairs(t)`
+///
+/// Iterator function, `t` and 0 to traverse `t[1]`, `t[2]`, ... up to the
+/// first nil in a generic for.
+pub fn ipairs(state: &mut LuaState) -> Result<usize, LuaError> {
+    // values with a metatable may be indexable
+    let val = state.stack.get(0);
+    if state.type_name(0) != "table" && state.get_metatable_of(&val).is_none() {
+        let msg = format!("table expected, got {}", state.type_name(0));
+        return Err(state.arg_error(1, "ipairs", &msg));
+    }
+    state.push_rust_function(ipairs_aux);
+    state.push_value(0);
+    state.push_integer(0);
+    Ok(3)
+}
+
+/// Iterator of `ipairs`: the next index and its value, nothing at the end.
+fn ipairs_aux(state: &mut LuaState) -> Result<usize, LuaError> {
+    let i = state.to_integer(1).unwrap_or(0).wrapping_add(1);
+    state.push_integer(i);
+    state.get_i(0, i)?;
+    if state.type_name(-1) == "nil" {
+        Ok(1)
+    } else {
+        Ok(2)
+    }
+}
+
+/// `rawequal(v1, v2)`
+///
+/// Whether `v1` and `v2` are equal without calling `__eq`.
+pub fn rawequal(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_any(state, 1, "rawequal")?;
+    check_any(state, 2, "rawequal")?;
+    let equal = state.raw_equal(0, 1);
+    state.push_boolean(equal);
+    Ok(1)
+}
+
+/// `rawget(table, index)`
+///
+/// `table[index]` without calling `__index`.
+pub fn rawget(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_table(state, 1, "rawget")?;
+    check_any(state, 2, "rawget")?;
+    state.set_top(2);
+    state.raw_get(0)?;
+    Ok(1)
+}
+
+/// `rawset(table, index, value)`
+///
+/// `table[index] = value` without calling `__newindex`, returns `table`.
+pub fn rawset(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_table(state, 1, "rawset")?;
+    check_any(state, 2, "rawset")?;
+    check_any(state, 3, "rawset")?;
+    state.set_top(3);
+    state.raw_set(0)?;
+    Ok(1)
+}
+
+/// `getmetatable(object)`
+///
+/// The `__metatable` field of the metatable is returned instead of the
+/// metatable when set.
+pub fn getmetatable(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.get_top() == 0 {
+        return Err(state.arg_error(1, "getmetatable", "value expected"));
+    }
+    let val = state.stack.get(0);
+    match state.get_metatable_of(&val) {
+        Some(mt) => {
+            let protected = mt.borrow().get_str("__metatable");
+            if protected.is_nil() {
+                state.stack.push(LuaValue::Table(mt));
+            } else {
+                state.stack.push(protected);
+            }
+        }
+        None => state.push_nil(),
+    }
+    Ok(1)
+}
+
+/// `setmetatable(table, metatable)`
+///
+/// Set or with nil remove the metatable of `table`, which is returned.
+pub fn setmetatable(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.type_name(0) != "table" {
+        let msg = format!("table expected, got {}", state.type_name(0));
+        return Err(state.arg_error(1, "setmetatable", &msg));
+    }
+    if !matches!(state.type_name(1), "nil" | "table") {
+        return Err(state.arg_error(2, "setmetatable", "nil or table expected"));
+    }
+    let val = state.stack.get(0);
+    if let Some(mt) = state.get_metatable_of(&val) {
+        if !mt.borrow().get_str("__metatable").is_nil() {
+            return Err(state.lib_error("cannot change a protected metatable"));
+        }
+    }
+    state.set_top(2);
+    state.set_metatable(0);
+    Ok(1)
+}
+
+/// `collectgarbage([opt [, arg...]])`
+///
+/// Control the garbage collector, `opt` is one of "collect" (the default),
+/// "stop", "restart", "count", "step", "isrunning", "incremental" and
+/// "generational".
+///
+/// Cycles always run to completion, "step" runs a whole one once enough was
+/// allocated. So "incremental" and "generational" only set the pause before
+/// the next cycle, from the first extra argument, and return the previous
+/// mode. Their other tuning arguments are ignored.
+pub fn collectgarbage(state: &mut LuaState) -> Result<usize, LuaError> {
+    let opt = match state.type_name(0) {
+        "no value" | "nil" => "collect".to_string(),
+        _ => match state.to_string(0) {
+            Some(opt) => opt.to_str_lossy().into_owned(),
+            None => {
+                let msg = format!("string expected, got {}", state.type_name(0));
+                return Err(state.arg_error(1, "collectgarbage", &msg));
+            }
+        },
+    };
+    let mut arg = |n: usize| state.to_integer(n).unwrap_or(0).max(0) as usize;
+    let what = match opt.as_str() {
+        "collect" => GcOption::Collect,
+        "stop" => GcOption::Stop,
+        "restart" => GcOption::Restart,
+        "count" => GcOption::Count,
+        "step" => GcOption::Step(arg(1)),
+        "isrunning" => GcOption::IsRunning,
+        "incremental" => GcOption::Incremental { pause: arg(1) },
+        "generational" => GcOption::Generational { minormul: arg(1) },
+        _ => {
+            let msg = format!("invalid option '{}'", opt);
+            return Err(state.arg_error(1, "collectgarbage", &msg));
+        }
+    };
+    let res = state.gc(what);
+    match what {
+        GcOption::Count => {
+            let bytes = state.gc(GcOption::CountB);
+            state.push_number(res as f64 + bytes as f64 / 1024.0);
+        }
+        GcOption::Step(_) | GcOption::IsRunning => state.push_boolean(res != 0),
+        GcOption::Incremental { .. } | GcOption::Generational { .. } => {
+            state.push_string(if res == LUA_GCGEN {
+                "generational"
+            } else {
+                "incremental"
+            });
+        }
+        _ => state.push_integer(res),
+    }
+    Ok(1)
+}
+
+#[test]
+fn test_base_library() {
+    use super::call_value;
+    use crate::vm::lua_table::LuaTable;
+    let mut state = LuaState::new();
+    state.open_base().unwrap();
+    let mut call = |name: &str, args: Vec<LuaValue>| {
+        state.get_global(name).unwrap();
+        let f = state.stack.pop();
+        call_value(&mut state, f, args)
+    };
+    use LuaValue::{Boolean, Integer, Nil};
+    let s = |s: &str| LuaValue::String(s.into());
+    let t = LuaTable::new_ref(0, 0);
+    for (k, v) in [(Integer(1), s("a")), (Integer(2), s("b")), (s("k"), s("v"))] {
+        t.borrow_mut().put(k, v).unwrap();
+    }
+    let table = LuaValue::Table(t.clone());
+
+    assert_eq!(call("type", vec![Integer(1)]).unwrap(), [s("number")]);
+    assert_eq!(call("type", vec![Nil]).unwrap(), [s("nil")]);
+    assert_eq!(
+        call("_G", vec![]).unwrap_err().to_string(),
+        "attempt to call a table value"
+    );
+    assert_eq!(
+        call("rawequal", vec![table.clone(), table.clone()]).unwrap(),
+        [Boolean(true)]
+    );
+    assert_eq!(
+        call("rawget", vec![table.clone(), Integer(2)]).unwrap(),
+        [s("b")]
+    );
+    assert_eq!(
+        call("rawset", vec![table.clone(), s("k"), Integer(3)]).unwrap(),
+        std::slice::from_ref(&table)
+    );
+    assert_eq!(t.borrow().get_str("k"), Integer(3));
+
+    // traversals
+    assert_eq!(
+        call("next", vec![table.clone()]).unwrap(),
+        [Integer(1), s("a")]
+    );
+    assert_eq!(call("next", vec![table.clone(), s("k")]).unwrap(), [Nil]);
+    let iter = call("pairs", vec![table.clone()]).unwrap();
+    assert_eq!(iter[1..], [table.clone(), Nil]);
+    let iter = call("ipairs", vec![table.clone()]).unwrap();
+    assert_eq!(iter[1..], [table.clone(), Integer(0)]);
+    // assert
+    assert_eq!(
+        call("assert", vec![Integer(1), s("x")]).unwrap(),
+        [Integer(1), s("x")]
+    );
+    assert_eq!(
+        call("assert", vec![Nil]).unwrap_err().to_string(),
+        "assertion failed!"
+    );
+    assert_eq!(
+        call("assert", vec![Boolean(false), s("boom")])
+            .unwrap_err()
+            .to_string(),
+        "boom"
+    );
+    let err = call("assert", vec![Boolean(false), Integer(7)]).unwrap_err();
+    assert_eq!(err.value(), Integer(7));
+
+    // argument checks
+    let error = |res: Result<Vec<LuaValue>, LuaError>| res.unwrap_err().to_string();
+    assert_eq!(
+        error(call("ipairs", vec![Nil])),
+        "bad argument #1 to 'ipairs' (table expected, got nil)"
+    );
+    assert_eq!(
+        error(call("next", vec![Integer(1)])),
+        "bad argument #1 to 'next' (table expected, got number)"
+    );
+    assert_eq!(
+        error(call("next", vec![table.clone(), s("nope")])),
+        "invalid key to 'next'"
+    );
+    assert_eq!(
+        error(call("rawget", vec![table.clone()])),
+        "bad argument #2 to 'rawget' (value expected)"
+    );
+    assert_eq!(
+        error(call("type", vec![])),
+        "bad argument #1 to 'type' (value expected)"
+    );
+    assert_eq!(
+        error(call("assert", vec![])),
+        "bad argument #1 to 'assert' (value expected)"
+    );
+    assert_eq!(
+        error(call("pairs", vec![])),
+        "bad argument #1 to 'pairs' (value expected)"
+    );
+
+    let mut ipairs_aux =
+        |i| call_value(&mut state, iter[0].clone(), vec![table.clone(), Integer(i)]).unwrap();
+    assert_eq!(ipairs_aux(1), [Integer(2), s("b")]);
+    assert_eq!(ipairs_aux(2), [Nil]);
+
+    state.get_global("_VERSION").unwrap();
+    assert_eq!(state.to_string(-1).unwrap(), "Lua 5.4");
+}
+
+#[test]
+fn test_error_level() {
+    use super::call;
+    let mut state = LuaState::new();
+    let mut error = |msg: &str, level: LuaValue| {
+        let msg = LuaValue::String(msg.into());
+        call(&mut state, error, vec![msg, level])
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error("boom", LuaValue::Number(0.0)), "boom");
+    assert_eq!(error("boom", LuaValue::String("0".into())), "boom");
+    assert_eq!(
+        error("boom", LuaValue::Number(0.5)),
+        "bad argument #2 to 'error' (number has no integer representation)"
+    );
+    assert_eq!(
+        error("boom", LuaValue::Boolean(true)),
+        "bad argument #2 to 'error' (number expected, got boolean)"
+    );
+}
+
+#[test]
+fn test_select() {
+    use super::call;
+    let mut state = LuaState::new();
+    let mut select = |args: Vec<LuaValue>| call(&mut state, select, args);
+    use LuaValue::Integer;
+    let abc = |i: LuaValue| vec![i, Integer(1), Integer(2), Integer(3)];
+    assert_eq!(select(abc(Integer(2))).unwrap(), [Integer(2), Integer(3)]);
+    assert_eq!(select(abc(Integer(-1))).unwrap(), [Integer(3)]);
+    assert_eq!(select(abc(Integer(5))).unwrap(), []);
+    // the index converts like any integer argument
+    assert_eq!(
+        select(abc(LuaValue::Number(2.0))).unwrap(),
+        [Integer(2), Integer(3)]
+    );
+    assert_eq!(select(abc(LuaValue::Number(-1.0))).unwrap(), [Integer(3)]);
+    assert_eq!(
+        select(abc(LuaValue::String("3".into()))).unwrap(),
+        [Integer(3)]
+    );
+    assert_eq!(
+        select(abc(LuaValue::Number(1.5))).unwrap_err().to_string(),
+        "bad argument #1 to 'select' (number has no integer representation)"
+    );
+    assert_eq!(
+        select(abc(LuaValue::Boolean(true)))
+            .unwrap_err()
+            .to_string(),
+        "bad argument #1 to 'select' (number expected, got boolean)"
+    );
+    assert_eq!(
+        select(abc(LuaValue::String("#".into()))).unwrap(),
+        [Integer(3)]
+    );
+    assert_eq!(
+        select(abc(Integer(-4))).unwrap_err().to_string(),
+        "bad argument #1 to 'select' (index out of range)"
+    );
+    assert_eq!(
+        select(vec![]).unwrap_err().to_string(),
+        "bad argument #1 to 'select' (number expected, got no value)"
+    );
+}
+
+#[test]
+fn test_tonumber() {
+    use super::call;
+    let mut state = LuaState::new();
+    let mut tonumber =
+        |args: Vec<LuaValue>| call(&mut state, tonumber, args).map(|res| res[0].clone());
+    use LuaValue::{Integer, Nil, Number};
+    let s = |s: &str| LuaValue::String(s.into());
+    assert!(matches!(tonumber(vec![s(" 0x10 ")]), Ok(Integer(16))));
+    assert!(matches!(tonumber(vec![s("1e2")]), Ok(Number(n)) if n == 100.0));
+    assert!(matches!(tonumber(vec![s("0x1p4")]), Ok(Number(n)) if n == 16.0));
+    assert!(matches!(tonumber(vec![Number(2.5)]), Ok(Number(n)) if n == 2.5));
+    assert!(matches!(tonumber(vec![s("1e")]), Ok(Nil)));
+    assert!(matches!(tonumber(vec![LuaValue::Boolean(true)]), Ok(Nil)));
+    assert!(matches!(
+        tonumber(vec![s("ff"), Integer(16)]),
+        Ok(Integer(255))
+    ));
+    assert!(matches!(
+        tonumber(vec![s("zz"), Integer(36)]),
+        Ok(Integer(1295))
+    ));
+    assert!(matches!(tonumber(vec![s("8"), Integer(8)]), Ok(Nil)));
+    assert!(matches!(
+        tonumber(vec![s("ff"), Number(16.0)]),
+        Ok(Integer(255))
+    ));
+    assert!(matches!(tonumber(vec![s("11"), s("2")]), Ok(Integer(3))));
+    assert_eq!(
+        tonumber(vec![s("1"), Number(2.5)]).unwrap_err().to_string(),
+        "bad argument #2 to 'tonumber' (number has no integer representation)"
+    );
+    assert_eq!(
+        tonumber(vec![]).unwrap_err().to_string(),
+        "bad argument #1 to 'tonumber' (value expected)"
+    );
+    assert_eq!(
+        tonumber(vec![Integer(10), Integer(16)])
+            .unwrap_err()
+            .to_string(),
+        "bad argument #1 to 'tonumber' (string expected, got number)"
+    );
+    assert_eq!(
+        tonumber(vec![s("1"), Integer(37)]).unwrap_err().to_string(),
+        "bad argument #2 to 'tonumber' (base out of range)"
+    );
+}
+
+#[test]
+fn test_tostring() {
+    use super::call;
+    use crate::vm::lua_table::LuaTable;
+    let mut state = LuaState::new();
+    let mut to_str = |val: LuaValue| {
+        call(&mut state, tostring, vec![val])?;
+        Ok::<_, LuaError>(state.to_string(0).unwrap().to_string())
+    };
+    assert_eq!(to_str(LuaValue::Number(1e100)).unwrap(), "1e+100");
+    assert_eq!(to_str(LuaValue::Number(-3.0)).unwrap(), "-3.0");
+    assert_eq!(
+        to_str(LuaValue::Integer(i64::MIN)).unwrap(),
+        "-9223372036854775808"
+    );
+    assert_eq!(to_str(LuaValue::Boolean(false)).unwrap(), "false");
+    assert_eq!(to_str(LuaValue::Nil).unwrap(), "nil");
+    let table = to_str(LuaValue::Table(LuaTable::new_ref(0, 0))).unwrap();
+    assert!(table.starts_with("table: 0x"), "{}", table);
+
+    // __tostring, then __name
+    let mut state = LuaState::new();
+    state.push_rust_function(tostring);
+    state.new_table();
+    state.new_table();
+    state.push_rust_function(|state| {
+        let name = state.type_name(0);
+        state.push_string(name);
+        Ok(1)
+    });
+    state.set_field(-2, "__tostring").unwrap();
+    state.set_metatable(-2);
+    state.call(1, 1).unwrap();
+    assert_eq!(state.to_string(-1).unwrap(), "table");
+    state.new_table();
+    state.new_table();
+    state.push_string("Point");
+    state.set_field(-2, "__name").unwrap();
+    state.set_metatable(-2);
+    let s = state.to_lstring(-1).unwrap().to_string();
+    assert!(s.starts_with("Point: 0x"), "{}", s);
+    state.new_table();
+    state.new_table();
+    state.push_rust_function(|state| {
+        state.push_boolean(true);
+        Ok(1)
+    });
+    state.set_field(-2, "__tostring").unwrap();
+    state.set_metatable(-2);
+    assert_eq!(
+        state.to_lstring(-1).unwrap_err().to_string(),
+        "'__tostring' must return a string"
+    );
+
+    // concatenation formats numbers the same way
+    state.set_top(0);
+    state.push_number(0.1);
+    state.push_string("|");
+    state.push_number(1e15);
+    state.push_integer(7);
+    state.concat(4).unwrap();
+    assert_eq!(state.to_string(-1).unwrap(), "0.1|1e+157");
+}
@@ -0,0 +1,275 @@
+use std::rc::Rc;
+
+use crate::vm::{
+    lua_closure::RustFn,
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState},
+    lua_string::LuaString,
+    lua_thread::{LuaThreadRef, ThreadStatus},
+    lua_value::LuaValue,
+};
+
+/// Functions of the `coroutine` table.
+const FUNCS: [(&str, RustFn); 8] = [
+    ("create", create),
+    ("resume", resume),
+    ("yield", coroutine_yield),
+    ("status", status),
+    ("wrap", wrap),
+    ("isyieldable", isyieldable),
+    ("running", running),
+    ("close", close),
+];
+
+impl LuaState {
+    /// Set the global `coroutine` to a table with the coroutine functions.
+    pub fn open_coroutine(&mut self) -> Result<(), LuaError> {
+        self.create_table(0, FUNCS.len());
+        for (name, f) in FUNCS {
+            self.push_rust_function(f);
+            self.set_field(-2, name)?;
+        }
+        self.set_global("coroutine")
+    }
+}
+
+/// The coroutine argument `arg` of the function `fname`.
+fn check_thread(state: &mut LuaState, arg: usize, fname: &str) -> Result<LuaThreadRef, LuaError> {
+    match state.to_thread(arg as i32 - 1) {
+        Some(co) => Ok(co),
+        None => {
+            let msg = format!(
+                "coroutine expected, got {}",
+                state.type_name(arg as i32 - 1)
+            );
+            Err(state.arg_error(arg, fname, &msg))
+        }
+    }
+}
+
+/// `coroutine.create(f)`
+///
+/// New coroutine with the body `f`.
+pub fn create(state: &mut LuaState) -> Result<usize, LuaError> {
+    if state.type_name(0) != "function" {
+        let msg = format!("function expected, got {}", state.type_name(0));
+        return Err(state.arg_error(1, "create", &msg));
+    }
+    state.set_top(1);
+    state.new_thread();
+    Ok(1)
+}
+
+/// `coroutine.resume(co, ...)`
+///
+/// Start or continue `co`: `true` and the values passed to `yield` or
+/// returned by the body, or `false` and the error object.
+pub fn resume(state: &mut LuaState) -> Result<usize, LuaError> {
+    check_thread(state, 1, "resume")?;
+    let ok = state.resume(state.get_top() - 1).is_ok();
+    state.push_boolean(ok);
+    state.insert(0);
+    Ok(state.get_top())
+}
+
+/// `coroutine.yield(...)`
+///
+/// Suspend the running coroutine, the arguments are the results of the
+/// `resume` and the arguments of the next `resume` are the results here.
+pub fn coroutine_yield(state: &mut LuaState) -> Result<usize, LuaError> {
+    Err(state.yield_values(state.get_top()))
+}
+
+/// `coroutine.status(co)`
+///
+/// "running", "suspended", "normal" or "dead".
+pub fn status(state: &mut LuaState) -> Result<usize, LuaError> {
+    let co = check_thread(state, 1, "status")?;
+    let status = state.thread_status_of(&co);
+    state.push_string(status.name());
+    Ok(1)
+}
+
+/// `coroutine.wrap(f)`
+///
+/// Function resuming a new coroutine with the body `f` on each call. It
+/// returns the values passed to `yield` and raises the errors of the
+/// coroutine.
+pub fn wrap(state: &mut LuaState) -> Result<usize, LuaError> {
+    create(state)?;
+    let co = state.to_thread(-1).expect("coroutine created");
+    state.push_rust_closure(move |state| {
+        state.stack.push(LuaValue::Thread(co.clone()));
+        state.insert(0);
+        if state.resume(state.get_top() - 1).is_ok() {
+            return Ok(state.get_top());
+        }
+        let mut err = state.error();
+        // the coroutine can not be resumed again, close its variables
+        if co.borrow().error.is_some() {
+            err = state.reset_thread(&co).unwrap_or(err);
+        }
+        match err.value() {
+            LuaValue::String(msg) => {
+                let location = LuaString::from(state.location(1));
+                Err(LuaError::Runtime(LuaValue::String(location.concat(&msg))))
+            }
+            _ => Err(err),
+        }
+    });
+    Ok(1)
+}
+
+/// `coroutine.isyieldable([co])`
+///
+/// Whether `co`, by default the running coroutine, can yield: the main
+/// thread and coroutines inside calls from Rust can not.
+pub fn isyieldable(state: &mut LuaState) -> Result<usize, LuaError> {
+    let yieldable = if state.get_top() == 0 {
+        state.is_yieldable()
+    } else {
+        let co = check_thread(state, 1, "isyieldable")?;
+        match state.thread_status_of(&co) {
+            ThreadStatus::Running => state.is_yieldable(),
+            _ => !Rc::ptr_eq(&co, &state.main_thread) && co.borrow().non_yieldable == 0,
+        }
+    };
+    state.push_boolean(yieldable);
+    Ok(1)
+}
+
+/// `coroutine.running()`
+///
+/// The running coroutine and whether it is the main thread.
+pub fn running(state: &mut LuaState) -> Result<usize, LuaError> {
+    let is_main = state.push_thread();
+    state.push_boolean(is_main);
+    Ok(2)
+}
+
+/// `coroutine.close(co)`
+///
+/// Kill the suspended or dead coroutine `co`: `true`, or `false` and the
+/// error object if an error stopped it.
+pub fn close(state: &mut LuaState) -> Result<usize, LuaError> {
+    let co = check_thread(state, 1, "close")?;
+    match state.thread_status_of(&co) {
+        ThreadStatus::Running => Err(state.lib_error("cannot close a running coroutine")),
+        ThreadStatus::Normal => Err(state.lib_error("cannot close a normal coroutine")),
+        ThreadStatus::Suspended | ThreadStatus::Dead => match state.close_thread(0) {
+            Ok(()) => {
+                state.push_boolean(true);
+                Ok(1)
+            }
+            Err(err) => {
+                state.push_boolean(false);
+                state.stack.push(err.value());
+                Ok(2)
+            }
+        },
+    }
+}
+
+#[test]
+fn test_coroutine_library() {
+    use super::{call, call_value};
+    use crate::vm::{gc::GcOption, lua_closure::RustFunction};
+    fn probe(state: &mut LuaState) -> Result<usize, LuaError> {
+        let yieldable = state.is_yieldable();
+        state.push_boolean(yieldable);
+        let is_main = state.push_thread();
+        state.push_boolean(is_main);
+        let main = state.main_thread.clone();
+        state.push_string(state.thread_status_of(&main).name());
+        Ok(4)
+    }
+    fn call_yield(state: &mut LuaState) -> Result<usize, LuaError> {
+        state.push_rust_function(coroutine_yield);
+        state.call(0, 0)?;
+        Ok(0)
+    }
+    let new_coroutine = |state: &mut LuaState, body: RustFn| {
+        let body = LuaValue::RustFunction(RustFunction::Fn(body));
+        call(state, create, vec![body]).unwrap();
+        state.stack.get(0)
+    };
+
+    let mut state = LuaState::new();
+    state.open_coroutine().unwrap();
+    state.get_global("coroutine").unwrap();
+    state.get_field(-1, "wrap").unwrap();
+    assert_eq!(state.type_name(-1), "function");
+
+    // running and isyieldable in the main thread
+    call(&mut state, running, vec![]).unwrap();
+    assert_eq!(
+        state.stack.get(0),
+        LuaValue::Thread(state.main_thread.clone())
+    );
+    assert_eq!(state.stack.get(1), LuaValue::Boolean(true));
+    call(&mut state, isyieldable, vec![]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::Boolean(false));
+    let err = call(&mut state, coroutine_yield, vec![]).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to yield from outside a coroutine");
+    let err = call(&mut state, create, vec![]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'create' (function expected, got no value)"
+    );
+
+    let co = new_coroutine(&mut state, probe);
+    call(&mut state, resume, vec![co.clone()]).unwrap();
+    assert_eq!(state.get_top(), 5);
+    assert_eq!(state.stack.get(0), LuaValue::Boolean(true));
+    assert_eq!(state.stack.get(1), LuaValue::Boolean(true));
+    assert_eq!(state.stack.get(2), co);
+    assert_eq!(state.stack.get(3), LuaValue::Boolean(false));
+    assert_eq!(state.stack.get(4), LuaValue::String("normal".into()));
+
+    let co = new_coroutine(&mut state, call_yield);
+    call(&mut state, resume, vec![co.clone()]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::Boolean(false));
+    assert_eq!(
+        state.stack.get(1),
+        LuaValue::String("attempt to yield across a C-call boundary".into())
+    );
+    call(&mut state, close, vec![co.clone()]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::Boolean(false));
+    assert_eq!(
+        state.stack.get(1),
+        LuaValue::String("attempt to yield across a C-call boundary".into())
+    );
+
+    // a suspended coroutine is closed and dead afterwards
+    let co = new_coroutine(&mut state, coroutine_yield);
+    call(&mut state, resume, vec![co.clone()]).unwrap();
+    state.gc(GcOption::Collect);
+    call(&mut state, status, vec![co.clone()]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::String("suspended".into()));
+    call(&mut state, close, vec![co.clone()]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::Boolean(true));
+    call(&mut state, status, vec![co.clone()]).unwrap();
+    assert_eq!(state.stack.get(0), LuaValue::String("dead".into()));
+    let err = call(&mut state, close, vec![LuaValue::Nil]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'close' (coroutine expected, got nil)"
+    );
+
+    // wrap(yield) returns its arguments, then the values passed back
+    let body = LuaValue::RustFunction(RustFunction::Fn(coroutine_yield));
+    call(&mut state, wrap, vec![body]).unwrap();
+    let w = state.stack.get(0);
+    let call_w = |state: &mut LuaState, args: &[i64]| {
+        let args = args.iter().map(|&arg| LuaValue::Integer(arg)).collect();
+        call_value(state, w.clone(), args)
+    };
+    call_w(&mut state, &[1, 2]).unwrap();
+    assert_eq!(state.get_top(), 2);
+    assert_eq!(state.stack.get(1), LuaValue::Integer(2));
+    call_w(&mut state, &[3]).unwrap();
+    assert_eq!(state.get_top(), 1);
+    assert_eq!(state.stack.get(0), LuaValue::Integer(3));
+    let err = call_w(&mut state, &[]).unwrap_err();
+    assert_eq!(err.to_string(), "cannot resume dead coroutine");
+}
@@ -0,0 +1,349 @@
+use crate::vm::number::{format_a, format_e, format_f, format_g};
+
+/// Flags allowed in any conversion specification.
+pub const FLAGS: &[u8] = b"-+ #0";
+/// Flags of `%d` and `%i`.
+pub const FLAGS_I: &[u8] = b"-+0 ";
+/// Flags of `%u`.
+pub const FLAGS_U: &[u8] = b"-0";
+/// Flags of `%o`, `%x` and `%X`.
+pub const FLAGS_X: &[u8] = b"-#0";
+/// Flags of `%c` and `%s`.
+pub const FLAGS_C: &[u8] = b"-";
+
+/// Flags, width and precision of a conversion specification like `%-5.2f`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Up to two digits at the start of `s`, their value and count.
+fn two_digits(s: &[u8]) -> (usize, usize) {
+    let n = s.iter().take(2).take_while(|c| c.is_ascii_digit()).count();
+    let value = s[..n].iter().fold(0, |v, c| v * 10 + (c - b'0') as usize);
+    (value, n)
+}
+
+impl Spec {
+    /// Parse `form`, the specification between `%` and the conversion
+    /// character, allowing `flags` and, if `precision`, a precision. The
+    /// width and precision have at most two digits.
+    pub fn parse(form: &[u8], flags: &[u8], precision: bool) -> Option<Spec> {
+        let mut spec = Spec::default();
+        let mut i = 0;
+        while i < form.len() && flags.contains(&form[i]) {
+            match form[i] {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alt = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        // a width can not start with '0'
+        if form.get(i) != Some(&b'0') {
+            let (width, n) = two_digits(&form[i..]);
+            spec.width = width;
+            i += n;
+            if form.get(i) == Some(&b'.') && precision {
+                let (precision, n) = two_digits(&form[i + 1..]);
+                spec.precision = Some(precision);
+                i += 1 + n;
+            }
+        }
+        (i == form.len()).then_some(spec)
+    }
+
+    /// Sign of a number, with the `+` and space flags for positive ones.
+    fn sign(&self, negative: bool) -> &'static str {
+        match (negative, self.plus, self.space) {
+            (true, _, _) => "-",
+            (false, true, _) => "+",
+            (false, false, true) => " ",
+            _ => "",
+        }
+    }
+
+    /// Append `prefix` and `body` padded to the width. Zeros of the `0`
+    /// flag go between them when `zeros` allows it, spaces go to the left
+    /// or, with the `-` flag, to the right.
+    fn pad(&self, out: &mut Vec<u8>, prefix: &[u8], body: &[u8], zeros: bool) {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        if self.left {
+            out.extend_from_slice(prefix);
+            out.extend_from_slice(body);
+            out.resize(out.len() + fill, b' ');
+        } else if self.zero && zeros {
+            out.extend_from_slice(prefix);
+            out.resize(out.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(out.len() + fill, b' ');
+            out.extend_from_slice(prefix);
+            out.extend_from_slice(body);
+        }
+    }
+
+    /// `%d`, `%i`, `%u`, `%o`, `%x` and `%X`, all but the first two show
+    /// `n` as unsigned.
+    pub fn format_integer(&self, out: &mut Vec<u8>, n: i64, conv: u8) {
+        let (negative, digits) = match conv {
+            b'd' | b'i' => (n < 0, n.unsigned_abs().to_string()),
+            b'u' => (false, (n as u64).to_string()),
+            b'o' => (false, format!("{:o}", n)),
+            b'x' => (false, format!("{:x}", n)),
+            _ => (false, format!("{:X}", n)),
+        };
+        let mut digits = digits.into_bytes();
+        if self.precision == Some(0) && n == 0 {
+            digits.clear();
+        }
+        let min_digits = self.precision.unwrap_or(1);
+        if digits.len() < min_digits {
+            digits.splice(0..0, std::iter::repeat_n(b'0', min_digits - digits.len()));
+        }
+        let prefix = match conv {
+            b'o' if self.alt && digits.first() != Some(&b'0') => "0",
+            b'x' if self.alt && n != 0 => "0x",
+            b'X' if self.alt && n != 0 => "0X",
+            _ => self.sign(negative),
+        };
+        // a precision turns the '0' flag off
+        self.pad(out, prefix.as_bytes(), &digits, self.precision.is_none());
+    }
+
+    /// `%e`, `%E`, `%f`, `%F`, `%g`, `%G`, `%a` and `%A`, the upper case
+    /// conversions also write letters in upper case.
+    pub fn format_float(&self, out: &mut Vec<u8>, n: f64, conv: u8) {
+        let precision = self.precision.unwrap_or(6);
+        let abs = n.abs();
+        let mut body = match conv.to_ascii_lowercase() {
+            b'e' => format_e(abs, precision, self.alt),
+            b'f' => format_f(abs, precision, self.alt),
+            b'g' => format_g(abs, precision, self.alt),
+            _ => format_a(abs, self.precision, self.alt),
+        };
+        let mut prefix = self.sign(n.is_sign_negative()).to_string();
+        if n.is_finite() && conv.eq_ignore_ascii_case(&b'a') {
+            // zeros of the padding go after the "0x"
+            body.drain(..2);
+            prefix.push_str("0x");
+        }
+        if conv.is_ascii_uppercase() {
+            body.make_ascii_uppercase();
+            prefix.make_ascii_uppercase();
+        }
+        self.pad(out, prefix.as_bytes(), body.as_bytes(), n.is_finite());
+    }
+
+    /// `%c`, the character with code `c`.
+    pub fn format_char(&self, out: &mut Vec<u8>, c: u8) {
+        self.pad(out, b"", &[c], false);
+    }
+
+    /// `%s`, at most as many characters of `s` as the precision.
+    pub fn format_string(&self, out: &mut Vec<u8>, s: &[u8]) {
+        let len = self.precision.map_or(s.len(), |p| p.min(s.len()));
+        self.pad(out, b"", &s[..len], false);
+    }
+}
+
+/// `s` as a string literal in Lua source, for `%q`.
+pub fn quote_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', c]),
+            // a digit after the escape would be read as part of it
+            c if c.is_ascii_control() => match s.get(i + 1) {
+                Some(next) if next.is_ascii_digit() => {
+                    out.extend_from_slice(format!("\\{:03}", c).as_bytes())
+                }
+                _ => out.extend_from_slice(format!("\\{}", c).as_bytes()),
+            },
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+}
+
+/// `n` as a numeral in Lua source reading back as the same float, for `%q`.
+pub fn quote_float(n: f64) -> String {
+    if n == f64::INFINITY {
+        "1e9999".to_string()
+    } else if n == f64::NEG_INFINITY {
+        "-1e9999".to_string()
+    } else if n.is_nan() {
+        "(0/0)".to_string()
+    } else {
+        format_a(n, None, false)
+    }
+}
+
+#[test]
+fn test_format_spec() {
+    let spec = |form: &str| Spec::parse(form.as_bytes(), FLAGS, true);
+    let format = |form: &str, f: &dyn Fn(&Spec, &mut Vec<u8>)| {
+        let mut out = vec![];
+        f(&spec(form).unwrap(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    let int = |form, n, conv| format(form, &|spec, out| spec.format_integer(out, n, conv));
+    let float = |form, n, conv| format(form, &|spec, out| spec.format_float(out, n, conv));
+
+    assert_eq!(
+        spec("-5.2"),
+        Some(Spec {
+            left: true,
+            width: 5,
+            precision: Some(2),
+            ..Spec::default()
+        })
+    );
+    assert_eq!(spec("100"), None);
+    assert_eq!(spec("1.100"), None);
+    assert_eq!(Spec::parse(b"05", FLAGS_C, true), None);
+    assert_eq!(Spec::parse(b"#x", FLAGS_I, true), None);
+    assert_eq!(Spec::parse(b".3", FLAGS_C, false), None);
+
+    assert_eq!(int("", -42, b'd'), "-42");
+    assert_eq!(int("+05", 42, b'i'), "+0042");
+    assert_eq!(int(" ", 42, b'd'), " 42");
+    assert_eq!(int("-6", 42, b'd'), "42    ");
+    assert_eq!(int("08.3", 7, b'd'), "     007");
+    assert_eq!(int(".0", 0, b'd'), "");
+    assert_eq!(int("", i64::MIN, b'd'), "-9223372036854775808");
+    assert_eq!(int("", -1, b'u'), "18446744073709551615");
+    assert_eq!(int("#", 255, b'x'), "0xff");
+    assert_eq!(int("#08", 255, b'X'), "0X0000FF");
+    assert_eq!(int("#", 0, b'x'), "0");
+    assert_eq!(int("#", 8, b'o'), "010");
+    assert_eq!(int("#.0", 0, b'o'), "0");
+
+    assert_eq!(float("", 1.23456, b'f'), "1.234560");
+    assert_eq!(float("010.3", -1.23456, b'f'), "-00001.235");
+    assert_eq!(float("+.2", 1e10, b'e'), "+1.00e+10");
+    assert_eq!(float("", 1e-10, b'G'), "1E-10");
+    assert_eq!(float("#", 2.0, b'g'), "2.00000");
+    assert_eq!(float("08", f64::NEG_INFINITY, b'f'), "    -inf");
+    assert_eq!(float("", f64::NAN, b'F'), "NAN");
+    assert_eq!(float("", 1.0, b'a'), "0x1p+0");
+    assert_eq!(float("012", -0.5, b'A'), "-0X000001P-1");
+
+    let s = format("-4.2", &|spec, out| spec.format_string(out, b"abc"));
+    assert_eq!(s, "ab  ");
+    let c = format("3", &|spec, out| spec.format_char(out, b'x'));
+    assert_eq!(c, "  x");
+}
+
+#[test]
+fn test_quote() {
+    let mut out = vec![];
+    quote_string(&mut out, b"a\"b\\c\nd\0e\x011\xff");
+    assert_eq!(out, b"\"a\\\"b\\\\c\\\nd\\0e\\0011\xff\"");
+
+    assert_eq!(quote_float(0.5), "0x1p-1");
+    assert_eq!(quote_float(1.0), "0x1p+0");
+    assert_eq!(quote_float(f64::NEG_INFINITY), "-1e9999");
+    assert_eq!(quote_float(f64::NAN), "(0/0)");
+}
+
+#[test]
+fn test_string_format() {
+    use crate::{
+        stdlib::{call, string},
+        vm::{
+            lua_error::LuaError,
+            lua_state::{LuaApi, LuaState},
+            lua_table::LuaTable,
+            lua_value::LuaValue,
+        },
+    };
+    let mut state = LuaState::new();
+    state.open_base().unwrap();
+    state.open_string().unwrap();
+    let mut format = |args: Vec<LuaValue>| {
+        call(&mut state, string::format, args)?;
+        Ok::<_, LuaError>(state.to_string(0).unwrap().to_string())
+    };
+    use LuaValue::{Boolean, Integer, Nil, Number};
+    let s = |s: &str| LuaValue::String(s.into());
+
+    assert_eq!(
+        format(vec![s("%d items at %5.2f%%"), Integer(3), Number(9.5)]).unwrap(),
+        "3 items at  9.50%"
+    );
+    assert_eq!(
+        format(vec![
+            s("%x %X %#o %u"),
+            Integer(255),
+            Integer(255),
+            Integer(8),
+            Integer(-1)
+        ])
+        .unwrap(),
+        "ff FF 010 18446744073709551615"
+    );
+    assert_eq!(
+        format(vec![s("[%-5s|%5.1s|%c]"), s("ab"), s("xyz"), Integer(65)]).unwrap(),
+        "[ab   |    x|A]"
+    );
+    assert_eq!(format(vec![s("%d"), Number(3.0)]).unwrap(), "3");
+    assert_eq!(
+        format(vec![s("%g %e %a"), s("10"), Number(0.5), Number(1.0)]).unwrap(),
+        "10 5.000000e-01 0x1p+0"
+    );
+    assert_eq!(
+        format(vec![s("%s %s %s"), Nil, Boolean(true), Number(1.0)]).unwrap(),
+        "nil true 1.0"
+    );
+    assert_eq!(
+        format(vec![
+            s("%q %q %q %q"),
+            s("a\n\"\0"),
+            Integer(i64::MIN),
+            Number(0.1),
+            Number(f64::INFINITY)
+        ])
+        .unwrap(),
+        "\"a\\\n\\\"\\0\" 0x8000000000000000 0x1.999999999999ap-4 1e9999"
+    );
+
+    let mut err = |args| format(args).unwrap_err().to_string();
+    assert_eq!(err(vec![s("%d")]), "bad argument #2 to 'format' (no value)");
+    assert_eq!(
+        err(vec![s("%d"), Number(1.5)]),
+        "bad argument #2 to 'format' (number has no integer representation)"
+    );
+    assert_eq!(
+        err(vec![s("%y"), Integer(1)]),
+        "invalid conversion '%y' to 'format'"
+    );
+    assert_eq!(
+        err(vec![s("%#d"), Integer(1)]),
+        "invalid conversion specification: '%#d'"
+    );
+    assert_eq!(
+        err(vec![s("%100d"), Integer(1)]),
+        "invalid conversion specification: '%100d'"
+    );
+    assert_eq!(
+        err(vec![s("%10q"), Integer(1)]),
+        "specifier '%q' cannot have modifiers"
+    );
+    assert_eq!(
+        err(vec![s("%q"), LuaValue::Table(LuaTable::new_ref(0, 0))]),
+        "bad argument #2 to 'format' (value has no literal form)"
+    );
+    assert_eq!(
+        err(vec![s("%5s"), s("a\0b")]),
+        "bad argument #2 to 'format' (string contains zeros)"
+    );
+}
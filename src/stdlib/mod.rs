@@ -0,0 +1,43 @@
+pub mod base;
+pub mod coroutine;
+pub mod format;
+pub mod pack;
+pub mod pattern;
+pub mod string;
+pub mod table;
+
+#[cfg(test)]
+use crate::vm::{
+    lua_closure::{RustFn, RustFunction},
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState, LUA_MULTRET},
+    lua_value::LuaValue,
+};
+
+/// Call `f` with `args` on an emptied stack. The results are left on the
+/// stack and returned.
+#[cfg(test)]
+pub(crate) fn call_value(
+    state: &mut LuaState,
+    f: LuaValue,
+    args: Vec<LuaValue>,
+) -> Result<Vec<LuaValue>, LuaError> {
+    state.set_top(0);
+    state.stack.push(f);
+    let n = args.len();
+    args.into_iter().for_each(|arg| state.stack.push(arg));
+    state.call(n, LUA_MULTRET)?;
+    Ok((0..state.get_top() as i32)
+        .map(|idx| state.stack.get(idx))
+        .collect())
+}
+
+/// `call_value` for a library function.
+#[cfg(test)]
+pub(crate) fn call(
+    state: &mut LuaState,
+    f: RustFn,
+    args: Vec<LuaValue>,
+) -> Result<Vec<LuaValue>, LuaError> {
+    call_value(state, LuaValue::RustFunction(RustFunction::Fn(f)), args)
+}
@@ -0,0 +1,392 @@
+use crate::vm::codec::{Endian, INTEGER_SIZE};
+
+/// Largest size of integers in formats.
+pub const MAX_INT_SIZE: usize = 16;
+/// Largest size of a packed string, and of the sizes in a format.
+pub const MAX_SIZE: usize = i32::MAX as usize;
+/// Alignment of the most aligned native type, the default of `!`.
+const MAX_ALIGN: usize = 8;
+/// Size of a C `int`, the default of `i` and `I`.
+const INT_SIZE: usize = 4;
+/// Size of a C `size_t`, the default of `s` and the size of `T`.
+const SIZE_T_SIZE: usize = 8;
+
+/// What an option of a pack format stands for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Signed integer.
+    Int,
+    /// Unsigned integer.
+    Uint,
+    /// Float, of 4 or 8 bytes.
+    Float,
+    /// String of a fixed size, `cn`.
+    Char,
+    /// String preceded by its length, `sn`.
+    String,
+    /// Zero terminated string, `z`.
+    Zstr,
+    /// One byte of padding, `x`.
+    Padding,
+    /// Alignment to the next option, `Xop`.
+    PadAlign,
+    /// Options only changing the endianness or alignment.
+    Nop,
+}
+
+/// An option of a pack format.
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub kind: Kind,
+    pub size: usize,
+    /// Padding bytes before the value to align it.
+    pub align_pad: usize,
+}
+
+/// Error in a pack format.
+#[derive(Debug, PartialEq)]
+pub enum FormatError {
+    /// Raised as is.
+    Option(String),
+    /// Raised as an error of the format argument.
+    Argument(&'static str),
+}
+
+/// Reader of the options of a format of `string.pack`, `string.unpack` and
+/// `string.packsize`.
+pub struct PackFormat<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    /// Byte order of the next values, changed by `<`, `>` and `=`.
+    pub endian: Endian,
+    max_align: usize,
+}
+
+impl<'a> PackFormat<'a> {
+    pub fn new(fmt: &'a [u8]) -> PackFormat<'a> {
+        PackFormat {
+            fmt,
+            pos: 0,
+            endian: Endian::NATIVE,
+            max_align: 1,
+        }
+    }
+
+    /// Number at the current position, `default` when there is none.
+    fn number(&mut self, default: usize) -> usize {
+        if !self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            return default;
+        }
+        let mut n = 0;
+        while let Some(c) = self.fmt.get(self.pos).filter(|c| c.is_ascii_digit()) {
+            if n > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+            n = n * 10 + (c - b'0') as usize;
+            self.pos += 1;
+        }
+        n
+    }
+
+    /// Size of an integral option, `default` when not given.
+    fn int_size(&mut self, default: usize) -> Result<usize, FormatError> {
+        let size = self.number(default);
+        if size == 0 || size > MAX_INT_SIZE {
+            return Err(FormatError::Option(format!(
+                "integral size ({}) out of limits [1,{}]",
+                size, MAX_INT_SIZE
+            )));
+        }
+        Ok(size)
+    }
+
+    /// Read one option, its kind and size.
+    fn option(&mut self) -> Result<(Kind, usize), FormatError> {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        let option = match opt {
+            b'b' => (Kind::Int, 1),
+            b'B' => (Kind::Uint, 1),
+            b'h' => (Kind::Int, 2),
+            b'H' => (Kind::Uint, 2),
+            b'l' | b'j' => (Kind::Int, INTEGER_SIZE),
+            b'L' | b'J' => (Kind::Uint, INTEGER_SIZE),
+            b'T' => (Kind::Uint, SIZE_T_SIZE),
+            b'f' => (Kind::Float, 4),
+            b'n' | b'd' => (Kind::Float, 8),
+            b'i' => (Kind::Int, self.int_size(INT_SIZE)?),
+            b'I' => (Kind::Uint, self.int_size(INT_SIZE)?),
+            b's' => (Kind::String, self.int_size(SIZE_T_SIZE)?),
+            b'c' => match self.number(usize::MAX) {
+                usize::MAX => {
+                    let msg = "missing size for format option 'c'";
+                    return Err(FormatError::Option(msg.to_string()));
+                }
+                size => (Kind::Char, size),
+            },
+            b'z' => (Kind::Zstr, 0),
+            b'x' => (Kind::Padding, 1),
+            b'X' => (Kind::PadAlign, 0),
+            b' ' => (Kind::Nop, 0),
+            b'<' => {
+                self.endian = Endian::Little;
+                (Kind::Nop, 0)
+            }
+            b'>' => {
+                self.endian = Endian::Big;
+                (Kind::Nop, 0)
+            }
+            b'=' => {
+                self.endian = Endian::NATIVE;
+                (Kind::Nop, 0)
+            }
+            b'!' => {
+                self.max_align = self.int_size(MAX_ALIGN)?;
+                (Kind::Nop, 0)
+            }
+            _ => {
+                let msg = format!("invalid format option '{}'", opt as char);
+                return Err(FormatError::Option(msg));
+            }
+        };
+        Ok(option)
+    }
+
+    /// The next option, with the padding aligning it when packed after
+    /// `total` bytes, or `None` at the end of the format.
+    pub fn next_item(&mut self, total: usize) -> Result<Option<Item>, FormatError> {
+        if self.pos == self.fmt.len() {
+            return Ok(None);
+        }
+        let (kind, size) = self.option()?;
+        let mut align = size;
+        if kind == Kind::PadAlign {
+            // aligns like the next option, which is consumed
+            let next = if self.pos < self.fmt.len() {
+                Some(self.option()?)
+            } else {
+                None
+            };
+            match next {
+                Some((next_kind, next_size)) if next_kind != Kind::Char && next_size != 0 => {
+                    align = next_size;
+                }
+                _ => return Err(FormatError::Argument("invalid next option for option 'X'")),
+            }
+        }
+        let align_pad = if align <= 1 || kind == Kind::Char {
+            0
+        } else {
+            let align = align.min(self.max_align);
+            if !align.is_power_of_two() {
+                return Err(FormatError::Argument(
+                    "format asks for alignment not power of 2",
+                ));
+            }
+            (align - (total & (align - 1))) & (align - 1)
+        };
+        Ok(Some(Item {
+            kind,
+            size,
+            align_pad,
+        }))
+    }
+}
+
+#[test]
+fn test_pack_format() {
+    let items = |fmt: &str| {
+        let mut format = PackFormat::new(fmt.as_bytes());
+        let mut total = 0;
+        let mut items = vec![];
+        while let Some(item) = format.next_item(total)? {
+            total += item.align_pad + item.size;
+            items.push((item.kind, item.size, item.align_pad));
+        }
+        Ok::<_, FormatError>(items)
+    };
+    use Kind::*;
+
+    assert_eq!(
+        items("<i2 I j n c3 s1 z x").unwrap(),
+        [
+            (Nop, 0, 0),
+            (Int, 2, 0),
+            (Nop, 0, 0),
+            (Uint, 4, 0),
+            (Nop, 0, 0),
+            (Int, 8, 0),
+            (Nop, 0, 0),
+            (Float, 8, 0),
+            (Nop, 0, 0),
+            (Char, 3, 0),
+            (Nop, 0, 0),
+            (String, 1, 0),
+            (Nop, 0, 0),
+            (Zstr, 0, 0),
+            (Nop, 0, 0),
+            (Padding, 1, 0),
+        ]
+    );
+    // alignment only applies with '!'
+    assert_eq!(items("bi4").unwrap(), [(Int, 1, 0), (Int, 4, 0)]);
+    assert_eq!(items("!bi4").unwrap()[2], (Int, 4, 3));
+    assert_eq!(items("!2bd").unwrap()[2], (Float, 8, 1));
+    assert_eq!(items("!bXi8").unwrap()[2], (PadAlign, 0, 7));
+
+    let err = |fmt| items(fmt).unwrap_err();
+    let option = |msg: &str| FormatError::Option(msg.to_string());
+    assert_eq!(err("y"), option("invalid format option 'y'"));
+    assert_eq!(err("c"), option("missing size for format option 'c'"));
+    assert_eq!(
+        err("i17"),
+        option("integral size (17) out of limits [1,16]")
+    );
+    assert_eq!(err("i0"), option("integral size (0) out of limits [1,16]"));
+    assert_eq!(
+        err("c9223372036854775807"),
+        option("invalid format option '6'")
+    );
+    let argument = FormatError::Argument;
+    assert_eq!(err("X"), argument("invalid next option for option 'X'"));
+    assert_eq!(err("Xc1"), argument("invalid next option for option 'X'"));
+    assert_eq!(
+        err("!4i3"),
+        argument("format asks for alignment not power of 2")
+    );
+}
+
+#[test]
+fn test_string_pack() {
+    use crate::{
+        stdlib::{call, string},
+        vm::{lua_state::LuaState, lua_string::LuaString, lua_value::LuaValue},
+    };
+    let mut state = LuaState::new();
+    use LuaValue::{Integer, Number};
+    let s = |s: &[u8]| LuaValue::String(LuaString::new(s));
+
+    let packed = call(
+        &mut state,
+        string::pack,
+        vec![
+            s(b">i2 <I3 b s1 z"),
+            Integer(-2),
+            Integer(0x010203),
+            Integer(7),
+            s(b"ab"),
+            s(b"c"),
+        ],
+    )
+    .unwrap();
+    let bytes = b"\xff\xfe\x03\x02\x01\x07\x02abc\0";
+    assert_eq!(packed, [s(bytes)]);
+    assert_eq!(
+        call(
+            &mut state,
+            string::unpack,
+            vec![s(b">i2 <I3 b s1 z"), s(bytes)]
+        )
+        .unwrap(),
+        [
+            Integer(-2),
+            Integer(0x010203),
+            Integer(7),
+            s(b"ab"),
+            s(b"c"),
+            Integer(12)
+        ]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            string::pack,
+            vec![s(b"<!4 b i4 xXd c2"), Integer(1), Integer(2), s(b"z")]
+        )
+        .unwrap(),
+        [s(b"\x01\0\0\0\x02\0\0\0\0\0\0\0z\0")]
+    );
+    assert_eq!(
+        call(&mut state, string::packsize, vec![s(b"<!4 b i4 xXd c2")]).unwrap(),
+        [Integer(14)]
+    );
+    let floats = call(
+        &mut state,
+        string::pack,
+        vec![s(b"<d f"), Number(0.1), Number(1.5)],
+    )
+    .unwrap();
+    assert_eq!(
+        call(
+            &mut state,
+            string::unpack,
+            vec![s(b"<d f"), floats[0].clone()]
+        )
+        .unwrap(),
+        [Number(0.1), Number(1.5), Integer(13)]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            string::unpack,
+            vec![s(b"B"), s(b"abc"), Integer(-1)]
+        )
+        .unwrap(),
+        [Integer(99), Integer(4)]
+    );
+    assert_eq!(
+        call(&mut state, string::unpack, vec![s(b"<i9"), s(&[0xff; 9])]).unwrap(),
+        [Integer(-1), Integer(10)]
+    );
+
+    let mut err = |f, args| call(&mut state, f, args).unwrap_err().to_string();
+    assert_eq!(
+        err(string::pack, vec![s(b"b"), Integer(128)]),
+        "bad argument #2 to 'pack' (integer overflow)"
+    );
+    assert_eq!(
+        err(string::pack, vec![s(b"I2"), Integer(-1)]),
+        "bad argument #2 to 'pack' (unsigned overflow)"
+    );
+    assert_eq!(
+        err(string::pack, vec![s(b"c1"), s(b"ab")]),
+        "bad argument #2 to 'pack' (string longer than given size)"
+    );
+    assert_eq!(
+        err(string::pack, vec![s(b"i"), s(b"x")]),
+        "bad argument #2 to 'pack' (number expected, got string)"
+    );
+    assert_eq!(
+        err(string::pack, vec![s(b"q")]),
+        "invalid format option 'q'"
+    );
+    assert_eq!(
+        err(string::packsize, vec![s(b"s")]),
+        "bad argument #1 to 'packsize' (variable-length format)"
+    );
+    assert_eq!(
+        err(string::packsize, vec![s(b"c2147483000c2147483000")]),
+        "bad argument #1 to 'packsize' (format result too large)"
+    );
+    // sizes stop before overflowing, the digits left are options
+    assert_eq!(
+        err(string::packsize, vec![s(b"i16c9223372036854775807")]),
+        "invalid format option '6'"
+    );
+    assert_eq!(
+        err(string::unpack, vec![s(b"i4"), s(b"abc")]),
+        "bad argument #2 to 'unpack' (data string too short)"
+    );
+    assert_eq!(
+        err(string::unpack, vec![s(b"z"), s(b"abc")]),
+        "bad argument #2 to 'unpack' (unfinished string for format 'z')"
+    );
+    assert_eq!(
+        err(string::unpack, vec![s(b"b"), s(b"abc"), Integer(5)]),
+        "bad argument #3 to 'unpack' (initial position out of string)"
+    );
+    assert_eq!(
+        err(string::unpack, vec![s(b"<I9"), s(&[0xff; 9])]),
+        "9-byte integer does not fit into Lua Integer"
+    );
+}
@@ -0,0 +1,471 @@
+use crate::vm::lua_value::LuaValue;
+
+/// Escape character of patterns.
+const L_ESC: u8 = b'%';
+/// Characters making a pattern more than a plain string.
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+/// Maximum number of captures in a pattern.
+pub const MAXCAPTURES: usize = 32;
+/// Maximum recursion depth of the matcher.
+const MAXCCALLS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureLen {
+    /// The capture is still open.
+    Unfinished,
+    /// A `()` capture of the position.
+    Position,
+    Len(usize),
+}
+
+/// State of matching the pattern `pat` against the subject `src`,
+/// `lstrlib.c`'s `MatchState`. Positions are byte offsets into both.
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    capture: [(usize, CaptureLen); MAXCAPTURES],
+    matchdepth: usize,
+}
+
+/// Whether `pat` has no special characters and can be searched as is.
+pub fn no_specials(pat: &[u8]) -> bool {
+    !pat.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Whether `c` belongs to the class `%cl`, an upper case `cl` is the
+/// complement of the class.
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c'),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return cl == c,
+    };
+    if cl.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> MatchState<'a> {
+        MatchState {
+            src,
+            pat,
+            level: 0,
+            capture: [(0, CaptureLen::Unfinished); MAXCAPTURES],
+            matchdepth: MAXCCALLS,
+        }
+    }
+
+    /// Forget the captures of the previous attempt.
+    pub fn reprep(&mut self) {
+        self.level = 0;
+        self.matchdepth = MAXCCALLS;
+    }
+
+    /// The subject string.
+    pub fn src(&self) -> &'a [u8] {
+        self.src
+    }
+
+    /// Number of captures of the last match.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Pattern byte at `p`, 0 past the end like the terminator of a C
+    /// string.
+    fn pat_at(&self, p: usize) -> u8 {
+        self.pat.get(p).copied().unwrap_or(0)
+    }
+
+    /// End of the single character class starting at `p`.
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat[p];
+        p += 1;
+        match c {
+            L_ESC => {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if self.pat_at(p) == b'^' {
+                    p += 1;
+                }
+                // look for a ']', the first character of the set is never it
+                loop {
+                    if p >= self.pat.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = self.pat[p];
+                    p += 1;
+                    if c == L_ESC && p < self.pat.len() {
+                        p += 1;
+                    }
+                    if self.pat_at(p) == b']' {
+                        break;
+                    }
+                }
+                Ok(p + 1)
+            }
+            _ => Ok(p),
+        }
+    }
+
+    /// Whether `c` is in the set `[...]` from `p` to its closing bracket at
+    /// `ec`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat_at(p + 1) == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, self.pat_at(p)) {
+                    return sig;
+                }
+            } else if self.pat_at(p + 1) == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    /// Whether the subject character at `s` matches the class from `p` to
+    /// `ep`.
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pat_at(p + 1)),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    /// Match `pat[p..]` against `src[s..]`, the end of the match or `None`.
+    pub fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if self.matchdepth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.matchdepth -= 1;
+        let res = self.match_rest(s, p);
+        self.matchdepth += 1;
+        res
+    }
+
+    fn match_rest(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p == self.pat.len() {
+                return Ok(Some(s));
+            }
+            match self.pat[p] {
+                b'(' => {
+                    return if self.pat_at(p + 1) == b')' {
+                        self.start_capture(s, p + 2, CaptureLen::Position)
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unfinished)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                L_ESC if self.pat_at(p + 1) == b'b' => match self.match_balance(s, p + 2)? {
+                    Some(e) => {
+                        s = e;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                L_ESC if self.pat_at(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pat_at(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                L_ESC if self.pat_at(p + 1).is_ascii_digit() => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let ep_char = self.pat_at(ep);
+                    if !self.single_match(s, p, ep) {
+                        if matches!(ep_char, b'*' | b'?' | b'-') {
+                            // accept the empty match
+                            p = ep + 1;
+                            continue;
+                        }
+                        return Ok(None);
+                    }
+                    match ep_char {
+                        b'?' => {
+                            if let Some(res) = self.do_match(s + 1, ep + 1)? {
+                                return Ok(Some(res));
+                            }
+                            p = ep + 1;
+                        }
+                        b'+' => return self.max_expand(s + 1, p, ep),
+                        b'*' => return self.max_expand(s, p, ep),
+                        b'-' => return self.min_expand(s, p, ep),
+                        _ => {
+                            s += 1;
+                            p = ep;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Longest repetition of the class `p..ep` that lets the rest match.
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    /// Shortest repetition of the class `p..ep` that lets the rest match.
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        what: CaptureLen,
+    ) -> Result<Option<usize>, String> {
+        if self.level >= MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = CaptureLen::Len(s - self.capture[l].0);
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].1 = CaptureLen::Unfinished;
+        }
+        Ok(res)
+    }
+
+    /// The innermost open capture.
+    fn capture_to_close(&self) -> Result<usize, String> {
+        (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CaptureLen::Unfinished)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    /// `%bxy`: from an `x` to the matching `y`.
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (b, e) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&b) {
+            return Ok(None);
+        }
+        let mut cont = 1;
+        for i in s + 1..self.src.len() {
+            if self.src[i] == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if self.src[i] == b {
+                cont += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// `%1` to `%9`: the same text as a closed capture.
+    fn match_capture(&self, s: usize, l: u8) -> Result<Option<usize>, String> {
+        let l = self.check_capture(l)?;
+        let (init, CaptureLen::Len(len)) = self.capture[l] else {
+            return Ok(None);
+        };
+        let matches =
+            self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len];
+        Ok(matches.then_some(s + len))
+    }
+
+    fn check_capture(&self, l: u8) -> Result<usize, String> {
+        let idx = (l as usize).wrapping_sub(b'1' as usize);
+        if idx >= self.level || self.capture[idx].1 == CaptureLen::Unfinished {
+            return Err(format!(
+                "invalid capture index %{} in pattern",
+                (l as i32) - (b'0' as i32)
+            ));
+        }
+        Ok(idx)
+    }
+
+    /// Capture `i` of the match `s..e`, the whole match stands for capture 0
+    /// when the pattern has none.
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> Result<LuaValue, String> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
+            }
+            return Ok(LuaValue::String(self.src[s..e].into()));
+        }
+        match self.capture[i] {
+            (_, CaptureLen::Unfinished) => Err("unfinished capture".to_string()),
+            (init, CaptureLen::Position) => Ok(LuaValue::Integer(init as i64 + 1)),
+            (init, CaptureLen::Len(len)) => Ok(LuaValue::String(self.src[init..init + len].into())),
+        }
+    }
+
+    /// All captures of the match `s..e`, or the whole match without any.
+    pub fn captures(&self, s: usize, e: usize) -> Result<Vec<LuaValue>, String> {
+        let n = self.level.max(1);
+        (0..n).map(|i| self.get_capture(i, s, e)).collect()
+    }
+}
+
+#[cfg(test)]
+fn find(src: &str, pat: &str) -> Result<Option<(usize, usize, Vec<LuaValue>)>, String> {
+    let (anchor, pat) = match pat.strip_prefix('^') {
+        Some(pat) => (true, pat),
+        None => (false, pat),
+    };
+    let mut ms = MatchState::new(src.as_bytes(), pat.as_bytes());
+    for s in 0..=src.len() {
+        ms.reprep();
+        if let Some(e) = ms.do_match(s, 0)? {
+            return Ok(Some((s, e, ms.captures(s, e)?)));
+        }
+        if anchor {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_pattern_classes() {
+    let span = |src, pat| find(src, pat).unwrap().map(|(s, e, _)| (s, e));
+
+    assert_eq!(span("hello world", "o w"), Some((4, 7)));
+    assert_eq!(span("hello world", "%a+"), Some((0, 5)));
+    assert_eq!(span("x = 123", "%d+"), Some((4, 7)));
+    assert_eq!(span("x = 123", "%D+"), Some((0, 4)));
+    assert_eq!(span("  key", "%S"), Some((2, 3)));
+    assert_eq!(span("a.b", "%."), Some((1, 2)));
+    assert_eq!(span("abc", "^b"), None);
+    assert_eq!(span("abc", "c$"), Some((2, 3)));
+    assert_eq!(span("a$c", "$c"), Some((1, 3)));
+    assert_eq!(span("f(a(b)c)d", "%b()"), Some((1, 8)));
+    assert_eq!(span("THE (quick) fox", "%f[%a]%a+"), Some((0, 3)));
+    assert_eq!(span("THE (quick) fox", "%f[%l]%a+"), Some((5, 10)));
+    assert_eq!(span("hex: 0x1F!", "[%x]+"), Some((1, 2)));
+    assert_eq!(span("hex: 0x1F!", "0x[%dA-F]+"), Some((5, 9)));
+    assert_eq!(span("abc-def", "[^%a]"), Some((3, 4)));
+    assert_eq!(span("a]b", "[]]"), Some((1, 2)));
+    assert_eq!(span("a-b", "[a-]+"), Some((0, 2)));
+    assert_eq!(span("<<a>>", "<.->"), Some((0, 4)));
+    assert_eq!(span("<<a>>", "<.*>"), Some((0, 5)));
+    assert_eq!(span("ab", "ax?b"), Some((0, 2)));
+    assert_eq!(span("", "x*"), Some((0, 0)));
+}
+
+#[test]
+fn test_pattern_captures() {
+    let caps = |src, pat| find(src, pat).unwrap().unwrap().2;
+    let s = |s: &str| LuaValue::String(s.into());
+
+    assert_eq!(
+        caps("key = value", "(%w+)%s*=%s*(%w+)"),
+        [s("key"), s("value")]
+    );
+    assert_eq!(
+        caps("hello", "()ll()"),
+        [LuaValue::Integer(3), LuaValue::Integer(5)]
+    );
+    assert_eq!(caps("say 'hi' now", "(['\"])(.-)%1"), [s("'"), s("hi")]);
+    assert_eq!(caps("abc", "((a)(b))"), [s("ab"), s("a"), s("b")]);
+    assert_eq!(caps("abc", "b"), [s("b")]);
+
+    let err = |pat| find("abc", pat).unwrap_err();
+    assert_eq!(err("%"), "malformed pattern (ends with '%')");
+    assert_eq!(err("[a"), "malformed pattern (missing ']')");
+    assert_eq!(err("(a"), "unfinished capture");
+    assert_eq!(err("a)"), "invalid pattern capture");
+    assert_eq!(err("%1"), "invalid capture index %1 in pattern");
+    assert_eq!(err("%b"), "malformed pattern (missing arguments to '%b')");
+    assert_eq!(err("%fa"), "missing '[' after '%f' in pattern");
+    assert_eq!(
+        find(&"a".repeat(300), &"a?".repeat(300)).unwrap_err(),
+        "pattern too complex"
+    );
+}
@@ -0,0 +1,983 @@
+use std::cell::Cell;
+
+use crate::{
+    stdlib::{
+        format::{quote_float, quote_string, Spec, FLAGS, FLAGS_C, FLAGS_I, FLAGS_U, FLAGS_X},
+        pack::{self, FormatError, Kind, PackFormat},
+        pattern::{no_specials, MatchState},
+    },
+    vm::{
+        codec::{pack_float, pack_int, unpack_float, unpack_int, INTEGER_SIZE},
+        lua_closure::RustFn,
+        lua_error::LuaError,
+        lua_state::{LuaApi, LuaState},
+        lua_string::LuaString,
+        lua_value::LuaValue,
+        number::number_to_str,
+    },
+};
+
+/// Functions of the `string` table.
+const FUNCS: [(&str, RustFn); 16] = [
+    ("byte", byte),
+    ("char", char),
+    ("find", find),
+    ("format", format),
+    ("gmatch", gmatch),
+    ("gsub", gsub),
+    ("len", len),
+    ("lower", lower),
+    ("match", match_),
+    ("pack", pack),
+    ("packsize", packsize),
+    ("rep", rep),
+    ("reverse", reverse),
+    ("sub", sub),
+    ("unpack", unpack),
+    ("upper", upper),
+];
+
+/// Results longer than this are refused.
+const MAX_SIZE: usize = i32::MAX as usize;
+/// Maximum length of a conversion specification between `%` and the
+/// conversion character.
+const MAX_FORMAT: usize = 22;
+
+impl LuaState {
+    /// Set the global `string` to a table with the string functions, which
+    /// is also the `__index` of the metatable of strings.
+    pub fn open_string(&mut self) -> Result<(), LuaError> {
+        self.create_table(0, FUNCS.len());
+        for (name, f) in FUNCS {
+            self.push_rust_function(f);
+            self.set_field(-2, name)?;
+        }
+        self.create_table(0, 1);
+        self.push_value(-2);
+        self.set_field(-2, "__index")?;
+        self.push_string("");
+        self.push_value(-2);
+        self.set_metatable(-2);
+        self.pop(2);
+        self.set_global("string")
+    }
+}
+
+/// Position `pos` in a string of length `len`, negative ones count from the
+/// end. Positions before the start are clipped to 1.
+fn posrelat_start(pos: i64, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos.unsigned_abs() > len as u64 {
+        1
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/// End position `pos` in a string of length `len`, clipped to the string.
+fn posrelat_end(pos: i64, len: usize) -> usize {
+    if pos > len as i64 {
+        len
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/// Push all captures of the match `s..e`, their number.
+fn push_captures(
+    state: &mut LuaState,
+    ms: &MatchState,
+    s: usize,
+    e: usize,
+) -> Result<usize, LuaError> {
+    let captures = ms.captures(s, e).map_err(|msg| state.lib_error(msg))?;
+    let n = captures.len();
+    if !state.check_stack(n) {
+        return Err(state.lib_error("too many captures"));
+    }
+    for val in captures {
+        state.stack.push(val);
+    }
+    Ok(n)
+}
+
+/// `string.find(s, pattern [, init [, plain]])`
+///
+/// Start and end of the first match of `pattern` in `s` from `init`,
+/// followed by the captures, or nil. With `plain` the pattern is a plain
+/// substring.
+pub fn find(state: &mut LuaState) -> Result<usize, LuaError> {
+    str_find_aux(state, true)
+}
+
+/// `string.match(s, pattern [, init])`
+///
+/// The captures of the first match of `pattern` in `s` from `init`, the
+/// whole match without captures, or nil.
+pub fn match_(state: &mut LuaState) -> Result<usize, LuaError> {
+    str_find_aux(state, false)
+}
+
+fn str_find_aux(state: &mut LuaState, find: bool) -> Result<usize, LuaError> {
+    let fname = if find { "find" } else { "match" };
+    let s = state.check_string(1, fname)?;
+    let p = state.check_string(2, fname)?;
+    let init = posrelat_start(state.opt_integer(3, fname, 1)?, s.len()) - 1;
+    if init > s.len() {
+        state.push_nil();
+        return Ok(1);
+    }
+    if find && (state.to_boolean(3) || no_specials(&p)) {
+        let found = if p.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(p.len()).position(|w| w == &p[..])
+        };
+        if let Some(i) = found {
+            state.push_integer((init + i + 1) as i64);
+            state.push_integer((init + i + p.len()) as i64);
+            return Ok(2);
+        }
+    } else {
+        let (anchor, pat) = match p.strip_prefix(b"^") {
+            Some(pat) => (true, pat),
+            None => (false, &p[..]),
+        };
+        let mut ms = MatchState::new(&s, pat);
+        for s1 in init..=s.len() {
+            ms.reprep();
+            if let Some(e) = ms.do_match(s1, 0).map_err(|msg| state.lib_error(msg))? {
+                if !find {
+                    return push_captures(state, &ms, s1, e);
+                }
+                state.push_integer(s1 as i64 + 1);
+                state.push_integer(e as i64);
+                let n = if ms.level() > 0 {
+                    push_captures(state, &ms, s1, e)?
+                } else {
+                    0
+                };
+                return Ok(n + 2);
+            }
+            if anchor {
+                break;
+            }
+        }
+    }
+    state.push_nil();
+    Ok(1)
+}
+
+/// `string.gmatch(s, pattern [, init])`
+///
+/// Iterator over the matches of `pattern` in `s` from `init`, each call
+/// returns the captures of the next match.
+pub fn gmatch(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "gmatch")?;
+    let p = state.check_string(2, "gmatch")?;
+    let init = posrelat_start(state.opt_integer(3, "gmatch", 1)?, s.len()) - 1;
+    // past the end no match is possible, not even an empty one
+    let pos = Cell::new(init.min(s.len() + 1));
+    let last_match = Cell::new(None);
+    state.push_rust_closure(move |state| {
+        let mut ms = MatchState::new(&s, &p);
+        for src in pos.get()..=s.len() {
+            ms.reprep();
+            match ms.do_match(src, 0).map_err(|msg| state.lib_error(msg))? {
+                // an empty match right after the previous one is skipped
+                Some(e) if last_match.get() != Some(e) => {
+                    pos.set(e);
+                    last_match.set(Some(e));
+                    return push_captures(state, &ms, src, e);
+                }
+                _ => {}
+            }
+        }
+        pos.set(s.len() + 1);
+        Ok(0)
+    });
+    Ok(1)
+}
+
+/// `string.gsub(s, pattern, repl [, n])`
+///
+/// Copy of `s` with the first `n`, by default all, matches of `pattern`
+/// replaced by `repl`, and the number of matches. A string `repl` may refer
+/// to captures with `%1` to `%9` and to the whole match with `%0`, a table
+/// is indexed with the first capture and a function called with all of
+/// them. A false or nil replacement keeps the match.
+pub fn gsub(state: &mut LuaState) -> Result<usize, LuaError> {
+    let src = state.check_string(1, "gsub")?;
+    let p = state.check_string(2, "gsub")?;
+    let repl_type = state.type_name(2);
+    if !matches!(repl_type, "number" | "string" | "function" | "table") {
+        return Err(state.type_error(3, "gsub", "string/function/table"));
+    }
+    let max_n = state.opt_integer(4, "gsub", src.len() as i64 + 1)?;
+    let (anchor, pat) = match p.strip_prefix(b"^") {
+        Some(pat) => (true, pat),
+        None => (false, &p[..]),
+    };
+    let mut ms = MatchState::new(&src, pat);
+    let mut out = Vec::new();
+    let mut s = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while n < max_n {
+        ms.reprep();
+        match ms.do_match(s, 0).map_err(|msg| state.lib_error(msg))? {
+            Some(e) if last_match != Some(e) => {
+                n += 1;
+                add_value(state, &ms, &mut out, s, e, repl_type)?;
+                s = e;
+                last_match = Some(e);
+            }
+            _ if s < src.len() => {
+                out.push(src[s]);
+                s += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[s..]);
+    state.push_string(out);
+    state.push_integer(n);
+    Ok(2)
+}
+
+/// Append the replacement of the match `s..e` to `out`.
+fn add_value(
+    state: &mut LuaState,
+    ms: &MatchState,
+    out: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    repl_type: &str,
+) -> Result<(), LuaError> {
+    let val = match repl_type {
+        "function" => {
+            state.push_value(2);
+            let n = push_captures(state, ms, s, e)?;
+            state.call(n, 1)?;
+            state.stack.pop()
+        }
+        "table" => {
+            let key = ms
+                .get_capture(0, s, e)
+                .map_err(|msg| state.lib_error(msg))?;
+            state.stack.push(key);
+            state.get_table(2)?;
+            state.stack.pop()
+        }
+        _ => return add_string(state, ms, out, s, e),
+    };
+    match val {
+        LuaValue::Nil | LuaValue::Boolean(false) => out.extend_from_slice(&ms.src()[s..e]),
+        LuaValue::String(r) => out.extend_from_slice(&r),
+        LuaValue::Integer(_) | LuaValue::Number(_) => {
+            out.extend_from_slice(number_to_str(&val).unwrap().as_bytes())
+        }
+        val => {
+            let msg = format!("invalid replacement value (a {})", val.type_name());
+            return Err(state.lib_error(msg));
+        }
+    }
+    Ok(())
+}
+
+/// Append the replacement string, with its capture references resolved.
+fn add_string(
+    state: &mut LuaState,
+    ms: &MatchState,
+    out: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+) -> Result<(), LuaError> {
+    let repl = state.check_string(3, "gsub")?;
+    let mut chars = repl.iter();
+    while let Some(&c) = chars.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'%') => out.push(b'%'),
+            Some(b'0') => out.extend_from_slice(&ms.src()[s..e]),
+            Some(&d) if d.is_ascii_digit() => {
+                let capture = ms
+                    .get_capture((d - b'1') as usize, s, e)
+                    .map_err(|msg| state.lib_error(msg))?;
+                state.stack.push(capture);
+                let capture = state.to_lstring(-1)?;
+                state.pop(1);
+                out.extend_from_slice(&capture);
+            }
+            _ => return Err(state.lib_error("invalid use of '%' in replacement string")),
+        }
+    }
+    Ok(())
+}
+
+/// `string.format(formatstring, ...)`
+///
+/// The arguments formatted like C's `printf` does. `%q` writes a literal
+/// that reads back as the same value, `%s` converts like `tostring`.
+pub fn format(state: &mut LuaState) -> Result<usize, LuaError> {
+    let top = state.get_top();
+    let fmt = state.check_string(1, "format")?;
+    let mut out = Vec::with_capacity(fmt.len());
+    let mut arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        arg += 1;
+        if arg > top {
+            return Err(state.arg_error(arg, "format", "no value"));
+        }
+        // flags, width and precision, checked once the conversion is known
+        let len = fmt[i..]
+            .iter()
+            .take_while(|c| b"-+ #0123456789.".contains(c))
+            .count();
+        if len >= MAX_FORMAT {
+            return Err(state.lib_error("invalid format string to 'format'"));
+        }
+        let form = &fmt[i..i + len];
+        let conv = fmt.get(i + len).copied();
+        i += len + 1;
+        let form_str = || {
+            let conv = conv.map(|c| c as char).unwrap_or_default();
+            format!("%{}{}", String::from_utf8_lossy(form), conv)
+        };
+        let spec = |state: &mut LuaState, flags, precision| {
+            Spec::parse(form, flags, precision).ok_or_else(|| {
+                state.lib_error(format!(
+                    "invalid conversion specification: '{}'",
+                    form_str()
+                ))
+            })
+        };
+        match conv {
+            Some(b'c') => {
+                let c = state.check_integer(arg, "format")?;
+                spec(state, FLAGS_C, false)?.format_char(&mut out, c as u8);
+            }
+            Some(conv @ (b'd' | b'i' | b'u' | b'o' | b'x' | b'X')) => {
+                let n = state.check_integer(arg, "format")?;
+                let flags = match conv {
+                    b'd' | b'i' => FLAGS_I,
+                    b'u' => FLAGS_U,
+                    _ => FLAGS_X,
+                };
+                spec(state, flags, true)?.format_integer(&mut out, n, conv);
+            }
+            Some(conv @ (b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G')) => {
+                let n = state.check_number(arg, "format")?;
+                spec(state, FLAGS, true)?.format_float(&mut out, n, conv);
+            }
+            Some(b'q') => {
+                if len != 0 {
+                    return Err(state.lib_error("specifier '%q' cannot have modifiers"));
+                }
+                add_literal(state, &mut out, arg)?;
+            }
+            Some(b's') => {
+                let s = state.to_lstring(arg as i32 - 1)?;
+                if len == 0 {
+                    out.extend_from_slice(&s);
+                    continue;
+                }
+                if s.contains(&0) {
+                    return Err(state.arg_error(arg, "format", "string contains zeros"));
+                }
+                let spec = spec(state, FLAGS_C, true)?;
+                // no precision and long enough to not need padding
+                if !form.contains(&b'.') && s.len() >= 100 {
+                    out.extend_from_slice(&s);
+                } else {
+                    spec.format_string(&mut out, &s);
+                }
+            }
+            _ => {
+                let msg = format!("invalid conversion '{}' to 'format'", form_str());
+                return Err(state.lib_error(msg));
+            }
+        }
+    }
+    state.push_string(out);
+    Ok(1)
+}
+
+/// Append the argument `arg` as a literal in Lua source, for `%q`.
+fn add_literal(state: &mut LuaState, out: &mut Vec<u8>, arg: usize) -> Result<(), LuaError> {
+    match state.stack.get(arg as i32 - 1) {
+        LuaValue::String(s) => quote_string(out, &s),
+        // the minimum integer has no decimal numeral, its negation overflows
+        LuaValue::Integer(i64::MIN) => out.extend_from_slice(b"0x8000000000000000"),
+        LuaValue::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        LuaValue::Number(n) => out.extend_from_slice(quote_float(n).as_bytes()),
+        LuaValue::Nil | LuaValue::Boolean(_) => {
+            let s = state.to_lstring(arg as i32 - 1)?;
+            out.extend_from_slice(&s);
+        }
+        _ => return Err(state.arg_error(arg, "format", "value has no literal form")),
+    }
+    Ok(())
+}
+
+/// Error raised for a bad pack format of `fname`.
+fn format_error(state: &mut LuaState, fname: &str, err: FormatError) -> LuaError {
+    match err {
+        FormatError::Option(msg) => state.lib_error(msg),
+        FormatError::Argument(msg) => state.arg_error(1, fname, msg),
+    }
+}
+
+/// `string.pack(fmt, v1, v2, ...)`
+///
+/// Binary string with the values serialized as the format `fmt` says.
+pub fn pack(state: &mut LuaState) -> Result<usize, LuaError> {
+    let fmt = state.check_string(1, "pack")?;
+    let mut format = PackFormat::new(&fmt);
+    let mut out = Vec::new();
+    let mut arg = 1;
+    loop {
+        let item = format
+            .next_item(out.len())
+            .map_err(|err| format_error(state, "pack", err))?;
+        let Some(item) = item else {
+            break;
+        };
+        let (size, endian) = (item.size, format.endian);
+        out.resize(out.len() + item.align_pad, 0);
+        if !matches!(item.kind, Kind::Padding | Kind::PadAlign | Kind::Nop) {
+            arg += 1;
+        }
+        match item.kind {
+            Kind::Int => {
+                let n = state.check_integer(arg, "pack")?;
+                if size < INTEGER_SIZE {
+                    let lim = 1 << (size * 8 - 1);
+                    if !(-lim..lim).contains(&n) {
+                        return Err(state.arg_error(arg, "pack", "integer overflow"));
+                    }
+                }
+                pack_int(&mut out, n as u64, size, endian, n < 0);
+            }
+            Kind::Uint => {
+                let n = state.check_integer(arg, "pack")?;
+                if size < INTEGER_SIZE && (n as u64) >= 1 << (size * 8) {
+                    return Err(state.arg_error(arg, "pack", "unsigned overflow"));
+                }
+                pack_int(&mut out, n as u64, size, endian, false);
+            }
+            Kind::Float => {
+                let n = state.check_number(arg, "pack")?;
+                pack_float(&mut out, n, size, endian);
+            }
+            Kind::Char => {
+                let s = state.check_string(arg, "pack")?;
+                if s.len() > size {
+                    return Err(state.arg_error(arg, "pack", "string longer than given size"));
+                }
+                out.extend_from_slice(&s);
+                out.resize(out.len() + size - s.len(), 0);
+            }
+            Kind::String => {
+                let s = state.check_string(arg, "pack")?;
+                if size < INTEGER_SIZE && s.len() as u64 >= 1 << (size * 8) {
+                    let msg = "string length does not fit in given size";
+                    return Err(state.arg_error(arg, "pack", msg));
+                }
+                pack_int(&mut out, s.len() as u64, size, endian, false);
+                out.extend_from_slice(&s);
+            }
+            Kind::Zstr => {
+                let s = state.check_string(arg, "pack")?;
+                if s.contains(&0) {
+                    return Err(state.arg_error(arg, "pack", "string contains zeros"));
+                }
+                out.extend_from_slice(&s);
+                out.push(0);
+            }
+            Kind::Padding => out.push(0),
+            Kind::PadAlign | Kind::Nop => {}
+        }
+    }
+    state.push_string(out);
+    Ok(1)
+}
+
+/// `string.packsize(fmt)`
+///
+/// Length of the string `string.pack` returns for the format `fmt`, which
+/// can not have variable-length options.
+pub fn packsize(state: &mut LuaState) -> Result<usize, LuaError> {
+    let fmt = state.check_string(1, "packsize")?;
+    let mut format = PackFormat::new(&fmt);
+    let mut total = 0;
+    loop {
+        let item = format
+            .next_item(total)
+            .map_err(|err| format_error(state, "packsize", err))?;
+        let Some(item) = item else {
+            break;
+        };
+        if matches!(item.kind, Kind::String | Kind::Zstr) {
+            return Err(state.arg_error(1, "packsize", "variable-length format"));
+        }
+        let size = item.size + item.align_pad;
+        if total > pack::MAX_SIZE - size {
+            return Err(state.arg_error(1, "packsize", "format result too large"));
+        }
+        total += size;
+    }
+    state.push_integer(total as i64);
+    Ok(1)
+}
+
+/// `string.unpack(fmt, s [, pos])`
+///
+/// The values serialized in `s` from `pos` on as the format `fmt` says,
+/// followed by the position after the last byte read.
+pub fn unpack(state: &mut LuaState) -> Result<usize, LuaError> {
+    let fmt = state.check_string(1, "unpack")?;
+    let data = state.check_string(2, "unpack")?;
+    let mut pos = posrelat_start(state.opt_integer(3, "unpack", 1)?, data.len()) - 1;
+    if pos > data.len() {
+        return Err(state.arg_error(3, "unpack", "initial position out of string"));
+    }
+    let mut format = PackFormat::new(&fmt);
+    let mut n = 0;
+    loop {
+        let item = format
+            .next_item(pos)
+            .map_err(|err| format_error(state, "unpack", err))?;
+        let Some(item) = item else {
+            break;
+        };
+        let (size, endian) = (item.size, format.endian);
+        if item.align_pad + size > data.len() - pos {
+            return Err(state.arg_error(2, "unpack", "data string too short"));
+        }
+        pos += item.align_pad;
+        // room for the value and the final position
+        if !state.check_stack(2) {
+            return Err(state.lib_error("too many results"));
+        }
+        let bytes = &data[pos..pos + size];
+        let int = |state: &mut LuaState, signed| {
+            unpack_int(bytes, endian, signed).ok_or_else(|| {
+                let msg = format!("{}-byte integer does not fit into Lua Integer", size);
+                state.lib_error(msg)
+            })
+        };
+        match item.kind {
+            Kind::Int | Kind::Uint => {
+                let i = int(state, item.kind == Kind::Int)?;
+                state.push_integer(i);
+            }
+            Kind::Float => state.push_number(unpack_float(bytes, endian)),
+            Kind::Char => state.push_string(bytes),
+            Kind::String => {
+                let len = int(state, false)? as u64;
+                if len > (data.len() - pos - size) as u64 {
+                    return Err(state.arg_error(2, "unpack", "data string too short"));
+                }
+                let start = pos + size;
+                state.push_string(&data[start..start + len as usize]);
+                pos += len as usize;
+            }
+            Kind::Zstr => {
+                let Some(len) = data[pos..].iter().position(|&c| c == 0) else {
+                    let msg = "unfinished string for format 'z'";
+                    return Err(state.arg_error(2, "unpack", msg));
+                };
+                state.push_string(&data[pos..pos + len]);
+                pos += len + 1;
+            }
+            Kind::Padding | Kind::PadAlign | Kind::Nop => {
+                pos += size;
+                continue;
+            }
+        }
+        n += 1;
+        pos += size;
+    }
+    state.push_integer(pos as i64 + 1);
+    Ok(n + 1)
+}
+
+/// `string.sub(s, i [, j])`
+///
+/// The substring of `s` from `i` to `j`, by default the end. Negative
+/// positions count from the end.
+pub fn sub(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "sub")?;
+    let start = posrelat_start(state.check_integer(2, "sub")?, s.len());
+    let end = posrelat_end(state.opt_integer(3, "sub", -1)?, s.len());
+    if start <= end {
+        state.push_string(&s[start - 1..end]);
+    } else {
+        state.push_string("");
+    }
+    Ok(1)
+}
+
+/// `string.byte(s [, i [, j]])`
+///
+/// Codes of the characters `s[i]` to `s[j]`, `i` defaults to 1 and `j` to
+/// `i`.
+pub fn byte(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "byte")?;
+    let i = state.opt_integer(2, "byte", 1)?;
+    let start = posrelat_start(i, s.len());
+    let end = posrelat_end(state.opt_integer(3, "byte", i)?, s.len());
+    if start > end {
+        return Ok(0);
+    }
+    let n = end - start + 1;
+    if n >= MAX_SIZE || !state.check_stack(n) {
+        return Err(state.lib_error("string slice too long"));
+    }
+    for &c in &s[start - 1..end] {
+        state.push_integer(c as i64);
+    }
+    Ok(n)
+}
+
+/// `string.char(...)`
+///
+/// String of the characters with the given codes.
+pub fn char(state: &mut LuaState) -> Result<usize, LuaError> {
+    let n = state.get_top();
+    let mut bytes = Vec::with_capacity(n);
+    for arg in 1..=n {
+        let c = state.check_integer(arg, "char")?;
+        match u8::try_from(c) {
+            Ok(c) => bytes.push(c),
+            Err(_) => return Err(state.arg_error(arg, "char", "value out of range")),
+        }
+    }
+    state.push_string(bytes);
+    Ok(1)
+}
+
+/// `string.rep(s, n [, sep])`
+///
+/// `n` copies of `s` separated by `sep`, by default the empty string.
+pub fn rep(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "rep")?;
+    let n = state.check_integer(2, "rep")?;
+    let sep = match state.type_name(2) {
+        "no value" | "nil" => LuaString::from(""),
+        _ => state.check_string(3, "rep")?,
+    };
+    if n <= 0 {
+        state.push_string("");
+        return Ok(1);
+    }
+    let total = (s.len() + sep.len())
+        .checked_mul(n as usize)
+        .filter(|&total| total - sep.len() < MAX_SIZE);
+    let Some(total) = total else {
+        return Err(state.lib_error("resulting string too large"));
+    };
+    let mut out = Vec::with_capacity(total);
+    for i in 0..n {
+        if i > 0 {
+            out.extend_from_slice(&sep);
+        }
+        out.extend_from_slice(&s);
+    }
+    state.push_string(out);
+    Ok(1)
+}
+
+/// `string.reverse(s)`
+pub fn reverse(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "reverse")?;
+    let reversed: Vec<u8> = s.iter().rev().copied().collect();
+    state.push_string(reversed);
+    Ok(1)
+}
+
+/// `string.upper(s)`
+///
+/// Copy of `s` with lower case letters changed to upper case.
+pub fn upper(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "upper")?;
+    state.push_string(s.to_ascii_uppercase());
+    Ok(1)
+}
+
+/// `string.lower(s)`
+///
+/// Copy of `s` with upper case letters changed to lower case.
+pub fn lower(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "lower")?;
+    state.push_string(s.to_ascii_lowercase());
+    Ok(1)
+}
+
+/// `string.len(s)`
+///
+/// Length of `s` in bytes, embedded zeros count.
+pub fn len(state: &mut LuaState) -> Result<usize, LuaError> {
+    let s = state.check_string(1, "len")?;
+    state.push_integer(s.len() as i64);
+    Ok(1)
+}
+
+#[test]
+fn test_string_library() {
+    use super::call_value;
+    use crate::vm::{lua_closure::RustFunction, lua_table::LuaTable};
+    let mut state = LuaState::new();
+    state.open_string().unwrap();
+    let call = |state: &mut LuaState, name: &str, args: Vec<LuaValue>| {
+        state.get_global("string").unwrap();
+        state.get_field(-1, name).unwrap();
+        let f = state.stack.pop();
+        call_value(state, f, args)
+    };
+    use LuaValue::{Boolean, Integer, Nil};
+    let s = |s: &str| LuaValue::String(s.into());
+
+    assert_eq!(
+        call(&mut state, "find", vec![s("hello world"), s("o w")]).unwrap(),
+        [Integer(5), Integer(7)]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            "find",
+            vec![s("a.b"), s("."), Integer(1), Boolean(true)]
+        )
+        .unwrap(),
+        [Integer(2), Integer(2)]
+    );
+    assert_eq!(
+        call(&mut state, "find", vec![s("key=val"), s("(%w+)=(%w+)")]).unwrap(),
+        [Integer(1), Integer(7), s("key"), s("val")]
+    );
+    assert_eq!(
+        call(&mut state, "find", vec![s("abc"), s("b"), Integer(-1)]).unwrap(),
+        [Nil]
+    );
+    assert_eq!(
+        call(&mut state, "find", vec![s("abc"), s(""), Integer(10)]).unwrap(),
+        [Nil]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            "match",
+            vec![s("  x = 42"), s("^%s*(%a+)%s*=%s*(%d+)$")]
+        )
+        .unwrap(),
+        [s("x"), s("42")]
+    );
+    assert_eq!(
+        call(&mut state, "match", vec![s("f(a(b)c)"), s("%b()")]).unwrap(),
+        [s("(a(b)c)")]
+    );
+    assert_eq!(
+        call(&mut state, "match", vec![s("hello"), s("()ll()")]).unwrap(),
+        [Integer(3), Integer(5)]
+    );
+    assert_eq!(
+        call(&mut state, "match", vec![s("x"), s("(")])
+            .unwrap_err()
+            .to_string(),
+        "unfinished capture"
+    );
+
+    // gsub with the different replacements
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("hello world"), s("o"), s("0")]).unwrap(),
+        [s("hell0 w0rld"), Integer(2)]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            "gsub",
+            vec![s("hello world"), s("(%w+)"), s("<%1>"), Integer(1)]
+        )
+        .unwrap(),
+        [s("<hello> world"), Integer(1)]
+    );
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("abc"), s(""), s("-")]).unwrap(),
+        [s("-a-b-c-"), Integer(4)]
+    );
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("a,b"), s("%w"), s("%0%0")]).unwrap(),
+        [s("aa,bb"), Integer(2)]
+    );
+    let t = LuaTable::new_ref(0, 0);
+    t.borrow_mut().put(s("name"), s("lua")).unwrap();
+    t.borrow_mut().put(s("v"), Boolean(false)).unwrap();
+    assert_eq!(
+        call(
+            &mut state,
+            "gsub",
+            vec![s("$name $v $x"), s("%$(%w+)"), LuaValue::Table(t)]
+        )
+        .unwrap(),
+        [s("lua $v $x"), Integer(3)]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            "gsub",
+            vec![
+                s("a-b"),
+                s("%a"),
+                LuaValue::RustFunction(RustFunction::Fn(upper))
+            ]
+        )
+        .unwrap(),
+        [s("A-B"), Integer(2)]
+    );
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("a"), s("a"), s("%2")])
+            .unwrap_err()
+            .to_string(),
+        "invalid capture index %2"
+    );
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("a"), s("a"), s("%x")])
+            .unwrap_err()
+            .to_string(),
+        "invalid use of '%' in replacement string"
+    );
+    assert_eq!(
+        call(&mut state, "gsub", vec![s("a"), s("a")])
+            .unwrap_err()
+            .to_string(),
+        "bad argument #3 to 'gsub' (string/function/table expected, got no value)"
+    );
+
+    // gmatch
+    let iter = call(&mut state, "gmatch", vec![s("one two  three"), s("%a+")]).unwrap();
+    let mut words = vec![];
+    loop {
+        match call_value(&mut state, iter[0].clone(), vec![])
+            .unwrap()
+            .first()
+        {
+            None | Some(Nil) => break,
+            Some(word) => words.push(word.clone()),
+        }
+    }
+    assert_eq!(words, [s("one"), s("two"), s("three")]);
+
+    // the plain functions
+    assert_eq!(
+        call(&mut state, "sub", vec![s("hello"), Integer(2), Integer(-2)]).unwrap(),
+        [s("ell")]
+    );
+    assert_eq!(
+        call(&mut state, "sub", vec![s("hello"), Integer(-3)]).unwrap(),
+        [s("llo")]
+    );
+    assert_eq!(
+        call(&mut state, "sub", vec![s("hello"), Integer(4), Integer(2)]).unwrap(),
+        [s("")]
+    );
+    assert_eq!(
+        call(&mut state, "byte", vec![s("ABC"), Integer(1), Integer(-1)]).unwrap(),
+        [Integer(65), Integer(66), Integer(67)]
+    );
+    assert_eq!(call(&mut state, "byte", vec![s("")]).unwrap(), []);
+    assert_eq!(
+        call(&mut state, "byte", vec![s("abc")]).unwrap(),
+        [Integer(97)]
+    );
+    assert_eq!(
+        call(&mut state, "byte", vec![s("abc"), Integer(-1)]).unwrap(),
+        [Integer(99)]
+    );
+    // the end defaults to the unadjusted start
+    assert_eq!(
+        call(&mut state, "byte", vec![s("abc"), Integer(0)]).unwrap(),
+        []
+    );
+    assert_eq!(
+        call(&mut state, "byte", vec![s("abc"), Integer(-10)]).unwrap(),
+        []
+    );
+    assert_eq!(
+        call(&mut state, "byte", vec![s("abc"), Integer(4)]).unwrap(),
+        []
+    );
+    assert_eq!(
+        call(&mut state, "char", vec![Integer(72), Integer(105)]).unwrap(),
+        [s("Hi")]
+    );
+    assert_eq!(
+        call(&mut state, "char", vec![Integer(256)])
+            .unwrap_err()
+            .to_string(),
+        "bad argument #1 to 'char' (value out of range)"
+    );
+    assert_eq!(
+        call(&mut state, "rep", vec![s("ab"), Integer(3), s(",")]).unwrap(),
+        [s("ab,ab,ab")]
+    );
+    assert_eq!(
+        call(&mut state, "rep", vec![s("ab"), Integer(0)]).unwrap(),
+        [s("")]
+    );
+    assert_eq!(
+        call(&mut state, "reverse", vec![s("abc")]).unwrap(),
+        [s("cba")]
+    );
+    assert_eq!(
+        call(&mut state, "upper", vec![s("MiXed1")]).unwrap(),
+        [s("MIXED1")]
+    );
+    assert_eq!(
+        call(&mut state, "lower", vec![s("MiXed1")]).unwrap(),
+        [s("mixed1")]
+    );
+    assert_eq!(
+        call(&mut state, "len", vec![s("a\0b")]).unwrap(),
+        [Integer(3)]
+    );
+    assert_eq!(
+        call(&mut state, "len", vec![Integer(123)]).unwrap(),
+        [Integer(3)]
+    );
+}
+
+#[test]
+fn test_string_methods() {
+    // ("lua"):upper()
+    let mut state = LuaState::new();
+    state.open_string().unwrap();
+    state.push_string("lua");
+    state.get_field(-1, "upper").unwrap();
+    state.insert(-2);
+    state.call(1, 1).unwrap();
+    assert_eq!(state.stack.get(-1), LuaValue::String("LUA".into()));
+}
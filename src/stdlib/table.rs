@@ -0,0 +1,628 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::vm::{
+    lua_closure::RustFn,
+    lua_error::LuaError,
+    lua_state::{CampareOperator, LuaApi, LuaState},
+    lua_value::LuaValue,
+    number::number_to_str,
+    tag_method::TagMethod,
+};
+
+/// Functions of the `table` table.
+const FUNCS: [(&str, RustFn); 7] = [
+    ("concat", concat),
+    ("insert", insert),
+    ("move", move_),
+    ("pack", pack),
+    ("remove", remove),
+    ("sort", sort),
+    ("unpack", unpack),
+];
+
+/// Operations a table-like argument must support.
+const TAB_R: u8 = 1;
+const TAB_W: u8 = 2;
+const TAB_L: u8 = 4;
+const TAB_RW: u8 = TAB_R | TAB_W;
+
+impl LuaState {
+    /// Set the global `table` to a table with the table functions.
+    pub fn open_table(&mut self) -> Result<(), LuaError> {
+        self.create_table(0, FUNCS.len());
+        for (name, f) in FUNCS {
+            self.push_rust_function(f);
+            self.set_field(-2, name)?;
+        }
+        self.set_global("table")
+    }
+}
+
+/// Error unless the argument `arg` is a table, or has the metamethods for
+/// the operations in `what`.
+fn check_table(state: &mut LuaState, arg: usize, fname: &str, what: u8) -> Result<(), LuaError> {
+    let val = state.stack.get(arg as i32 - 1);
+    if matches!(val, LuaValue::Table(_)) {
+        return Ok(());
+    }
+    let supported = [
+        (TAB_R, TagMethod::Index),
+        (TAB_W, TagMethod::NewIndex),
+        (TAB_L, TagMethod::Len),
+    ]
+    .into_iter()
+    .all(|(op, event)| what & op == 0 || state.get_metamethod(&val, event) != LuaValue::Nil);
+    if !supported {
+        return Err(state.type_error(arg, fname, "table"));
+    }
+    Ok(())
+}
+
+/// Length of the value at `idx`, which must be an integer.
+fn length(state: &mut LuaState, idx: i32) -> Result<i64, LuaError> {
+    state.len(idx)?;
+    match state.stack.pop() {
+        LuaValue::Integer(n) => Ok(n),
+        _ => Err(state.lib_error("object length is not an integer")),
+    }
+}
+
+/// Length of the table argument `arg` of `fname`.
+fn check_length(state: &mut LuaState, arg: usize, fname: &str, what: u8) -> Result<i64, LuaError> {
+    check_table(state, arg, fname, what | TAB_L)?;
+    length(state, arg as i32 - 1)
+}
+
+/// `table.insert(list, [pos,] value)`
+///
+/// Insert `value` at `pos`, by default the end, shifting up the elements
+/// after it.
+pub fn insert(state: &mut LuaState) -> Result<usize, LuaError> {
+    // first empty element
+    let e = check_length(state, 1, "insert", TAB_RW)?.wrapping_add(1);
+    let pos = match state.get_top() {
+        2 => e,
+        3 => {
+            let pos = state.check_integer(2, "insert")?;
+            // pos in [1, e]
+            if (pos as u64).wrapping_sub(1) >= e as u64 {
+                return Err(state.arg_error(2, "insert", "position out of bounds"));
+            }
+            for i in (pos + 1..=e).rev() {
+                state.get_i(0, i - 1)?;
+                state.set_i(0, i)?;
+            }
+            pos
+        }
+        _ => return Err(state.lib_error("wrong number of arguments to 'insert'")),
+    };
+    state.set_i(0, pos)?;
+    Ok(0)
+}
+
+/// `table.remove(list [, pos])`
+///
+/// Remove and return the element at `pos`, by default the last one,
+/// shifting down the elements after it.
+pub fn remove(state: &mut LuaState) -> Result<usize, LuaError> {
+    let size = check_length(state, 1, "remove", TAB_RW)?;
+    let mut pos = state.opt_integer(2, "remove", size)?;
+    // pos in [1, size + 1] when given
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        return Err(state.arg_error(2, "remove", "position out of bounds"));
+    }
+    state.get_i(0, pos)?;
+    while pos < size {
+        state.get_i(0, pos + 1)?;
+        state.set_i(0, pos)?;
+        pos += 1;
+    }
+    state.push_nil();
+    state.set_i(0, pos)?;
+    Ok(1)
+}
+
+/// `table.move(a1, f, e, t [, a2])`
+///
+/// Copy `a1[f]` to `a1[e]` into `a2`, by default `a1`, from `a2[t]` on and
+/// return `a2`. Overlapping ranges are copied correctly.
+pub fn move_(state: &mut LuaState) -> Result<usize, LuaError> {
+    let f = state.check_integer(2, "move")?;
+    let e = state.check_integer(3, "move")?;
+    let t = state.check_integer(4, "move")?;
+    let dest = match state.type_name(4) {
+        "no value" | "nil" => 0,
+        _ => 4,
+    };
+    check_table(state, 1, "move", TAB_R)?;
+    check_table(state, dest as usize + 1, "move", TAB_W)?;
+    if e >= f {
+        if f <= 0 && e >= i64::MAX + f {
+            return Err(state.arg_error(3, "move", "too many elements to move"));
+        }
+        // number of elements to move
+        let n = e - f + 1;
+        if t > i64::MAX - n + 1 {
+            return Err(state.arg_error(4, "move", "destination wrap around"));
+        }
+        let forward =
+            t > e || t <= f || (dest != 0 && !state.compare(0, dest, CampareOperator::Equal)?);
+        for i in 0..n {
+            let i = if forward { i } else { n - 1 - i };
+            state.get_i(0, f + i)?;
+            state.set_i(dest, t + i)?;
+        }
+    }
+    state.push_value(dest);
+    Ok(1)
+}
+
+/// `table.concat(list [, sep [, i [, j]]])`
+///
+/// The strings or numbers `list[i]` to `list[j]` joined with `sep`, by
+/// default all of them from 1 to `#list` and the empty string.
+pub fn concat(state: &mut LuaState) -> Result<usize, LuaError> {
+    let last = check_length(state, 1, "concat", TAB_R)?;
+    let sep = match state.type_name(1) {
+        "no value" | "nil" => None,
+        _ => Some(state.check_string(2, "concat")?),
+    };
+    let first = state.opt_integer(3, "concat", 1)?;
+    let last = state.opt_integer(4, "concat", last)?;
+    let mut out = Vec::new();
+    let mut i = first;
+    while i <= last {
+        state.get_i(0, i)?;
+        match state.stack.pop() {
+            LuaValue::String(s) => out.extend_from_slice(&s),
+            val @ (LuaValue::Integer(_) | LuaValue::Number(_)) => {
+                out.extend_from_slice(number_to_str(&val).unwrap().as_bytes())
+            }
+            _ => {
+                let msg = format!("invalid value (at index {}) in table for 'concat'", i);
+                return Err(state.lib_error(msg));
+            }
+        }
+        if i == last {
+            break;
+        }
+        if let Some(sep) = &sep {
+            out.extend_from_slice(sep);
+        }
+        i += 1;
+    }
+    state.push_string(out);
+    Ok(1)
+}
+
+/// Intervals longer than this get a random pivot.
+const RANLIMIT: i64 = 100;
+
+/// Seed for choosing pivots, different for every call.
+fn random_pivot() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// `table.sort(list [, comp])`
+///
+/// Sort the elements `list[1]` to `list[#list]` in place with `comp`, by
+/// default `<`. The sort is not stable, and an inconsistent `comp` may
+/// raise "invalid order function for sorting".
+pub fn sort(state: &mut LuaState) -> Result<usize, LuaError> {
+    let n = check_length(state, 1, "sort", TAB_RW)?;
+    if n > 1 {
+        if n >= i32::MAX as i64 {
+            return Err(state.arg_error(1, "sort", "array too big"));
+        }
+        if !matches!(state.type_name(1), "no value" | "nil" | "function") {
+            return Err(state.type_error(2, "sort", "function"));
+        }
+        state.set_top(2);
+        aux_sort(state, 1, n, 0)?;
+    }
+    Ok(0)
+}
+
+/// Pop two values into `t[i]` and `t[j]`.
+fn set2(state: &mut LuaState, i: i64, j: i64) -> Result<(), LuaError> {
+    state.set_i(0, i)?;
+    state.set_i(0, j)
+}
+
+/// Whether the value at `a` sorts before the one at `b`.
+fn sort_comp(state: &mut LuaState, a: i32, b: i32) -> Result<bool, LuaError> {
+    if state.type_name(1) == "nil" {
+        return state.compare(a, b, CampareOperator::LessThen);
+    }
+    state.push_value(1);
+    // the pushed values shift the relative indices
+    state.push_value(a - 1);
+    state.push_value(b - 2);
+    state.call(2, 1)?;
+    let res = state.to_boolean(-1);
+    state.pop(1);
+    Ok(res)
+}
+
+fn order_error(state: &LuaState) -> LuaError {
+    state.lib_error("invalid order function for sorting")
+}
+
+/// Partition `t[lo..=up]` around the pivot `P` on top of the stack, which
+/// is also `t[up - 1]`. Returns the final position of the pivot.
+fn partition(state: &mut LuaState, lo: i64, up: i64) -> Result<i64, LuaError> {
+    let mut i = lo;
+    let mut j = up - 1;
+    // invariant: a[lo .. i] <= P <= a[j .. up], a[up - 1] == P
+    loop {
+        // repeat i += 1 while a[i] < P
+        loop {
+            i += 1;
+            state.get_i(0, i)?;
+            if !sort_comp(state, -1, -2)? {
+                break;
+            }
+            if i == up - 1 {
+                return Err(order_error(state));
+            }
+            state.pop(1);
+        }
+        // repeat j -= 1 while P < a[j]
+        loop {
+            j -= 1;
+            state.get_i(0, j)?;
+            if !sort_comp(state, -3, -1)? {
+                break;
+            }
+            if j < i {
+                return Err(order_error(state));
+            }
+            state.pop(1);
+        }
+        if j < i {
+            // no elements to exchange, put the pivot in its place
+            state.pop(1);
+            set2(state, up - 1, i)?;
+            return Ok(i);
+        }
+        set2(state, i, j)?;
+    }
+}
+
+/// Pivot in the middle half of `lo..=up`, chosen with `rnd`.
+fn choose_pivot(lo: i64, up: i64, rnd: u64) -> i64 {
+    let r4 = (up - lo) / 4;
+    (rnd % (r4 as u64 * 2)) as i64 + lo + r4
+}
+
+/// Quicksort of `t[lo..=up]`, recursing into the smaller half.
+fn aux_sort(state: &mut LuaState, mut lo: i64, mut up: i64, mut rnd: u64) -> Result<(), LuaError> {
+    while lo < up {
+        // sort a[lo], a[p] and a[up]
+        state.get_i(0, lo)?;
+        state.get_i(0, up)?;
+        if sort_comp(state, -1, -2)? {
+            set2(state, lo, up)?;
+        } else {
+            state.pop(2);
+        }
+        if up - lo == 1 {
+            break;
+        }
+        let mut p = if up - lo < RANLIMIT || rnd == 0 {
+            (lo + up) / 2
+        } else {
+            choose_pivot(lo, up, rnd)
+        };
+        state.get_i(0, p)?;
+        state.get_i(0, lo)?;
+        if sort_comp(state, -2, -1)? {
+            set2(state, p, lo)?;
+        } else {
+            state.pop(1);
+            state.get_i(0, up)?;
+            if sort_comp(state, -1, -2)? {
+                set2(state, p, up)?;
+            } else {
+                state.pop(2);
+            }
+        }
+        if up - lo == 2 {
+            break;
+        }
+        // the median as pivot, swapped with a[up - 1]
+        state.get_i(0, p)?;
+        state.push_value(-1);
+        state.get_i(0, up - 1)?;
+        set2(state, p, up - 1)?;
+        p = partition(state, lo, up)?;
+        // a[lo .. p - 1] <= a[p] == P <= a[p + 1 .. up]
+        let n;
+        if p - lo < up - p {
+            aux_sort(state, lo, p - 1, rnd)?;
+            n = p - lo;
+            lo = p + 1;
+        } else {
+            aux_sort(state, p + 1, up, rnd)?;
+            n = up - p;
+            up = p - 1;
+        }
+        // a too unbalanced partition asks for a new randomization
+        if (up - lo) / 128 > n {
+            rnd = random_pivot();
+        }
+    }
+    Ok(())
+}
+
+/// `table.pack(...)`
+///
+/// New table with all arguments as elements 1 to n and the field `n` set to
+/// their number.
+pub fn pack(state: &mut LuaState) -> Result<usize, LuaError> {
+    let n = state.get_top();
+    state.create_table(n, 1);
+    state.insert(0);
+    for i in (1..=n).rev() {
+        state.set_i(0, i as i64)?;
+    }
+    state.push_integer(n as i64);
+    state.set_field(0, "n")?;
+    Ok(1)
+}
+
+/// `table.unpack(list [, i [, j]])`
+///
+/// The elements `list[i]` to `list[j]`, by default all of them from 1 to
+/// `#list`.
+pub fn unpack(state: &mut LuaState) -> Result<usize, LuaError> {
+    let i = state.opt_integer(2, "unpack", 1)?;
+    let j = if matches!(state.type_name(2), "no value" | "nil") {
+        length(state, 0)?
+    } else {
+        state.opt_integer(3, "unpack", 0)?
+    };
+    if i > j {
+        return Ok(0);
+    }
+    let n = (j as u64).wrapping_sub(i as u64);
+    if n >= i32::MAX as u64 || !state.check_stack(n as usize + 1) {
+        return Err(state.lib_error("too many results to unpack"));
+    }
+    for k in i..=j {
+        state.get_i(0, k)?;
+    }
+    Ok(n as usize + 1)
+}
+
+#[test]
+fn test_table_library() {
+    use super::call;
+    use crate::vm::{
+        lua_closure::RustFunction,
+        lua_table::{LuaTable, LuaTableRef},
+    };
+    fn greater(state: &mut LuaState) -> Result<usize, LuaError> {
+        let res = state.compare(0, 1, CampareOperator::GreatThen)?;
+        state.push_boolean(res);
+        Ok(1)
+    }
+    fn always(state: &mut LuaState) -> Result<usize, LuaError> {
+        state.push_boolean(true);
+        Ok(1)
+    }
+
+    let mut state = LuaState::new();
+    use LuaValue::{Integer, Nil};
+    let s = |s: &str| LuaValue::String(s.into());
+    let list = |values: &[i64]| {
+        let t = LuaTable::new_ref(values.len(), 0);
+        for (i, &v) in values.iter().enumerate() {
+            t.borrow_mut()
+                .put(Integer(i as i64 + 1), Integer(v))
+                .unwrap();
+        }
+        t
+    };
+    let elements = |t: &LuaTableRef| {
+        let t = t.borrow();
+        (1..=t.len())
+            .map(|i| t.get_int(i as i64))
+            .collect::<Vec<_>>()
+    };
+    let ints = |values: &[i64]| values.iter().map(|&v| Integer(v)).collect::<Vec<_>>();
+
+    // insert and remove
+    let t = list(&[1, 2, 3]);
+    let tv = LuaValue::Table(t.clone());
+    call(&mut state, insert, vec![tv.clone(), Integer(4)]).unwrap();
+    call(&mut state, insert, vec![tv.clone(), Integer(1), Integer(0)]).unwrap();
+    assert_eq!(elements(&t), ints(&[0, 1, 2, 3, 4]));
+    assert_eq!(
+        call(&mut state, remove, vec![tv.clone()]).unwrap(),
+        [Integer(4)]
+    );
+    assert_eq!(
+        call(&mut state, remove, vec![tv.clone(), Integer(1)]).unwrap(),
+        [Integer(0)]
+    );
+    assert_eq!(elements(&t), ints(&[1, 2, 3]));
+    assert_eq!(
+        call(&mut state, insert, vec![tv.clone(), Integer(5), Integer(0)])
+            .unwrap_err()
+            .to_string(),
+        "bad argument #2 to 'insert' (position out of bounds)"
+    );
+    assert_eq!(
+        call(&mut state, insert, vec![tv.clone()])
+            .unwrap_err()
+            .to_string(),
+        "wrong number of arguments to 'insert'"
+    );
+    assert_eq!(
+        call(&mut state, remove, vec![Nil]).unwrap_err().to_string(),
+        "bad argument #1 to 'remove' (table expected, got nil)"
+    );
+
+    // concat
+    t.borrow_mut().put(Integer(2), s("b")).unwrap();
+    assert_eq!(
+        call(&mut state, concat, vec![tv.clone()]).unwrap(),
+        [s("1b3")]
+    );
+    assert_eq!(
+        call(&mut state, concat, vec![tv.clone(), s(", "), Integer(2)]).unwrap(),
+        [s("b, 3")]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            concat,
+            vec![tv.clone(), s(","), Integer(3), Integer(2)]
+        )
+        .unwrap(),
+        [s("")]
+    );
+    assert_eq!(
+        call(
+            &mut state,
+            concat,
+            vec![tv.clone(), s(","), Integer(1), Integer(4)]
+        )
+        .unwrap_err()
+        .to_string(),
+        "invalid value (at index 4) in table for 'concat'"
+    );
+
+    // move, within a table and to another one
+    let t = list(&[1, 2, 3, 4, 5]);
+    let tv = LuaValue::Table(t.clone());
+    call(
+        &mut state,
+        move_,
+        vec![tv.clone(), Integer(1), Integer(3), Integer(2)],
+    )
+    .unwrap();
+    assert_eq!(elements(&t), ints(&[1, 1, 2, 3, 5]));
+    call(
+        &mut state,
+        move_,
+        vec![tv.clone(), Integer(2), Integer(5), Integer(1)],
+    )
+    .unwrap();
+    assert_eq!(elements(&t), ints(&[1, 2, 3, 5, 5]));
+    let dest = LuaValue::Table(LuaTable::new_ref(0, 0));
+    let moved = call(
+        &mut state,
+        move_,
+        vec![tv.clone(), Integer(1), Integer(2), Integer(3), dest.clone()],
+    )
+    .unwrap();
+    assert_eq!(moved, std::slice::from_ref(&dest));
+    let LuaValue::Table(dest) = dest else {
+        unreachable!()
+    };
+    assert_eq!(dest.borrow().get_int(4), Integer(2));
+    assert_eq!(
+        call(
+            &mut state,
+            move_,
+            vec![tv.clone(), Integer(1), Integer(2), Integer(i64::MAX)]
+        )
+        .unwrap_err()
+        .to_string(),
+        "bad argument #4 to 'move' (destination wrap around)"
+    );
+
+    // sort
+    let values = [5, 3, 9, 1, 1, 7, 0, -4, 12, 8, 2, 6, 11, 10, 4];
+    let t = list(&values);
+    call(&mut state, sort, vec![LuaValue::Table(t.clone())]).unwrap();
+    let mut sorted = values;
+    sorted.sort();
+    assert_eq!(elements(&t), ints(&sorted));
+    let greater = LuaValue::RustFunction(RustFunction::Fn(greater));
+    call(&mut state, sort, vec![LuaValue::Table(t.clone()), greater]).unwrap();
+    sorted.reverse();
+    assert_eq!(elements(&t), ints(&sorted));
+
+    let t = list(&(0..300).map(|i| (i * 7919) % 300).collect::<Vec<_>>());
+    call(&mut state, sort, vec![LuaValue::Table(t.clone())]).unwrap();
+    assert_eq!(elements(&t), ints(&(0..300).collect::<Vec<_>>()));
+
+    let always = LuaValue::RustFunction(RustFunction::Fn(always));
+    let t = LuaValue::Table(list(&values));
+    assert_eq!(
+        call(&mut state, sort, vec![t.clone(), always])
+            .unwrap_err()
+            .to_string(),
+        "invalid order function for sorting"
+    );
+    assert_eq!(
+        call(&mut state, sort, vec![t.clone(), Integer(1)])
+            .unwrap_err()
+            .to_string(),
+        "bad argument #2 to 'sort' (function expected, got number)"
+    );
+    let mixed = LuaTable::new_ref(2, 0);
+    mixed.borrow_mut().put(Integer(1), Integer(1)).unwrap();
+    mixed.borrow_mut().put(Integer(2), s("x")).unwrap();
+    assert_eq!(
+        call(&mut state, sort, vec![LuaValue::Table(mixed)])
+            .unwrap_err()
+            .to_string(),
+        "attempt to compare string with number"
+    );
+}
+
+#[test]
+fn test_table_pack_unpack() {
+    use crate::vm::lua_state::LUA_MULTRET;
+    let mut state = LuaState::new();
+    state.open_table().unwrap();
+
+    state.get_global("table").unwrap();
+    state.get_field(-1, "pack").unwrap();
+    state.push_integer(1);
+    state.push_nil();
+    state.push_integer(3);
+    state.call(3, 1).unwrap();
+    let LuaValue::Table(packed) = state.stack.get(-1) else {
+        panic!("table.pack returns a table");
+    };
+    assert_eq!(packed.borrow().get_str("n"), LuaValue::Integer(3));
+    assert_eq!(packed.borrow().get_int(3), LuaValue::Integer(3));
+    assert_eq!(packed.borrow().get_int(2), LuaValue::Nil);
+
+    // table.unpack(t, 2, n)
+    state.push_rust_function(unpack);
+    state.push_value(-2);
+    state.push_integer(2);
+    state.push_integer(3);
+    state.call(3, LUA_MULTRET).unwrap();
+    assert_eq!(state.stack.get(-2), LuaValue::Nil);
+    assert_eq!(state.stack.get(-1), LuaValue::Integer(3));
+
+    // table.unpack({10, 20})
+    state.set_top(0);
+    state.push_rust_function(unpack);
+    state.create_table(2, 0);
+    state.push_integer(10);
+    state.set_i(-2, 1).unwrap();
+    state.push_integer(20);
+    state.set_i(-2, 2).unwrap();
+    state.call(1, LUA_MULTRET).unwrap();
+    assert_eq!(state.get_top(), 2);
+    assert_eq!(state.stack.get(1), LuaValue::Integer(20));
+
+    state.set_top(0);
+    state.push_rust_function(unpack);
+    state.new_table();
+    state.push_integer(i64::MIN);
+    state.push_integer(i64::MAX);
+    let err = state.call(3, LUA_MULTRET).unwrap_err();
+    assert_eq!(err.to_string(), "too many results to unpack");
+}
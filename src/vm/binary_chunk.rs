@@ -1,11 +1,6 @@
-use super::lua_value::LuaValue;
+use std::rc::Rc;
 
-#[derive(Debug)]
-pub struct BinaryChunk {
-    header: Header,
-    sized_upvalues: i32,
-    main_func: Box<Prototype>,
-}
+use super::lua_value::LuaValue;
 
 pub const LUA_SIGNATURE: [u8; 4] = [0x1B, 0x4C, 0x75, 0x61]; // => 0x1B4c7561=> ".Lua"
 pub const LUAC_VERSION: u8 = 0x54;
@@ -19,21 +14,6 @@ pub const LUA_NUMBER_SIZE: u8 = 8;
 pub const LUAC_INT: i64 = 0x5678;
 pub const LUAC_NUM: f64 = 370.5;
 
-#[derive(Debug)]
-pub struct Header {
-    signature: [u8; 4],
-    version: u8,
-    format: u8,
-    luac_data: [u8; 6],
-    cint_size: u8,
-    sizet_size: u8,
-    instruction_size: u8,
-    lua_number_size: u8,
-    lua_integer_size: u8,
-    luac_int: i64,
-    luac_num: f64,
-}
-
 #[derive(Debug)]
 pub struct AbsoluteLine {
     pub pc: u32,
@@ -51,25 +31,87 @@ pub struct Prototype {
     pub code: Vec<u32>,
     pub constants: Vec<LuaValue>,
     pub upvalues: Vec<Upvalue>,
-    pub prototypes: Option<Vec<Prototype>>,
+    pub prototypes: Vec<Rc<Prototype>>,
     pub line_info: Vec<u8>,
     pub abs_line_list: Vec<AbsoluteLine>,
     pub local_variable: Vec<LocalVariable>,
     pub upvalue_names: Vec<String>,
 }
 
+impl Prototype {
+    /// Source line of the instruction at `pc`, `None` if the chunk was
+    /// stripped of its line info.
+    pub fn get_line(&self, pc: usize) -> Option<i32> {
+        if pc >= self.line_info.len() {
+            return None;
+        }
+        // start from the last absolute line before pc, then add the deltas
+        let (mut base_pc, mut line) = match self.abs_line_list.iter().rfind(|l| l.pc as usize <= pc)
+        {
+            Some(abs) => (abs.pc as i64, abs.line as i32),
+            None => (-1, self.line_defined),
+        };
+        while base_pc < pc as i64 {
+            base_pc += 1;
+            line += self.line_info[base_pc as usize] as i8 as i32;
+        }
+        Some(line)
+    }
+
+    /// Name of the local variable in register `reg` at `pc`, `None` for
+    /// temporaries and chunks stripped of debug info.
+    pub fn local_name(&self, reg: usize, pc: usize) -> Option<&str> {
+        self.local_variable
+            .iter()
+            .take_while(|var| var.start_pc as usize <= pc)
+            .filter(|var| pc < var.end_pc as usize)
+            .nth(reg)
+            .map(|var| var.var_name.as_str())
+    }
+}
+
+/// Maximum size of a chunk name in messages.
+const LUA_IDSIZE: usize = 60;
+
+/// Printable name of a chunk source, as used in error messages: `=name` is
+/// used as is, `@file` is a file name and anything else is the source text.
+pub fn chunk_id(source: &str) -> String {
+    if let Some(name) = source.strip_prefix('=') {
+        name.chars().take(LUA_IDSIZE - 1).collect()
+    } else if let Some(file) = source.strip_prefix('@') {
+        let len = file.chars().count();
+        if len < LUA_IDSIZE {
+            file.to_string()
+        } else {
+            // keep the end of long file names
+            let tail: String = file.chars().skip(len - (LUA_IDSIZE - 4)).collect();
+            format!("...{}", tail)
+        }
+    } else {
+        let line = source.lines().next().unwrap_or("");
+        let max = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+        if line.len() == source.len() && line.chars().count() <= max {
+            format!("[string \"{}\"]", line)
+        } else {
+            let line: String = line.chars().take(max).collect();
+            format!("[string \"{}...\"]", line)
+        }
+    }
+}
+
 pub const TAG_NIL: u8 = 0b0;
 pub const TAG_FALSE: u8 = 0b1;
 pub const TAG_TRUE: u8 = 0b1_0001;
 pub const TAG_INTEGER: u8 = 0b11;
 pub const TAG_FLOAT: u8 = 0b1_0011;
 pub const TAG_SHORT_STRING: u8 = 0b100;
-pub const TAG_LONG_STRING: u8 = 0b1_100;
+pub const TAG_LONG_STRING: u8 = 0b1_0100;
 
 #[derive(Debug)]
 pub struct Upvalue {
     pub instack: u8,
     pub index: u8,
+    pub kind: u8,
 }
 
 #[derive(Debug)]
@@ -81,5 +123,16 @@ pub struct LocalVariable {
 
 #[test]
 fn fn_test() {
-    let a = [1, 2, 5];
+    let _a = [1, 2, 5];
+}
+
+#[test]
+fn test_chunk_id() {
+    assert_eq!(chunk_id("=stdin"), "stdin");
+    assert_eq!(chunk_id("@./hello.lua"), "./hello.lua");
+    assert_eq!(chunk_id("return 1"), "[string \"return 1\"]");
+    assert_eq!(chunk_id("x = 1\ny = 2"), "[string \"x = 1...\"]");
+    let long = format!("@{}.lua", "d/".repeat(40));
+    assert_eq!(chunk_id(&long).len(), LUA_IDSIZE - 1);
+    assert!(chunk_id(&long).starts_with("..."));
 }
@@ -0,0 +1,224 @@
+use std::rc::Rc;
+
+use super::{
+    binary_chunk::Prototype,
+    instruction::InstructionOperation,
+    lua_closure::{KFn, LuaClosure, RustFunction},
+    lua_error::LuaError,
+    lua_state::{LuaState, LuaVm, LUAI_MAXSTACK, LUA_MINSTACK, LUA_MULTRET},
+    lua_value::LuaValue,
+};
+
+/// Activation record of a running function.
+#[derive(Debug)]
+pub struct CallFrame {
+    /// Closure of a Lua function, `None` for Rust functions.
+    pub closure: Option<Rc<LuaClosure>>,
+    /// Stack slot of the called function, results are moved here on return.
+    pub func: usize,
+    /// First register of the frame, always `func + 1`.
+    pub base: usize,
+    pub pc: u32,
+    /// Arguments passed beyond the fixed parameters of a vararg function.
+    pub varargs: Vec<LuaValue>,
+    /// Number of results the caller wants, `LUA_MULTRET` for all of them.
+    pub n_results: i32,
+    /// Entered from Rust by `LuaApi::call`, its return leaves the execute loop.
+    pub fresh: bool,
+    /// Set on a Rust function while it calls into Lua with `LuaApi::pcallk`.
+    pub continuation: Option<Continuation>,
+    /// Slots of the to-be-closed variables, in the order they were declared.
+    pub tbc: Vec<usize>,
+}
+
+/// What is left of a Rust function after a coroutine yielded from the Lua
+/// code it called.
+#[derive(Debug)]
+pub struct Continuation {
+    pub func: KFn,
+    pub ctx: i64,
+    /// Slot of the called function and the message handler of a protected
+    /// call, an error unwinds to this frame and continues with `func`.
+    pub protected: Option<(usize, Option<LuaValue>)>,
+}
+
+impl CallFrame {
+    pub fn lua_closure(&self) -> &Rc<LuaClosure> {
+        self.closure.as_ref().expect("not a Lua function")
+    }
+
+    pub fn prototype(&self) -> &Prototype {
+        &self.lua_closure().prototype
+    }
+
+    /// Source line of the running instruction of a Lua function.
+    pub fn current_line(&self) -> Option<i32> {
+        let closure = self.closure.as_ref()?;
+        closure
+            .prototype
+            .get_line((self.pc as usize).saturating_sub(1))
+    }
+
+    /// Slot after the last register of the frame.
+    pub fn register_top(&self) -> usize {
+        self.base + self.prototype().max_statck_size as usize
+    }
+}
+
+impl LuaState {
+    /// Start calling the function in slot `func`, its arguments are the
+    /// values above it up to the top. Rust functions run to completion here,
+    /// Lua functions only get their frame pushed, the instructions run when
+    /// the execute loop gets to them.
+    pub(crate) fn pre_call(
+        &mut self,
+        func: usize,
+        n_results: i32,
+        fresh: bool,
+    ) -> Result<(), LuaError> {
+        let closure = match &self.stack.slots[func] {
+            LuaValue::Function(closure) => closure.clone(),
+            LuaValue::RustFunction(f) => {
+                let f = f.clone();
+                return self.call_rust_function(f, func, n_results, fresh);
+            }
+            _ => {
+                self.insert_call_metamethod(func)?;
+                return self.pre_call(func, n_results, fresh);
+            }
+        };
+        let prototype = closure.prototype.clone();
+        let base = func + 1;
+        let n_params = prototype.num_params as usize;
+        let n_args = self.stack.top - base;
+
+        let mut varargs = Vec::new();
+        if n_args > n_params {
+            let extra = base + n_params..self.stack.top;
+            if prototype.is_vararg == 1 {
+                varargs = self.stack.slots[extra.clone()].to_vec();
+            }
+            self.stack.slots[extra].fill(LuaValue::Nil);
+            self.stack.top = base + n_params;
+        }
+
+        let n_regs = prototype.max_statck_size as usize;
+        self.grow_stack(base + n_regs)?;
+        // missing parameters and the remaining registers start as nil
+        self.stack.slots[self.stack.top..base + n_regs].fill(LuaValue::Nil);
+        self.stack.top = base + n_regs;
+        self.stack.base = base;
+
+        self.frames.push(CallFrame {
+            closure: Some(closure),
+            func,
+            base,
+            pc: 0,
+            varargs,
+            n_results,
+            fresh,
+            continuation: None,
+            tbc: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn call_rust_function(
+        &mut self,
+        f: RustFunction,
+        func: usize,
+        n_results: i32,
+        fresh: bool,
+    ) -> Result<(), LuaError> {
+        let base = func + 1;
+        self.grow_stack(self.stack.top)?;
+        self.stack.base = base;
+        self.frames.push(CallFrame {
+            closure: None,
+            func,
+            base,
+            pc: 0,
+            varargs: Vec::new(),
+            n_results,
+            fresh,
+            continuation: None,
+            tbc: Vec::new(),
+        });
+
+        let n = f.call(self)?;
+        let first = self.stack.top - n;
+        self.post_call(first, n);
+        Ok(())
+    }
+
+    /// Make room for `LUA_MINSTACK` slots above `top` for a new frame.
+    fn grow_stack(&mut self, top: usize) -> Result<(), LuaError> {
+        if top + LUA_MINSTACK > LUAI_MAXSTACK {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        if !self.stack.check(top + LUA_MINSTACK - self.stack.top) {
+            return Err(LuaError::Memory);
+        }
+        Ok(())
+    }
+
+    /// Finish the running frame, its `n` results start at slot `first`.
+    pub(crate) fn post_call(&mut self, first: usize, n: usize) {
+        let frame = self.frames.pop().expect("no frame to return from");
+        self.close_upvalues(frame.base);
+        let res = frame.func;
+        for i in 0..n {
+            self.stack.slots[res + i] = std::mem::take(&mut self.stack.slots[first + i]);
+        }
+
+        let wanted = if frame.n_results == LUA_MULTRET {
+            n
+        } else {
+            frame.n_results as usize
+        };
+        let old_top = self.stack.top.max(res + wanted);
+        self.stack.slots[res + n.min(wanted)..old_top].fill(LuaValue::Nil);
+        self.stack.top = res + wanted;
+
+        match self.frames.last() {
+            Some(caller) => {
+                self.stack.base = caller.base;
+                // a Lua caller keeps using its registers, only open results
+                // are left at the top for the next instruction
+                if !frame.fresh && frame.n_results != LUA_MULTRET {
+                    self.stack.top = caller.register_top();
+                }
+            }
+            None => self.stack.base = 0,
+        }
+    }
+
+    /// Replace the running frame with a call to the function in slot `func`,
+    /// so that tail calls do not grow the frame stack.
+    pub(crate) fn tail_call(&mut self, func: usize) -> Result<(), LuaError> {
+        let val = &self.stack.slots[func];
+        if !matches!(val, LuaValue::Function(_) | LuaValue::RustFunction(_)) {
+            self.insert_call_metamethod(func)?;
+        }
+        let frame = self.frames.pop().expect("no frame to replace");
+        self.close_upvalues(frame.base);
+
+        let n = self.stack.top - func;
+        for i in 0..n {
+            self.stack.slots[frame.func + i] = std::mem::take(&mut self.stack.slots[func + i]);
+        }
+        self.stack.top = frame.func + n;
+
+        self.pre_call(frame.func, frame.n_results, frame.fresh)
+    }
+
+    /// Run instructions until the frame on top of the frame stack returns.
+    pub(crate) fn execute(&mut self) -> Result<(), LuaError> {
+        let depth = self.frames.len();
+        while self.frames.len() >= depth {
+            let instruction = self.fetch();
+            instruction.execute(self)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+/// Size of a Lua integer in bytes.
+pub const INTEGER_SIZE: usize = 8;
+
+/// Byte order of an encoded value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Byte order of the machine.
+    pub const NATIVE: Endian = if cfg!(target_endian = "little") {
+        Endian::Little
+    } else {
+        Endian::Big
+    };
+
+    /// Index of the `i`th least significant byte of a `size` bytes value.
+    fn index(self, i: usize, size: usize) -> usize {
+        match self {
+            Endian::Little => i,
+            Endian::Big => size - 1 - i,
+        }
+    }
+}
+
+/// Append `n` as a `size` bytes integer. Bytes beyond the 8 of `n` are
+/// filled with the sign, `negative` tells whether `n` is a negative integer.
+pub fn pack_int(out: &mut Vec<u8>, n: u64, size: usize, endian: Endian, negative: bool) {
+    let start = out.len();
+    out.resize(start + size, 0);
+    let fill = if negative { 0xff } else { 0 };
+    for i in 0..size {
+        let byte = if i < INTEGER_SIZE {
+            (n >> (i * 8)) as u8
+        } else {
+            fill
+        };
+        out[start + endian.index(i, size)] = byte;
+    }
+}
+
+/// Integer encoded in `bytes`, sign extended if `signed`. `None` when more
+/// than 8 bytes hold a value that does not fit in a Lua integer.
+pub fn unpack_int(bytes: &[u8], endian: Endian, signed: bool) -> Option<i64> {
+    let size = bytes.len();
+    let limit = size.min(INTEGER_SIZE);
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res = res << 8 | bytes[endian.index(i, size)] as u64;
+    }
+    if size < INTEGER_SIZE {
+        if signed && size > 0 {
+            let mask = 1 << (size * 8 - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else {
+        // the extra bytes can only repeat the sign
+        let fill = if signed && (res as i64) < 0 { 0xff } else { 0 };
+        if (limit..size).any(|i| bytes[endian.index(i, size)] != fill) {
+            return None;
+        }
+    }
+    Some(res as i64)
+}
+
+/// Append `n` as a float of `size` bytes, 4 or 8.
+pub fn pack_float(out: &mut Vec<u8>, n: f64, size: usize, endian: Endian) {
+    let bits = match size {
+        4 => (n as f32).to_bits() as u64,
+        _ => n.to_bits(),
+    };
+    pack_int(out, bits, size, endian, false);
+}
+
+/// Float encoded in `bytes`, 4 or 8 of them.
+pub fn unpack_float(bytes: &[u8], endian: Endian) -> f64 {
+    let bits = unpack_int(bytes, endian, false).unwrap_or_default() as u64;
+    match bytes.len() {
+        4 => f32::from_bits(bits as u32) as f64,
+        _ => f64::from_bits(bits),
+    }
+}
+
+#[test]
+fn test_int_codec() {
+    let pack = |n: i64, size, endian| {
+        let mut out = vec![];
+        pack_int(&mut out, n as u64, size, endian, n < 0);
+        out
+    };
+    assert_eq!(pack(0x0102, 2, Endian::Little), [0x02, 0x01]);
+    assert_eq!(pack(0x0102, 4, Endian::Big), [0, 0, 0x01, 0x02]);
+    assert_eq!(pack(-2, 3, Endian::Little), [0xfe, 0xff, 0xff]);
+    assert_eq!(pack(-1, 10, Endian::Big), [0xff; 10]);
+    assert_eq!(pack(1, 10, Endian::Big), [0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    assert_eq!(
+        unpack_int(&[0x02, 0x01], Endian::Little, false),
+        Some(0x0102)
+    );
+    assert_eq!(unpack_int(&[0xfe, 0xff], Endian::Little, true), Some(-2));
+    assert_eq!(
+        unpack_int(&[0xfe, 0xff], Endian::Little, false),
+        Some(0xfffe)
+    );
+    assert_eq!(unpack_int(&[0x80], Endian::Big, true), Some(-128));
+    assert_eq!(unpack_int(&[0xff; 8], Endian::Big, false), Some(-1));
+    assert_eq!(unpack_int(&[0xff; 12], Endian::Big, true), Some(-1));
+    assert_eq!(
+        unpack_int(&pack(i64::MIN, 16, Endian::Little), Endian::Little, true),
+        Some(i64::MIN)
+    );
+    assert_eq!(unpack_int(&[0xff; 12], Endian::Big, false), None);
+    assert_eq!(
+        unpack_int(&[1, 0, 0, 0, 0, 0, 0, 0, 1], Endian::Little, true),
+        None
+    );
+}
+
+#[test]
+fn test_float_codec() {
+    let mut out = vec![];
+    pack_float(&mut out, 1.5, 4, Endian::Big);
+    assert_eq!(out, [0x3f, 0xc0, 0, 0]);
+    assert_eq!(unpack_float(&out, Endian::Big), 1.5);
+
+    out.clear();
+    pack_float(&mut out, -0.1, 8, Endian::Little);
+    assert_eq!(out, (-0.1f64).to_le_bytes());
+    assert_eq!(unpack_float(&out, Endian::Little), -0.1);
+}
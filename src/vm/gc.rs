@@ -0,0 +1,611 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use super::{
+    call_frame::CallFrame,
+    lua_closure::{LuaClosure, LuaUpvalue, UpvalueRef},
+    lua_state::{LuaApi, LuaState},
+    lua_table::{LuaTable, LuaTableRef},
+    lua_thread::{LuaThread, LuaThreadRef},
+    lua_value::LuaValue,
+    tag_method::TagMethod,
+};
+
+/// Result of `LuaApi::gc` telling the previous mode, see `LUA_GCGEN`.
+pub const LUA_GCGEN: i64 = 10;
+pub const LUA_GCINC: i64 = 11;
+
+/// Collections never wait for less than this many bytes of new objects.
+const MIN_DEBT: usize = 64 * 1024;
+
+/// Requests to the collector, see `lua_gc`. Parameters of 0 keep their
+/// current value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcOption {
+    Stop,
+    Restart,
+    Collect,
+    /// Memory in use in KBytes.
+    Count,
+    /// Remainder of `Count` in bytes.
+    CountB,
+    /// Collect as if `n` KBytes had been allocated, 0 forces a cycle.
+    Step(usize),
+    IsRunning,
+    /// Switch to incremental mode: a cycle starts once memory grew by
+    /// `pause` percent of what the last one left.
+    Incremental {
+        pause: usize,
+    },
+    /// Switch to generational mode: a cycle starts once memory grew by
+    /// `minormul` percent of what the last one left.
+    Generational {
+        minormul: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+
+/// A collectable object, held weakly so that objects without cycles are
+/// still freed as soon as their reference count drops to zero.
+#[derive(Debug)]
+enum GcObject {
+    Table(Weak<RefCell<LuaTable>>),
+    Closure(Weak<LuaClosure>),
+    Upvalue(Weak<RefCell<LuaUpvalue>>),
+    Thread(Weak<RefCell<LuaThread>>),
+}
+
+/// Strong reference to a live object while a cycle runs.
+enum Live {
+    Table(LuaTableRef),
+    Closure(Rc<LuaClosure>),
+    Upvalue(UpvalueRef),
+    Thread(LuaThreadRef),
+}
+
+impl Live {
+    fn addr(&self) -> *const () {
+        match self {
+            Live::Table(t) => Rc::as_ptr(t) as *const (),
+            Live::Closure(c) => Rc::as_ptr(c) as *const (),
+            Live::Upvalue(u) => Rc::as_ptr(u) as *const (),
+            Live::Thread(t) => Rc::as_ptr(t) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Live::Table(t) => Rc::strong_count(t),
+            Live::Closure(c) => Rc::strong_count(c),
+            Live::Upvalue(u) => Rc::strong_count(u),
+            Live::Thread(t) => Rc::strong_count(t),
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            Live::Table(t) => t.borrow().heap_size(),
+            Live::Closure(c) => closure_size(c),
+            Live::Upvalue(_) => upvalue_size(),
+            Live::Thread(t) => thread_size(&t.borrow()),
+        }
+    }
+
+    /// Objects referenced by this one. Entries of weak tables are included,
+    /// so that they count as references from inside the heap.
+    fn for_each_child(&self, mut f: impl FnMut(*const ())) {
+        match self {
+            Live::Table(t) => {
+                let t = t.borrow();
+                t.values().filter_map(value_addr).for_each(&mut f);
+                if let Some(mt) = &t.metatable {
+                    f(Rc::as_ptr(mt) as *const ());
+                }
+            }
+            Live::Closure(c) => c
+                .upvalues
+                .iter()
+                .for_each(|u| f(Rc::as_ptr(u) as *const ())),
+            Live::Upvalue(u) => {
+                if let LuaUpvalue::Closed(v) = &*u.borrow() {
+                    value_addr(v).into_iter().for_each(f);
+                }
+            }
+            Live::Thread(t) => thread_children(&t.borrow(), f),
+        }
+    }
+}
+
+/// Objects held by a suspended thread: the values in its stack, the
+/// functions of its frames and the values kept for them, and its open
+/// upvalues. The stack of the running thread is in the state, which makes it
+/// a root.
+fn thread_children(thread: &LuaThread, mut f: impl FnMut(*const ())) {
+    let mut values = thread.stack.slots.iter().collect::<Vec<_>>();
+    for frame in &thread.frames {
+        if let Some(c) = &frame.closure {
+            f(Rc::as_ptr(c) as *const ());
+        }
+        values.extend(&frame.varargs);
+        if let Some((_, Some(handler))) = frame
+            .continuation
+            .as_ref()
+            .and_then(|k| k.protected.as_ref())
+        {
+            values.push(handler);
+        }
+    }
+    values.into_iter().filter_map(value_addr).for_each(&mut f);
+    thread
+        .open_upvalues
+        .values()
+        .for_each(|u| f(Rc::as_ptr(u) as *const ()));
+}
+
+fn value_addr(val: &LuaValue) -> Option<*const ()> {
+    match val {
+        LuaValue::Table(t) => Some(Rc::as_ptr(t) as *const ()),
+        LuaValue::Function(c) => Some(Rc::as_ptr(c) as *const ()),
+        LuaValue::Thread(t) => Some(Rc::as_ptr(t) as *const ()),
+        _ => None,
+    }
+}
+
+fn closure_size(closure: &LuaClosure) -> usize {
+    std::mem::size_of::<LuaClosure>() + closure.upvalues.len() * std::mem::size_of::<UpvalueRef>()
+}
+
+fn upvalue_size() -> usize {
+    std::mem::size_of::<RefCell<LuaUpvalue>>()
+}
+
+fn thread_size(thread: &LuaThread) -> usize {
+    std::mem::size_of::<RefCell<LuaThread>>()
+        + thread.stack.slots.len() * std::mem::size_of::<LuaValue>()
+        + thread.frames.len() * std::mem::size_of::<CallFrame>()
+}
+
+/// Weak mode of a table from the `__mode` field of its metatable.
+fn weak_mode(table: &LuaTable) -> (bool, bool) {
+    match table
+        .metatable
+        .as_ref()
+        .map(|mt| mt.borrow().get_str(TagMethod::Mode.name()))
+    {
+        Some(LuaValue::String(mode)) => (mode.contains(&b'k'), mode.contains(&b'v')),
+        _ => (false, false),
+    }
+}
+
+/// Tracing collector over the tables, closures and upvalues created by a
+/// state.
+///
+/// Values are still reference counted, the collector only has to find the
+/// garbage that counting misses: cycles, entries of weak tables and objects
+/// waiting for their finalizer. Everything referenced from outside the heap,
+/// like the stack, the registry or Rust code, is a root. These are found by
+/// comparing the reference count of each object with the number of
+/// references from other objects of the heap, so no root is ever missed.
+/// Unreachable objects are cleared, which breaks their cycles and frees
+/// them.
+///
+/// Cycles run atomically. The mode and its parameters only decide how much
+/// may be allocated before the next cycle starts.
+#[derive(Debug)]
+pub struct GarbageCollector {
+    objects: Vec<GcObject>,
+    /// Tables with a `__gc` metamethod, kept alive until finalized.
+    finobj: Vec<LuaTableRef>,
+    /// Bytes in use after the last cycle.
+    estimate: usize,
+    /// Bytes allocated since the last cycle.
+    debt: usize,
+    running: bool,
+    collecting: bool,
+    mode: GcMode,
+    pause: usize,
+    minormul: usize,
+}
+
+impl Default for GarbageCollector {
+    fn default() -> Self {
+        GarbageCollector {
+            objects: Vec::new(),
+            finobj: Vec::new(),
+            estimate: 0,
+            debt: 0,
+            running: true,
+            collecting: false,
+            mode: GcMode::Incremental,
+            pause: 200,
+            minormul: 20,
+        }
+    }
+}
+
+impl GarbageCollector {
+    /// Bytes that may be allocated before the next cycle.
+    fn threshold(&self) -> usize {
+        let percent = match self.mode {
+            GcMode::Incremental => self.pause.saturating_sub(100),
+            GcMode::Generational => self.minormul,
+        };
+        (self.estimate * percent / 100).max(MIN_DEBT)
+    }
+
+    fn mode_code(&self) -> i64 {
+        match self.mode {
+            GcMode::Incremental => LUA_GCINC,
+            GcMode::Generational => LUA_GCGEN,
+        }
+    }
+}
+
+impl LuaState {
+    /// New table owned by the collector.
+    pub fn new_table_ref(&mut self, narr: usize, nrec: usize) -> LuaTableRef {
+        let table = LuaTable::new_ref(narr, nrec);
+        self.gc.debt += table.borrow().heap_size();
+        self.gc.objects.push(GcObject::Table(Rc::downgrade(&table)));
+        table
+    }
+
+    pub(crate) fn new_closure(&mut self, closure: LuaClosure) -> Rc<LuaClosure> {
+        let closure = Rc::new(closure);
+        self.gc.debt += closure_size(&closure);
+        self.gc
+            .objects
+            .push(GcObject::Closure(Rc::downgrade(&closure)));
+        closure
+    }
+
+    pub(crate) fn new_upvalue(&mut self, upvalue: LuaUpvalue) -> UpvalueRef {
+        let upvalue = Rc::new(RefCell::new(upvalue));
+        self.gc.debt += upvalue_size();
+        self.gc
+            .objects
+            .push(GcObject::Upvalue(Rc::downgrade(&upvalue)));
+        upvalue
+    }
+
+    pub(crate) fn new_thread_ref(&mut self) -> LuaThreadRef {
+        let thread = Rc::new(RefCell::new(LuaThread::new()));
+        self.gc.debt += thread_size(&thread.borrow());
+        self.gc
+            .objects
+            .push(GcObject::Thread(Rc::downgrade(&thread)));
+        thread
+    }
+
+    /// Tables get finalized only if their metatable has a `__gc` field when
+    /// it is set.
+    pub(crate) fn check_finalizer(&mut self, table: &LuaTableRef) {
+        let has_gc = match &table.borrow().metatable {
+            Some(mt) => !mt.borrow().get_str(TagMethod::Gc.name()).is_nil(),
+            None => false,
+        };
+        if has_gc && !self.gc.finobj.iter().any(|t| Rc::ptr_eq(t, table)) {
+            self.gc.finobj.push(table.clone());
+        }
+    }
+
+    /// Run a cycle if enough has been allocated since the last one.
+    pub(crate) fn check_gc(&mut self) {
+        if self.gc.running && !self.gc.collecting && self.gc.debt >= self.gc.threshold() {
+            self.full_gc();
+        }
+    }
+
+    pub(crate) fn gc_control(&mut self, what: GcOption) -> i64 {
+        match what {
+            GcOption::Stop => self.gc.running = false,
+            GcOption::Restart => self.gc.running = true,
+            GcOption::Collect => self.full_gc(),
+            GcOption::Count => return (self.heap_size() / 1024) as i64,
+            GcOption::CountB => return (self.heap_size() % 1024) as i64,
+            GcOption::Step(kbytes) => {
+                self.gc.debt += kbytes * 1024;
+                if kbytes == 0 || self.gc.debt >= self.gc.threshold() {
+                    self.full_gc();
+                    return 1;
+                }
+                return 0;
+            }
+            GcOption::IsRunning => return self.gc.running as i64,
+            GcOption::Incremental { pause } => {
+                let previous = self.gc.mode_code();
+                let gc = &mut self.gc;
+                gc.mode = GcMode::Incremental;
+                gc.pause = if pause == 0 { gc.pause } else { pause };
+                return previous;
+            }
+            GcOption::Generational { minormul } => {
+                let previous = self.gc.mode_code();
+                let gc = &mut self.gc;
+                gc.mode = GcMode::Generational;
+                gc.minormul = if minormul == 0 { gc.minormul } else { minormul };
+                return previous;
+            }
+        }
+        0
+    }
+
+    /// Approximate bytes used by the live objects of the heap.
+    fn heap_size(&self) -> usize {
+        self.live_objects().iter().map(Live::heap_size).sum()
+    }
+
+    fn live_objects(&self) -> Vec<Live> {
+        self.gc
+            .objects
+            .iter()
+            .filter_map(|obj| match obj {
+                GcObject::Table(t) => t.upgrade().map(Live::Table),
+                GcObject::Closure(c) => c.upgrade().map(Live::Closure),
+                GcObject::Upvalue(u) => u.upgrade().map(Live::Upvalue),
+                GcObject::Thread(t) => t.upgrade().map(Live::Thread),
+            })
+            .collect()
+    }
+
+    /// Run a complete cycle, then call the finalizers of the tables found
+    /// unreachable.
+    pub fn full_gc(&mut self) {
+        if self.gc.collecting {
+            return;
+        }
+        self.gc.collecting = true;
+        let to_be_finalized = self.collect();
+        self.gc.collecting = false;
+
+        for table in to_be_finalized {
+            let mm = self.get_metamethod(&LuaValue::Table(table.clone()), TagMethod::Gc);
+            if mm.is_nil() {
+                continue;
+            }
+            self.stack.push(mm);
+            self.stack.push(LuaValue::Table(table));
+            // errors in finalizers are ignored
+            if self.pcall(1, 0, None).is_err() {
+                self.pop(1);
+            }
+        }
+    }
+
+    /// Mark and sweep, returns the tables to finalize.
+    fn collect(&mut self) -> Vec<LuaTableRef> {
+        let live = self.live_objects();
+        self.gc.objects = live
+            .iter()
+            .map(|obj| match obj {
+                Live::Table(t) => GcObject::Table(Rc::downgrade(t)),
+                Live::Closure(c) => GcObject::Closure(Rc::downgrade(c)),
+                Live::Upvalue(u) => GcObject::Upvalue(Rc::downgrade(u)),
+                Live::Thread(t) => GcObject::Thread(Rc::downgrade(t)),
+            })
+            .collect();
+        let index: HashMap<*const (), usize> = live
+            .iter()
+            .enumerate()
+            .map(|(i, obj)| (obj.addr(), i))
+            .collect();
+
+        // references from inside the heap, the rest come from roots
+        let mut internal = vec![0; live.len()];
+        for obj in &live {
+            obj.for_each_child(|addr| {
+                if let Some(&i) = index.get(&addr) {
+                    internal[i] += 1;
+                }
+            });
+        }
+        for table in &self.gc.finobj {
+            if let Some(&i) = index.get(&(Rc::as_ptr(table) as *const ())) {
+                internal[i] += 1;
+            }
+        }
+
+        let mut marker = Marker {
+            live: &live,
+            index: &index,
+            marked: vec![false; live.len()],
+            gray: Vec::new(),
+            ephemerons: Vec::new(),
+        };
+        for (i, obj) in live.iter().enumerate() {
+            // one reference is held by `live` itself
+            if obj.strong_count() - 1 > internal[i] {
+                marker.mark(i);
+            }
+        }
+        marker.propagate();
+
+        // unreachable tables with finalizers come back to life until their
+        // finalizer has run
+        let (to_be_finalized, finobj): (Vec<_>, Vec<_>) = std::mem::take(&mut self.gc.finobj)
+            .into_iter()
+            .partition(|t| !marker.is_marked_table(t));
+        self.gc.finobj = finobj;
+        marker.clear_weak(false, true);
+        for table in &to_be_finalized {
+            if let Some(&i) = index.get(&(Rc::as_ptr(table) as *const ())) {
+                marker.mark(i);
+            }
+        }
+        marker.propagate();
+        marker.clear_weak(true, true);
+
+        // clear what is left to break its cycles, the contents are dropped
+        // once nothing is borrowed anymore
+        let (mut tables, mut values, mut frames) = (Vec::new(), Vec::new(), Vec::new());
+        for (i, obj) in live.iter().enumerate() {
+            if marker.marked[i] {
+                continue;
+            }
+            match obj {
+                Live::Table(t) => tables.push(std::mem::take(&mut *t.borrow_mut())),
+                Live::Upvalue(u) => {
+                    if let LuaUpvalue::Closed(v) = &mut *u.borrow_mut() {
+                        values.push(std::mem::take(v));
+                    }
+                }
+                Live::Thread(t) => {
+                    let mut t = t.borrow_mut();
+                    t.close_upvalues();
+                    values.extend(std::mem::take(&mut t.stack.slots));
+                    frames.push(std::mem::take(&mut t.frames));
+                }
+                Live::Closure(_) => (),
+            }
+        }
+        drop((tables, values, frames));
+
+        self.gc.estimate = live
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| marker.marked[*i])
+            .map(|(_, obj)| obj.heap_size())
+            .sum();
+        self.gc.debt = 0;
+        to_be_finalized
+    }
+}
+
+struct Marker<'a> {
+    live: &'a [Live],
+    index: &'a HashMap<*const (), usize>,
+    marked: Vec<bool>,
+    gray: Vec<usize>,
+    /// Tables with weak keys, their values are only reachable through
+    /// reachable keys.
+    ephemerons: Vec<usize>,
+}
+
+impl Marker<'_> {
+    fn mark(&mut self, i: usize) {
+        if !self.marked[i] {
+            self.marked[i] = true;
+            self.gray.push(i);
+        }
+    }
+
+    fn mark_value(&mut self, val: &LuaValue) {
+        if let Some(&i) = value_addr(val).and_then(|addr| self.index.get(&addr)) {
+            self.mark(i);
+        }
+    }
+
+    /// Values that are not collected count as marked, so do objects outside
+    /// the heap.
+    fn is_marked(&self, val: &LuaValue) -> bool {
+        match value_addr(val).and_then(|addr| self.index.get(&addr)) {
+            Some(&i) => self.marked[i],
+            None => true,
+        }
+    }
+
+    fn is_marked_table(&self, table: &LuaTableRef) -> bool {
+        match self.index.get(&(Rc::as_ptr(table) as *const ())) {
+            Some(&i) => self.marked[i],
+            None => true,
+        }
+    }
+
+    fn propagate(&mut self) {
+        loop {
+            while let Some(i) = self.gray.pop() {
+                self.traverse(i);
+            }
+            // a value of an ephemeron may have become reachable by its key
+            let ephemerons = std::mem::take(&mut self.ephemerons);
+            for &i in &ephemerons {
+                self.traverse(i);
+            }
+            if self.gray.is_empty() {
+                break;
+            }
+        }
+    }
+
+    fn traverse(&mut self, i: usize) {
+        let live = self.live;
+        match &live[i] {
+            Live::Table(t) => {
+                let t = t.borrow();
+                if let Some(mt) = &t.metatable {
+                    if let Some(&j) = self.index.get(&(Rc::as_ptr(mt) as *const ())) {
+                        self.mark(j);
+                    }
+                }
+                let (weak_keys, weak_values) = weak_mode(&t);
+                if weak_keys && !weak_values {
+                    self.ephemerons.push(i);
+                }
+                if !weak_values {
+                    t.arr.iter().for_each(|v| self.mark_value(v));
+                }
+                for (k, v) in t.nodes() {
+                    if v.is_nil() {
+                        continue;
+                    }
+                    if !weak_keys {
+                        self.mark_value(k);
+                    }
+                    if !weak_values && (!weak_keys || self.is_marked(k)) {
+                        self.mark_value(v);
+                    }
+                }
+            }
+            Live::Closure(c) => {
+                for u in &c.upvalues {
+                    if let Some(&j) = self.index.get(&(Rc::as_ptr(u) as *const ())) {
+                        self.mark(j);
+                    }
+                }
+            }
+            Live::Upvalue(u) => match &*u.borrow() {
+                LuaUpvalue::Closed(v) => self.mark_value(v),
+                // the variable lives in the stack of a suspended thread, the
+                // running thread only has an empty stack stored
+                LuaUpvalue::Open { slot, thread } => {
+                    if let Some(t) = thread.upgrade() {
+                        if let Some(v) = t.borrow().stack.slots.get(*slot) {
+                            self.mark_value(v);
+                        }
+                    }
+                }
+            },
+            Live::Thread(t) => thread_children(&t.borrow(), |addr| {
+                if let Some(&j) = self.index.get(&addr) {
+                    self.mark(j);
+                }
+            }),
+        }
+    }
+
+    /// Remove entries of weak tables whose weak part is unreachable.
+    fn clear_weak(&self, keys: bool, values: bool) {
+        for (i, obj) in self.live.iter().enumerate() {
+            let Live::Table(t) = obj else { continue };
+            if !self.marked[i] {
+                continue;
+            }
+            let (weak_keys, weak_values) = weak_mode(&t.borrow());
+            let (keys, values) = (keys && weak_keys, values && weak_values);
+            if !keys && !values {
+                continue;
+            }
+            t.borrow_mut().remove_entries(|k, v| {
+                (keys && !self.is_marked(k)) || (values && !self.is_marked(v))
+            });
+        }
+    }
+}
@@ -1,158 +1,220 @@
-use crate::vm::lua_state::LuaVm;
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm, tag_method::TagMethod};
 
-use super::{Instruction, InstructionOperation};
+use super::{Instruction, InstructionOperation, MAXARG_C};
 
+/// Store the result into `R[A]` and skip the following MMBIN instruction,
+/// unless the operands are not numbers and need their metamethod.
 #[inline]
-fn arith_k(
-    i: Instruction,
-    vm: &mut dyn LuaVm,
-    i_func: Option<fn(a: i64, a: i64) -> i64>,
-    f_func: Option<fn(a: f64, b: f64) -> f64>,
-) {
+fn arith_result(vm: &mut dyn LuaVm, a: i32, op: TagMethod) -> Result<(), LuaError> {
+    if vm.arith(op)? {
+        vm.replace(a);
+        vm.add_pc(1);
+    }
+    Ok(())
+}
+
+/// R[A] := R[B] op K[C]
+#[inline]
+fn arith_k(i: Instruction, vm: &mut dyn LuaVm, op: TagMethod) -> Result<(), LuaError> {
     let (a, b, c) = i.abc();
-    vm.get_pk(b);
+    vm.push_value(b);
     vm.get_const(c as usize);
-    vm.is_number(vm.get_top() - 1);
-
-    vm.arith(i_func, f_func);
-    vm.replace(a);
+    arith_result(vm, a, op)
 }
 
-pub fn add_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a + b), Some(|a, b| a + b));
+pub fn add_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Add)
 }
 
-pub fn sub_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a - b), Some(|a, b| a - b));
+pub fn sub_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Sub)
 }
 
-pub fn mul_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a * b), Some(|a, b| a * b));
+pub fn mul_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Mul)
 }
 
-pub fn mod_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a % b), Some(|a, b| a % b));
+pub fn mod_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Mod)
 }
 
-pub fn pow_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, None, Some(|a, b| a.powf(b)));
+pub fn pow_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Pow)
 }
 
-pub fn div_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, None, Some(|a, b| a / b));
+pub fn div_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::Div)
 }
 
-/// TODO idiv implement
-pub fn idiv_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a / b), Some(|a, b| (a / b).floor()));
+pub fn idiv_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::IDiv)
 }
 
-pub fn b_and_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a & b), None)
+pub fn b_and_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::BAnd)
 }
-pub fn b_or_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a | b), None)
+pub fn b_or_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::BOr)
 }
 
-pub fn b_xor_k(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_k(i, vm, Some(|a, b| a ^ b), None)
+pub fn b_xor_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith_k(i, vm, TagMethod::BXor)
 }
 
+/// The signed immediate operand sC.
 #[inline]
-fn arith_i(
-    i: Instruction,
-    vm: &mut dyn LuaVm,
-    i_func: Option<fn(a: i64, a: i64) -> i64>,
-    f_func: Option<fn(a: f64, b: f64) -> f64>,
-) {
-    let (a, b, c) = i.abc();
-    let ic = c - (1 << 8 - 1) >> 1;
-    vm.get_pk(b);
-    vm.push_integer(ic.into());
+fn immediate(c: i32) -> i64 {
+    (c - (MAXARG_C >> 1)).into()
+}
 
-    vm.arith(i_func, f_func);
-    vm.replace(a);
+/// R[A] := R[B] + sC
+pub fn add_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_value(b);
+    vm.push_integer(immediate(c));
+    arith_result(vm, a, TagMethod::Add)
 }
 
-// TODO: logic shift left
-pub fn shl_i(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_i(i, vm, Some(|a, b| a >> b), None)
+/// R[A] := sC << R[B]
+pub fn shl_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_integer(immediate(c));
+    vm.push_value(b);
+    arith_result(vm, a, TagMethod::Shl)
 }
 
-// TODO: logic shift right
-pub fn shr_i(i: Instruction, vm: &mut dyn LuaVm) {
-    arith_i(i, vm, Some(|a, b| a << b), None)
+/// R[A] := R[B] >> sC
+pub fn shr_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_value(b);
+    vm.push_integer(immediate(c));
+    arith_result(vm, a, TagMethod::Shr)
 }
 
+/// R[A] := R[B] op R[C]
 #[inline]
-fn arith(
-    i: Instruction,
-    vm: &mut dyn LuaVm,
-    i_func: Option<fn(a: i64, a: i64) -> i64>,
-    f_func: Option<fn(a: f64, b: f64) -> f64>,
-) {
+fn arith(i: Instruction, vm: &mut dyn LuaVm, op: TagMethod) -> Result<(), LuaError> {
     let (a, b, c) = i.abc();
-    vm.get_pk(c);
-    vm.get_pk(b);
+    vm.push_value(b);
+    vm.push_value(c);
+    arith_result(vm, a, op)
+}
 
-    vm.arith(i_func, f_func);
-    vm.replace(a);
+pub fn add(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Add)
 }
 
-pub fn add(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a + b), Some(|a, b| a + b));
+pub fn sub(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Sub)
 }
 
-pub fn sub(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a - b), Some(|a, b| a - b));
+pub fn mul(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Mul)
 }
 
-pub fn mul(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a * b), Some(|a, b| a * b));
+pub fn mod_(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Mod)
 }
 
-pub fn mod_(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a % b), Some(|a, b| a % b));
+pub fn pow(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Pow)
 }
 
-pub fn pow(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, None, Some(|a, b| a.powf(b)));
+pub fn div(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Div)
 }
 
-pub fn div(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, None, Some(|a, b| a / b));
+pub fn idiv(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::IDiv)
 }
 
-/// TODO idiv implement
-pub fn idiv(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a / b), Some(|a, b| (a / b).floor()));
+pub fn b_and(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::BAnd)
+}
+pub fn b_or(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::BOr)
 }
 
-pub fn b_and(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a & b), None)
+pub fn b_xor(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::BXor)
 }
-pub fn b_or(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a | b), None)
+
+pub fn shl(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Shl)
 }
 
-pub fn b_xor(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a ^ b), None)
+pub fn shr(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    arith(i, vm, TagMethod::Shr)
 }
 
-// TODO: logic shift left
-pub fn shl(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a >> b), None)
+#[inline]
+fn unary(i: Instruction, vm: &mut dyn LuaVm, event: TagMethod) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    vm.push_value(b);
+    vm.push_value(b);
+    if !vm.arith(event)? {
+        // the metamethod gets the operand twice
+        vm.push_value(b);
+        vm.push_value(b);
+        vm.arith_metamethod(event)?;
+    }
+    vm.replace(a);
+    Ok(())
 }
 
-// TODO: logic shift right
-pub fn shr(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, b| a << b), None)
+/// R[A] := -R[B]
+pub fn unm(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    unary(i, vm, TagMethod::Unm)
 }
 
-pub fn unm(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, _| -a), Some(|a, _| -a))
+/// R[A] := ~R[B]
+pub fn b_not(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    unary(i, vm, TagMethod::BNot)
 }
 
-pub fn b_not(i: Instruction, vm: &mut dyn LuaVm) {
-    arith(i, vm, Some(|a, _| !a), None)
+/// call C metamethod over R[A] and R[B]
+///
+/// Only reached when the arithmetic instruction before failed, its result
+/// goes to the A register of that instruction.
+pub fn mm_bin(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_value(a);
+    vm.push_value(b);
+    metamethod_result(vm, c)
+}
+
+/// call C metamethod over R[A] and sB, k means the operands are flipped
+pub fn mm_bin_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, c) = i.abc();
+    let imm = i.bx() as i64;
+    if i.k() == 1 {
+        vm.push_integer(imm);
+        vm.push_value(a);
+    } else {
+        vm.push_value(a);
+        vm.push_integer(imm);
+    }
+    metamethod_result(vm, c)
+}
+
+/// call C metamethod over R[A] and K[B], k means the operands are flipped
+pub fn mm_bin_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    if i.k() == 1 {
+        vm.get_const(b as usize);
+        vm.push_value(a);
+    } else {
+        vm.push_value(a);
+        vm.get_const(b as usize);
+    }
+    metamethod_result(vm, c)
+}
+
+#[inline]
+fn metamethod_result(vm: &mut dyn LuaVm, event: i32) -> Result<(), LuaError> {
+    let event = TagMethod::try_from(event).map_err(LuaError::new)?;
+    vm.arith_metamethod(event)?;
+    let (result, _, _) = vm.get_code(vm.get_pc() - 2).abc();
+    vm.replace(result);
+    Ok(())
 }
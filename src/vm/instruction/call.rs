@@ -0,0 +1,100 @@
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm};
+
+use super::{Instruction, InstructionOperation};
+
+/// R[A] := closure(KPROTO[Bx])
+pub fn closure(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, bx) = i.a_bx();
+    vm.load_proto(bx as usize);
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+///
+/// Look up the method C of R[B] and pass R[B] as its `self` argument.
+pub fn self_(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.copy(b, a + 1);
+    vm.get_rk(c, i.k() == 1);
+    vm.get_table(b)?;
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+///
+/// B = 0 passes all values up to the top, C = 0 (`LUA_MULTRET` after the
+/// decrement) keeps all results and leaves the top after the last one.
+pub fn call(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    if b != 0 {
+        vm.set_top(a + b);
+    }
+    vm.pre_call(a, c - 1)
+}
+
+/// return R[A](R[A+1], ... ,R[A+B-1])
+pub fn tail_call(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    if b != 0 {
+        vm.set_top(a + b);
+    }
+    vm.tail_call(a)
+}
+
+/// return R[A], ... ,R[A+B-2]
+///
+/// k is set when the function has to-be-closed variables, they are closed
+/// before returning, above the values returned.
+pub fn return_(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    let n = if b != 0 {
+        b - 1
+    } else {
+        vm.get_top() as i32 - a
+    };
+    if i.k() != 0 {
+        vm.close(0)?;
+    }
+    vm.post_call(a, n as usize);
+    Ok(())
+}
+
+/// return
+pub fn return0(_i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    vm.post_call(0, 0);
+    Ok(())
+}
+
+/// return R[A]
+pub fn return1(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    vm.post_call(a, 1);
+    Ok(())
+}
+
+/// Prepare a vararg function with A fixed parameters. `pre_call` already
+/// moved the extra arguments out of the registers into the frame, where
+/// VARARG reads them.
+pub fn vararg_prep(_i: Instruction, _vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    Ok(())
+}
+
+/// R[A], R[A+1], ..., R[A+C-2] = vararg
+///
+/// C = 0 loads all extra arguments and leaves the top after the last one.
+pub fn vararg(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, c) = i.abc();
+    let n = c - 1;
+    if n < 0 {
+        vm.set_top(a);
+        vm.load_vararg(n);
+    } else {
+        vm.load_vararg(n);
+        for k in (0..n).rev() {
+            vm.replace(a + k);
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,135 @@
-use crate::vm::lua_state::{CampareOperator, LuaVm};
+use crate::vm::{
+    lua_error::LuaError,
+    lua_state::{CampareOperator, LuaVm},
+};
 
 use super::{Instruction, InstructionOperation};
 
-pub fn equal_i(i: Instruction, vm: &mut dyn LuaVm) {
+/// Skip the jump following a test unless the condition matches `k`.
+#[inline]
+fn cond_jump(i: Instruction, vm: &mut dyn LuaVm, cond: bool) {
+    if cond != (i.k() == 1) {
+        vm.add_pc(1);
+    }
+}
+
+/// if ((R[A] == R[B]) ~= k) then pc++
+pub fn equal(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    let cond = vm.compare(a, b, CampareOperator::Equal)?;
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// if ((R[A] < R[B]) ~= k) then pc++
+pub fn less_than(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    let cond = vm.compare(a, b, CampareOperator::LessThen)?;
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// if ((R[A] <= R[B]) ~= k) then pc++
+pub fn less_equal(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    let cond = vm.compare(a, b, CampareOperator::LessEqual)?;
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// if ((R[A] == K[B]) ~= k) then pc++
+pub fn equal_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    vm.get_const(b as usize);
+    let cond = vm.raw_equal(a, -1);
+    vm.pop(1);
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// if ((R[A] == sB) ~= k) then pc++
+pub fn equal_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    vm.push_integer(i.bx().into());
+    let cond = vm.raw_equal(a, -1);
+    vm.pop(1);
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// Compare `R[A]` with the immediate sB, `flip` puts the immediate first. C
+/// tells whether the immediate was a float in the source, which matters
+/// for metamethods only.
+#[inline]
+fn compare_i(
+    i: Instruction,
+    vm: &mut dyn LuaVm,
+    op: CampareOperator,
+    flip: bool,
+) -> Result<(), LuaError> {
+    let (a, _, c) = i.abc();
+    let imm = i.bx();
+    if c == 1 {
+        vm.push_number(imm.into());
+    } else {
+        vm.push_integer(imm.into());
+    }
+    let cond = if flip {
+        vm.compare(-1, a, op)
+    } else {
+        vm.compare(a, -1, op)
+    };
+    vm.pop(1);
+    cond_jump(i, vm, cond?);
+    Ok(())
+}
+
+/// if ((R[A] < sB) ~= k) then pc++
+pub fn less_than_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    compare_i(i, vm, CampareOperator::LessThen, false)
+}
+
+/// if ((R[A] <= sB) ~= k) then pc++
+pub fn less_equal_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    compare_i(i, vm, CampareOperator::LessEqual, false)
+}
+
+/// if ((R[A] > sB) ~= k) then pc++
+pub fn great_than_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    compare_i(i, vm, CampareOperator::LessThen, true)
+}
+
+/// if ((R[A] >= sB) ~= k) then pc++
+pub fn great_equal_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    compare_i(i, vm, CampareOperator::LessEqual, true)
+}
+
+/// if (not R[A] == k) then pc++
+pub fn test(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, _, _) = i.abc();
-    let i_val = i.bx();
-    vm.get_pk(a);
-    vm.push_integer(i_val.into());
-    println!("k: {}", i.k());
-    if vm.compare(-1, -2, CampareOperator::Equal) {
+    let cond = vm.to_boolean(a);
+    cond_jump(i, vm, cond);
+    Ok(())
+}
+
+/// if (not R[B] == k) then pc++ else R[A] := R[B]
+///
+/// Used by `and` and `or` to keep the deciding operand as the result.
+pub fn test_set(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    if vm.to_boolean(b) != (i.k() == 1) {
         vm.add_pc(1);
+    } else {
+        vm.copy(b, a);
     }
-    vm.pop(2);
+    Ok(())
+}
+
+/// R[A] := not R[B]
+pub fn not(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    let val = vm.to_boolean(b);
+    vm.push_boolean(!val);
+    vm.replace(a);
+    Ok(())
 }
@@ -1,34 +1,67 @@
-use crate::vm::lua_state::LuaVm;
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm};
 
 use super::{Instruction, InstructionOperation};
 
-pub fn load_nil(i: Instruction, vm: &mut dyn LuaVm) {
-    let (mut a, b, _) = i.abc();
-    a += 1;
+/// R[A], R[A+1], ..., R[A+B] := nil
+pub fn load_nil(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
     vm.push_nil();
-    for i in a..(a + b) {
+    for i in a..=(a + b) {
         vm.copy(-1, i);
     }
     vm.pop(1);
+    Ok(())
 }
 
-pub fn load_i(i: Instruction, vm: &mut dyn LuaVm) {
+/// R[A] := false
+pub fn load_false(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    vm.push_boolean(false);
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A] := false; pc++
+pub fn load_false_skip(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    load_false(i, vm)?;
+    vm.add_pc(1);
+    Ok(())
+}
+
+/// R[A] := true
+pub fn load_true(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    vm.push_boolean(true);
+    vm.replace(a);
+    Ok(())
+}
+
+pub fn load_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, sbx) = i.a_sbx();
     vm.push_integer(sbx.into());
     vm.replace(a);
+    Ok(())
+}
+
+pub fn load_f(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, sbx) = i.a_sbx();
+    vm.push_number(sbx.into());
+    vm.replace(a);
+    Ok(())
 }
 
-pub fn load_k(i: Instruction, vm: &mut dyn LuaVm) {
+pub fn load_k(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, bx) = i.a_bx();
-    println!("a=>{}, bx=>{}", a, bx);
     vm.get_const(bx as usize);
     vm.replace(a);
+    Ok(())
 }
 
-pub fn load_kx(i: Instruction, vm: &mut dyn LuaVm) {
+pub fn load_kx(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, _) = i.a_bx();
     let ax = vm.fetch().ax();
 
     vm.get_const(ax as usize);
     vm.replace(a);
+    Ok(())
 }
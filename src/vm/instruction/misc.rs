@@ -1,30 +1,36 @@
-use crate::vm::lua_state::LuaVm;
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm};
 
 use super::{Instruction, InstructionOperation};
 
-pub fn moving(i: Instruction, vm: &mut dyn LuaVm) {
+pub fn moving(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, b, _) = i.abc();
-    println!("a:{}, b:{}", a, b);
     vm.copy(b, a);
+    Ok(())
 }
 
-pub fn jump(i: Instruction, vm: &mut dyn LuaVm) {
+pub fn jump(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let sj = i.sj();
     vm.add_pc(sj);
     // assert!(a == 0, "TODO");
+    Ok(())
 }
 
-pub fn len(i: Instruction, vm: &mut dyn LuaVm) {
+pub fn len(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, b, _) = i.abc();
-    vm.len(b);
+    vm.len(b)?;
     vm.replace(a);
+    Ok(())
 }
 
-pub fn concat(i: Instruction, vm: &mut dyn LuaVm) {
-    let (a, _, c) = i.abc();
-    // let n = c - b + 1;
+/// R[A] := R[A].. ... ..R[A + B - 1]
+pub fn concat(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
 
-    vm.check_stack(c as usize);
-    vm.concat(c as usize);
+    vm.check_stack(b as usize);
+    for idx in a..(a + b) {
+        vm.push_value(idx);
+    }
+    vm.concat(b as usize)?;
     vm.replace(a);
+    Ok(())
 }
@@ -1,18 +1,23 @@
 pub mod arith;
+pub mod call;
 pub mod compare;
 pub mod load;
 pub mod misc;
 pub mod repeat;
+pub mod table;
+pub mod upvalue;
 
 use super::{
+    lua_error::LuaError,
     lua_state::LuaState,
     op_code::{OpArg, OpMode, OP_CODE},
 };
 
 pub type Instruction = u32;
 
-const MAXARG_Bx: i32 = (1 << 17) - 1;
-const MAXARG_sBx: i32 = MAXARG_Bx >> 1;
+const MAXARG_BX: i32 = (1 << 17) - 1;
+const MAXARG_SBX: i32 = MAXARG_BX >> 1;
+const MAXARG_C: i32 = (1 << 8) - 1;
 
 pub trait InstructionOperation {
     fn op_code(&self) -> usize;
@@ -39,7 +44,7 @@ pub trait InstructionOperation {
 
     fn c_mode(&self) -> OpArg;
 
-    fn execute(&self, state: &mut LuaState);
+    fn execute(&self, state: &mut LuaState) -> Result<(), LuaError>;
 }
 
 impl InstructionOperation for Instruction {
@@ -63,7 +68,7 @@ impl InstructionOperation for Instruction {
 
     fn a_sbx(&self) -> (i32, i32) {
         let (a, bx) = self.a_bx();
-        (a, bx - MAXARG_sBx)
+        (a, bx - MAXARG_SBX)
     }
 
     fn ax(&self) -> i32 {
@@ -78,8 +83,8 @@ impl InstructionOperation for Instruction {
         (self >> 15 & 0b1) as i32
     }
     fn bx(&self) -> i32 {
-        let (_, b, c) = self.abc();
-        b - (((1 << 8) - 1) >> 1)
+        let (_, b, _) = self.abc();
+        b - (MAXARG_C >> 1)
     }
     fn op_name(&self) -> &'static str {
         OP_CODE[self.op_code()].name
@@ -97,9 +102,9 @@ impl InstructionOperation for Instruction {
         OP_CODE[self.op_code()].arg_c_mode
     }
 
-    fn execute(&self, state: &mut LuaState) {
+    fn execute(&self, state: &mut LuaState) -> Result<(), LuaError> {
         let action = OP_CODE[self.op_code()].action;
-        action(self.clone(), state);
+        action(*self, state)
     }
 }
 
@@ -114,13 +119,13 @@ fn test_instruction() {
                 print!("\ta => {:?}", a);
                 match i.b_mode() {
                     OpArg::OpArgN => print!("\tb => {:?}", b),
-                    _ => print!("\tb => {:?}", -1 - b & 0xFF),
+                    _ => print!("\tb => {:?}", (-1 - b) & 0xFF),
                 }
                 match i.c_mode() {
                     OpArg::OpArgN => print!("\tc => {:?}", c),
-                    _ => print!("\tc => {:?}", -1 - c & 0xFF),
+                    _ => print!("\tc => {:?}", (-1 - c) & 0xFF),
                 }
-                println!("");
+                println!();
             }
             OpMode::IABx => {
                 let (a, bx) = i.a_bx();
@@ -130,23 +135,23 @@ fn test_instruction() {
                     OpArg::OpArgU => print!("\tbx => {:?}", bx),
                     _ => (),
                 }
-                println!("");
+                println!();
             }
             OpMode::IAsBx => {
                 let (a, sbx) = i.a_sbx();
                 print!("\ta => {:?}\t sbx => {:?}", a, sbx);
-                println!("");
+                println!();
             }
             OpMode::IAx => {
                 let ax = i.ax();
                 print!("\tax => {}", ax);
-                println!("");
+                println!();
             }
         }
     }
     let codes: [Instruction; 3] = [81, 8, 16842950];
     for code in codes.iter() {
-        print_operands(code.clone());
+        print_operands(*code);
     }
 }
 
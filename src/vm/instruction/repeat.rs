@@ -1,26 +1,159 @@
-use crate::vm::lua_state::{CampareOperator, LuaVm};
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm, lua_value::float_to_integer};
 
 use super::{Instruction, InstructionOperation};
 
-pub fn for_prep(i: Instruction, vm: &mut dyn LuaVm) {
+/// Error for a control expression of a numeric loop that is not a number.
+fn for_error(vm: &mut dyn LuaVm, what: &str) -> LuaError {
+    vm.instruction_error(&format!("'for' {} must be a number", what))
+}
+
+/// Register `idx` as a number, without converting strings.
+fn for_number(vm: &mut dyn LuaVm, idx: i32, what: &str) -> Result<f64, LuaError> {
+    match vm.to_numberx(idx as usize) {
+        Some(n) => Ok(n),
+        None => Err(for_error(vm, what)),
+    }
+}
+
+fn set_integer(vm: &mut dyn LuaVm, idx: i32, val: i64) {
+    vm.push_integer(val);
+    vm.replace(idx);
+}
+
+fn set_number(vm: &mut dyn LuaVm, idx: i32, val: f64) {
+    vm.push_number(val);
+    vm.replace(idx);
+}
+
+/// Limit of an integer loop in register `idx`. A float limit is rounded
+/// towards the inside of the loop and clipped to the integer range, `None`
+/// if the loop must not run at all.
+fn for_limit(vm: &mut dyn LuaVm, idx: i32, step: i64) -> Result<Option<i64>, LuaError> {
+    if let Some(limit) = vm.to_integer(idx as usize) {
+        return Ok(Some(limit));
+    }
+    let limit = for_number(vm, idx, "limit")?;
+    let rounded = if step < 0 {
+        limit.ceil()
+    } else {
+        limit.floor()
+    };
+    Ok(match float_to_integer(rounded) {
+        Some(limit) => Some(limit),
+        None if limit.is_nan() => None,
+        None if limit > 0.0 => (step > 0).then_some(i64::MAX),
+        None => (step < 0).then_some(i64::MIN),
+    })
+}
+
+/// Prepare an integer loop, the limit is replaced by the number of
+/// iterations left so that the index never overflows. `true` if the loop
+/// does not run at all.
+fn prep_int_loop(vm: &mut dyn LuaVm, a: i32, init: i64, step: i64) -> Result<bool, LuaError> {
+    if step == 0 {
+        return Err(vm.instruction_error("'for' step is zero"));
+    }
+    let limit = match for_limit(vm, a + 1, step)? {
+        Some(limit) if (step > 0 && init <= limit) || (step < 0 && init >= limit) => limit,
+        _ => return Ok(true),
+    };
+    let count = if step > 0 {
+        (limit as u64).wrapping_sub(init as u64) / step as u64
+    } else {
+        // -step without overflowing for the minimum integer
+        (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+    };
+    set_integer(vm, a + 1, count as i64);
+    set_integer(vm, a + 3, init);
+    Ok(false)
+}
+
+/// Prepare a float loop, all control values become floats.
+fn prep_float_loop(vm: &mut dyn LuaVm, a: i32) -> Result<bool, LuaError> {
+    let limit = for_number(vm, a + 1, "limit")?;
+    let step = for_number(vm, a + 2, "step")?;
+    let init = for_number(vm, a, "initial value")?;
+    if step == 0.0 {
+        return Err(vm.instruction_error("'for' step is zero"));
+    }
+    if (step > 0.0 && limit < init) || (step < 0.0 && init < limit) {
+        return Ok(true);
+    }
+    set_number(vm, a, init);
+    set_number(vm, a + 1, limit);
+    set_number(vm, a + 2, step);
+    set_number(vm, a + 3, init);
+    Ok(false)
+}
+
+/// Prepare a numeric loop over `R[A]`, `R[A+1]` and `R[A+2]`, the control
+/// variable is `R[A+3]`. Skip the loop if it does not run: `pc += Bx + 1`.
+pub fn for_prep(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, bx) = i.a_bx();
+    let skip = match (vm.to_integer(a as usize), vm.to_integer(a as usize + 2)) {
+        (Some(init), Some(step)) => prep_int_loop(vm, a, init, step)?,
+        _ => prep_float_loop(vm, a)?,
+    };
+    if skip {
+        vm.add_pc(bx + 1);
+    }
+    Ok(())
+}
+
+/// Advance the loop and jump back while iterations are left: `pc -= Bx`.
+pub fn for_loop(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, bx) = i.a_bx();
+    if let Some(step) = vm.to_integer(a as usize + 2) {
+        let count = vm.to_integer(a as usize + 1).unwrap_or(0) as u64;
+        if count > 0 {
+            let idx = vm.to_integer(a as usize).unwrap_or(0).wrapping_add(step);
+            set_integer(vm, a + 1, (count - 1) as i64);
+            set_integer(vm, a, idx);
+            set_integer(vm, a + 3, idx);
+            vm.add_pc(-bx);
+        }
+        return Ok(());
+    }
 
-    vm.push_value(a + 2); // step
-    vm.push_value(a); // init value
-    vm.arith(Some(|a, b| a + b), Some(|a, b| a + b));
-    vm.replace(a);
-    // TODO judge RA(a) > MAX_INTEGER
-    vm.add_pc(bx);
+    let number = |vm: &mut dyn LuaVm, idx: i32| vm.to_numberx(idx as usize).unwrap_or(f64::NAN);
+    let (limit, step) = (number(vm, a + 1), number(vm, a + 2));
+    let idx = number(vm, a) + step;
+    if (step > 0.0 && idx <= limit) || (step < 0.0 && limit <= idx) {
+        set_number(vm, a, idx);
+        set_number(vm, a + 3, idx);
+        vm.add_pc(-bx);
+    }
+    Ok(())
 }
 
-pub fn for_loop(i: Instruction, vm: &mut dyn LuaVm) {
+/// Check the closing value `R[A+3]` of a generic loop and jump to its
+/// TFORCALL: `pc += Bx`.
+pub fn tfor_prep(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
     let (a, bx) = i.a_bx();
-    let is_positive_step = vm.to_numberx((a + 2) as usize).unwrap_or(0.0) >= 0.0;
+    vm.to_be_closed(a + 3, "(for state)")?;
+    vm.add_pc(bx);
+    Ok(())
+}
 
-    if (is_positive_step && vm.compare(a, a + 1, CampareOperator::LessThen))
-        || (!is_positive_step && vm.compare(a + 1, a, CampareOperator::LessThen))
-    {
-        vm.copy(a, a + 3);
+/// R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2])
+///
+/// The results are left for the TFORLOOP that follows, a Lua iterator
+/// returns to it like to any other call.
+pub fn tfor_call(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, c) = i.abc();
+    vm.set_top(a + 7);
+    for k in 0..3 {
+        vm.copy(a + k, a + 4 + k);
+    }
+    vm.pre_call(a + 4, c)
+}
+
+/// if R[A+4] ~= nil then { R[A+2] = R[A+4]; pc -= Bx }
+pub fn tfor_loop(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, bx) = i.a_bx();
+    if vm.type_name(a + 4) != "nil" {
+        vm.copy(a + 4, a + 2);
         vm.add_pc(-bx);
     }
+    Ok(())
 }
@@ -0,0 +1,94 @@
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm};
+
+use super::{Instruction, InstructionOperation, MAXARG_C};
+
+/// R[A] := {}
+pub fn new_table(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, mut c) = i.abc();
+    // NEWTABLE is always followed by an EXTRAARG, which holds the high bits of
+    // the array size when k is set
+    let extra = vm.fetch();
+    if i.k() == 1 {
+        c += extra.ax() * (MAXARG_C + 1);
+    }
+    let nrec = if b > 0 { 1 << (b - 1) } else { 0 };
+
+    vm.create_table(c as usize, nrec);
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A] := R[B][R[C]]
+pub fn get_table(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_value(c);
+    vm.get_table(b)?;
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A] := R[B][C]
+pub fn get_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_i(b, c.into())?;
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A] := R[B][K[C]:string]
+pub fn get_field(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_const(c as usize);
+    vm.get_table(b)?;
+    vm.replace(a);
+    Ok(())
+}
+
+/// R[A][R[B]] := RK(C)
+pub fn set_table(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.push_value(b);
+    vm.get_rk(c, i.k() == 1);
+    vm.set_table(a)
+}
+
+/// R[A][B] := RK(C)
+pub fn set_i(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(c, i.k() == 1);
+    vm.set_i(a, b.into())
+}
+
+/// R[A][K[B]:string] := RK(C)
+pub fn set_field(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_const(b as usize);
+    vm.get_rk(c, i.k() == 1);
+    vm.set_table(a)
+}
+
+/// R[A][C+i] := R[A+i], 1 <= i <= B
+///
+/// B = 0 stores all values up to the top. When k is set, the EXTRAARG that
+/// follows holds the high bits of C.
+pub fn set_list(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    let n = if b == 0 {
+        vm.get_top() as i32 - a - 1
+    } else {
+        b
+    };
+    let mut last = c as i64;
+    if i.k() == 1 {
+        last += vm.fetch().ax() as i64 * (MAXARG_C as i64 + 1);
+    }
+    for k in 1..=n {
+        vm.push_integer(last + k as i64);
+        vm.push_value(a + k);
+        vm.raw_set(a)?;
+    }
+    if b == 0 {
+        vm.restore_top();
+    }
+    Ok(())
+}
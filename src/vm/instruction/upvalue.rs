@@ -0,0 +1,54 @@
+use crate::vm::{lua_error::LuaError, lua_state::LuaVm};
+
+use super::{Instruction, InstructionOperation};
+
+/// R[A] := UpValue[B]
+pub fn get_upvalue(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    vm.get_upvalue(b as usize);
+    vm.replace(a);
+    Ok(())
+}
+
+/// UpValue[B] := R[A]
+pub fn set_upvalue(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, _) = i.abc();
+    vm.push_value(a);
+    vm.set_upvalue(b as usize);
+    Ok(())
+}
+
+/// R[A] := UpValue[B][K[C]:string]
+pub fn get_tab_up(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_upvalue(b as usize);
+    vm.get_const(c as usize);
+    vm.get_table(-2)?;
+    vm.replace(a);
+    vm.pop(1);
+    Ok(())
+}
+
+/// UpValue[A][K[B]:string] := RK(C)
+pub fn set_tab_up(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, b, c) = i.abc();
+    vm.get_upvalue(a as usize);
+    vm.get_const(b as usize);
+    vm.get_rk(c, i.k() == 1);
+    vm.set_table(-3)?;
+    vm.pop(1);
+    Ok(())
+}
+
+/// close all upvalues and to-be-closed variables >= R[A]
+pub fn close(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    vm.close(a)
+}
+
+/// mark variable A "to be closed"
+pub fn tbc(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    let (a, _, _) = i.abc();
+    let name = vm.local_name(a).unwrap_or_else(|| "?".to_string());
+    vm.to_be_closed(a, &name)
+}
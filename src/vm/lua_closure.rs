@@ -0,0 +1,98 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use super::{
+    binary_chunk::Prototype, lua_error::LuaError, lua_state::LuaState, lua_thread::LuaThread,
+    lua_value::LuaValue,
+};
+
+pub type UpvalueRef = Rc<RefCell<LuaUpvalue>>;
+
+/// A variable captured by a closure. While the variable is still alive in the
+/// stack the upvalue aliases its slot, once the scope ends the value moves
+/// into the upvalue itself.
+#[derive(Debug)]
+pub enum LuaUpvalue {
+    /// Absolute index of the stack slot holding the value, in the stack of
+    /// `thread`.
+    Open {
+        slot: usize,
+        thread: Weak<RefCell<LuaThread>>,
+    },
+    Closed(LuaValue),
+}
+
+impl LuaUpvalue {
+    pub fn new_closed(val: LuaValue) -> UpvalueRef {
+        Rc::new(RefCell::new(LuaUpvalue::Closed(val)))
+    }
+}
+
+/// A Lua function: the prototype shared by all its instances plus the
+/// upvalues captured when the CLOSURE instruction created it.
+pub struct LuaClosure {
+    pub prototype: Rc<Prototype>,
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+impl LuaClosure {
+    pub fn new(prototype: Rc<Prototype>, upvalues: Vec<UpvalueRef>) -> LuaClosure {
+        LuaClosure {
+            prototype,
+            upvalues,
+        }
+    }
+}
+
+impl std::fmt::Debug for LuaClosure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaClosure")
+            .field("source", &self.prototype.source)
+            .field("line_defined", &self.prototype.line_defined)
+            .field("upvalues", &self.upvalues.len())
+            .finish()
+    }
+}
+
+/// Signature of functions implemented in Rust. Arguments are on the stack
+/// starting at index 0, the function pushes its results and returns how
+/// many there are.
+pub type RustFn = fn(&mut LuaState) -> Result<usize, LuaError>;
+pub type RustClosure = Rc<dyn Fn(&mut LuaState) -> Result<usize, LuaError>>;
+/// Continuation of a Rust function whose call into Lua yielded, see
+/// `LuaApi::pcallk`. It gets whether the call succeeded and the context given
+/// to `pcallk`, and finishes the function the same way a `RustFn` would.
+pub type KFn = fn(&mut LuaState, bool, i64) -> Result<usize, LuaError>;
+
+/// A function implemented in Rust, either a plain function pointer or a
+/// closure carrying its own state.
+#[derive(Clone)]
+pub enum RustFunction {
+    Fn(RustFn),
+    Closure(RustClosure),
+}
+
+impl RustFunction {
+    pub fn call(&self, state: &mut LuaState) -> Result<usize, LuaError> {
+        match self {
+            RustFunction::Fn(f) => f(state),
+            RustFunction::Closure(f) => f(state),
+        }
+    }
+
+    /// Address identifying the function, used for equality and hashing.
+    pub fn as_ptr(&self) -> *const () {
+        match self {
+            RustFunction::Fn(f) => *f as *const (),
+            RustFunction::Closure(f) => Rc::as_ptr(f) as *const (),
+        }
+    }
+}
+
+impl std::fmt::Debug for RustFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RustFunction({:p})", self.as_ptr())
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt::Display;
+
+use super::{lua_string::LuaString, lua_value::LuaValue};
+
+#[derive(Debug, Clone)]
+pub enum LuaError {
+    /// Error raised with a Lua value, usually the message string.
+    Runtime(LuaValue),
+    /// The chunk given to `load` could not be read.
+    Syntax(String),
+    /// An allocation failed.
+    Memory,
+    /// The message handler of a protected call raised an error itself.
+    ErrorHandler,
+    /// The running coroutine yields, unwinds to the `resume` that runs it.
+    Yield,
+}
+
+impl LuaError {
+    /// Runtime error with a message string.
+    pub fn new(msg: impl Into<String>) -> LuaError {
+        LuaError::Runtime(LuaValue::String(LuaString::from(msg.into())))
+    }
+
+    /// The error object as seen by Lua code, e.g. the second result of `pcall`.
+    pub fn value(&self) -> LuaValue {
+        match self {
+            LuaError::Runtime(val) => val.clone(),
+            LuaError::Syntax(msg) => LuaValue::String(LuaString::from(msg.as_str())),
+            LuaError::Memory => LuaValue::String(LuaString::from("not enough memory")),
+            LuaError::ErrorHandler => LuaValue::String(LuaString::from("error in error handling")),
+            LuaError::Yield => LuaValue::Nil,
+        }
+    }
+}
+
+impl Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value() {
+            LuaValue::String(msg) => write!(f, "{}", msg),
+            val => write!(f, "(error object is a {} value)", val.type_name()),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
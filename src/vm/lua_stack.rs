@@ -1,9 +1,12 @@
 use super::lua_value::LuaValue;
 
+/// Value stack shared by all call frames of a state. `base` is the first slot
+/// of the running frame, non-negative indices are relative to it.
 #[derive(Debug)]
 pub struct LuaStack {
     pub slots: Vec<LuaValue>,
     pub top: usize,
+    pub base: usize,
 }
 
 impl LuaStack {
@@ -11,6 +14,7 @@ impl LuaStack {
         let mut stack = LuaStack {
             slots: Vec::with_capacity(size),
             top: 0,
+            base: 0,
         };
         for _ in 0..size {
             stack.slots.push(LuaValue::Nil);
@@ -18,16 +22,26 @@ impl LuaStack {
         stack
     }
 
-    pub fn check(&mut self, n: usize) {
+    /// Make sure there are at least `n` free slots above the top, `false` if
+    /// the memory for them can not be allocated.
+    pub fn check(&mut self, n: usize) -> bool {
         let free = self.slots.len() - self.top;
-        for _ in free..n {
-            self.slots.push(LuaValue::Nil);
+        if free >= n {
+            return true;
         }
+        if self.slots.try_reserve(n - free).is_err() {
+            return false;
+        }
+        self.slots.resize(self.top + n, LuaValue::Nil);
+        true
     }
 
     pub fn push(&mut self, val: LuaValue) {
-        assert!(self.top < self.slots.len(), "stack overflow");
-        self.slots[self.top] = val;
+        if self.top == self.slots.len() {
+            self.slots.push(val);
+        } else {
+            self.slots[self.top] = val;
+        }
         self.top += 1;
     }
 
@@ -41,7 +55,7 @@ impl LuaStack {
 
     pub fn abs_index(&self, index: i32) -> usize {
         if index >= 0 {
-            index as usize
+            self.base + index as usize
         } else {
             ((self.top as i32) + index) as usize
         }
@@ -50,7 +64,7 @@ impl LuaStack {
     pub fn is_valid(&self, index: i32) -> bool {
         let abs_idx = self.abs_index(index);
 
-        0 < abs_idx && abs_idx <= self.top
+        self.base <= abs_idx && abs_idx < self.top
     }
 
     pub fn get(&mut self, index: i32) -> LuaValue {
@@ -63,17 +77,6 @@ impl LuaStack {
         let abs_idx = self.abs_index(index);
         self.slots[abs_idx] = val;
     }
-
-    pub fn reverse(&mut self, mut from: i32, mut to: i32) {
-        while from < to {
-            let from_val = self.get(from);
-            let to_val = self.get(to);
-            self.set(from, to_val);
-            self.set(to, from_val);
-            from += 1;
-            to -= 1;
-        }
-    }
 }
 
 #[test]
@@ -84,7 +87,7 @@ fn test_lua_stack() {
     stack.push(LuaValue::Integer(1));
 
     stack.push(LuaValue::Number(2.0));
-    stack.push(LuaValue::String("string".to_string()));
+    stack.push(LuaValue::String("string".into()));
     stack.push(LuaValue::Nil);
 
     let pop_value = stack.pop();
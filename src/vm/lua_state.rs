@@ -1,56 +1,401 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use crate::compiler::compile;
+
 use super::{
-    binary_chunk::Prototype, instruction::Instruction, lua_stack::LuaStack, lua_value::LuaValue,
+    binary_chunk::{chunk_id, Prototype, LUA_SIGNATURE},
+    call_frame::{CallFrame, Continuation},
+    gc::{GarbageCollector, GcOption},
+    instruction::Instruction,
+    lua_closure::{KFn, LuaClosure, LuaUpvalue, RustFn, RustFunction, UpvalueRef},
+    lua_error::LuaError,
+    lua_stack::LuaStack,
+    lua_string::LuaString,
+    lua_table::{LuaTable, LuaTableRef},
+    lua_thread::{LuaThread, LuaThreadRef, Resumed, ThreadStatus},
+    lua_value::{self, LuaValue},
+    number::number_to_str,
+    tag_method::TagMethod,
+    undump::undump,
 };
 
+/// Option for multiple returns in `LuaApi::call`.
+pub const LUA_MULTRET: i32 = -1;
+/// Free slots guaranteed above the registers of every frame.
+pub const LUA_MINSTACK: usize = 20;
+/// Limit for the size of the value stack.
+pub const LUAI_MAXSTACK: usize = 1_000_000;
+/// Index of the globals table in the registry.
+pub const LUA_RIDX_GLOBALS: i64 = 2;
+/// Limit for nested calls going through Rust, e.g. Rust functions calling
+/// back into Lua.
+pub const LUAI_MAXCCALLS: usize = 200;
+
 #[derive(Debug)]
 pub struct LuaState {
     pub stack: LuaStack,
-    pub prototype: Prototype,
-    pub pc: u32,
+    pub frames: Vec<CallFrame>,
+    /// Upvalues still pointing into the stack, by slot.
+    pub open_upvalues: BTreeMap<usize, UpvalueRef>,
+    /// Table only reachable from Rust, holds the globals table.
+    pub registry: LuaTableRef,
+    /// Metatables shared by all values of a type other than table, by type name.
+    pub type_metatables: HashMap<&'static str, LuaTableRef>,
+    /// Number of nested `LuaApi::call`s on the Rust stack.
+    pub rust_calls: usize,
+    pub gc: GarbageCollector,
+    /// The running thread, its stack and frames are the ones above.
+    pub thread: LuaThreadRef,
+    pub main_thread: LuaThreadRef,
+    /// Number of calls from Rust in the running thread a yield can not cross.
+    pub non_yieldable: usize,
+    /// Number of values passed to the pending yield.
+    pub yielded: usize,
 }
 
 impl LuaState {
-    pub fn new(stack_size: usize, prototype: Prototype) -> LuaState {
-        LuaState {
-            stack: LuaStack::new(stack_size),
-            prototype,
-            pc: 0,
+    pub fn new() -> LuaState {
+        let mut state = LuaState {
+            stack: LuaStack::new(LUA_MINSTACK),
+            frames: Vec::new(),
+            open_upvalues: BTreeMap::new(),
+            registry: LuaTable::new_ref(0, 0),
+            type_metatables: HashMap::new(),
+            rust_calls: 0,
+            gc: GarbageCollector::default(),
+            thread: Rc::new(RefCell::new(LuaThread::new())),
+            main_thread: Rc::new(RefCell::new(LuaThread::new())),
+            non_yieldable: 0,
+            yielded: 0,
+        };
+        state.thread = state.main_thread.clone();
+        state.main_thread.borrow_mut().status = ThreadStatus::Running;
+        state.registry = state.new_table_ref(0, 0);
+        let globals = state.new_table_ref(0, 0);
+        state
+            .registry
+            .borrow_mut()
+            .put_int(LUA_RIDX_GLOBALS, LuaValue::Table(globals));
+        state
+    }
+
+    /// Push the main function of a chunk onto the stack, its first upvalue
+    /// (`_ENV`) is the globals table.
+    pub fn load_prototype(&mut self, prototype: Prototype) {
+        let globals = self.registry.borrow().get_int(LUA_RIDX_GLOBALS);
+        let upvalues = (0..prototype.upvalues.len())
+            .map(|i| {
+                self.new_upvalue(LuaUpvalue::Closed(if i == 0 {
+                    globals.clone()
+                } else {
+                    LuaValue::Nil
+                }))
+            })
+            .collect();
+        let closure = self.new_closure(LuaClosure::new(Rc::new(prototype), upvalues));
+        self.stack.push(LuaValue::Function(closure));
+    }
+
+    /// Upvalue for the stack slot `idx`, shared by all closures capturing it.
+    fn find_upvalue(&mut self, idx: usize) -> UpvalueRef {
+        if let Some(upvalue) = self.open_upvalues.get(&idx) {
+            return upvalue.clone();
+        }
+        let upvalue = self.new_upvalue(LuaUpvalue::Open {
+            slot: idx,
+            thread: Rc::downgrade(&self.thread),
+        });
+        self.open_upvalues.insert(idx, upvalue.clone());
+        upvalue
+    }
+
+    /// Close the upvalues of all slots from `level` upwards, they keep the
+    /// current values of the slots.
+    pub(crate) fn close_upvalues(&mut self, level: usize) {
+        for (idx, upvalue) in self.open_upvalues.split_off(&level) {
+            let val = self.stack.slots[idx].clone();
+            *upvalue.borrow_mut() = LuaUpvalue::Closed(val);
+        }
+    }
+
+    /// Position of the function at `level` of the call stack as a message
+    /// prefix, level 0 is the running function. Empty for Rust functions and
+    /// chunks without line info.
+    pub fn location(&self, level: usize) -> String {
+        let frame = match self.frames.len().checked_sub(level + 1) {
+            Some(idx) => &self.frames[idx],
+            None => return String::new(),
+        };
+        match (&frame.closure, frame.current_line()) {
+            (Some(closure), Some(line)) => {
+                format!("{}:{}: ", chunk_id(&closure.prototype.source), line)
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Runtime error with a message, prefixed with the current position when
+    /// raised by a Lua function.
+    pub fn runtime_error(&self, msg: impl Into<String>) -> LuaError {
+        let msg = msg.into();
+        match self.frames.last() {
+            Some(
+                frame @ CallFrame {
+                    closure: Some(closure),
+                    ..
+                },
+            ) => {
+                let line = frame.current_line().unwrap_or(-1);
+                let source = chunk_id(&closure.prototype.source);
+                LuaError::new(format!("{}:{}: {}", source, line, msg))
+            }
+            _ => LuaError::new(msg),
+        }
+    }
+
+    /// Error raised by a library function, prefixed with the position of the
+    /// Lua code calling it.
+    pub fn lib_error(&self, msg: impl Into<String>) -> LuaError {
+        LuaError::new(format!("{}{}", self.location(1), msg.into()))
+    }
+
+    /// Error for the bad argument `arg` (counting from 1) of the library
+    /// function `fname`.
+    pub fn arg_error(&self, arg: usize, fname: &str, extramsg: &str) -> LuaError {
+        self.lib_error(format!(
+            "bad argument #{} to '{}' ({})",
+            arg, fname, extramsg
+        ))
+    }
+
+    /// Error for the argument `arg` of `fname` not being of type `expected`.
+    pub fn type_error(&mut self, arg: usize, fname: &str, expected: &str) -> LuaError {
+        let msg = format!(
+            "{} expected, got {}",
+            expected,
+            self.type_name(arg as i32 - 1)
+        );
+        self.arg_error(arg, fname, &msg)
+    }
+
+    /// The integer argument `arg` of `fname`, floats and strings convert
+    /// when they have an exact integer value.
+    pub fn check_integer(&mut self, arg: usize, fname: &str) -> Result<i64, LuaError> {
+        let val = self.stack.get(arg as i32 - 1);
+        match (val.to_integer(), val.to_number()) {
+            (Some(i), _) => Ok(i),
+            (None, Some(_)) => {
+                Err(self.arg_error(arg, fname, "number has no integer representation"))
+            }
+            _ => Err(self.type_error(arg, fname, "number")),
+        }
+    }
+
+    /// The number argument `arg` of `fname` as a float, strings convert.
+    pub fn check_number(&mut self, arg: usize, fname: &str) -> Result<f64, LuaError> {
+        match self.stack.get(arg as i32 - 1).to_float() {
+            Some(n) => Ok(n),
+            None => Err(self.type_error(arg, fname, "number")),
+        }
+    }
+
+    /// Like `check_integer`, `default` when the argument is absent or nil.
+    pub fn opt_integer(&mut self, arg: usize, fname: &str, default: i64) -> Result<i64, LuaError> {
+        if matches!(self.type_name(arg as i32 - 1), "no value" | "nil") {
+            return Ok(default);
+        }
+        self.check_integer(arg, fname)
+    }
+
+    /// The string argument `arg` of `fname`, numbers are converted.
+    pub fn check_string(&mut self, arg: usize, fname: &str) -> Result<LuaString, LuaError> {
+        match self.stack.get(arg as i32 - 1) {
+            LuaValue::String(s) => Ok(s),
+            val => match number_to_str(&val) {
+                Some(s) => Ok(LuaString::from(s)),
+                None => Err(self.type_error(arg, fname, "string")),
+            },
+        }
+    }
+
+    /// Call the function below the `n_args` arguments on top of the stack.
+    /// On error the frames stay in place, so that a message handler can still
+    /// look at them. Unless `yieldable`, the called code can not yield.
+    fn call_unprotected(
+        &mut self,
+        n_args: usize,
+        n_results: i32,
+        yieldable: bool,
+    ) -> Result<(), LuaError> {
+        if self.rust_calls >= LUAI_MAXCCALLS {
+            return Err(self.runtime_error("C stack overflow"));
+        }
+        let func = self.stack.top - n_args - 1;
+        let depth = self.frames.len();
+        let non_yieldable = !yieldable as usize;
+        self.rust_calls += 1;
+        self.non_yieldable += non_yieldable;
+        let res = LuaState::pre_call(self, func, n_results, true).and_then(|_| {
+            if self.frames.len() > depth {
+                self.execute()
+            } else {
+                Ok(())
+            }
+        });
+        self.non_yieldable -= non_yieldable;
+        self.rust_calls -= 1;
+        res
+    }
+
+    /// Error object of a protected call after running the message handler
+    /// on it, on top of the frames that raised the error.
+    pub(crate) fn handle_error(&mut self, err: LuaError, handler: Option<LuaValue>) -> LuaError {
+        match (handler, &err) {
+            (Some(handler), LuaError::Runtime(val)) => {
+                let val = val.clone();
+                self.stack.push(handler);
+                self.stack.push(val);
+                match self.call_unprotected(1, 1, false) {
+                    Ok(()) => LuaError::Runtime(self.stack.pop()),
+                    Err(_) => LuaError::ErrorHandler,
+                }
+            }
+            _ => err,
         }
     }
+
+    /// Drop the frames above `depth` and the stack from slot `top` upwards,
+    /// undoing a call that raised an error.
+    pub(crate) fn unwind(&mut self, depth: usize, top: usize) {
+        LuaState::close_upvalues(self, top);
+        self.frames.truncate(depth);
+        self.stack.base = self.frames.last().map_or(0, |frame| frame.base);
+        let old_top = self.stack.top.max(top);
+        self.stack.slots[top..old_top].fill(LuaValue::Nil);
+        self.stack.top = top;
+    }
+
+    /// Call the `__close` metamethod of the to-be-closed variable in slot
+    /// `slot` with the error object `err`.
+    fn call_close_method(&mut self, slot: usize, err: LuaValue) -> Result<(), LuaError> {
+        let val = self.stack.slots[slot].clone();
+        let mm = self.get_metamethod(&val, TagMethod::Close);
+        self.stack.push(mm);
+        self.stack.push(val);
+        self.stack.push(err);
+        self.call_unprotected(2, 0, false)
+    }
+
+    /// Close the to-be-closed variables of the frames above `depth` before
+    /// they are dropped because of `err`, the innermost first. An error in a
+    /// `__close` metamethod replaces `err` for the remaining ones.
+    pub(crate) fn close_frames(
+        &mut self,
+        depth: usize,
+        mut err: Option<LuaError>,
+    ) -> Option<LuaError> {
+        for level in (depth..self.frames.len()).rev() {
+            while let Some(slot) = self.frames[level].tbc.pop() {
+                let (frames, top) = (self.frames.len(), self.stack.top);
+                let val = err.as_ref().map_or(LuaValue::Nil, LuaError::value);
+                if let Err(e) = self.call_close_method(slot, val) {
+                    self.unwind(frames, top);
+                    err = Some(e);
+                }
+            }
+        }
+        err
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no running Lua function")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no running Lua function")
+    }
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub trait LuaVm: LuaApi {
     fn get_pc(&self) -> u32;
     fn add_pc(&mut self, n: i32);
     fn fetch(&mut self) -> Instruction;
+    /// Instruction at `pc` of the running function.
+    fn get_code(&self, pc: u32) -> Instruction;
     fn get_const(&mut self, idx: usize);
     fn get_pk(&mut self, rk: i32);
+    fn get_rk(&mut self, arg: i32, k: bool);
+    /// Push a new closure of the `idx`-th nested prototype.
+    fn load_proto(&mut self, idx: usize);
+    /// Push the value of the `idx`-th upvalue of the running function.
+    fn get_upvalue(&mut self, idx: usize);
+    /// Pop a value and store it into the `idx`-th upvalue.
+    fn set_upvalue(&mut self, idx: usize);
+    /// Close all upvalues and to-be-closed variables from `R[idx]` upwards.
+    fn close(&mut self, idx: i32) -> Result<(), LuaError>;
+    /// Push `n` extra arguments of the running function, all of them if `n`
+    /// is negative, padded with nil.
+    fn load_vararg(&mut self, n: i32);
+    /// Call `R[func]` with the values above it up to the top as arguments.
+    fn pre_call(&mut self, func: i32, n_results: i32) -> Result<(), LuaError>;
+    /// Tail call `R[func]` with the values above it up to the top.
+    fn tail_call(&mut self, func: i32) -> Result<(), LuaError>;
+    /// Return `n` values starting at `R[first]` from the running function.
+    fn post_call(&mut self, first: i32, n: usize);
+    /// Set the top back above the registers of the running function, once
+    /// the open results left by a call or VARARG were used.
+    fn restore_top(&mut self);
 
-    fn arith(
-        &mut self,
-        i_func: Option<fn(a: i64, a: i64) -> i64>,
-        f_func: Option<fn(a: f64, b: f64) -> f64>,
-    );
+    /// Pop two operands and push the result of the operation `op`, `false`
+    /// if they are not numbers and nothing was pushed.
+    fn arith(&mut self, op: TagMethod) -> Result<bool, LuaError>;
+    /// Pop two operands and push the result of their `event` metamethod.
+    fn arith_metamethod(&mut self, event: TagMethod) -> Result<(), LuaError>;
+
+    /// Runtime error raised by the running instruction, with its position.
+    fn instruction_error(&self, msg: &str) -> LuaError;
+    /// Name of the local variable in `R[reg]` at the running instruction.
+    fn local_name(&self, reg: i32) -> Option<String>;
+    /// Mark the value at `idx`, held by the variable `name`, to be closed
+    /// when it goes out of scope. It must be `nil`, `false` or a value with
+    /// a `__close` metamethod, only the latter are closed.
+    fn to_be_closed(&mut self, idx: i32, name: &str) -> Result<(), LuaError>;
 }
 
 impl LuaVm for LuaState {
     fn get_pc(&self) -> u32 {
-        self.pc
+        self.frame().pc
     }
 
     fn add_pc(&mut self, n: i32) {
-        self.pc = ((self.pc as i32) + n) as u32;
+        let frame = self.frame_mut();
+        frame.pc = ((frame.pc as i32) + n) as u32;
     }
 
     fn fetch(&mut self) -> Instruction {
-        let instr = self.prototype.code[self.pc as usize];
-        self.pc += 1;
-        return instr;
+        let frame = self.frame_mut();
+        let instr = frame.lua_closure().prototype.code[frame.pc as usize];
+        frame.pc += 1;
+        instr
+    }
+
+    fn get_code(&self, pc: u32) -> Instruction {
+        self.frame().prototype().code[pc as usize]
     }
 
     fn get_const(&mut self, idx: usize) {
-        let constant = self.prototype.constants.get(idx).unwrap();
-        self.stack.push(constant.clone());
+        let constant = self.frame().prototype().constants[idx].clone();
+        self.stack.push(constant);
     }
 
     fn get_pk(&mut self, rk: i32) {
@@ -61,33 +406,148 @@ impl LuaVm for LuaState {
         }
     }
 
-    fn arith(
-        &mut self,
-        i_func: Option<fn(a: i64, a: i64) -> i64>,
-        f_func: Option<fn(a: f64, b: f64) -> f64>,
-    ) {
-        let val_c = self.stack.pop();
-        let val_b = self.stack.pop();
-
-        // TODO: remove clone method
-        let b = val_b.clone().try_into();
-        let c = val_c.clone().try_into();
-        if f_func.is_none() {
-            if i_func.is_some() {
-                let d = i_func.unwrap()(b.unwrap(), c.unwrap());
-                self.push_integer(d);
-            }
+    /// Push `K[arg]` if the `k` bit of the instruction is set, otherwise `R[arg]`.
+    fn get_rk(&mut self, arg: i32, k: bool) {
+        if k {
+            self.get_const(arg as usize);
         } else {
-            if i_func.is_some() {
-                let d = i_func.unwrap()(b.unwrap(), c.unwrap());
-                self.push_integer(d);
-                return;
+            self.push_value(arg);
+        }
+    }
+
+    fn load_proto(&mut self, idx: usize) {
+        let frame = self.frame();
+        let (base, parent) = (frame.base, frame.lua_closure().clone());
+        let prototype = parent.prototype.prototypes[idx].clone();
+        let upvalues = prototype
+            .upvalues
+            .iter()
+            .map(|upvalue| {
+                if upvalue.instack == 1 {
+                    self.find_upvalue(base + upvalue.index as usize)
+                } else {
+                    parent.upvalues[upvalue.index as usize].clone()
+                }
+            })
+            .collect();
+
+        let closure = self.new_closure(LuaClosure::new(prototype, upvalues));
+        self.stack.push(LuaValue::Function(closure));
+        self.check_gc();
+    }
+
+    fn get_upvalue(&mut self, idx: usize) {
+        let upvalue = self.frame().lua_closure().upvalues[idx].clone();
+        let val = match &*upvalue.borrow() {
+            LuaUpvalue::Open { slot, thread } => match thread.upgrade() {
+                Some(thread) if !Rc::ptr_eq(&thread, &self.thread) => {
+                    thread.borrow().stack.slots[*slot].clone()
+                }
+                _ => self.stack.slots[*slot].clone(),
+            },
+            LuaUpvalue::Closed(val) => val.clone(),
+        };
+        self.stack.push(val);
+    }
+
+    fn set_upvalue(&mut self, idx: usize) {
+        let upvalue = self.frame().lua_closure().upvalues[idx].clone();
+        let val = self.stack.pop();
+        let mut upvalue = upvalue.borrow_mut();
+        match &mut *upvalue {
+            LuaUpvalue::Open { slot, thread } => match thread.upgrade() {
+                Some(thread) if !Rc::ptr_eq(&thread, &self.thread) => {
+                    thread.borrow_mut().stack.slots[*slot] = val
+                }
+                _ => self.stack.slots[*slot] = val,
+            },
+            LuaUpvalue::Closed(closed) => *closed = val,
+        }
+    }
+
+    fn close(&mut self, idx: i32) -> Result<(), LuaError> {
+        let level = self.abs_index(idx);
+        LuaState::close_upvalues(self, level);
+        while let Some(&slot) = self.frame().tbc.last().filter(|&&slot| slot >= level) {
+            self.frame_mut().tbc.pop();
+            self.call_close_method(slot, LuaValue::Nil)?;
+        }
+        Ok(())
+    }
+
+    fn load_vararg(&mut self, n: i32) {
+        let varargs = &self.frame().varargs;
+        let n = if n < 0 { varargs.len() } else { n as usize };
+        let values: Vec<_> = (0..n)
+            .map(|i| varargs.get(i).cloned().unwrap_or_default())
+            .collect();
+        self.stack.check(n);
+        values.into_iter().for_each(|val| self.stack.push(val));
+    }
+
+    fn pre_call(&mut self, func: i32, n_results: i32) -> Result<(), LuaError> {
+        let func = self.abs_index(func);
+        LuaState::pre_call(self, func, n_results, false)
+    }
+
+    fn tail_call(&mut self, func: i32) -> Result<(), LuaError> {
+        let func = self.abs_index(func);
+        LuaState::tail_call(self, func)
+    }
+
+    fn post_call(&mut self, first: i32, n: usize) {
+        let first = self.abs_index(first);
+        LuaState::post_call(self, first, n);
+    }
+
+    fn restore_top(&mut self) {
+        let frame = self.frame();
+        let top = frame.register_top() - frame.base;
+        self.set_top(top as i32);
+    }
+
+    fn arith(&mut self, op: TagMethod) -> Result<bool, LuaError> {
+        let b = self.stack.pop();
+        let a = self.stack.pop();
+        match lua_value::arith(op, &a, &b).map_err(|msg| self.runtime_error(msg))? {
+            Some(val) => {
+                self.stack.push(val);
+                Ok(true)
             }
-            let f_b = val_b.try_into();
-            let f_c = val_c.try_into();
-            let d = f_func.unwrap()(f_b.unwrap(), f_c.unwrap());
-            self.push_number(d);
+            None => Ok(false),
+        }
+    }
+
+    fn arith_metamethod(&mut self, event: TagMethod) -> Result<(), LuaError> {
+        let b = self.stack.pop();
+        let a = self.stack.pop();
+        let val = LuaState::arith_metamethod(self, a, b, event)?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn instruction_error(&self, msg: &str) -> LuaError {
+        self.runtime_error(msg)
+    }
+
+    fn local_name(&self, reg: i32) -> Option<String> {
+        let frame = self.frame();
+        let pc = frame.pc.saturating_sub(1) as usize;
+        let name = frame.prototype().local_name(reg as usize, pc)?;
+        Some(name.to_string())
+    }
+
+    fn to_be_closed(&mut self, idx: i32, name: &str) -> Result<(), LuaError> {
+        let val = self.stack.get(idx);
+        if val.to_boolean() && self.get_metamethod(&val, TagMethod::Close).is_nil() {
+            let msg = format!("variable '{}' got a non-closable value", name);
+            return Err(self.runtime_error(msg));
+        }
+        if val.to_boolean() {
+            let slot = self.abs_index(idx);
+            self.frame_mut().tbc.push(slot);
         }
+        Ok(())
     }
 }
 
@@ -106,8 +566,15 @@ pub trait LuaApi {
     fn push_nil(&mut self);
     fn push_integer(&mut self, val: i64);
     fn push_boolean(&mut self, val: bool);
-    fn push_string(&mut self, val: String);
+    fn push_string(&mut self, val: impl Into<LuaString>)
+    where
+        Self: Sized;
     fn push_number(&mut self, val: f64);
+    fn push_rust_function(&mut self, f: RustFn);
+    fn push_rust_closure<F>(&mut self, f: F)
+    where
+        F: Fn(&mut LuaState) -> Result<usize, LuaError> + 'static,
+        Self: Sized;
 
     fn is_number(&mut self, idx: usize) -> bool;
     fn to_numberx(&mut self, idx: usize) -> Option<f64>;
@@ -115,18 +582,105 @@ pub trait LuaApi {
     fn is_integer(&mut self, idx: usize) -> bool;
     fn to_integer(&mut self, idx: usize) -> Option<i64>;
 
+    fn to_boolean(&mut self, idx: i32) -> bool;
+
     fn is_string(&mut self, idx: i32) -> bool;
-    fn to_string(&mut self, idx: i32) -> Option<String>;
+    fn to_string(&mut self, idx: i32) -> Option<LuaString>;
+
+    fn len(&mut self, idx: i32) -> Result<(), LuaError>;
+    /// Length of the string or table at `idx` without `__len`, 0 for other
+    /// values.
+    fn raw_len(&mut self, idx: i32) -> usize;
+    fn concat(&mut self, idx: usize) -> Result<(), LuaError>;
 
-    fn len(&mut self, idx: i32);
-    fn concat(&mut self, idx: usize);
+    fn compare(&mut self, idx1: i32, idex2: i32, op: CampareOperator) -> Result<bool, LuaError>;
+    fn raw_equal(&mut self, idx1: i32, idx2: i32) -> bool;
 
-    fn compare(&mut self, idx1: i32, idex2: i32, op: CampareOperator) -> bool;
+    /// Name of the type of the value at `idx`, "no value" for an index above
+    /// the top.
+    fn type_name(&mut self, idx: i32) -> &'static str;
+
+    fn new_table(&mut self);
+    fn create_table(&mut self, narr: usize, nrec: usize);
+    fn get_table(&mut self, idx: i32) -> Result<(), LuaError>;
+    fn get_field(&mut self, idx: i32, k: &str) -> Result<(), LuaError>;
+    fn get_i(&mut self, idx: i32, i: i64) -> Result<(), LuaError>;
+    fn set_table(&mut self, idx: i32) -> Result<(), LuaError>;
+    fn set_field(&mut self, idx: i32, k: &str) -> Result<(), LuaError>;
+    fn set_i(&mut self, idx: i32, i: i64) -> Result<(), LuaError>;
+    /// Like `get_table` without metamethods.
+    fn raw_get(&mut self, idx: i32) -> Result<(), LuaError>;
+    /// Like `set_table` without metamethods.
+    fn raw_set(&mut self, idx: i32) -> Result<(), LuaError>;
+    /// Pop a key and push the next key and value of the table at `idx`,
+    /// `false` and nothing pushed once the traversal is over.
+    fn next(&mut self, idx: i32) -> Result<bool, LuaError>;
+    /// Push the metatable of the value at `idx`, `false` if it has none.
+    fn get_metatable(&mut self, idx: i32) -> bool;
+    /// Pop a table or nil and make it the metatable of the value at `idx`.
+    fn set_metatable(&mut self, idx: i32);
+
+    /// Call the function below the `n_args` arguments on top of the stack.
+    /// Errors propagate to the caller, use `pcall` to recover from them.
+    fn call(&mut self, n_args: usize, n_results: i32) -> Result<(), LuaError>;
+    /// Call like `call`, but on error the stack is restored and the error
+    /// object is pushed in place of the function and its arguments. The
+    /// function at `msgh` is called with the error object first and its
+    /// result becomes the new error object.
+    fn pcall(&mut self, n_args: usize, n_results: i32, msgh: Option<i32>) -> Result<(), LuaError>;
+    /// Like `pcall`, but the called code may yield and the result tells
+    /// whether the call succeeded. A yield is the only error, the calling
+    /// Rust function must return it and is gone when the coroutine is
+    /// resumed: `k` finishes it with whether the call succeeded and `ctx`
+    /// once the call returns.
+    fn pcallk(
+        &mut self,
+        n_args: usize,
+        n_results: i32,
+        msgh: Option<i32>,
+        ctx: i64,
+        k: KFn,
+    ) -> Result<bool, LuaError>;
+    /// Pop the error object from the stack, to be returned by a Rust function.
+    fn error(&mut self) -> LuaError;
+
+    /// Pop a function and push a new coroutine calling it.
+    fn new_thread(&mut self);
+    /// Push the running thread, `true` if it is the main thread.
+    fn push_thread(&mut self) -> bool;
+    fn to_thread(&mut self, idx: i32) -> Option<LuaThreadRef>;
+    /// Status of the thread at `idx`, `None` for other values.
+    fn thread_status(&mut self, idx: i32) -> Option<ThreadStatus>;
+    /// Start or continue the coroutine below the `n_args` arguments on top
+    /// of the stack. The coroutine and the arguments are replaced by the
+    /// values it yielded or returned, or by the error object.
+    fn resume(&mut self, n_args: usize) -> Result<Resumed, LuaError>;
+    /// Yield the `n` values on top of the stack from the running coroutine.
+    /// The returned error must be returned by the calling Rust function, the
+    /// values passed to the next `resume` become its results.
+    fn yield_values(&mut self, n: usize) -> LuaError;
+    fn is_yieldable(&mut self) -> bool;
+    /// Kill the suspended or dead coroutine at `idx` and close its upvalues,
+    /// gives back the error that stopped it if any.
+    fn close_thread(&mut self, idx: i32) -> Result<(), LuaError>;
+    /// Load a binary chunk, or compile a text chunk, and push its main
+    /// function. With `env`, the value at that index becomes the `_ENV` of
+    /// the chunk instead of the globals.
+    fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, env: Option<i32>) -> Result<(), LuaError>;
+
+    fn push_global_table(&mut self);
+    fn get_global(&mut self, name: &str) -> Result<(), LuaError>;
+    fn set_global(&mut self, name: &str) -> Result<(), LuaError>;
+    /// Set the global `name` to the Rust function `f`.
+    fn register(&mut self, name: &str, f: RustFn) -> Result<(), LuaError>;
+
+    /// Control the garbage collector, the result depends on `what`.
+    fn gc(&mut self, what: GcOption) -> i64;
 }
 
 impl LuaApi for LuaState {
     fn get_top(&self) -> usize {
-        self.stack.top
+        self.stack.top - self.stack.base
     }
 
     fn abs_index(&mut self, index: i32) -> usize {
@@ -134,8 +688,7 @@ impl LuaApi for LuaState {
     }
 
     fn check_stack(&mut self, n: usize) -> bool {
-        self.stack.check(n);
-        true
+        self.stack.top + n <= LUAI_MAXSTACK && self.stack.check(n)
     }
 
     fn pop(&mut self, n: usize) {
@@ -155,35 +708,30 @@ impl LuaApi for LuaState {
     }
 
     fn replace(&mut self, index: i32) {
+        let idx = self.abs_index(index);
         let val = self.stack.pop();
-        self.stack.set(index, val);
+        self.stack.slots[idx] = val;
     }
 
     fn insert(&mut self, index: i32) {
-        self.rotate(index, -1);
-        self.pop(1);
+        self.rotate(index, 1);
     }
 
     fn rotate(&mut self, index: i32, n: i32) {
-        let top_index = (self.get_top() - 1) as i32;
-        let abs_index = self.abs_index(index) as i32;
+        let abs_index = self.abs_index(index);
+        let slots = &mut self.stack.slots[abs_index..self.stack.top];
 
-        let m: i32 = if n > 0 {
-            top_index - n
+        if n >= 0 {
+            slots.rotate_right(n as usize);
         } else {
-            abs_index - n - 1
-        };
-
-        self.stack.reverse(abs_index, m);
-        self.stack.reverse(m + 1, top_index);
-        self.stack.reverse(abs_index, top_index);
+            slots.rotate_left(n.unsigned_abs() as usize);
+        }
     }
 
     fn set_top(&mut self, index: i32) {
         let new_top = self.abs_index(index);
-        assert!(new_top >= 0, "stack underflow");
 
-        let n: i32 = (self.get_top() as i32) - (new_top as i32);
+        let n: i32 = (self.stack.top as i32) - (new_top as i32);
         if n > 0 {
             for _ in 0..n {
                 self.stack.pop();
@@ -207,14 +755,26 @@ impl LuaApi for LuaState {
         self.stack.push(LuaValue::Boolean(val));
     }
 
-    fn push_string(&mut self, val: String) {
-        self.stack.push(LuaValue::String(val));
+    fn push_string(&mut self, val: impl Into<LuaString>) {
+        self.stack.push(LuaValue::String(val.into()));
     }
 
     fn push_number(&mut self, val: f64) {
         self.stack.push(LuaValue::Number(val));
     }
 
+    fn push_rust_function(&mut self, f: RustFn) {
+        self.stack.push(LuaValue::RustFunction(RustFunction::Fn(f)));
+    }
+
+    fn push_rust_closure<F>(&mut self, f: F)
+    where
+        F: Fn(&mut LuaState) -> Result<usize, LuaError> + 'static,
+    {
+        self.stack
+            .push(LuaValue::RustFunction(RustFunction::Closure(Rc::new(f))));
+    }
+
     fn is_number(&mut self, idx: usize) -> bool {
         self.to_numberx(idx).is_some()
     }
@@ -240,11 +800,15 @@ impl LuaApi for LuaState {
         }
     }
 
+    fn to_boolean(&mut self, idx: i32) -> bool {
+        self.stack.get(idx).to_boolean()
+    }
+
     fn is_string(&mut self, idx: i32) -> bool {
         self.to_string(idx).is_some()
     }
 
-    fn to_string(&mut self, idx: i32) -> Option<String> {
+    fn to_string(&mut self, idx: i32) -> Option<LuaString> {
         let val = self.stack.get(idx);
         match val {
             LuaValue::String(s) => Some(s),
@@ -252,47 +816,392 @@ impl LuaApi for LuaState {
         }
     }
 
-    fn len(&mut self, idx: i32) {
+    fn len(&mut self, idx: i32) -> Result<(), LuaError> {
         let val = self.stack.get(idx);
-        match val {
-            LuaValue::String(s) => self.push_integer(s.len() as i64),
-            _ => panic!("Only String has length"),
-        };
+        let len = self.length(val)?;
+        self.stack.push(len);
+        Ok(())
     }
 
-    fn concat(&mut self, idx: usize) {
+    fn raw_len(&mut self, idx: i32) -> usize {
+        match self.stack.get(idx) {
+            LuaValue::String(s) => s.len(),
+            LuaValue::Table(t) => t.borrow().len(),
+            _ => 0,
+        }
+    }
+
+    fn concat(&mut self, idx: usize) -> Result<(), LuaError> {
         if idx == 0 {
-            self.stack.push(LuaValue::String("".to_string()));
+            self.stack.push(LuaValue::String(LuaString::from("")));
         } else if idx >= 2 {
-            if self.is_string(-1) && self.is_string(-2) {
-                let s2 = self.to_string(-1).unwrap();
-                let s1 = self.to_string(-2).unwrap();
+            for _ in 1..idx {
+                let val = match (self.concat_operand(-2), self.concat_operand(-1)) {
+                    (Some(s1), Some(s2)) => LuaValue::String(s1.concat(&s2)),
+                    _ => {
+                        let (a, b) = (self.stack.get(-2), self.stack.get(-1));
+                        self.arith_metamethod(a, b, TagMethod::Concat)?
+                    }
+                };
                 self.stack.pop();
                 self.stack.pop();
-                self.stack.push(LuaValue::String(s1 + &s2));
-            } else {
-                panic!("concat string error!");
+                self.stack.push(val);
             }
         }
-        //  else {
-        //     do nothine
-        //  }
+        Ok(())
     }
 
-    fn compare(&mut self, idx1: i32, idx2: i32, op: CampareOperator) -> bool {
+    fn compare(&mut self, idx1: i32, idx2: i32, op: CampareOperator) -> Result<bool, LuaError> {
         let a_val = self.stack.get(idx1);
         let b_val = self.stack.get(idx2);
 
         match op {
-            CampareOperator::Equal => a_val == b_val,
-            CampareOperator::LessThen => a_val < b_val,
-            CampareOperator::GreatThen => a_val > b_val,
+            CampareOperator::Equal => self.equal(a_val, b_val),
+            CampareOperator::LessThen => self.order(a_val, b_val, TagMethod::Lt),
+            CampareOperator::LessEqual => self.order(a_val, b_val, TagMethod::Le),
+            CampareOperator::GreatThen => self.order(b_val, a_val, TagMethod::Lt),
+        }
+    }
+
+    fn raw_equal(&mut self, idx1: i32, idx2: i32) -> bool {
+        self.stack.get(idx1) == self.stack.get(idx2)
+    }
+
+    fn type_name(&mut self, idx: i32) -> &'static str {
+        let abs_idx = self.abs_index(idx);
+        if abs_idx < self.stack.base || abs_idx >= self.stack.top {
+            "no value"
+        } else {
+            self.stack.slots[abs_idx].type_name()
+        }
+    }
+
+    fn new_table(&mut self) {
+        self.create_table(0, 0);
+    }
+
+    fn create_table(&mut self, narr: usize, nrec: usize) {
+        let table = self.new_table_ref(narr, nrec);
+        self.stack.push(LuaValue::Table(table));
+        self.check_gc();
+    }
+
+    fn get_table(&mut self, idx: i32) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let key = self.stack.pop();
+        let val = self.index(t, key)?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn get_field(&mut self, idx: i32, k: &str) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let val = self.index(t, LuaValue::String(LuaString::from(k)))?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn get_i(&mut self, idx: i32, i: i64) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let val = self.index(t, LuaValue::Integer(i))?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn set_table(&mut self, idx: i32) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let val = self.stack.pop();
+        let key = self.stack.pop();
+        self.new_index(t, key, val)
+    }
+
+    fn set_field(&mut self, idx: i32, k: &str) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let val = self.stack.pop();
+        self.new_index(t, LuaValue::String(LuaString::from(k)), val)
+    }
+
+    fn set_i(&mut self, idx: i32, i: i64) -> Result<(), LuaError> {
+        let t = self.stack.get(idx);
+        let val = self.stack.pop();
+        self.new_index(t, LuaValue::Integer(i), val)
+    }
+
+    fn raw_get(&mut self, idx: i32) -> Result<(), LuaError> {
+        let table = self.table_at(idx)?;
+        let key = self.stack.pop();
+        let val = table.borrow().get(&key);
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn raw_set(&mut self, idx: i32) -> Result<(), LuaError> {
+        let table = self.table_at(idx)?;
+        let val = self.stack.pop();
+        let key = self.stack.pop();
+        let res = table.borrow_mut().put(key, val);
+        res.map_err(|msg| self.runtime_error(format!("table {}", msg)))
+    }
+
+    fn next(&mut self, idx: i32) -> Result<bool, LuaError> {
+        let table = self.table_at(idx)?;
+        let key = self.stack.pop();
+        let entry = table.borrow().next(&key);
+        match entry.map_err(|msg| self.runtime_error(msg))? {
+            Some((k, v)) => {
+                self.stack.push(k);
+                self.stack.push(v);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn get_metatable(&mut self, idx: i32) -> bool {
+        let val = self.stack.get(idx);
+        match self.get_metatable_of(&val) {
+            Some(mt) => {
+                self.stack.push(LuaValue::Table(mt));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_metatable(&mut self, idx: i32) {
+        let val = self.stack.get(idx);
+        let mt = match self.stack.pop() {
+            LuaValue::Table(mt) => Some(mt),
+            _ => None,
+        };
+        match (val, mt) {
+            (LuaValue::Table(table), mt) => {
+                table.borrow_mut().metatable = mt;
+                self.check_finalizer(&table);
+            }
+            (val, Some(mt)) => {
+                self.type_metatables.insert(val.type_name(), mt);
+            }
+            (val, None) => {
+                self.type_metatables.remove(val.type_name());
+            }
+        }
+    }
+
+    fn call(&mut self, n_args: usize, n_results: i32) -> Result<(), LuaError> {
+        let func = self.stack.top - n_args - 1;
+        let depth = self.frames.len();
+        let res = self.call_unprotected(n_args, n_results, false);
+        // nobody above a call from the host can clean up after the error
+        match res {
+            Err(err) if depth == 0 => {
+                let err = self.close_frames(0, Some(err)).expect("error kept");
+                self.unwind(0, func);
+                Err(err)
+            }
+            res => res,
+        }
+    }
+
+    fn pcall(&mut self, n_args: usize, n_results: i32, msgh: Option<i32>) -> Result<(), LuaError> {
+        let handler = msgh.map(|idx| self.stack.get(idx));
+        let func = self.stack.top - n_args - 1;
+        let (depth, rust_calls) = (self.frames.len(), self.rust_calls);
+
+        let err = match self.call_unprotected(n_args, n_results, false) {
+            Ok(()) => return Ok(()),
+            Err(err) => self.handle_error(err, handler),
+        };
+        let err = self.close_frames(depth, Some(err)).expect("error kept");
+        self.unwind(depth, func);
+        self.rust_calls = rust_calls;
+        self.stack.push(err.value());
+        Err(err)
+    }
+
+    fn pcallk(
+        &mut self,
+        n_args: usize,
+        n_results: i32,
+        msgh: Option<i32>,
+        ctx: i64,
+        k: KFn,
+    ) -> Result<bool, LuaError> {
+        let handler = msgh.map(|idx| self.stack.get(idx));
+        let func = self.stack.top - n_args - 1;
+        let (depth, rust_calls) = (self.frames.len(), self.rust_calls);
+        if let Some(frame) = self.frames.last_mut() {
+            frame.continuation = Some(Continuation {
+                func: k,
+                ctx,
+                protected: Some((func, handler.clone())),
+            });
+        }
+
+        let res = self.call_unprotected(n_args, n_results, true);
+        // after a yield the frame waits for the continuation to be called
+        if let Err(LuaError::Yield) = res {
+            return Err(LuaError::Yield);
+        }
+        if let Some(frame) = depth.checked_sub(1).map(|i| &mut self.frames[i]) {
+            frame.continuation = None;
         }
+        let err = match res {
+            Ok(()) => return Ok(true),
+            Err(err) => self.handle_error(err, handler),
+        };
+        let err = self.close_frames(depth, Some(err)).expect("error kept");
+        self.unwind(depth, func);
+        self.rust_calls = rust_calls;
+        self.stack.push(err.value());
+        Ok(false)
+    }
+
+    fn error(&mut self) -> LuaError {
+        LuaError::Runtime(self.stack.pop())
+    }
+
+    fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, env: Option<i32>) -> Result<(), LuaError> {
+        let env = env.map(|idx| self.stack.get(idx));
+        let mut prototype = if chunk.first() == Some(&LUA_SIGNATURE[0]) {
+            match undump(chunk) {
+                Ok(prototype) => prototype,
+                Err(why) => {
+                    let msg = format!("{}: bad binary format ({})", chunk_id(chunk_name), why);
+                    return Err(LuaError::Syntax(msg));
+                }
+            }
+        } else {
+            compile(&String::from_utf8_lossy(&chunk), chunk_name)
+        };
+        if prototype.source.is_empty() {
+            prototype.source = chunk_name.to_string();
+        }
+        self.load_prototype(prototype);
+
+        if let (Some(env), LuaValue::Function(closure)) = (env, self.stack.get(-1)) {
+            if let Some(upvalue) = closure.upvalues.first() {
+                *upvalue.borrow_mut() = LuaUpvalue::Closed(env);
+            }
+        }
+        Ok(())
+    }
+
+    fn push_global_table(&mut self) {
+        let globals = self.registry.borrow().get_int(LUA_RIDX_GLOBALS);
+        self.stack.push(globals);
+    }
+
+    fn get_global(&mut self, name: &str) -> Result<(), LuaError> {
+        self.push_global_table();
+        self.get_field(-1, name)?;
+        self.replace(-2);
+        Ok(())
+    }
+
+    fn set_global(&mut self, name: &str) -> Result<(), LuaError> {
+        self.push_global_table();
+        self.insert(-2);
+        self.set_field(-2, name)?;
+        self.pop(1);
+        Ok(())
+    }
+
+    fn register(&mut self, name: &str, f: RustFn) -> Result<(), LuaError> {
+        self.push_rust_function(f);
+        self.set_global(name)
+    }
+
+    fn gc(&mut self, what: GcOption) -> i64 {
+        self.gc_control(what)
+    }
+
+    fn new_thread(&mut self) {
+        let func = self.stack.pop();
+        let thread = self.new_thread_ref();
+        thread.borrow_mut().stack.push(func);
+        self.stack.push(LuaValue::Thread(thread));
+    }
+
+    fn push_thread(&mut self) -> bool {
+        self.stack.push(LuaValue::Thread(self.thread.clone()));
+        self.is_main_thread()
+    }
+
+    fn to_thread(&mut self, idx: i32) -> Option<LuaThreadRef> {
+        match self.stack.get(idx) {
+            LuaValue::Thread(thread) => Some(thread),
+            _ => None,
+        }
+    }
+
+    fn thread_status(&mut self, idx: i32) -> Option<ThreadStatus> {
+        let thread = self.to_thread(idx)?;
+        Some(self.thread_status_of(&thread))
+    }
+
+    fn resume(&mut self, n_args: usize) -> Result<Resumed, LuaError> {
+        if n_args >= self.get_top() {
+            return Err(LuaError::new("cannot resume non-suspended coroutine"));
+        }
+        let slot = self.stack.top - n_args - 1;
+        self.resume_thread(slot)
+    }
+
+    fn yield_values(&mut self, n: usize) -> LuaError {
+        if self.is_main_thread() {
+            self.runtime_error("attempt to yield from outside a coroutine")
+        } else if self.non_yieldable > 0 {
+            self.runtime_error("attempt to yield across a C-call boundary")
+        } else {
+            self.yielded = n;
+            LuaError::Yield
+        }
+    }
+
+    fn is_yieldable(&mut self) -> bool {
+        !self.is_main_thread() && self.non_yieldable == 0
+    }
+
+    fn close_thread(&mut self, idx: i32) -> Result<(), LuaError> {
+        let thread = self.to_thread(idx).expect("thread expected");
+        let err = self.reset_thread(&thread);
+        match err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl LuaState {
+    fn table_at(&mut self, idx: i32) -> Result<LuaTableRef, LuaError> {
+        match self.stack.get(idx) {
+            LuaValue::Table(table) => Ok(table),
+            val => Err(self.operand_error("index", &val)),
+        }
+    }
+
+    /// String for an operand of `..`, numbers are converted.
+    fn concat_operand(&mut self, idx: i32) -> Option<LuaString> {
+        match self.stack.get(idx) {
+            LuaValue::String(s) => Some(s),
+            val => number_to_str(&val).map(LuaString::from),
+        }
+    }
+
+    /// Error for an operation on a value of the wrong type.
+    pub(crate) fn operand_error(&self, operation: &str, val: &LuaValue) -> LuaError {
+        self.runtime_error(format!(
+            "attempt to {} a {} value",
+            operation,
+            val.type_name()
+        ))
     }
 }
 
 pub enum CampareOperator {
     Equal,
     LessThen,
+    LessEqual,
     GreatThen,
 }
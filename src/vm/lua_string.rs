@@ -0,0 +1,184 @@
+use std::{
+    borrow::{Borrow, Cow},
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    rc::{Rc, Weak},
+};
+
+/// Strings up to this length are interned, see `LUAI_MAXSHORTLEN`.
+pub const MAX_SHORT_LEN: usize = 40;
+
+thread_local! {
+    static SHORT_STRINGS: RefCell<StringTable> = RefCell::new(StringTable::default());
+}
+
+/// Interned short strings. Entries only hold weak references, dead ones are
+/// swept whenever the table doubles in size.
+#[derive(Default)]
+struct StringTable {
+    strings: HashMap<Box<[u8]>, Weak<[u8]>>,
+    sweep_at: usize,
+}
+
+impl StringTable {
+    fn intern(&mut self, bytes: &[u8]) -> Rc<[u8]> {
+        if let Some(s) = self.strings.get(bytes).and_then(Weak::upgrade) {
+            return s;
+        }
+        if self.strings.len() >= self.sweep_at {
+            self.strings.retain(|_, s| s.strong_count() > 0);
+            self.sweep_at = (self.strings.len() * 2).max(64);
+        }
+        let s: Rc<[u8]> = Rc::from(bytes);
+        self.strings.insert(Box::from(bytes), Rc::downgrade(&s));
+        s
+    }
+}
+
+/// Immutable Lua string, an arbitrary sequence of bytes. Cloning only bumps a
+/// reference count, and equal short strings share the same buffer.
+#[derive(Clone)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> LuaString {
+        if bytes.len() <= MAX_SHORT_LEN {
+            LuaString(SHORT_STRINGS.with(|table| table.borrow_mut().intern(bytes)))
+        } else {
+            LuaString(Rc::from(bytes))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The string as UTF-8, invalid sequences are replaced.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// Concatenation of `self` and `other` as a new string.
+    pub fn concat(&self, other: &LuaString) -> LuaString {
+        let mut bytes = Vec::with_capacity(self.len() + other.len());
+        bytes.extend_from_slice(self);
+        bytes.extend_from_slice(other);
+        LuaString::from(bytes)
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for LuaString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        LuaString::new(bytes)
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.len() <= MAX_SHORT_LEN {
+            LuaString::new(&bytes)
+        } else {
+            LuaString(Rc::from(bytes))
+        }
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString::new(s.as_bytes())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        LuaString::from(s.into_bytes())
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for LuaString {}
+
+impl PartialEq<str> for LuaString {
+    fn eq(&self, other: &str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for LuaString {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+/// Byte order, independent of any locale.
+impl Ord for LuaString {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.as_bytes().escape_ascii())
+    }
+}
+
+#[test]
+fn test_lua_string() {
+    let a = LuaString::from("hello");
+    let b = LuaString::from(b"hello".to_vec());
+    assert!(Rc::ptr_eq(&a.0, &b.0));
+    assert_eq!(a, "hello");
+
+    let long = "x".repeat(MAX_SHORT_LEN + 1);
+    let (c, d) = (LuaString::from(long.as_str()), LuaString::from(long));
+    assert!(!Rc::ptr_eq(&c.0, &d.0));
+    assert_eq!(c, d);
+
+    let bin = LuaString::new(&[0xff, 0, b'a']);
+    assert_eq!(bin.len(), 3);
+    assert_eq!(format!("{:?}", bin), "\"\\xff\\x00a\"");
+    assert_eq!(bin.to_str_lossy(), "\u{fffd}\0a");
+    assert!(bin > a);
+    assert_eq!(a.concat(&bin).as_bytes(), b"hello\xff\0a");
+}
@@ -0,0 +1,459 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use super::{
+    lua_string::LuaString,
+    lua_value::{float_to_integer, LuaValue},
+};
+
+pub type LuaTableRef = Rc<RefCell<LuaTable>>;
+
+/// Lua table with a dense array part (keys `1..=arr.len()`) and a hash part.
+///
+/// The hash part keeps its entries in insertion order, so traversal is stable
+/// as long as no new key is inserted. Removing a key only clears its value,
+/// the slot is reclaimed the next time the hash part grows.
+#[derive(Default)]
+pub struct LuaTable {
+    pub arr: Vec<LuaValue>,
+    node: Vec<(LuaValue, LuaValue)>,
+    index: HashMap<LuaValue, usize>,
+    pub metatable: Option<LuaTableRef>,
+}
+
+impl LuaTable {
+    pub fn new(narr: usize, nrec: usize) -> LuaTable {
+        LuaTable {
+            arr: Vec::with_capacity(narr),
+            node: Vec::with_capacity(nrec),
+            index: HashMap::with_capacity(nrec),
+            metatable: None,
+        }
+    }
+
+    pub fn new_ref(narr: usize, nrec: usize) -> LuaTableRef {
+        Rc::new(RefCell::new(LuaTable::new(narr, nrec)))
+    }
+
+    /// A border of the table, `luaH_getn`: `t[n]` is not nil and `t[n + 1]`
+    /// is nil, or 0 when `t[1]` is nil. Any border may be returned when the
+    /// table has holes.
+    pub fn len(&self) -> usize {
+        // the array part ends with a value, so it is a border unless the
+        // hash part continues it
+        let n = self.arr.len() as u64;
+        if self
+            .get_from_hash(&LuaValue::Integer(n as i64 + 1))
+            .is_nil()
+        {
+            return n as usize;
+        }
+        self.hash_search(n + 1) as usize
+    }
+
+    /// Border above `j`, where `t[j]` is not nil: double `j` until `t[j]` is
+    /// nil, then binary search between the last two.
+    fn hash_search(&self, mut j: u64) -> u64 {
+        const MAX: u64 = i64::MAX as u64;
+        let absent = |k: u64| self.get_int(k as i64).is_nil();
+        let mut i;
+        loop {
+            i = j;
+            if j <= MAX / 2 {
+                j *= 2;
+            } else {
+                j = MAX;
+                if !absent(j) {
+                    return j;
+                }
+            }
+            if absent(j) {
+                break;
+            }
+        }
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if absent(m) {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arr.is_empty() && self.node.iter().all(|(_, v)| v.is_nil())
+    }
+
+    pub fn get(&self, key: &LuaValue) -> LuaValue {
+        let key = normalize_key(key);
+        if let LuaValue::Integer(i) = key {
+            return self.get_int(i);
+        }
+        self.get_from_hash(&key)
+    }
+
+    pub fn get_int(&self, i: i64) -> LuaValue {
+        if i >= 1 && (i as u64) <= self.arr.len() as u64 {
+            return self.arr[(i - 1) as usize].clone();
+        }
+        self.get_from_hash(&LuaValue::Integer(i))
+    }
+
+    pub fn get_str(&self, key: &str) -> LuaValue {
+        self.get_from_hash(&LuaValue::String(LuaString::from(key)))
+    }
+
+    fn get_from_hash(&self, key: &LuaValue) -> LuaValue {
+        match self.index.get(key) {
+            Some(&idx) => self.node[idx].1.clone(),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// `t[key] = val` without metamethods.
+    pub fn put(&mut self, key: LuaValue, val: LuaValue) -> Result<(), &'static str> {
+        let key = match key {
+            LuaValue::Nil => return Err("index is nil"),
+            LuaValue::Number(f) if f.is_nan() => return Err("index is NaN"),
+            key => normalize_key(&key),
+        };
+
+        if let LuaValue::Integer(i) = key {
+            self.put_int(i, val);
+        } else {
+            self.put_into_hash(key, val);
+        }
+        Ok(())
+    }
+
+    pub fn put_int(&mut self, i: i64, val: LuaValue) {
+        let len = self.arr.len() as i64;
+        if i >= 1 && i <= len {
+            self.arr[(i - 1) as usize] = val;
+            if i == len {
+                self.shrink_array();
+            }
+        } else if i == len + 1 && !val.is_nil() && !self.index.contains_key(&LuaValue::Integer(i)) {
+            self.arr.push(val);
+            self.expand_array();
+        } else {
+            self.put_into_hash(LuaValue::Integer(i), val);
+        }
+    }
+
+    fn put_into_hash(&mut self, key: LuaValue, val: LuaValue) {
+        if let Some(&idx) = self.index.get(&key) {
+            self.node[idx].1 = val;
+            return;
+        }
+        if val.is_nil() {
+            return;
+        }
+        if self.node.len() == self.node.capacity() {
+            self.compact_hash();
+        }
+        self.index.insert(key.clone(), self.node.len());
+        self.node.push((key, val));
+    }
+
+    /// Drop trailing nils so that the array part always ends with a value.
+    fn shrink_array(&mut self) {
+        while let Some(LuaValue::Nil) = self.arr.last() {
+            self.arr.pop();
+        }
+    }
+
+    /// Move integer keys that now follow the array part out of the hash part.
+    fn expand_array(&mut self) {
+        loop {
+            let key = LuaValue::Integer(self.arr.len() as i64 + 1);
+            match self.index.remove(&key) {
+                Some(idx) => {
+                    let val = std::mem::replace(&mut self.node[idx].1, LuaValue::Nil);
+                    self.node[idx].0 = LuaValue::Nil;
+                    if val.is_nil() {
+                        break;
+                    }
+                    self.arr.push(val);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The entry following `key` in a traversal, `key` nil starts it: first
+    /// the array part in order, then the hash part in insertion order.
+    /// `None` at the end, `Err` when `key` is not in the table.
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>, &'static str> {
+        let key = normalize_key(key);
+        let array_start = match key {
+            LuaValue::Nil => Some(0),
+            // a key past the array part may have been cleared from its end
+            LuaValue::Integer(i) if i >= 1 && !self.index.contains_key(&key) => {
+                Some((i as u64).min(self.arr.len() as u64) as usize)
+            }
+            _ => None,
+        };
+        let node_start = match array_start {
+            Some(start) => {
+                let entry = self.arr[start..]
+                    .iter()
+                    .enumerate()
+                    .find(|(_, v)| !v.is_nil());
+                if let Some((i, v)) = entry {
+                    let k = LuaValue::Integer((start + i) as i64 + 1);
+                    return Ok(Some((k, v.clone())));
+                }
+                0
+            }
+            None => match self.index.get(&key) {
+                Some(&idx) => idx + 1,
+                None => return Err("invalid key to 'next'"),
+            },
+        };
+        let entry = self.node[node_start..].iter().find(|(_, v)| !v.is_nil());
+        Ok(entry.cloned())
+    }
+
+    /// Entries of the hash part, removed ones have a nil value.
+    pub(crate) fn nodes(&self) -> &[(LuaValue, LuaValue)] {
+        &self.node
+    }
+
+    /// Every value held by the table, keys of the hash part appear twice as
+    /// the index keeps a copy of them.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &LuaValue> {
+        let nodes = self.node.iter().flat_map(|(k, v)| [k, v]);
+        self.arr.iter().chain(nodes).chain(self.index.keys())
+    }
+
+    /// Remove the entries for which `dead(key, val)` holds, used by the
+    /// collector to clear weak tables. Keys of the array part are integers.
+    pub(crate) fn remove_entries(&mut self, mut dead: impl FnMut(&LuaValue, &LuaValue) -> bool) {
+        let mut dropped = false;
+        for (i, val) in self.arr.iter_mut().enumerate() {
+            if !val.is_nil() && dead(&LuaValue::Integer(i as i64 + 1), val) {
+                *val = LuaValue::Nil;
+                dropped = true;
+            }
+        }
+        if dropped {
+            self.shrink_array();
+        }
+        for (key, val) in self.node.iter_mut() {
+            if !val.is_nil() && dead(key, val) {
+                self.index.remove(key);
+                *key = LuaValue::Nil;
+                *val = LuaValue::Nil;
+            }
+        }
+    }
+
+    /// Approximate number of bytes owned by the table.
+    pub(crate) fn heap_size(&self) -> usize {
+        let value = std::mem::size_of::<LuaValue>();
+        std::mem::size_of::<LuaTable>()
+            + self.arr.capacity() * value
+            + self.node.capacity() * 2 * value
+            + self.index.capacity() * (value + std::mem::size_of::<usize>())
+    }
+
+    /// Forget removed entries. Only called before inserting a new key, which
+    /// is not allowed while the table is being traversed.
+    fn compact_hash(&mut self) {
+        if self.node.iter().all(|(_, v)| !v.is_nil()) {
+            return;
+        }
+        self.node.retain(|(k, v)| !k.is_nil() && !v.is_nil());
+        self.index.clear();
+        for (idx, (k, _)) in self.node.iter().enumerate() {
+            self.index.insert(k.clone(), idx);
+        }
+    }
+}
+
+impl std::fmt::Debug for LuaTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaTable")
+            .field("arr", &self.arr)
+            .field("node", &self.node)
+            .finish()
+    }
+}
+
+/// Float keys with an exact integer value are stored as integers, so that
+/// `t[2.0]` and `t[2]` denote the same slot.
+fn normalize_key(key: &LuaValue) -> LuaValue {
+    match key {
+        LuaValue::Number(f) => match float_to_integer(*f) {
+            Some(i) => LuaValue::Integer(i),
+            None => key.clone(),
+        },
+        _ => key.clone(),
+    }
+}
+
+#[test]
+fn test_array_part() {
+    let mut table = LuaTable::new(0, 0);
+    table
+        .put(LuaValue::Integer(1), LuaValue::Integer(10))
+        .unwrap();
+    table
+        .put(LuaValue::Integer(2), LuaValue::Integer(20))
+        .unwrap();
+    table
+        .put(LuaValue::Integer(4), LuaValue::Integer(40))
+        .unwrap();
+    assert_eq!(table.len(), 2);
+
+    // filling the gap pulls key 4 from the hash part into the array part
+    table
+        .put(LuaValue::Integer(3), LuaValue::Integer(30))
+        .unwrap();
+    assert_eq!(table.len(), 4);
+    assert_eq!(table.get_int(4), LuaValue::Integer(40));
+
+    table.put(LuaValue::Integer(4), LuaValue::Nil).unwrap();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.get_int(4), LuaValue::Nil);
+}
+
+#[test]
+fn test_hash_part() {
+    let mut table = LuaTable::new(0, 0);
+    table
+        .put(LuaValue::String("x".into()), LuaValue::Boolean(true))
+        .unwrap();
+    table
+        .put(LuaValue::Number(1.5), LuaValue::Integer(15))
+        .unwrap();
+    table
+        .put(LuaValue::Integer(-1), LuaValue::Integer(-1))
+        .unwrap();
+
+    assert_eq!(table.get_str("x"), LuaValue::Boolean(true));
+    assert_eq!(table.get(&LuaValue::Number(1.5)), LuaValue::Integer(15));
+    assert_eq!(table.get_int(-1), LuaValue::Integer(-1));
+    assert_eq!(table.len(), 0);
+
+    table
+        .put(LuaValue::String("x".into()), LuaValue::Nil)
+        .unwrap();
+    assert_eq!(table.get_str("x"), LuaValue::Nil);
+}
+
+#[test]
+fn test_float_key_normalization() {
+    let mut table = LuaTable::new(0, 0);
+    table
+        .put(LuaValue::Number(1.0), LuaValue::Integer(1))
+        .unwrap();
+    table
+        .put(LuaValue::Number(2.0f64.powi(60)), LuaValue::Integer(2))
+        .unwrap();
+
+    assert_eq!(table.len(), 1);
+    assert_eq!(table.get(&LuaValue::Integer(1)), LuaValue::Integer(1));
+    assert_eq!(table.get_int(1 << 60), LuaValue::Integer(2));
+}
+
+#[test]
+fn test_invalid_key() {
+    let mut table = LuaTable::new(0, 0);
+    assert_eq!(
+        table.put(LuaValue::Nil, LuaValue::Integer(1)),
+        Err("index is nil")
+    );
+    assert_eq!(
+        table.put(LuaValue::Number(f64::NAN), LuaValue::Integer(1)),
+        Err("index is NaN")
+    );
+    assert_eq!(table.get(&LuaValue::Number(f64::NAN)), LuaValue::Nil);
+}
+
+#[test]
+fn test_table_identity() {
+    let t1 = LuaTable::new_ref(0, 0);
+    let t2 = LuaTable::new_ref(0, 0);
+    let mut table = LuaTable::new(0, 0);
+    table
+        .put(LuaValue::Table(t1.clone()), LuaValue::Integer(1))
+        .unwrap();
+    table
+        .put(LuaValue::Table(t2.clone()), LuaValue::Integer(2))
+        .unwrap();
+
+    assert_ne!(LuaValue::Table(t1.clone()), LuaValue::Table(t2.clone()));
+    assert_eq!(table.get(&LuaValue::Table(t1)), LuaValue::Integer(1));
+    assert_eq!(table.get(&LuaValue::Table(t2)), LuaValue::Integer(2));
+}
+
+#[test]
+fn test_border() {
+    let mut table = LuaTable::new(0, 0);
+    assert_eq!(table.len(), 0);
+
+    // keys continuing the array part in the hash part
+    table.put_int(1, LuaValue::Integer(1));
+    for i in 2..=100 {
+        table.put_into_hash(LuaValue::Integer(i), LuaValue::Integer(i));
+    }
+    assert_eq!(table.arr.len(), 1);
+    assert_eq!(table.len(), 100);
+    table.put_into_hash(LuaValue::Integer(64), LuaValue::Nil);
+    let border = table.len() as i64;
+    assert!(!table.get_int(border).is_nil() && table.get_int(border + 1).is_nil());
+
+    // t[#t + 1] = v appends
+    let mut table = LuaTable::new(0, 0);
+    for i in 1..=10 {
+        let n = table.len() as i64;
+        table.put_int(n + 1, LuaValue::Integer(i));
+    }
+    assert_eq!(table.len(), 10);
+    assert_eq!(table.get_int(10), LuaValue::Integer(10));
+
+    let mut table = LuaTable::new(0, 0);
+    table.put_int(i64::MAX, LuaValue::Boolean(true));
+    assert_eq!(table.len(), 0);
+}
+
+#[test]
+fn test_next() {
+    let mut table = LuaTable::new(0, 0);
+    for i in 1..=3 {
+        table.put_int(i, LuaValue::Integer(i * 10));
+    }
+    table
+        .put(LuaValue::String("x".into()), LuaValue::Boolean(true))
+        .unwrap();
+    table
+        .put(LuaValue::String("y".into()), LuaValue::Boolean(false))
+        .unwrap();
+    table.put_int(2, LuaValue::Nil);
+
+    let mut keys = Vec::new();
+    let mut key = LuaValue::Nil;
+    while let Some((k, _)) = table.next(&key).unwrap() {
+        keys.push(k.clone());
+        key = k;
+    }
+    let s = |s: &str| LuaValue::String(s.into());
+    assert_eq!(
+        keys,
+        [LuaValue::Integer(1), LuaValue::Integer(3), s("x"), s("y")]
+    );
+
+    // clearing the current entry during the traversal is allowed
+    table.put_int(3, LuaValue::Nil);
+    assert_eq!(
+        table.next(&LuaValue::Integer(3)).unwrap().unwrap().0,
+        s("x")
+    );
+    table.put(s("x"), LuaValue::Nil).unwrap();
+    assert_eq!(table.next(&s("x")).unwrap().unwrap().0, s("y"));
+    assert_eq!(table.next(&s("y")).unwrap(), None);
+    assert_eq!(table.next(&s("z")), Err("invalid key to 'next'"));
+}
@@ -0,0 +1,284 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use super::{
+    call_frame::CallFrame,
+    instruction::InstructionOperation,
+    lua_closure::{LuaUpvalue, UpvalueRef},
+    lua_error::LuaError,
+    lua_stack::LuaStack,
+    lua_state::{LuaState, LuaVm, LUAI_MAXCCALLS, LUA_MINSTACK, LUA_MULTRET},
+    lua_value::LuaValue,
+};
+
+pub type LuaThreadRef = Rc<RefCell<LuaThread>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    /// Not started yet or stopped in a yield.
+    Suspended,
+    Running,
+    /// Resumed another coroutine and waits for it.
+    Normal,
+    /// Returned or stopped by an error.
+    Dead,
+}
+
+impl ThreadStatus {
+    /// Name as returned by `coroutine.status`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadStatus::Suspended => "suspended",
+            ThreadStatus::Running => "running",
+            ThreadStatus::Normal => "normal",
+            ThreadStatus::Dead => "dead",
+        }
+    }
+}
+
+/// Outcome of `LuaApi::resume`, with the number of values pushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resumed {
+    Yielded(usize),
+    Returned(usize),
+}
+
+/// A thread of execution with its own stack and frames, i.e. a coroutine.
+/// The state shares everything else between its threads.
+///
+/// While a thread runs, its stack, frames and open upvalues are moved into
+/// the `LuaState`, which is what all instructions work on, and the thread
+/// only keeps empty placeholders.
+#[derive(Debug)]
+pub struct LuaThread {
+    pub stack: LuaStack,
+    pub frames: Vec<CallFrame>,
+    pub open_upvalues: BTreeMap<usize, UpvalueRef>,
+    /// Number of calls from Rust that a yield can not cross.
+    pub non_yieldable: usize,
+    pub status: ThreadStatus,
+    /// Error that stopped the coroutine, reported by `coroutine.close`.
+    pub error: Option<LuaError>,
+}
+
+impl LuaThread {
+    pub fn new() -> LuaThread {
+        LuaThread {
+            stack: LuaStack::new(LUA_MINSTACK),
+            frames: Vec::new(),
+            open_upvalues: BTreeMap::new(),
+            non_yieldable: 0,
+            status: ThreadStatus::Suspended,
+            error: None,
+        }
+    }
+
+    /// Close all upvalues still pointing into the stack.
+    pub fn close_upvalues(&mut self) {
+        for (idx, upvalue) in std::mem::take(&mut self.open_upvalues) {
+            let val = std::mem::take(&mut self.stack.slots[idx]);
+            *upvalue.borrow_mut() = LuaUpvalue::Closed(val);
+        }
+    }
+
+    /// Drop the frames and values of a coroutine that will not run again,
+    /// returns the error that stopped it.
+    pub fn reset(&mut self) -> Option<LuaError> {
+        self.close_upvalues();
+        self.frames.clear();
+        let top = self.stack.top;
+        self.stack.slots[..top].fill(LuaValue::Nil);
+        self.stack.top = 0;
+        self.stack.base = 0;
+        self.status = ThreadStatus::Dead;
+        self.error.take()
+    }
+}
+
+impl Default for LuaThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Closures may outlive the thread they captured variables of.
+impl Drop for LuaThread {
+    fn drop(&mut self) {
+        self.close_upvalues();
+    }
+}
+
+impl LuaState {
+    /// Give the running thread back its stack and frames and continue with
+    /// those of `thread`.
+    fn switch_thread(&mut self, thread: LuaThreadRef) {
+        for thread in [&self.thread.clone(), &thread] {
+            let mut t = thread.borrow_mut();
+            std::mem::swap(&mut self.stack, &mut t.stack);
+            std::mem::swap(&mut self.frames, &mut t.frames);
+            std::mem::swap(&mut self.open_upvalues, &mut t.open_upvalues);
+            std::mem::swap(&mut self.non_yieldable, &mut t.non_yieldable);
+        }
+        self.thread = thread;
+    }
+
+    pub(crate) fn is_main_thread(&self) -> bool {
+        Rc::ptr_eq(&self.thread, &self.main_thread)
+    }
+
+    pub(crate) fn thread_status_of(&self, thread: &LuaThreadRef) -> ThreadStatus {
+        if Rc::ptr_eq(thread, &self.thread) {
+            ThreadStatus::Running
+        } else {
+            thread.borrow().status
+        }
+    }
+
+    /// Resume the thread in slot `slot` with the values above it.
+    pub(crate) fn resume_thread(&mut self, slot: usize) -> Result<Resumed, LuaError> {
+        let co = match &self.stack.slots[slot] {
+            LuaValue::Thread(co) => Some(co.clone()),
+            _ => None,
+        };
+        let msg = match co.as_ref().map(|co| self.thread_status_of(co)) {
+            Some(ThreadStatus::Suspended) if self.rust_calls >= LUAI_MAXCCALLS => {
+                "C stack overflow"
+            }
+            Some(ThreadStatus::Suspended) => "",
+            Some(ThreadStatus::Dead) => "cannot resume dead coroutine",
+            _ => "cannot resume non-suspended coroutine",
+        };
+        let args = self.take_values(slot + 1);
+        self.take_values(slot);
+        let Some(co) = co.filter(|_| msg.is_empty()) else {
+            let err = LuaError::new(msg);
+            self.stack.push(err.value());
+            return Err(err);
+        };
+
+        let previous = self.thread.clone();
+        previous.borrow_mut().status = ThreadStatus::Normal;
+        self.switch_thread(co.clone());
+        co.borrow_mut().status = ThreadStatus::Running;
+        let n_args = args.len();
+        args.into_iter().for_each(|arg| self.stack.push(arg));
+
+        self.rust_calls += 1;
+        let res = if self.frames.is_empty() {
+            LuaState::pre_call(self, 0, LUA_MULTRET, true).and_then(|_| self.finish_frames())
+        } else {
+            // the values passed in are the results of the pending yield
+            self.post_call(self.stack.top - n_args, n_args);
+            self.finish_frames()
+        };
+        self.rust_calls -= 1;
+
+        let (values, status, res) = match res {
+            Ok(()) => {
+                let values = self.take_values(0);
+                (values, ThreadStatus::Dead, Ok(Resumed::Returned(0)))
+            }
+            Err(LuaError::Yield) => {
+                let values = self.take_values(self.stack.top - self.yielded);
+                (values, ThreadStatus::Suspended, Ok(Resumed::Yielded(0)))
+            }
+            Err(err) => (vec![err.value()], ThreadStatus::Dead, Err(err)),
+        };
+        {
+            let mut co = co.borrow_mut();
+            co.status = status;
+            if let Err(err) = &res {
+                co.error = Some(err.clone());
+            }
+        }
+        self.switch_thread(previous);
+        self.thread.borrow_mut().status = ThreadStatus::Running;
+
+        let n = values.len();
+        values.into_iter().for_each(|val| self.stack.push(val));
+        res.map(|resumed| match resumed {
+            Resumed::Yielded(_) => Resumed::Yielded(n),
+            Resumed::Returned(_) => Resumed::Returned(n),
+        })
+    }
+
+    /// Close the to-be-closed variables of the suspended or dead coroutine
+    /// `co` and drop its frames for good. Returns the error that stopped it,
+    /// or the one raised by a `__close` metamethod.
+    pub(crate) fn reset_thread(&mut self, co: &LuaThreadRef) -> Option<LuaError> {
+        let pending = co.borrow().frames.iter().any(|frame| !frame.tbc.is_empty());
+        if pending {
+            // the metamethods run on the coroutine, as if it was resumed
+            let previous = self.thread.clone();
+            previous.borrow_mut().status = ThreadStatus::Normal;
+            self.switch_thread(co.clone());
+            let err = co.borrow_mut().error.take();
+            let err = self.close_frames(0, err);
+            co.borrow_mut().error = err;
+            self.switch_thread(previous);
+            self.thread.borrow_mut().status = ThreadStatus::Running;
+        }
+        co.borrow_mut().reset()
+    }
+
+    /// Remove the values from slot `from` up to the top.
+    fn take_values(&mut self, from: usize) -> Vec<LuaValue> {
+        let top = self.stack.top;
+        let values = self.stack.slots[from..top]
+            .iter_mut()
+            .map(std::mem::take)
+            .collect();
+        self.stack.top = from;
+        values
+    }
+
+    /// Run the frames of a coroutine until all of them returned. Errors are
+    /// caught by the protected calls still pending from before a yield.
+    fn finish_frames(&mut self) -> Result<(), LuaError> {
+        loop {
+            match self.run_frames() {
+                Err(LuaError::Yield) => return Err(LuaError::Yield),
+                Err(err) => self.recover(err)?,
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    fn run_frames(&mut self) -> Result<(), LuaError> {
+        while let Some(frame) = self.frames.last_mut() {
+            if frame.closure.is_some() {
+                let instruction = self.fetch();
+                instruction.execute(self)?;
+                continue;
+            }
+            // a Rust function whose call into Lua yielded, the function is
+            // gone and its continuation finishes the call instead
+            let Some(k) = frame.continuation.take() else {
+                return Err(self.runtime_error("attempt to yield across a C-call boundary"));
+            };
+            let n = (k.func)(self, true, k.ctx)?;
+            self.post_call(self.stack.top - n, n);
+        }
+        Ok(())
+    }
+
+    /// Finish the innermost protected call pending from before a yield with
+    /// `err`, or give the error back if there is none.
+    fn recover(&mut self, err: LuaError) -> Result<(), LuaError> {
+        let pending = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(&frame.continuation, Some(k) if k.protected.is_some()));
+        let Some(level) = pending else {
+            return Err(err);
+        };
+        let k = self.frames[level].continuation.take().unwrap();
+        let (func, handler) = k.protected.unwrap();
+        let err = self.handle_error(err, handler);
+        let err = self.close_frames(level + 1, Some(err)).expect("error kept");
+        self.unwind(level + 1, func);
+        self.stack.push(err.value());
+        let n = (k.func)(self, false, k.ctx)?;
+        self.post_call(self.stack.top - n, n);
+        Ok(())
+    }
+}
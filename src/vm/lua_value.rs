@@ -1,103 +1,395 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
 
-#[derive(Debug, Clone)]
+use super::{
+    lua_closure::{LuaClosure, RustFunction},
+    lua_string::LuaString,
+    lua_table::LuaTableRef,
+    lua_thread::LuaThreadRef,
+    number::str_to_number,
+    tag_method::TagMethod,
+};
+
+#[derive(Clone, Default)]
 pub enum LuaValue {
+    #[default]
     Nil,
     Boolean(bool),
     Integer(i64),
     Number(f64),
-    String(String),
+    String(LuaString),
+    Table(LuaTableRef),
+    Function(Rc<LuaClosure>),
+    RustFunction(RustFunction),
+    Thread(LuaThreadRef),
+}
+
+impl LuaValue {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+
+    /// Truth value in conditions, only `nil` and `false` are false.
+    pub fn to_boolean(&self) -> bool {
+        !matches!(self, LuaValue::Nil | LuaValue::Boolean(false))
+    }
+
+    /// Name of the type as returned by `type()`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Nil => "nil",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::Integer(_) | LuaValue::Number(_) => "number",
+            LuaValue::String(_) => "string",
+            LuaValue::Table(_) => "table",
+            LuaValue::Function(_) | LuaValue::RustFunction(_) => "function",
+            LuaValue::Thread(_) => "thread",
+        }
+    }
+
+    /// Number a value converts to in arithmetic, strings holding a numeral
+    /// convert to an integer or a float.
+    pub fn to_number(&self) -> Option<LuaValue> {
+        match self {
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(self.clone()),
+            LuaValue::String(s) => str_to_number(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Float value of a number or of a string converting to one.
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            LuaValue::Integer(i) => Some(i as f64),
+            LuaValue::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Integer value for bitwise operations, floats only convert when they
+    /// have an exact integer representation.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            LuaValue::Integer(i) => Some(i),
+            LuaValue::Number(n) => float_to_integer(n),
+            _ => None,
+        }
+    }
+}
+
+impl Debug for LuaValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nil => write!(f, "Nil"),
+            Self::Boolean(b) => f.debug_tuple("Boolean").field(b).finish(),
+            Self::Integer(i) => f.debug_tuple("Integer").field(i).finish(),
+            Self::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            Self::String(s) => write!(f, "String({:?})", s),
+            // tables may reference themselves, only print the address
+            Self::Table(t) => write!(f, "Table({:p})", Rc::as_ptr(t)),
+            Self::Function(c) => write!(f, "Function({:p})", Rc::as_ptr(c)),
+            Self::RustFunction(func) => func.fmt(f),
+            Self::Thread(t) => write!(f, "Thread({:p})", Rc::as_ptr(t)),
+        }
+    }
 }
 
 impl PartialEq for LuaValue {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Self::Nil, Self::Nil) => true,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
             (Self::Integer(l0), Self::Integer(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
+            (Self::Integer(i), Self::Number(f)) | (Self::Number(f), Self::Integer(i)) => {
+                float_to_integer(*f) == Some(*i)
+            }
             (Self::String(l0), Self::String(r0)) => l0 == r0,
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+            (Self::Table(l0), Self::Table(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Function(l0), Self::Function(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::RustFunction(l0), Self::RustFunction(r0)) => l0.as_ptr() == r0.as_ptr(),
+            (Self::Thread(l0), Self::Thread(r0)) => Rc::ptr_eq(l0, r0),
+            _ => false,
+        }
+    }
+}
+
+/// Order of an integer and a float, exact even where the integer has no
+/// float representation. `None` if the float is NaN.
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // f is in range now, compare with its integral part first
+        let floor = f.floor();
+        match i.cmp(&(floor as i64)) {
+            Ordering::Equal if f > floor => Some(Ordering::Less),
+            ord => Some(ord),
         }
     }
 }
 
+/// Numbers are ordered by value and strings by their bytes, values of other
+/// types have no order.
 impl PartialOrd for LuaValue {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self {
-            LuaValue::Nil => match other {
-                LuaValue::Nil => Some(Ordering::Equal),
-                _ => None,
-            },
-            LuaValue::Boolean(a_val) => match other {
-                LuaValue::Boolean(b_val) => Some(a_val.cmp(b_val)),
-                _ => None,
-            },
-            LuaValue::Integer(a_int) => match other {
-                LuaValue::Integer(b_int) => Some(a_int.cmp(b_int)),
-                LuaValue::Number(b_float) => {
-                    let a_float = a_int.clone() as f64;
-
-                    a_float.clone().partial_cmp(b_float)
-                }
-                _ => None,
-            },
-            LuaValue::Number(a_float) => match other {
-                LuaValue::Integer(b_int) => {
-                    let b_float = b_int.clone() as f64;
-                    a_float.clone().partial_cmp(&b_float)
-                }
-                LuaValue::Number(b_float) => a_float.clone().partial_cmp(b_float),
-                _ => None,
-            },
-            LuaValue::String(a_str) => match other {
-                LuaValue::String(b_str) => Some(a_str.cmp(b_str)),
-                _ => None,
-            },
+        match (self, other) {
+            (LuaValue::Integer(a), LuaValue::Integer(b)) => Some(a.cmp(b)),
+            (LuaValue::Number(a), LuaValue::Number(b)) => a.partial_cmp(b),
+            (LuaValue::Integer(i), LuaValue::Number(f)) => cmp_int_float(*i, *f),
+            (LuaValue::Number(f), LuaValue::Integer(i)) => {
+                cmp_int_float(*i, *f).map(Ordering::reverse)
+            }
+            (LuaValue::String(a), LuaValue::String(b)) => Some(a.cmp(b)),
+            _ => None,
         }
     }
 }
 
-impl TryInto<i64> for LuaValue {
-    type Error = &'static str;
+/// Only used for table keys: floats with an integral value are normalized to
+/// integers before hashing, and NaN is never stored.
+impl Eq for LuaValue {}
 
-    fn try_into(self) -> Result<i64, Self::Error> {
+impl Hash for LuaValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
         match self {
-            LuaValue::Integer(val) => Ok(val),
-            LuaValue::Number(v) => Ok(v.round() as i64),
-            LuaValue::String(v) => {
-                let res = v.parse::<i64>();
-                if res.is_ok() {
-                    Ok(res.unwrap())
-                } else {
-                    let f_res = v.parse::<f64>();
-                    if f_res.is_ok() {
-                        Ok(f_res.unwrap().round() as i64)
-                    } else {
-                        Err("could not convert string to int")
-                    }
-                }
-            }
-            _ => Err("Lua Value must be Integer/Number/String"),
+            LuaValue::Nil => (),
+            LuaValue::Boolean(b) => b.hash(state),
+            LuaValue::Integer(i) => i.hash(state),
+            LuaValue::Number(n) => n.to_bits().hash(state),
+            LuaValue::String(s) => s.hash(state),
+            LuaValue::Table(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::Function(c) => Rc::as_ptr(c).hash(state),
+            LuaValue::RustFunction(f) => f.as_ptr().hash(state),
+            LuaValue::Thread(t) => Rc::as_ptr(t).hash(state),
         }
     }
 }
 
-impl TryInto<f64> for LuaValue {
-    type Error = &'static str;
+/// Convert a float to an integer if it has an exact integer representation.
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 is exactly representable, 2^63 is already out of range
+    if n.floor() == n && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Result of the arithmetic or bitwise operation `op` on two operands, unary
+/// operations ignore `b`. `None` when an operand is not a number, or has no
+/// integer representation for a bitwise operation, so that its metamethod
+/// has to be tried.
+pub fn arith(op: TagMethod, a: &LuaValue, b: &LuaValue) -> Result<Option<LuaValue>, &'static str> {
+    if op.is_bitwise() {
+        let (Some(x), Some(y)) = (a.to_integer(), b.to_integer()) else {
+            return Ok(None);
+        };
+        let r = match op {
+            TagMethod::BAnd => x & y,
+            TagMethod::BOr => x | y,
+            TagMethod::BXor => x ^ y,
+            TagMethod::Shl => shift_left(x, y),
+            TagMethod::Shr => shift_left(x, y.wrapping_neg()),
+            _ => !x,
+        };
+        return Ok(Some(LuaValue::Integer(r)));
+    }
 
-    fn try_into(self) -> Result<f64, Self::Error> {
-        match self {
-            LuaValue::Integer(v) => Ok(v as f64),
-            LuaValue::Number(v) => Ok(v),
-            LuaValue::String(v) => {
-                let res = v.parse::<f64>();
-                if res.is_ok() {
-                    Ok(res.unwrap())
+    let (Some(a), Some(b)) = (a.to_number(), b.to_number()) else {
+        return Ok(None);
+    };
+    // `/` and `^` always operate on floats
+    if let (LuaValue::Integer(x), LuaValue::Integer(y), false) =
+        (&a, &b, matches!(op, TagMethod::Div | TagMethod::Pow))
+    {
+        let (x, y) = (*x, *y);
+        let r = match op {
+            TagMethod::Add => x.wrapping_add(y),
+            TagMethod::Sub => x.wrapping_sub(y),
+            TagMethod::Mul => x.wrapping_mul(y),
+            TagMethod::IDiv => {
+                if y == 0 {
+                    return Err("attempt to perform 'n//0'");
+                }
+                let q = x.wrapping_div(y);
+                // round towards minus infinity
+                if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+                    q - 1
+                } else {
+                    q
+                }
+            }
+            TagMethod::Mod => {
+                if y == 0 {
+                    return Err("attempt to perform 'n%0'");
+                }
+                let r = x.wrapping_rem(y);
+                // the result has the sign of the divisor
+                if r != 0 && (r ^ y) < 0 {
+                    r + y
                 } else {
-                    Err("could not convert String to Number")
+                    r
                 }
             }
-            _ => Err("Lua Value must be Integer/Number/String"),
+            _ => x.wrapping_neg(),
+        };
+        return Ok(Some(LuaValue::Integer(r)));
+    }
+
+    let (x, y) = (a.to_float().unwrap(), b.to_float().unwrap());
+    let r = match op {
+        TagMethod::Add => x + y,
+        TagMethod::Sub => x - y,
+        TagMethod::Mul => x * y,
+        TagMethod::Div => x / y,
+        TagMethod::Pow => x.powf(y),
+        TagMethod::IDiv => (x / y).floor(),
+        TagMethod::Mod => {
+            let m = x % y;
+            if (m > 0.0 && y < 0.0) || (m < 0.0 && y > 0.0) {
+                m + y
+            } else {
+                m
+            }
         }
+        _ => -x,
+    };
+    Ok(Some(LuaValue::Number(r)))
+}
+
+/// `x << y` as a logical shift, negative `y` shifts to the right and
+/// shifting by 64 bits or more gives 0.
+fn shift_left(x: i64, y: i64) -> i64 {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y < 0 {
+        ((x as u64) >> -y) as i64
+    } else {
+        ((x as u64) << y) as i64
     }
 }
+
+#[test]
+fn test_compare_int_float() {
+    use LuaValue::{Integer, Number};
+
+    assert_eq!(Integer(1), Number(1.0));
+    assert_ne!(Integer(1), Number(1.5));
+    assert!(Integer(1) < Number(1.5));
+    assert!(Number(-0.5) < Integer(0));
+    // 2^53 + 1 is not exactly representable as a float
+    let big = (1i64 << 53) + 1;
+    assert_ne!(Integer(big), Number(big as f64));
+    assert!(Integer(big) > Number((1i64 << 53) as f64));
+    assert!(Integer(i64::MAX) < Number(9223372036854775808.0));
+    assert!(Integer(i64::MIN) == Number(-9223372036854775808.0));
+    assert_eq!(Integer(0).partial_cmp(&Number(f64::NAN)), None);
+    let s = |s: &[u8]| LuaValue::String(LuaString::new(s));
+    assert!(s(b"a") < s(b"b"));
+    assert!(s(b"Z") < s(b"a"));
+    assert!(s(b"a") < s(b"a\0"));
+    assert!(s(b"z") < s(b"\xe9"));
+    assert_eq!(
+        LuaValue::Boolean(false).partial_cmp(&LuaValue::Boolean(true)),
+        None
+    );
+}
+
+#[test]
+fn test_arith() {
+    use LuaValue::{Integer, Number};
+    let op = |op, a: LuaValue, b: LuaValue| arith(op, &a, &b).unwrap().unwrap();
+
+    assert_eq!(op(TagMethod::Add, Number(2.5), Integer(1)), Number(3.5));
+    assert!(matches!(
+        op(TagMethod::Add, Integer(2), Integer(1)),
+        Integer(3)
+    ));
+    assert!(matches!(
+        op(TagMethod::Add, Number(2.0), Integer(1)),
+        Number(_)
+    ));
+    assert!(matches!(
+        op(TagMethod::Div, Integer(4), Integer(2)),
+        Number(_)
+    ));
+    assert!(matches!(
+        op(TagMethod::Pow, Integer(2), Integer(3)),
+        Number(_)
+    ));
+    assert_eq!(
+        op(TagMethod::Add, Integer(i64::MAX), Integer(1)),
+        Integer(i64::MIN)
+    );
+    assert_eq!(
+        op(TagMethod::Mul, Integer(i64::MIN), Integer(-1)),
+        Integer(i64::MIN)
+    );
+    assert_eq!(
+        op(TagMethod::Unm, Integer(i64::MIN), Integer(i64::MIN)),
+        Integer(i64::MIN)
+    );
+
+    // floor division and modulo
+    assert_eq!(op(TagMethod::IDiv, Integer(7), Integer(-2)), Integer(-4));
+    assert_eq!(op(TagMethod::IDiv, Integer(-7), Integer(2)), Integer(-4));
+    assert_eq!(
+        op(TagMethod::IDiv, Integer(i64::MIN), Integer(-1)),
+        Integer(i64::MIN)
+    );
+    assert_eq!(op(TagMethod::Mod, Integer(-7), Integer(2)), Integer(1));
+    assert_eq!(op(TagMethod::Mod, Integer(7), Integer(-2)), Integer(-1));
+    assert_eq!(
+        op(TagMethod::Mod, Integer(i64::MIN), Integer(-1)),
+        Integer(0)
+    );
+    assert_eq!(op(TagMethod::IDiv, Number(7.0), Number(-2.0)), Number(-4.0));
+    assert_eq!(op(TagMethod::Mod, Number(-5.5), Integer(2)), Number(0.5));
+    assert_eq!(op(TagMethod::Mod, Number(5.5), Integer(-2)), Number(-0.5));
+
+    // division by zero
+    let err = |op| arith(op, &Integer(1), &Integer(0)).unwrap_err();
+    assert_eq!(err(TagMethod::IDiv), "attempt to perform 'n//0'");
+    assert_eq!(err(TagMethod::Mod), "attempt to perform 'n%0'");
+    assert_eq!(
+        op(TagMethod::Div, Integer(1), Integer(0)),
+        Number(f64::INFINITY)
+    );
+    assert_eq!(
+        op(TagMethod::IDiv, Number(-1.0), Integer(0)),
+        Number(f64::NEG_INFINITY)
+    );
+    assert!(matches!(op(TagMethod::Mod, Number(1.0), Integer(0)), Number(n) if n.is_nan()));
+
+    // bitwise operations
+    assert_eq!(op(TagMethod::BAnd, Number(3.0), Integer(6)), Integer(2));
+    assert_eq!(arith(TagMethod::BOr, &Number(1.5), &Integer(1)), Ok(None));
+    assert_eq!(
+        op(TagMethod::Shl, Integer(1), Integer(63)),
+        Integer(i64::MIN)
+    );
+    assert_eq!(op(TagMethod::Shl, Integer(1), Integer(64)), Integer(0));
+    assert_eq!(op(TagMethod::Shr, Integer(-1), Integer(60)), Integer(15));
+    assert_eq!(op(TagMethod::Shr, Integer(1), Integer(-2)), Integer(4));
+    assert_eq!(op(TagMethod::BNot, Integer(0), Integer(0)), Integer(-1));
+
+    // strings convert to numbers
+    let s = |s: &str| LuaValue::String(LuaString::new(s.as_bytes()));
+    assert!(matches!(
+        op(TagMethod::Add, s("10"), Integer(1)),
+        Integer(11)
+    ));
+    assert_eq!(op(TagMethod::Mul, s(" 0.5 "), Integer(3)), Number(1.5));
+    assert_eq!(arith(TagMethod::Add, &s("inf"), &Integer(1)), Ok(None));
+    assert_eq!(arith(TagMethod::Sub, &LuaValue::Nil, &Integer(1)), Ok(None));
+}
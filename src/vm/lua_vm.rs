@@ -1,36 +1,126 @@
 use super::{
     binary_chunk::Prototype,
     instruction::{Instruction, InstructionOperation},
-    lua_state::{LuaApi, LuaState, LuaVm},
-    op_code::OpCodeEnum,
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState, LuaVm, LUA_MULTRET},
 };
 
-pub fn load_main(prototype: Prototype) {
-    let n_regs = prototype.max_statck_size as i32;
-    let mut state = LuaState::new((n_regs as usize) + 8, prototype);
-
-    state.set_top(n_regs);
-    loop {
-        let pc = state.get_pc();
-        let instruction: Instruction = state.fetch();
-        let op_code = OpCodeEnum::try_from(instruction.op_code()).unwrap();
-        match op_code {
-            OpCodeEnum::OpReturn => break,
-            _code => {
-                println!("pc: {:?}, op name: {:?}", pc, instruction.op_name());
-                instruction.execute(&mut state);
-            }
-        }
-    }
-    println!("{:#?}", state.stack);
+/// Run the main function of a chunk, its results are left on the stack.
+pub fn load_main(prototype: Prototype) -> Result<LuaState, LuaError> {
+    let mut state = LuaState::new();
+    state.load_prototype(prototype);
+    state.call(0, LUA_MULTRET)?;
+    Ok(state)
+}
+
+/// Enter the main function of a chunk without running it, see `step`.
+pub fn new_main_state(prototype: Prototype) -> LuaState {
+    let mut state = LuaState::new();
+    state.load_prototype(prototype);
+    state
+        .pre_call(0, LUA_MULTRET, true)
+        .expect("main chunk is a Lua function");
+    state
+}
+
+/// Execute one instruction, returns `false` once the main function has returned.
+pub fn step(state: &mut LuaState) -> Result<bool, LuaError> {
+    let instruction: Instruction = state.fetch();
+    instruction.execute(state)?;
+    Ok(!state.frames.is_empty())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::reader::LuaChunkReader;
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        stdlib::{
+            base::{self, error, getmetatable, pcall, setmetatable, xpcall},
+            coroutine::{self, coroutine_yield},
+        },
+        vm::{
+            binary_chunk::{LocalVariable, Upvalue},
+            gc::GcOption,
+            lua_closure::RustFn,
+            lua_error::LuaError,
+            lua_state::CampareOperator,
+            lua_string::LuaString,
+            lua_table::LuaTable,
+            lua_thread::{Resumed, ThreadStatus},
+            lua_value::LuaValue,
+            op_code::OpCodeEnum,
+            reader::LuaChunkReader,
+            tag_method::TagMethod,
+        },
+    };
 
     use super::*;
 
+    pub fn iabc(op: OpCodeEnum, a: u32, b: u32, c: u32, k: bool) -> Instruction {
+        op as u32 | a << 7 | (k as u32) << 15 | b << 16 | c << 24
+    }
+
+    pub fn iabx(op: OpCodeEnum, a: u32, bx: u32) -> Instruction {
+        op as u32 | a << 7 | bx << 15
+    }
+
+    pub fn iasbx(op: OpCodeEnum, a: u32, sbx: i32) -> Instruction {
+        iabx(op, a, (sbx + (((1 << 17) - 1) >> 1)) as u32)
+    }
+
+    pub fn isj(op: OpCodeEnum, sj: i32) -> Instruction {
+        op as u32 | ((sj + (((1 << 25) - 1) >> 1)) as u32) << 7
+    }
+
+    pub fn iax(op: OpCodeEnum, ax: u32) -> Instruction {
+        op as u32 | ax << 7
+    }
+
+    /// Build a main chunk prototype from hand assembled instructions.
+    pub fn prototype(code: Vec<Instruction>, constants: Vec<LuaValue>, max_stack: u8) -> Prototype {
+        Prototype {
+            source: "=test".to_string(),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_statck_size: max_stack,
+            code,
+            constants,
+            upvalues: vec![Upvalue {
+                instack: 1,
+                index: 0,
+                kind: 0,
+            }],
+            prototypes: vec![],
+            line_info: vec![],
+            abs_line_list: vec![],
+            local_variable: vec![],
+            upvalue_names: vec![],
+        }
+    }
+
+    /// Step through the main function and stop right before it returns, so
+    /// that its registers can be inspected.
+    fn run_to_return(prototype: Prototype) -> LuaState {
+        let mut state = new_main_state(prototype);
+        loop {
+            let frame = state.frames.last().unwrap();
+            let next = frame.prototype().code[frame.pc as usize];
+            let op_code = OpCodeEnum::try_from(next.op_code()).unwrap();
+            if state.frames.len() == 1
+                && matches!(
+                    op_code,
+                    OpCodeEnum::OpReturn | OpCodeEnum::OpReturn0 | OpCodeEnum::OpReturn1
+                )
+            {
+                return state;
+            }
+            step(&mut state).unwrap();
+        }
+    }
+
     fn read_prototype_fixture(filename: &'static str) -> Prototype {
         let cur_dir = std::env::current_dir()
             .unwrap()
@@ -40,14 +130,13 @@ mod tests {
         let chunk_file = cur_dir + "/fixtures/" + filename;
         let file = std::fs::File::open(chunk_file).unwrap();
         let mut buf = Vec::new();
-        std::io::Read::read_to_end(&mut std::io::BufReader::new(file), &mut buf);
+        std::io::Read::read_to_end(&mut std::io::BufReader::new(file), &mut buf).unwrap();
 
         let mut reader = LuaChunkReader::new(buf);
 
-        reader.check_header();
-        reader.read_byte();
-        let proto = reader.read_function_prototype("".to_string()).unwrap();
-        proto
+        reader.check_header().unwrap();
+        reader.read_byte().unwrap();
+        reader.read_function_prototype("".to_string()).unwrap()
     }
 
     #[test]
@@ -56,7 +145,11 @@ mod tests {
         // 1       [1]     VARARGPREP      0
         // 2       [2]     JMP             -1      ; to 2
         // 3       [2]     RETURN          0 1 1   ; 0 out
-        load_main(proto);
+        let mut state = new_main_state(proto);
+        for _ in 0..10 {
+            assert!(step(&mut state).unwrap());
+        }
+        assert_eq!(state.get_pc(), 1);
     }
 
     #[test]
@@ -65,20 +158,21 @@ mod tests {
         // 1       [1]     VARARGPREP      0
         // 2       [1]     LOADNIL         0 1     ; 2 out
         // 3       [1]     RETURN          2 1 1   ; 0 out
-        load_main(proto);
+        load_main(proto).unwrap();
     }
 
     #[test]
     fn test_add_2_integer_program() {
         let proto = read_prototype_fixture("add-2-int.luac");
         // 1       [1]     VARARGPREP      0
-        // 2       [1]     LOADI           0 1
-        // 3       [1]     LOADI           1 2
+        // 2       [1]     LOADI           0 2
+        // 3       [1]     LOADI           1 3
         // 4       [1]     LOADNIL         2 0     ; 1 out
         // 5       [1]     ADD             2 0 1
         // 6       [1]     MMBIN           0 1 6   ; __add
         // 7       [1]     RETURN          3 1 1   ; 0 out
-        load_main(proto);
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(2), LuaValue::Integer(5));
     }
 
     #[test]
@@ -91,7 +185,7 @@ mod tests {
         // 5       [2]     ADD             2 0 1
         // 6       [2]     MMBIN           0 1 6   ; __add
         // 7       [2]     RETURN          3 1 1   ; 0 out
-        load_main(proto);
+        load_main(proto).unwrap();
     }
 
     #[test]
@@ -102,7 +196,8 @@ mod tests {
         // 3       [1]     LOADNIL         1 0     ; 1 out
         // 4       [2]     LEN             1 0
         // 5       [2]     RETURN          2 1 1   ; 0 out
-        load_main(proto);
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(1), LuaValue::Integer(3));
     }
 
     #[test]
@@ -117,7 +212,35 @@ mod tests {
         // 6       [2]     CONCAT          2 2
         // 7       [2]     MOVE            1 2
         // 8       [2]     RETURN          2 1 1   ; 0 out
-        load_main(proto);
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(1), LuaValue::String("strstr".into()));
+    }
+
+    #[test]
+    fn test_binary_strings() {
+        // R[2] = K[0] .. K[1] .. 1; R[3] = #R[2]; R[4] = R[2] < K[1]
+        let code = vec![
+            iabx(OpCodeEnum::OpLOADK, 2, 0),
+            iabx(OpCodeEnum::OpLOADK, 3, 1),
+            iasbx(OpCodeEnum::OpLOADI, 4, 1),
+            iabc(OpCodeEnum::OpCONCAT, 2, 3, 0, false),
+            iabc(OpCodeEnum::OpLEN, 3, 2, 0, false),
+            iabx(OpCodeEnum::OpLOADK, 5, 1),
+            iabc(OpCodeEnum::OpLoadFalse, 4, 0, 0, false),
+            iabc(OpCodeEnum::OpLt, 2, 5, 0, false),
+            isj(OpCodeEnum::OpJmp, 1),
+            iabc(OpCodeEnum::OpLoadTrue, 4, 0, 0, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ];
+        let consts = vec![
+            LuaValue::String(LuaString::new(b"\xff\0")),
+            LuaValue::String(LuaString::new(b"\0\xfe")),
+        ];
+        let mut state = run_to_return(prototype(code, consts, 6));
+        assert_eq!(state.to_string(2).unwrap().as_bytes(), b"\xff\0\0\xfe1");
+        assert_eq!(state.stack.get(3), LuaValue::Integer(5));
+        // "\xff..." sorts after "\0..."
+        assert_eq!(state.stack.get(4), LuaValue::Boolean(false));
     }
 
     #[test]
@@ -137,6 +260,2248 @@ mod tests {
         //     12      [4]     MMBIN           0 4 6   ; __add
         //     13      [2]     FORLOOP         1 7     ; to 7
         //     14      [6]     RETURN          1 1 1   ; 0 out
-        load_main(proto);
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(1680));
+    }
+
+    #[test]
+    fn test_function_call_program() {
+        let proto = read_prototype_fixture("function.luac");
+        // main <function.lua:0,0>
+        // 1       [1]     VARARGPREP      0
+        // 2       [3]     CLOSURE         0 0     ; 0x0
+        // 3       [4]     MOVE            1 0
+        // 4       [4]     LOADI           2 1
+        // 5       [4]     LOADI           3 2
+        // 6       [4]     CALL            1 3 2   ; 2 in 1 out
+        // 7       [4]     RETURN          2 1 1   ; 0 out
+        //
+        // function <function.lua:1,3>
+        // 1       [2]     ADD             2 0 1
+        // 2       [2]     MMBIN           0 1 6   ; __add
+        // 3       [2]     RETURN1         2
+        // 4       [3]     RETURN0
+        assert_eq!(proto.prototypes.len(), 1);
+        assert_eq!(proto.prototypes[0].source, "@function.lua");
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(1), LuaValue::Integer(3));
+    }
+
+    #[test]
+    fn test_table_instructions() {
+        // local t = {}
+        // t[1] = 10; t.x = "a"; t[2.0] = 20
+        // local a, b, c = t[1], t.x, t[2]
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpNEWTABLE, 0, 0, 0, false),
+                iax(OpCodeEnum::OpExtraArg, 0),
+                iabc(OpCodeEnum::OpSetI, 0, 1, 0, true),
+                iabc(OpCodeEnum::OpSetField, 0, 1, 2, true),
+                iabx(OpCodeEnum::OpLOADK, 1, 3),
+                iabc(OpCodeEnum::OpSetTable, 0, 1, 4, true),
+                iabc(OpCodeEnum::OpGetI, 1, 0, 1, false),
+                iabc(OpCodeEnum::OpGetField, 2, 0, 1, false),
+                iasbx(OpCodeEnum::OpLOADI, 4, 2),
+                iabc(OpCodeEnum::OpGetTable, 3, 0, 4, false),
+                iabc(OpCodeEnum::OpReturn, 0, 1, 1, false),
+            ],
+            vec![
+                LuaValue::Integer(10),
+                LuaValue::String("x".into()),
+                LuaValue::String("a".into()),
+                LuaValue::Number(2.0),
+                LuaValue::Integer(20),
+            ],
+            5,
+        );
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(1), LuaValue::Integer(10));
+        assert_eq!(state.stack.get(2), LuaValue::String("a".into()));
+        assert_eq!(state.stack.get(3), LuaValue::Integer(20));
+        match state.stack.get(0) {
+            LuaValue::Table(t) => assert_eq!(t.borrow().len(), 2),
+            v => panic!("expect table, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_new_table_size_hint() {
+        // array size 300 does not fit in C and spills into EXTRAARG
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpNEWTABLE, 0, 3, 300 & 0xff, true),
+                iax(OpCodeEnum::OpExtraArg, 1),
+                iabc(OpCodeEnum::OpReturn, 0, 1, 1, false),
+            ],
+            vec![],
+            1,
+        );
+        let mut state = run_to_return(proto);
+        match state.stack.get(0) {
+            LuaValue::Table(t) => assert!(t.borrow().arr.capacity() >= 300),
+            v => panic!("expect table, got {:?}", v),
+        }
+    }
+
+    /// Build a nested function prototype taking `num_params` fixed parameters
+    /// and capturing `upvalues` given as `(instack, index)` pairs.
+    fn function(
+        code: Vec<Instruction>,
+        num_params: u8,
+        upvalues: &[(u8, u8)],
+        max_stack: u8,
+    ) -> Rc<Prototype> {
+        let mut proto = prototype(code, vec![], max_stack);
+        proto.num_params = num_params;
+        proto.is_vararg = 0;
+        proto.upvalues = upvalues
+            .iter()
+            .map(|&(instack, index)| Upvalue {
+                instack,
+                index,
+                kind: 0,
+            })
+            .collect();
+        Rc::new(proto)
+    }
+
+    #[test]
+    fn test_call_lua_function() {
+        // local function add(a, b) return a + b end
+        // local c = add(2, 3)
+        let add = function(
+            vec![
+                iabc(OpCodeEnum::OpAdd, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 0, 1, 6, false),
+                iabc(OpCodeEnum::OpReturn1, 2, 0, 0, false),
+            ],
+            2,
+            &[],
+            3,
+        );
+        let mut proto = prototype(
+            vec![
+                iabx(OpCodeEnum::OpClosure, 0, 0),
+                iasbx(OpCodeEnum::OpLOADI, 1, 2),
+                iasbx(OpCodeEnum::OpLOADI, 2, 3),
+                iabc(OpCodeEnum::OpCall, 0, 3, 2, false),
+                iabc(OpCodeEnum::OpReturn, 0, 1, 1, false),
+            ],
+            vec![],
+            3,
+        );
+        proto.prototypes = vec![add];
+
+        let mut state = run_to_return(proto);
+        assert_eq!(state.frames.len(), 1);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(5));
+        assert_eq!(state.get_top(), 3);
+    }
+
+    #[test]
+    fn test_multiple_results() {
+        // local function f() return 1, 2, 3 end
+        // local function g(a, b, c, d) return c, d end
+        // local x, y = g(f())
+        let f = function(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 1),
+                iasbx(OpCodeEnum::OpLOADI, 1, 2),
+                iasbx(OpCodeEnum::OpLOADI, 2, 3),
+                iabc(OpCodeEnum::OpReturn, 0, 4, 1, false),
+            ],
+            0,
+            &[],
+            3,
+        );
+        let g = function(
+            vec![
+                iabc(OpCodeEnum::OpMove, 4, 2, 0, false),
+                iabc(OpCodeEnum::OpMove, 5, 3, 0, false),
+                iabc(OpCodeEnum::OpReturn, 4, 3, 1, false),
+            ],
+            4,
+            &[],
+            6,
+        );
+        let mut proto = prototype(
+            vec![
+                iabx(OpCodeEnum::OpClosure, 0, 0),
+                iabx(OpCodeEnum::OpClosure, 1, 1),
+                iabc(OpCodeEnum::OpMove, 2, 1, 0, false),
+                iabc(OpCodeEnum::OpMove, 3, 0, 0, false),
+                iabc(OpCodeEnum::OpCall, 3, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 2, 0, 3, false),
+                iabc(OpCodeEnum::OpReturn, 2, 3, 1, false),
+            ],
+            vec![],
+            5,
+        );
+        proto.prototypes = vec![f.clone(), g];
+
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(2), LuaValue::Integer(3));
+        assert_eq!(state.stack.get(3), LuaValue::Nil);
+        assert_eq!(state.get_top(), 5);
+
+        // results of a call from Rust are adjusted to the wanted count
+        let mut state = LuaState::new();
+        state.load_prototype(prototype(f.code.clone(), vec![], 3));
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.get_top(), 3);
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(3));
+
+        state.load_prototype(prototype(f.code.clone(), vec![], 3));
+        state.call(0, 5).unwrap();
+        assert_eq!(state.get_top(), 8);
+        assert_eq!(state.stack.get(5), LuaValue::Integer(3));
+        assert_eq!(state.stack.get(7), LuaValue::Nil);
+        assert!(state.frames.is_empty());
+    }
+
+    #[test]
+    fn test_tail_call() {
+        // local function loop(f, n)
+        //     if n == 0 then return n end
+        //     return f(f, n - 1)
+        // end
+        // return loop(loop, 10000)
+        let lp = function(
+            vec![
+                iabc(OpCodeEnum::OpEqI, 1, 127, 0, false),
+                isj(OpCodeEnum::OpJmp, 1),
+                iabc(OpCodeEnum::OpReturn1, 1, 0, 0, false),
+                iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+                iabc(OpCodeEnum::OpMove, 3, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 4, 1, 126, false),
+                iabc(OpCodeEnum::OpMmbinI, 1, 126, 7, false),
+                iabc(OpCodeEnum::OpTailCall, 2, 3, 0, false),
+                iabc(OpCodeEnum::OpReturn, 2, 0, 0, false),
+            ],
+            2,
+            &[],
+            5,
+        );
+        let mut proto = prototype(
+            vec![
+                iabx(OpCodeEnum::OpClosure, 0, 0),
+                iabc(OpCodeEnum::OpMove, 1, 0, 0, false),
+                iasbx(OpCodeEnum::OpLOADI, 2, 10000),
+                iabc(OpCodeEnum::OpTailCall, 0, 3, 0, false),
+                iabc(OpCodeEnum::OpReturn, 0, 0, 0, false),
+            ],
+            vec![],
+            3,
+        );
+        proto.prototypes = vec![lp];
+
+        let mut state = new_main_state(proto);
+        let mut max_depth = 0;
+        while step(&mut state).unwrap() {
+            max_depth = max_depth.max(state.frames.len());
+        }
+        assert_eq!(max_depth, 1);
+        assert_eq!(state.get_top(), 1);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(0));
+    }
+
+    #[test]
+    fn test_closure_program() {
+        let proto = read_prototype_fixture("closure.luac");
+        // local function counter()
+        //     local n = 0
+        //     return function()
+        //         n = n + 1
+        //         return n
+        //     end
+        // end
+        // local c1 = counter()
+        // c1()
+        // local a = c1()
+        // local c2 = counter()
+        // local b = c2()
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(2), LuaValue::Integer(2));
+        assert_eq!(state.stack.get(4), LuaValue::Integer(1));
+        assert!(state.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_open_upvalue_aliases_register() {
+        // local n = 0
+        // local function inc() n = n + 1 end
+        // inc(); inc()
+        let inc = function(
+            vec![
+                iabc(OpCodeEnum::OpGetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 0, 0, 128, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 128, 6, false),
+                iabc(OpCodeEnum::OpSetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+            ],
+            0,
+            &[(1, 0)],
+            1,
+        );
+        let mut proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 0),
+                iabx(OpCodeEnum::OpClosure, 1, 0),
+                iabc(OpCodeEnum::OpMove, 2, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 2, 1, 1, false),
+                iabc(OpCodeEnum::OpMove, 2, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 2, 1, 1, false),
+                iabc(OpCodeEnum::OpReturn, 3, 1, 1, true),
+            ],
+            vec![],
+            3,
+        );
+        proto.prototypes = vec![inc];
+
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(2));
+        assert_eq!(state.open_upvalues.len(), 1);
+
+        step(&mut state).unwrap();
+        assert!(state.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_close_upvalues_in_loop() {
+        // local fs = {}
+        // for i = 1, 3 do
+        //     local j = i
+        //     fs[i] = function() return j end
+        // end
+        // local a, b = fs[1](), fs[3]()
+        let get_j = function(
+            vec![
+                iabc(OpCodeEnum::OpGetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn1, 0, 0, 0, false),
+            ],
+            0,
+            &[(1, 5)],
+            1,
+        );
+        let mut proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpNEWTABLE, 0, 0, 0, false),
+                iax(OpCodeEnum::OpExtraArg, 0),
+                iasbx(OpCodeEnum::OpLOADI, 1, 1),
+                iasbx(OpCodeEnum::OpLOADI, 2, 3),
+                iasbx(OpCodeEnum::OpLOADI, 3, 1),
+                iabx(OpCodeEnum::OpForPrep, 1, 4),
+                iabc(OpCodeEnum::OpMove, 5, 4, 0, false),
+                iabx(OpCodeEnum::OpClosure, 6, 0),
+                iabc(OpCodeEnum::OpSetTable, 0, 4, 6, false),
+                iabc(OpCodeEnum::OpClose, 5, 0, 0, false),
+                iabx(OpCodeEnum::OpForLoop, 1, 5),
+                iabc(OpCodeEnum::OpGetI, 1, 0, 1, false),
+                iabc(OpCodeEnum::OpCall, 1, 1, 2, false),
+                iabc(OpCodeEnum::OpGetI, 2, 0, 3, false),
+                iabc(OpCodeEnum::OpCall, 2, 1, 2, false),
+                iabc(OpCodeEnum::OpReturn, 0, 1, 1, false),
+            ],
+            vec![],
+            7,
+        );
+        proto.prototypes = vec![get_j];
+
+        let mut state = run_to_return(proto);
+        assert_eq!(state.stack.get(1), LuaValue::Integer(1));
+        assert_eq!(state.stack.get(2), LuaValue::Integer(3));
+    }
+
+    #[test]
+    fn test_globals_program() {
+        // x = 1
+        // y = x + 41
+        // local function f() z = y end
+        // f()
+        let chunk = std::fs::read("fixtures/globals.luac").unwrap();
+        let mut state = LuaState::new();
+        state.load(chunk, "globals", None).unwrap();
+        state.call(0, 0).unwrap();
+
+        state.get_global("y").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(42));
+        state.get_global("z").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(42));
+        state.pop(2);
+
+        state.push_integer(9);
+        state.set_global("x").unwrap();
+        state.push_global_table();
+        state.get_field(-1, "x").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(9));
+    }
+
+    #[test]
+    fn test_custom_env() {
+        let chunk = std::fs::read("fixtures/globals.luac").unwrap();
+        let mut state = LuaState::new();
+        state.new_table();
+        state.load(chunk, "sandbox", Some(-1)).unwrap();
+        state.call(0, 0).unwrap();
+
+        // the chunk and its nested function only touched the env table
+        state.get_field(-1, "z").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(42));
+        state.get_global("x").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Nil);
+        state.get_global("z").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_register_rust_function() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut state = LuaState::new();
+        let sink = output.clone();
+        state.push_rust_closure(move |state| {
+            for i in 0..state.get_top() {
+                sink.borrow_mut().push(state.to_string(i as i32).unwrap());
+            }
+            Ok(0)
+        });
+        state.set_global("print").unwrap();
+
+        // print("Hello, Lua!")
+        let chunk = std::fs::read("fixtures/hello.luac").unwrap();
+        state.load(chunk, "hello", None).unwrap();
+        state.call(0, 0).unwrap();
+        assert_eq!(*output.borrow(), vec!["Hello, Lua!"]);
+        assert_eq!(state.get_top(), 0);
+    }
+
+    #[test]
+    fn test_rust_function_results() {
+        fn swap(state: &mut LuaState) -> Result<usize, LuaError> {
+            state.push_value(1);
+            state.push_value(0);
+            Ok(2)
+        }
+
+        let mut state = LuaState::new();
+        state.register("swap", swap).unwrap();
+
+        // local a, b = swap(1, 2)
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iasbx(OpCodeEnum::OpLOADI, 1, 1),
+                iasbx(OpCodeEnum::OpLOADI, 2, 2),
+                iabc(OpCodeEnum::OpCall, 0, 3, 3, false),
+                iabc(OpCodeEnum::OpReturn, 2, 1, 1, false),
+            ],
+            vec![LuaValue::String("swap".into())],
+            3,
+        );
+        state.load_prototype(proto);
+        state.pre_call(0, LUA_MULTRET, true).unwrap();
+        for _ in 0..4 {
+            step(&mut state).unwrap();
+        }
+        assert_eq!(state.stack.get(0), LuaValue::Integer(2));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(1));
+        assert_eq!(state.get_top(), 3);
+
+        // return swap(1, 2)
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iasbx(OpCodeEnum::OpLOADI, 1, 1),
+                iasbx(OpCodeEnum::OpLOADI, 2, 2),
+                iabc(OpCodeEnum::OpTailCall, 0, 3, 0, false),
+                iabc(OpCodeEnum::OpReturn, 0, 0, 0, false),
+            ],
+            vec![LuaValue::String("swap".into())],
+            3,
+        );
+        let mut state = LuaState::new();
+        state.register("swap", swap).unwrap();
+        state.load_prototype(proto);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert!(state.frames.is_empty());
+        assert_eq!(state.get_top(), 2);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(2));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(1));
+    }
+
+    /// Line info for a function starting at line 1, one line per instruction.
+    fn one_line_each(code: &[Instruction]) -> Vec<u8> {
+        vec![1; code.len()]
+    }
+
+    #[test]
+    fn test_runtime_error_position() {
+        // local t
+        // return t.x
+        let code = vec![
+            iabc(OpCodeEnum::OpLOADNIL, 0, 0, 0, false),
+            iabc(OpCodeEnum::OpGetField, 1, 0, 0, false),
+            iabc(OpCodeEnum::OpReturn1, 1, 0, 0, false),
+        ];
+        let mut proto = prototype(code, vec![LuaValue::String("x".into())], 2);
+        proto.line_info = one_line_each(&proto.code);
+
+        let mut state = LuaState::new();
+        state.load_prototype(proto);
+        let err = state.call(0, 1).unwrap_err();
+        assert_eq!(err.to_string(), "test:2: attempt to index a nil value");
+        // the state is usable again
+        assert!(state.frames.is_empty());
+        assert_eq!(state.get_top(), 0);
+
+        // stripped chunks have no line info
+        let code = vec![
+            iabc(OpCodeEnum::OpAdd, 0, 0, 0, false),
+            iabc(OpCodeEnum::OpMmbin, 0, 0, 6, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ];
+        state.load_prototype(prototype(code, vec![], 1));
+        let err = state.call(0, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:-1: attempt to perform arithmetic on a nil value"
+        );
+    }
+
+    #[test]
+    fn test_bad_binary_chunk() {
+        let mut state = LuaState::new();
+        let err = state
+            .load(b"\x1breturn 1".to_vec(), "=chunk", None)
+            .unwrap_err();
+        assert!(matches!(err, LuaError::Syntax(_)));
+        assert_eq!(
+            err.to_string(),
+            "chunk: bad binary format (not a binary chunk)"
+        );
+        assert_eq!(state.get_top(), 0);
+    }
+
+    #[test]
+    fn test_truncated_binary_chunk() {
+        let chunk = std::fs::read("fixtures/function.luac").unwrap();
+        let mut state = LuaState::new();
+        // an empty chunk is an empty text chunk
+        for len in 1..chunk.len() {
+            let err = state
+                .load(chunk[..len].to_vec(), "=chunk", None)
+                .unwrap_err();
+            assert!(matches!(err, LuaError::Syntax(_)), "{}", len);
+            assert_eq!(state.get_top(), 0);
+        }
+        let err = state
+            .load(chunk[..chunk.len() - 1].to_vec(), "=chunk", None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chunk: bad binary format (truncated precompiled chunk)"
+        );
+        state.load(chunk, "=chunk", None).unwrap();
+    }
+
+    /// `local function f(x) error(x, level) end`, all on line 1.
+    fn raise(level: i64) -> Prototype {
+        let code = vec![
+            iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+            iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+            iasbx(OpCodeEnum::OpLOADI, 3, level as i32),
+            iabc(OpCodeEnum::OpCall, 1, 3, 1, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ];
+        let mut f = prototype(code, vec![LuaValue::String("error".into())], 4);
+        f.num_params = 1;
+        f.is_vararg = 0;
+        f.line_defined = 1;
+        f.line_info = vec![0; f.code.len()];
+        f.upvalues[0].instack = 0;
+        f
+    }
+
+    /// `return pcall(f, x)` where `f` is the nested function and `x` a global.
+    fn pcall_chunk(f: Prototype) -> Prototype {
+        let mut proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iabx(OpCodeEnum::OpClosure, 1, 0),
+                iabc(OpCodeEnum::OpGetTabUp, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpTailCall, 0, 3, 0, false),
+                iabc(OpCodeEnum::OpReturn, 0, 0, 0, false),
+            ],
+            vec![
+                LuaValue::String("pcall".into()),
+                LuaValue::String("x".into()),
+            ],
+            3,
+        );
+        proto.prototypes = vec![Rc::new(f)];
+        proto
+    }
+
+    fn run_pcall(f: Prototype, x: LuaValue) -> (LuaValue, LuaValue) {
+        let mut state = LuaState::new();
+        state.register("pcall", pcall).unwrap();
+        state.register("error", error).unwrap();
+        state.stack.push(x);
+        state.set_global("x").unwrap();
+        state.load_prototype(pcall_chunk(f));
+        state.call(0, 2).unwrap();
+        assert!(state.frames.is_empty());
+        (state.stack.get(0), state.stack.get(1))
+    }
+
+    #[test]
+    fn test_pcall_error() {
+        let boom = || LuaValue::String("boom".into());
+        let (ok, e) = run_pcall(raise(1), boom());
+        assert_eq!(ok, LuaValue::Boolean(false));
+        assert_eq!(e, LuaValue::String("test:1: boom".into()));
+
+        // level 2 points at the caller, which is pcall itself
+        let (_, e) = run_pcall(raise(2), boom());
+        assert_eq!(e, boom());
+        let (_, e) = run_pcall(raise(0), boom());
+        assert_eq!(e, boom());
+
+        // error objects which are not strings are passed through untouched
+        let table = LuaTable::new_ref(0, 0);
+        let (ok, e) = run_pcall(raise(1), LuaValue::Table(table.clone()));
+        assert_eq!(ok, LuaValue::Boolean(false));
+        assert_eq!(e, LuaValue::Table(table));
+
+        // errors raised by the VM
+        let code = vec![
+            iabc(OpCodeEnum::OpLEN, 1, 0, 0, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ];
+        let mut f = prototype(code, vec![], 2);
+        f.num_params = 1;
+        f.line_defined = 7;
+        f.line_info = vec![1, 0];
+        let (ok, e) = run_pcall(f, LuaValue::Integer(1));
+        assert_eq!(ok, LuaValue::Boolean(false));
+        assert_eq!(
+            e,
+            LuaValue::String("test:8: attempt to get length of a number value".into())
+        );
+    }
+
+    #[test]
+    fn test_pcall_success() {
+        // local function f(x) return x end
+        let mut f = prototype(vec![iabc(OpCodeEnum::OpReturn1, 0, 0, 0, false)], vec![], 1);
+        f.num_params = 1;
+        let (ok, x) = run_pcall(f, LuaValue::Integer(42));
+        assert_eq!(ok, LuaValue::Boolean(true));
+        assert_eq!(x, LuaValue::Integer(42));
+    }
+
+    #[test]
+    fn test_xpcall_handler() {
+        fn handler(state: &mut LuaState) -> Result<usize, LuaError> {
+            // the frames raising the error are still there
+            assert!(state.frames.len() > 3);
+            let msg = state.to_string(0).unwrap();
+            state.push_string(format!("handled: {}", msg));
+            Ok(1)
+        }
+        fn failing_handler(state: &mut LuaState) -> Result<usize, LuaError> {
+            state.push_string("again".to_string());
+            Err(state.error())
+        }
+
+        let mut state = LuaState::new();
+        state.register("error", error).unwrap();
+        for (msgh, expected) in [
+            (handler as RustFn, "handled: test:1: boom"),
+            (failing_handler, "error in error handling"),
+        ] {
+            state.push_rust_function(xpcall);
+            state.load_prototype(raise(1));
+            state.push_rust_function(msgh);
+            state.push_string("boom".to_string());
+            state.call(3, LUA_MULTRET).unwrap();
+            assert_eq!(state.get_top(), 2);
+            assert_eq!(state.stack.get(0), LuaValue::Boolean(false));
+            assert_eq!(state.stack.get(1), LuaValue::String(expected.into()));
+            state.set_top(0);
+        }
+    }
+
+    #[test]
+    fn test_host_pcall() {
+        let mut state = LuaState::new();
+        state.register("error", error).unwrap();
+        state.push_integer(7);
+        state.load_prototype(raise(1));
+        state.push_string("boom".to_string());
+
+        let err = state.pcall(1, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "test:1: boom");
+        // the function and its arguments are replaced by the error object
+        assert!(state.frames.is_empty());
+        assert_eq!(state.get_top(), 2);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(7));
+        assert_eq!(state.stack.get(1), LuaValue::String("test:1: boom".into()));
+        assert!(state.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // function f() return f() + 1 end
+        let mut f = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 40, 0, 0, false),
+                iabc(OpCodeEnum::OpCall, 40, 1, 2, false),
+                iabc(OpCodeEnum::OpReturn1, 40, 0, 0, false),
+            ],
+            vec![LuaValue::String("f".into())],
+            41,
+        );
+        f.upvalues[0].instack = 0;
+        // f = <closure>; return f
+        let mut proto = prototype(
+            vec![
+                iabx(OpCodeEnum::OpClosure, 0, 0),
+                iabc(OpCodeEnum::OpSetTabUp, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn1, 0, 0, 0, false),
+            ],
+            vec![LuaValue::String("f".into())],
+            1,
+        );
+        proto.prototypes = vec![Rc::new(f)];
+
+        let mut state = LuaState::new();
+        state.load_prototype(proto);
+        state.call(0, 1).unwrap();
+        let err = state.pcall(0, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "test:-1: stack overflow");
+        assert!(state.frames.is_empty());
+        assert_eq!(state.get_top(), 1);
+    }
+
+    #[test]
+    fn test_rust_stack_overflow() {
+        fn recurse(state: &mut LuaState) -> Result<usize, LuaError> {
+            state.push_rust_function(recurse);
+            state.call(0, 0)?;
+            Ok(0)
+        }
+
+        let mut state = LuaState::new();
+        state.push_rust_function(recurse);
+        let err = state.pcall(0, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "C stack overflow");
+        assert_eq!(state.rust_calls, 0);
+        assert!(state.frames.is_empty());
+        assert_eq!(state.get_top(), 1);
+    }
+
+    /// Rust function naming the types of its arguments, "table-number".
+    fn arg_types(state: &mut LuaState) -> Result<usize, LuaError> {
+        let names: Vec<_> = (0..state.get_top())
+            .map(|i| state.type_name(i as i32))
+            .collect();
+        state.push_string(names.join("-"));
+        Ok(1)
+    }
+
+    /// Set the global `t` to an empty table with a metatable holding `events`.
+    fn table_with_metamethods(state: &mut LuaState, events: &[&str]) {
+        state.new_table();
+        state.new_table();
+        for event in events {
+            state.push_rust_function(arg_types);
+            state.set_field(-2, event).unwrap();
+        }
+        state.set_metatable(-2);
+        state.set_global("t").unwrap();
+    }
+
+    #[test]
+    fn test_arith_metamethods() {
+        // return t + 1, 1 - t, -t
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iasbx(OpCodeEnum::OpLOADI, 1, 1),
+                iabc(OpCodeEnum::OpAdd, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 0, 1, 6, false),
+                iabc(OpCodeEnum::OpADDI, 3, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 7, true),
+                iabc(OpCodeEnum::OpUNM, 4, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn, 2, 4, 1, false),
+            ],
+            vec![LuaValue::String("t".into())],
+            5,
+        );
+        let mut state = LuaState::new();
+        table_with_metamethods(&mut state, &["__add", "__sub", "__unm"]);
+        state.load_prototype(proto);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.get_top(), 3);
+        assert_eq!(state.to_string(0).unwrap(), "table-number");
+        assert_eq!(state.to_string(1).unwrap(), "number-table");
+        assert_eq!(state.to_string(2).unwrap(), "table-table");
+
+        // numbers still skip the MMBIN
+        let proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 2),
+                iasbx(OpCodeEnum::OpLOADI, 1, 3),
+                iabc(OpCodeEnum::OpAdd, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 0, 1, 6, false),
+                iabc(OpCodeEnum::OpReturn1, 2, 0, 0, false),
+            ],
+            vec![],
+            3,
+        );
+        state.set_top(0);
+        state.load_prototype(proto);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.stack.get(0), LuaValue::Integer(5));
+
+        // without a handler the operand without one is blamed
+        let err = state
+            .arith_metamethod(LuaValue::Integer(1), LuaValue::Nil, TagMethod::BAnd)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "attempt to perform bitwise operation on a nil value"
+        );
+        let err = state
+            .arith_metamethod(LuaValue::Number(1.5), LuaValue::Integer(1), TagMethod::Shl)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "number has no integer representation");
+    }
+
+    #[test]
+    fn test_arith_instructions() {
+        // local a, b = 7, 2.5
+        // return a - b, a + 1, a // -2, a % -2, 1 << a, a >> 1, a / 7
+        let proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 7),
+                iabx(OpCodeEnum::OpLOADK, 1, 0),
+                iabc(OpCodeEnum::OpSub, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 0, 1, 7, false),
+                iabc(OpCodeEnum::OpADDI, 3, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 6, false),
+                iabc(OpCodeEnum::OpIdivK, 4, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbinK, 0, 1, 12, false),
+                iabc(OpCodeEnum::OpModK, 5, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbinK, 0, 1, 9, false),
+                iabc(OpCodeEnum::OpSHLI, 6, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 16, true),
+                iabc(OpCodeEnum::OpSHRI, 7, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 17, false),
+                iabc(OpCodeEnum::OpDivK, 8, 0, 2, false),
+                iabc(OpCodeEnum::OpMmbinK, 0, 2, 11, false),
+                iabc(OpCodeEnum::OpReturn, 2, 8, 1, false),
+            ],
+            vec![
+                LuaValue::Number(2.5),
+                LuaValue::Integer(-2),
+                LuaValue::Integer(7),
+            ],
+            9,
+        );
+        let mut state = LuaState::new();
+        state.load_prototype(proto);
+        state.call(0, LUA_MULTRET).unwrap();
+        let results: Vec<_> = (0..7).map(|i| state.stack.get(i)).collect();
+        assert!(matches!(results[0], LuaValue::Number(n) if n == 4.5));
+        assert!(matches!(results[1], LuaValue::Integer(8)));
+        assert!(matches!(results[2], LuaValue::Integer(-4)));
+        assert!(matches!(results[3], LuaValue::Integer(-1)));
+        assert!(matches!(results[4], LuaValue::Integer(128)));
+        assert!(matches!(results[5], LuaValue::Integer(3)));
+        assert!(matches!(results[6], LuaValue::Number(n) if n == 1.0));
+
+        // local a = 1
+        // return a // 0
+        let proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 1),
+                iasbx(OpCodeEnum::OpLOADI, 1, 0),
+                iabc(OpCodeEnum::OpIdiv, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 0, 1, 12, false),
+                iabc(OpCodeEnum::OpReturn1, 2, 0, 0, false),
+            ],
+            vec![],
+            3,
+        );
+        state.set_top(0);
+        state.load_prototype(proto);
+        let err = state.call(0, 1).unwrap_err();
+        assert_eq!(err.to_string(), "test:-1: attempt to perform 'n//0'");
+    }
+
+    #[test]
+    fn test_index_metamethods() {
+        let mut state = LuaState::new();
+        // proxy = setmetatable({}, {__index = base, __newindex = base})
+        state.new_table();
+        state.push_integer(1);
+        state.set_field(-2, "x").unwrap();
+        let base = state.stack.get(-1);
+        state.new_table();
+        state.new_table();
+        state.stack.push(base.clone());
+        state.set_field(-2, "__index").unwrap();
+        state.stack.push(base.clone());
+        state.set_field(-2, "__newindex").unwrap();
+        state.set_metatable(-2);
+        state.replace(0);
+
+        state.get_field(0, "x").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(1));
+        state.push_integer(2);
+        state.set_field(0, "y").unwrap();
+        state.push_string("y".to_string());
+        state.raw_get(0).unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Nil);
+        state.get_field(0, "y").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(2));
+        state.set_top(1);
+
+        // functions get the table and the key
+        state.new_table();
+        state.push_rust_function(arg_types);
+        state.set_field(-2, "__index").unwrap();
+        state.set_metatable(0);
+        state.get_i(0, 5).unwrap();
+        assert_eq!(state.to_string(-1).unwrap(), "table-number");
+        state.set_top(1);
+
+        // strings share the metatable of their type
+        state.push_string("abc".to_string());
+        state.new_table();
+        state.new_table();
+        state.push_integer(3);
+        state.set_field(-2, "n").unwrap();
+        state.set_field(-2, "__index").unwrap();
+        state.set_metatable(-2);
+        state.push_string("xyz".to_string());
+        state.get_field(-1, "n").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(3));
+        state.set_top(0);
+
+        let err = state
+            .index(LuaValue::Integer(1), LuaValue::Nil)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "attempt to index a number value");
+
+        // __index pointing to the table itself never finds the key
+        state.new_table();
+        state.new_table();
+        state.push_value(-1);
+        state.set_field(-2, "__index").unwrap();
+        state.push_value(-1);
+        state.set_metatable(-2);
+        let err = state.get_field(-1, "missing").unwrap_err();
+        assert_eq!(err.to_string(), "'__index' chain too long; possible loop");
+    }
+
+    #[test]
+    fn test_call_metamethod() {
+        // return t(1)
+        let proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iasbx(OpCodeEnum::OpLOADI, 1, 1),
+                iabc(OpCodeEnum::OpCall, 0, 2, 2, false),
+                iabc(OpCodeEnum::OpReturn1, 0, 0, 0, false),
+            ],
+            vec![LuaValue::String("t".into())],
+            2,
+        );
+        let mut state = LuaState::new();
+        table_with_metamethods(&mut state, &["__call"]);
+        state.load_prototype(proto);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.to_string(0).unwrap(), "table-number");
+
+        state.push_integer(1);
+        let err = state.call(0, 0).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to call a number value");
+    }
+
+    #[test]
+    fn test_compare_metamethods() {
+        fn always(state: &mut LuaState) -> Result<usize, LuaError> {
+            state.push_boolean(true);
+            Ok(1)
+        }
+
+        let mut state = LuaState::new();
+        state.new_table();
+        state.new_table();
+        state.new_table();
+        for event in ["__eq", "__lt", "__le"] {
+            state.push_rust_function(always);
+            state.set_field(-2, event).unwrap();
+        }
+        state.set_metatable(0);
+
+        assert!(state.compare(0, 1, CampareOperator::Equal).unwrap());
+        assert!(state.compare(1, 0, CampareOperator::LessThen).unwrap());
+        assert!(state.compare(0, 1, CampareOperator::LessEqual).unwrap());
+        assert!(!state.raw_equal(0, 1));
+
+        state.push_integer(1);
+        assert!(!state.compare(1, 2, CampareOperator::Equal).unwrap());
+        let err = state.compare(1, 2, CampareOperator::LessThen).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to compare table with number");
+        let err = state.compare(1, 1, CampareOperator::LessEqual).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to compare two table values");
+    }
+
+    #[test]
+    fn test_len_and_concat_metamethods() {
+        let mut state = LuaState::new();
+        table_with_metamethods(&mut state, &["__len", "__concat"]);
+        state.get_global("t").unwrap();
+        state.len(0).unwrap();
+        assert_eq!(state.to_string(-1).unwrap(), "table-table");
+
+        state.push_integer(1);
+        state.push_value(0);
+        state.push_string("x".to_string());
+        state.concat(3).unwrap();
+        // t and "x" are joined first, then 1 and the result
+        assert_eq!(state.to_string(-1).unwrap(), "1table-string");
+
+        state.push_boolean(true);
+        state.push_string("x".to_string());
+        let err = state.concat(2).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to concatenate a boolean value");
+        state.push_boolean(false);
+        let err = state.len(-1).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to get length of a boolean value");
+
+        // rawlen skips __len
+        state.set_top(0);
+        state.push_rust_function(base::rawlen);
+        state.get_global("t").unwrap();
+        state.push_integer(1);
+        state.set_i(-2, 1).unwrap();
+        state.call(1, 1).unwrap();
+        assert_eq!(state.stack.get(0), LuaValue::Integer(1));
+        state.push_rust_function(base::rawlen);
+        state.push_integer(1);
+        let err = state.call(1, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad argument #1 to 'rawlen' (table or string expected)"
+        );
+    }
+
+    #[test]
+    fn test_setmetatable() {
+        let mut state = LuaState::new();
+        state.register("setmetatable", setmetatable).unwrap();
+        state.register("getmetatable", getmetatable).unwrap();
+
+        state.push_rust_function(setmetatable);
+        state.new_table();
+        state.new_table();
+        state.push_string("locked".to_string());
+        state.set_field(-2, "__metatable").unwrap();
+        state.call(2, 1).unwrap();
+
+        state.push_rust_function(getmetatable);
+        state.push_value(0);
+        state.call(1, 1).unwrap();
+        assert_eq!(state.to_string(-1).unwrap(), "locked");
+        state.pop(1);
+
+        state.push_rust_function(setmetatable);
+        state.push_value(0);
+        state.push_nil();
+        let err = state.call(2, 1).unwrap_err();
+        assert_eq!(err.to_string(), "cannot change a protected metatable");
+
+        state.set_top(0);
+        state.push_rust_function(setmetatable);
+        state.push_integer(1);
+        let err = state.call(1, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad argument #1 to 'setmetatable' (table expected, got number)"
+        );
+    }
+
+    /// Run `cmp` on R[0] = K[0] and R[1] = K[1], R[2] tells whether the
+    /// following jump was taken.
+    fn jumps(cmp: Instruction, consts: Vec<LuaValue>) -> bool {
+        let code = vec![
+            iabx(OpCodeEnum::OpLOADK, 0, 0),
+            iabx(OpCodeEnum::OpLOADK, 1, 1),
+            iabc(OpCodeEnum::OpLoadTrue, 2, 0, 0, false),
+            cmp,
+            isj(OpCodeEnum::OpJmp, 1),
+            iabc(OpCodeEnum::OpLoadFalse, 2, 0, 0, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ];
+        let mut state = run_to_return(prototype(code, consts, 3));
+        state.to_boolean(2)
+    }
+
+    #[test]
+    fn test_compare_instructions() {
+        use LuaValue::{Integer, Number};
+        let s = |s: &str| LuaValue::String(s.into());
+        let lt = |k| iabc(OpCodeEnum::OpLt, 0, 1, 0, k);
+        let le = |k| iabc(OpCodeEnum::OpLe, 0, 1, 0, k);
+        let eq = |k| iabc(OpCodeEnum::OpEq, 0, 1, 0, k);
+
+        assert!(jumps(lt(true), vec![Integer(1), Number(1.5)]));
+        assert!(!jumps(lt(false), vec![Integer(1), Number(1.5)]));
+        assert!(jumps(eq(true), vec![Integer(3), Number(3.0)]));
+        assert!(jumps(eq(false), vec![s("3"), Integer(3)]));
+        // exact even where the integer has no float representation
+        let big = (1 << 53) + 1;
+        assert!(!jumps(
+            eq(true),
+            vec![Integer(big), Number((1i64 << 53) as f64)]
+        ));
+        assert!(jumps(
+            lt(true),
+            vec![Number((1i64 << 53) as f64), Integer(big)]
+        ));
+        assert!(jumps(
+            lt(true),
+            vec![Integer(i64::MAX), Number(i64::MAX as f64)]
+        ));
+        assert!(!jumps(le(true), vec![Number(f64::NAN), Number(f64::NAN)]));
+        // strings compare by bytes
+        assert!(jumps(lt(true), vec![s("Z"), s("a")]));
+        assert!(jumps(le(true), vec![s("ab"), s("abc")]));
+        assert!(!jumps(lt(true), vec![s("\u{e9}"), s("z")]));
+
+        let eq_k = iabc(OpCodeEnum::OpEqK, 0, 1, 0, true);
+        assert!(jumps(eq_k, vec![s("x"), s("x")]));
+        let eq_i = iabc(OpCodeEnum::OpEqI, 0, 127 - 2, 0, true);
+        assert!(jumps(eq_i, vec![Number(-2.0), Integer(0)]));
+        // 2 > -1 and not (2 >= 3)
+        let gt_i = iabc(OpCodeEnum::OpGtI, 0, 127 - 1, 0, true);
+        assert!(jumps(gt_i, vec![Integer(2), Integer(0)]));
+        let ge_i = iabc(OpCodeEnum::OpGeI, 0, 127 + 3, 0, true);
+        assert!(!jumps(ge_i, vec![Integer(2), Integer(0)]));
+        let lt_i = iabc(OpCodeEnum::OpLtI, 0, 127 + 3, 1, false);
+        assert!(!jumps(lt_i, vec![Number(2.5), Integer(0)]));
+        let le_i = iabc(OpCodeEnum::OpLeI, 0, 127 + 3, 0, true);
+        assert!(jumps(le_i, vec![Number(3.0), Integer(0)]));
+
+        let test = |k| iabc(OpCodeEnum::OpTest, 0, 0, 0, k);
+        assert!(jumps(test(false), vec![LuaValue::Nil, Integer(0)]));
+        assert!(jumps(test(true), vec![Integer(0), Integer(0)]));
+        assert!(!jumps(
+            test(true),
+            vec![LuaValue::Boolean(false), Integer(0)]
+        ));
+    }
+
+    #[test]
+    fn test_and_or() {
+        // R[2] = R[0] and R[1] (k = 0), R[2] = R[0] or R[1] (k = 1)
+        let run = |a: LuaValue, b: LuaValue, k| {
+            let code = vec![
+                iabx(OpCodeEnum::OpLOADK, 0, 0),
+                iabx(OpCodeEnum::OpLOADK, 1, 1),
+                iabc(OpCodeEnum::OpTestSet, 2, 0, 0, k),
+                isj(OpCodeEnum::OpJmp, 1),
+                iabc(OpCodeEnum::OpMove, 2, 1, 0, false),
+                iabc(OpCodeEnum::OpNOT, 3, 2, 0, false),
+                iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+            ];
+            let mut state = run_to_return(prototype(code, vec![a, b], 4));
+            (state.stack.get(2), state.stack.get(3))
+        };
+        use LuaValue::{Boolean, Integer, Nil};
+
+        assert_eq!(
+            run(Integer(1), Integer(2), false),
+            (Integer(2), Boolean(false))
+        );
+        assert_eq!(run(Nil, Integer(2), false), (Nil, Boolean(true)));
+        assert_eq!(
+            run(Boolean(false), Integer(2), false),
+            (Boolean(false), Boolean(true))
+        );
+        assert_eq!(
+            run(Integer(1), Integer(2), true),
+            (Integer(1), Boolean(false))
+        );
+        assert_eq!(
+            run(Nil, Boolean(false), true),
+            (Boolean(false), Boolean(true))
+        );
+    }
+
+    /// Weak reference to the table on top of the stack.
+    fn weak_table(state: &mut LuaState, idx: i32) -> std::rc::Weak<RefCell<LuaTable>> {
+        match state.stack.get(idx) {
+            LuaValue::Table(t) => Rc::downgrade(&t),
+            val => panic!("not a table: {:?}", val),
+        }
+    }
+
+    #[test]
+    fn test_collect_cycles() {
+        // local t = {}; t.f = function() return t end; return t
+        let f = function(
+            vec![
+                iabc(OpCodeEnum::OpGetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn1, 0, 2, 0, false),
+            ],
+            0,
+            &[(1, 0)],
+            1,
+        );
+        let mut proto = prototype(
+            vec![
+                iabc(OpCodeEnum::OpNEWTABLE, 0, 1, 0, false),
+                iax(OpCodeEnum::OpExtraArg, 0),
+                iabx(OpCodeEnum::OpClosure, 1, 0),
+                iabc(OpCodeEnum::OpSetField, 0, 0, 1, false),
+                iabc(OpCodeEnum::OpReturn, 0, 2, 1, true),
+            ],
+            vec![LuaValue::String("f".into())],
+            2,
+        );
+        proto.prototypes = vec![f];
+        let mut state = load_main(proto).unwrap();
+        let t = weak_table(&mut state, -1);
+
+        // t.self = t
+        state.new_table();
+        state.push_value(-1);
+        state.set_field(-2, "self").unwrap();
+        let u = weak_table(&mut state, -1);
+
+        state.gc(GcOption::Collect);
+        assert!(t.upgrade().is_some() && u.upgrade().is_some());
+        state.set_top(0);
+        state.gc(GcOption::Collect);
+        assert!(t.upgrade().is_none() && u.upgrade().is_none());
+
+        // allocating starts cycles on its own
+        for _ in 0..10_000 {
+            state.new_table();
+            state.push_value(-1);
+            state.set_field(-2, "self").unwrap();
+            state.pop(1);
+        }
+        assert!(state.gc(GcOption::Count) < 1024);
+    }
+
+    #[test]
+    fn test_weak_tables() {
+        let mut state = LuaState::new();
+        let mut weak = |mode: &str| {
+            state.new_table();
+            state.new_table();
+            state.push_string(mode);
+            state.set_field(-2, "__mode").unwrap();
+            state.set_metatable(-2);
+        };
+        weak("k");
+        weak("v");
+        weak("k");
+        let (weak_keys, weak_values, ephemeron) = (0, 1, 2);
+
+        // kept = {}; weak_keys[{}] = 1; weak_keys[kept] = 2
+        state.new_table();
+        state.set_global("kept").unwrap();
+        state.new_table();
+        state.push_integer(1);
+        state.set_table(weak_keys).unwrap();
+        state.get_global("kept").unwrap();
+        state.push_integer(2);
+        state.set_table(weak_keys).unwrap();
+        // weak_values[1] = {}; weak_values[2] = kept; weak_values.x = "str"
+        state.new_table();
+        state.set_i(weak_values, 1).unwrap();
+        state.get_global("kept").unwrap();
+        state.set_i(weak_values, 2).unwrap();
+        state.push_string("str");
+        state.set_field(weak_values, "x").unwrap();
+        // local k = {}; ephemeron[k] = {k}
+        state.new_table();
+        state.new_table();
+        state.push_value(-2);
+        state.set_i(-2, 1).unwrap();
+        state.set_table(ephemeron).unwrap();
+
+        state.gc(GcOption::Collect);
+        let entries = |state: &mut LuaState, idx: i32| match state.stack.get(idx) {
+            LuaValue::Table(t) => {
+                t.borrow().arr.iter().filter(|v| !v.is_nil()).count()
+                    + t.borrow()
+                        .nodes()
+                        .iter()
+                        .filter(|(_, v)| !v.is_nil())
+                        .count()
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(entries(&mut state, weak_keys), 1);
+        assert_eq!(entries(&mut state, weak_values), 2);
+        state.get_i(weak_values, 2).unwrap();
+        state.get_global("kept").unwrap();
+        assert!(state.raw_equal(-1, -2));
+        assert_eq!(entries(&mut state, ephemeron), 0);
+    }
+
+    #[test]
+    fn test_finalizers() {
+        fn finalize(state: &mut LuaState) -> Result<usize, LuaError> {
+            // resurrect the object, its finalizer runs only once
+            state.push_value(0);
+            state.set_global("resurrected").unwrap();
+            state.get_global("count").unwrap();
+            let count = state.to_integer(2).unwrap_or(0);
+            state.push_integer(count + 1);
+            state.set_global("count").unwrap();
+            Ok(0)
+        }
+
+        let mut state = LuaState::new();
+        state.new_table();
+        state.new_table();
+        state.push_rust_function(finalize);
+        state.set_field(-2, "__gc").unwrap();
+        state.set_metatable(-2);
+        let t = weak_table(&mut state, -1);
+
+        state.gc(GcOption::Collect);
+        state.get_global("count").unwrap();
+        assert!(state.stack.get(-1).is_nil());
+        state.set_top(0);
+        state.gc(GcOption::Collect);
+        state.get_global("count").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(1));
+        assert!(t.upgrade().is_some());
+
+        state.push_nil();
+        state.set_global("resurrected").unwrap();
+        state.gc(GcOption::Collect);
+        state.get_global("count").unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(1));
+        assert!(t.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_collectgarbage() {
+        let mut state = LuaState::new();
+        let mut collectgarbage = |args: &[&str]| {
+            state.push_rust_function(base::collectgarbage);
+            for arg in args {
+                state.push_string(*arg);
+            }
+            state.call(args.len(), 1).map(|_| state.stack.pop())
+        };
+
+        assert!(matches!(
+            collectgarbage(&["count"]),
+            Ok(LuaValue::Number(kb)) if kb > 0.0
+        ));
+        assert_eq!(collectgarbage(&[]).unwrap(), LuaValue::Integer(0));
+        assert_eq!(
+            collectgarbage(&["generational"]).unwrap(),
+            LuaValue::String("incremental".into())
+        );
+        assert_eq!(
+            collectgarbage(&["incremental"]).unwrap(),
+            LuaValue::String("generational".into())
+        );
+        assert_eq!(collectgarbage(&["step"]).unwrap(), LuaValue::Boolean(true));
+        collectgarbage(&["stop"]).unwrap();
+        assert_eq!(
+            collectgarbage(&["isrunning"]).unwrap(),
+            LuaValue::Boolean(false)
+        );
+        let err = collectgarbage(&["full"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad argument #1 to 'collectgarbage' (invalid option 'full')"
+        );
+    }
+
+    #[test]
+    fn test_resume_yield() {
+        // function inner(v) return yield(v + 10) end
+        let mut inner = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 2, 0, 127 + 10, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 10, 6, false),
+                iabc(OpCodeEnum::OpTailCall, 1, 2, 0, false),
+                iabc(OpCodeEnum::OpReturn, 1, 0, 0, false),
+            ],
+            vec![LuaValue::String("yield".into())],
+            3,
+        );
+        // function(a) local x = yield(a + 1); return inner(x), "done" end
+        let mut body = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 2, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 6, false),
+                iabc(OpCodeEnum::OpCall, 1, 2, 2, false),
+                iabc(OpCodeEnum::OpGetTabUp, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMove, 3, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 2, 2, 2, false),
+                iabx(OpCodeEnum::OpLOADK, 3, 2),
+                iabc(OpCodeEnum::OpReturn, 2, 3, 0, false),
+            ],
+            vec![
+                LuaValue::String("yield".into()),
+                LuaValue::String("inner".into()),
+                LuaValue::String("done".into()),
+            ],
+            4,
+        );
+        inner.num_params = 1;
+        inner.is_vararg = 0;
+        body.num_params = 1;
+        body.is_vararg = 0;
+
+        let mut state = LuaState::new();
+        state.register("yield", coroutine_yield).unwrap();
+        state.load_prototype(inner);
+        state.set_global("inner").unwrap();
+        state.load_prototype(body);
+        state.new_thread();
+        assert_eq!(state.thread_status(0), Some(ThreadStatus::Suspended));
+
+        let resume = |state: &mut LuaState, arg: i64| {
+            state.set_top(1);
+            state.push_value(0);
+            state.push_integer(arg);
+            state.resume(1)
+        };
+        assert_eq!(resume(&mut state, 1).unwrap(), Resumed::Yielded(1));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(2));
+        // the yield in the nested call
+        assert_eq!(resume(&mut state, 5).unwrap(), Resumed::Yielded(1));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(15));
+        assert_eq!(resume(&mut state, 7).unwrap(), Resumed::Returned(2));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(7));
+        assert_eq!(state.stack.get(2), LuaValue::String("done".into()));
+        assert_eq!(state.thread_status(0), Some(ThreadStatus::Dead));
+
+        let err = resume(&mut state, 0).unwrap_err();
+        assert_eq!(err.to_string(), "cannot resume dead coroutine");
+        assert_eq!(state.get_top(), 2);
+        assert!(state.frames.is_empty());
+
+        // values other than threads and missing coroutines are errors too
+        state.set_top(0);
+        state.push_integer(42);
+        state.push_integer(1);
+        let err = state.resume(1).unwrap_err();
+        assert_eq!(err.to_string(), "cannot resume non-suspended coroutine");
+        assert_eq!(state.get_top(), 1);
+        let err = state.resume(3).unwrap_err();
+        assert_eq!(err.to_string(), "cannot resume non-suspended coroutine");
+    }
+
+    #[test]
+    fn test_yield_across_pcall() {
+        let nested = |code| {
+            let mut f = prototype(
+                code,
+                vec![
+                    LuaValue::String("yield".into()),
+                    LuaValue::String("error".into()),
+                ],
+                5,
+            );
+            f.num_params = 1;
+            f.is_vararg = 0;
+            f.upvalues[0].instack = 0;
+            f
+        };
+        // local function f(x) local r = yield(x); error(r, 0) end
+        let f = nested(vec![
+            iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+            iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+            iabc(OpCodeEnum::OpCall, 1, 2, 2, false),
+            iabc(OpCodeEnum::OpGetTabUp, 2, 0, 1, false),
+            iabc(OpCodeEnum::OpMove, 3, 1, 0, false),
+            iasbx(OpCodeEnum::OpLOADI, 4, 0),
+            iabc(OpCodeEnum::OpCall, 2, 3, 1, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ]);
+        // local function g(x) return yield(x) end
+        let g = nested(vec![
+            iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+            iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+            iabc(OpCodeEnum::OpTailCall, 1, 2, 0, false),
+            iabc(OpCodeEnum::OpReturn, 1, 0, 0, false),
+        ]);
+
+        let run = |f: Prototype, arg: &str| {
+            let mut state = LuaState::new();
+            state.register("pcall", pcall).unwrap();
+            state.register("error", error).unwrap();
+            state.register("yield", coroutine_yield).unwrap();
+            state.push_integer(1);
+            state.set_global("x").unwrap();
+            // return pcall(f, x)
+            state.load_prototype(pcall_chunk(f));
+            state.new_thread();
+
+            state.push_value(0);
+            assert_eq!(state.resume(0).unwrap(), Resumed::Yielded(1));
+            assert_eq!(state.stack.get(1), LuaValue::Integer(1));
+            state.set_top(1);
+            state.push_value(0);
+            state.push_string(arg);
+            assert_eq!(state.resume(1).unwrap(), Resumed::Returned(2));
+            (state.stack.get(1), state.stack.get(2))
+        };
+
+        let (ok, e) = run(f, "boom");
+        assert_eq!(ok, LuaValue::Boolean(false));
+        assert_eq!(e, LuaValue::String("boom".into()));
+        let (ok, val) = run(g, "fine");
+        assert_eq!(ok, LuaValue::Boolean(true));
+        assert_eq!(val, LuaValue::String("fine".into()));
+    }
+
+    #[test]
+    fn test_upvalues_shared_with_coroutine() {
+        // function() v = v + 10; yield(); v = v + 100 end
+        let mut f = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 0, 0, 127 + 10, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 10, 6, false),
+                iabc(OpCodeEnum::OpSetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpGetTabUp, 0, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 0, 1, 1, false),
+                iabc(OpCodeEnum::OpGetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpADDI, 0, 0, 127 + 100, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 100, 6, false),
+                iabc(OpCodeEnum::OpSetUpval, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+            ],
+            vec![LuaValue::String("yield".into())],
+            1,
+        );
+        f.is_vararg = 0;
+        f.upvalues = vec![
+            Upvalue {
+                instack: 1,
+                index: 0,
+                kind: 0,
+            },
+            Upvalue {
+                instack: 0,
+                index: 0,
+                kind: 0,
+            },
+        ];
+        // local v = 1
+        // local co = create(f)
+        // resume(co); local a = v
+        // resume(co); return a, v
+        let mut proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 1),
+                iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+                iabx(OpCodeEnum::OpClosure, 2, 0),
+                iabc(OpCodeEnum::OpCall, 1, 2, 2, false),
+                iabc(OpCodeEnum::OpGetTabUp, 2, 0, 1, false),
+                iabc(OpCodeEnum::OpMove, 3, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 2, 2, 1, false),
+                iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+                iabc(OpCodeEnum::OpGetTabUp, 3, 0, 1, false),
+                iabc(OpCodeEnum::OpMove, 4, 1, 0, false),
+                iabc(OpCodeEnum::OpCall, 3, 2, 1, false),
+                iabc(OpCodeEnum::OpMove, 3, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn, 2, 3, 0, false),
+            ],
+            vec![
+                LuaValue::String("create".into()),
+                LuaValue::String("resume".into()),
+            ],
+            5,
+        );
+        proto.prototypes = vec![Rc::new(f)];
+
+        let mut state = LuaState::new();
+        state.register("create", coroutine::create).unwrap();
+        state.register("resume", coroutine::resume).unwrap();
+        state.register("yield", coroutine_yield).unwrap();
+        state.load_prototype(proto);
+        state.call(0, 2).unwrap();
+        assert_eq!(state.stack.get(0), LuaValue::Integer(11));
+        assert_eq!(state.stack.get(1), LuaValue::Integer(111));
+    }
+
+    /// `local n, last = 0; for i = init, limit, step do n = n + 1; last = i end;
+    /// return n, last`
+    fn run_numeric_for(
+        init: LuaValue,
+        limit: LuaValue,
+        step: LuaValue,
+    ) -> Result<(i64, LuaValue), LuaError> {
+        let proto = prototype(
+            vec![
+                iasbx(OpCodeEnum::OpLOADI, 0, 0),
+                iabc(OpCodeEnum::OpLOADNIL, 1, 0, 0, false),
+                iabx(OpCodeEnum::OpLOADK, 2, 0),
+                iabx(OpCodeEnum::OpLOADK, 3, 1),
+                iabx(OpCodeEnum::OpLOADK, 4, 2),
+                iabx(OpCodeEnum::OpForPrep, 2, 3),
+                iabc(OpCodeEnum::OpADDI, 0, 0, 127 + 1, false),
+                iabc(OpCodeEnum::OpMmbinI, 0, 127 + 1, 6, false),
+                iabc(OpCodeEnum::OpMove, 1, 5, 0, false),
+                iabx(OpCodeEnum::OpForLoop, 2, 4),
+                iabc(OpCodeEnum::OpReturn, 0, 3, 0, false),
+            ],
+            vec![init, limit, step],
+            6,
+        );
+        let mut state = LuaState::new();
+        state.load_prototype(proto);
+        state.call(0, 2)?;
+        Ok((state.to_integer(0).unwrap(), state.stack.get(1)))
+    }
+
+    #[test]
+    fn test_numeric_for() {
+        use LuaValue::{Integer, Nil, Number};
+
+        let count = |init, limit, step| run_numeric_for(init, limit, step).unwrap();
+        assert_eq!(count(Integer(1), Integer(3), Integer(1)), (3, Integer(3)));
+        assert_eq!(count(Integer(3), Integer(1), Integer(-1)), (3, Integer(1)));
+        assert_eq!(count(Integer(1), Integer(0), Integer(1)), (0, Nil));
+        // the index never wraps around
+        let max = i64::MAX;
+        assert_eq!(
+            count(Integer(max - 2), Integer(max), Integer(1)),
+            (3, Integer(max))
+        );
+        assert_eq!(
+            count(Integer(i64::MIN), Integer(i64::MIN + 4), Integer(2)),
+            (3, Integer(i64::MIN + 4))
+        );
+        assert_eq!(
+            count(Integer(1), Integer(max), Integer(max)),
+            (1, Integer(1))
+        );
+        assert_eq!(
+            count(Integer(-1), Integer(i64::MIN), Integer(i64::MIN)),
+            (1, Integer(-1))
+        );
+        // float limits are rounded and clipped for integer loops
+        assert_eq!(count(Integer(1), Number(3.5), Integer(1)), (3, Integer(3)));
+        assert_eq!(
+            count(Integer(-1), Number(-3.5), Integer(-1)),
+            (3, Integer(-3))
+        );
+        assert_eq!(
+            count(Integer(1), Number(1e300), Integer(max)),
+            (1, Integer(1))
+        );
+        assert_eq!(count(Integer(1), Number(-1e300), Integer(1)), (0, Nil));
+        assert_eq!(count(Integer(1), Number(f64::NAN), Integer(1)), (0, Nil));
+        // a float initial value or step makes a float loop
+        assert_eq!(
+            count(Number(1.0), Integer(2), Number(0.5)),
+            (3, Number(2.0))
+        );
+        assert_eq!(count(Integer(1), Integer(2), Number(0.5)), (3, Number(2.0)));
+        assert_eq!(
+            count(Number(0.1), Number(0.3), Number(0.1)),
+            (2, Number(0.2))
+        );
+
+        let error = |init, limit, step| run_numeric_for(init, limit, step).unwrap_err().to_string();
+        assert_eq!(
+            error(Integer(1), Integer(10), Integer(0)),
+            "test:-1: 'for' step is zero"
+        );
+        assert_eq!(
+            error(Number(1.0), Integer(10), Number(0.0)),
+            "test:-1: 'for' step is zero"
+        );
+        assert_eq!(
+            error(LuaValue::String("1".into()), Integer(2), Integer(1)),
+            "test:-1: 'for' initial value must be a number"
+        );
+        assert_eq!(
+            error(Integer(1), Nil, Integer(1)),
+            "test:-1: 'for' limit must be a number"
+        );
+        assert_eq!(
+            error(Integer(1), Integer(2), Nil),
+            "test:-1: 'for' step must be a number"
+        );
+    }
+
+    #[test]
+    fn test_generic_for() {
+        /// Iterator yielding `c + 1, (c + 1) * 10` while `c < 3`.
+        fn iter(state: &mut LuaState) -> Result<usize, LuaError> {
+            let c = state.to_integer(1).unwrap();
+            if c >= 3 {
+                state.push_nil();
+                return Ok(1);
+            }
+            state.push_integer(c + 1);
+            state.push_integer((c + 1) * 10);
+            Ok(2)
+        }
+
+        // local sum = 0
+        // for k, v in iter, nil, 0, closing do sum = sum + v end
+        // return sum
+        let proto = |closing: Instruction| {
+            prototype(
+                vec![
+                    iasbx(OpCodeEnum::OpLOADI, 0, 0),
+                    iabc(OpCodeEnum::OpGetTabUp, 1, 0, 0, false),
+                    iabc(OpCodeEnum::OpLOADNIL, 2, 0, 0, false),
+                    iasbx(OpCodeEnum::OpLOADI, 3, 0),
+                    closing,
+                    iabx(OpCodeEnum::OpTForPrep, 1, 2),
+                    iabc(OpCodeEnum::OpAdd, 0, 0, 6, false),
+                    iabc(OpCodeEnum::OpMmbin, 0, 6, 6, false),
+                    iabc(OpCodeEnum::OpTForCall, 1, 0, 2, false),
+                    iabx(OpCodeEnum::OpTForLoop, 1, 4),
+                    iabc(OpCodeEnum::OpReturn1, 0, 0, 0, false),
+                ],
+                vec![LuaValue::String("iter".into())],
+                7,
+            )
+        };
+
+        let mut state = LuaState::new();
+        state.register("iter", iter).unwrap();
+        state.load_prototype(proto(iabc(OpCodeEnum::OpLOADNIL, 4, 0, 0, false)));
+        state.call(0, 1).unwrap();
+        assert_eq!(state.stack.get(0), LuaValue::Integer(60));
+
+        state.set_top(0);
+        state.load_prototype(proto(iabc(OpCodeEnum::OpLoadTrue, 4, 0, 0, false)));
+        let err = state.call(0, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:-1: variable '(for state)' got a non-closable value"
+        );
+    }
+
+    #[test]
+    fn test_varargs() {
+        // local a, b = ...
+        // return a, b, select('#', ...), ...
+        let proto = || {
+            prototype(
+                vec![
+                    iabc(OpCodeEnum::OpVarArgPrep, 0, 0, 0, false),
+                    iabc(OpCodeEnum::OpVararg, 0, 0, 3, false),
+                    iabc(OpCodeEnum::OpGetTabUp, 2, 0, 0, false),
+                    iabx(OpCodeEnum::OpLOADK, 3, 1),
+                    iabc(OpCodeEnum::OpVararg, 4, 0, 0, false),
+                    iabc(OpCodeEnum::OpCall, 2, 0, 2, false),
+                    iabc(OpCodeEnum::OpVararg, 3, 0, 0, false),
+                    iabc(OpCodeEnum::OpReturn, 0, 0, 0, false),
+                ],
+                vec![
+                    LuaValue::String("select".into()),
+                    LuaValue::String("#".into()),
+                ],
+                5,
+            )
+        };
+        let run = |args: &[i64]| {
+            let mut state = LuaState::new();
+            state.register("select", base::select).unwrap();
+            state.load_prototype(proto());
+            args.iter().for_each(|&arg| state.push_integer(arg));
+            state.call(args.len(), LUA_MULTRET).unwrap();
+            (0..state.get_top() as i32)
+                .map(|idx| state.stack.get(idx))
+                .collect::<Vec<_>>()
+        };
+        use LuaValue::{Integer, Nil};
+        assert_eq!(
+            run(&[10, 20, 30]),
+            [
+                Integer(10),
+                Integer(20),
+                Integer(3),
+                Integer(10),
+                Integer(20),
+                Integer(30)
+            ]
+        );
+        assert_eq!(run(&[10]), [Integer(10), Nil, Integer(1), Integer(10)]);
+        assert_eq!(run(&[]), [Nil, Nil, Integer(0)]);
+    }
+
+    /// State with a global `obj` whose `__close` metamethod logs the error
+    /// objects it is called with.
+    fn state_with_closable() -> (LuaState, Rc<RefCell<Vec<LuaValue>>>) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut state = LuaState::new();
+        state.register("error", error).unwrap();
+        state.register("yield", coroutine_yield).unwrap();
+        state.new_table();
+        state.new_table();
+        let closed = log.clone();
+        state.push_rust_closure(move |state| {
+            closed.borrow_mut().push(state.stack.get(1));
+            Ok(0)
+        });
+        state.set_field(-2, "__close").unwrap();
+        state.set_metatable(-2);
+        state.set_global("obj").unwrap();
+        (state, log)
+    }
+
+    /// `local x <close> = obj` followed by `code`.
+    fn closing_prototype(mut code: Vec<Instruction>) -> Prototype {
+        code.splice(
+            0..0,
+            [
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpTbc, 0, 0, 0, false),
+            ],
+        );
+        let end_pc = code.len() as i32;
+        let mut proto = prototype(
+            code,
+            vec![
+                LuaValue::String("obj".into()),
+                LuaValue::String("error".into()),
+                LuaValue::String("boom".into()),
+                LuaValue::String("yield".into()),
+            ],
+            4,
+        );
+        proto.local_variable = vec![LocalVariable {
+            var_name: "x".to_string(),
+            start_pc: 1,
+            end_pc,
+        }];
+        proto
+    }
+
+    #[test]
+    fn test_to_be_closed() {
+        // do local x <close> = obj end; return 7
+        let (mut state, log) = state_with_closable();
+        state.load_prototype(closing_prototype(vec![
+            iabc(OpCodeEnum::OpClose, 0, 0, 0, false),
+            iasbx(OpCodeEnum::OpLOADI, 1, 7),
+            iabc(OpCodeEnum::OpReturn1, 1, 0, 0, false),
+        ]));
+        state.call(0, 1).unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(7));
+        assert_eq!(*log.borrow(), [LuaValue::Nil]);
+
+        // local x <close> = obj; return 5, x
+        let (mut state, log) = state_with_closable();
+        state.load_prototype(closing_prototype(vec![
+            iasbx(OpCodeEnum::OpLOADI, 1, 5),
+            iabc(OpCodeEnum::OpMove, 2, 0, 0, false),
+            iabc(OpCodeEnum::OpReturn, 1, 3, 0, true),
+        ]));
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.get_top(), 2);
+        assert_eq!(state.stack.get(0), LuaValue::Integer(5));
+        assert_eq!(*log.borrow(), [LuaValue::Nil]);
+
+        // local x <close> = obj; error("boom", 0)
+        let (mut state, log) = state_with_closable();
+        state.load_prototype(closing_prototype(vec![
+            iabc(OpCodeEnum::OpGetTabUp, 1, 0, 1, false),
+            iabx(OpCodeEnum::OpLOADK, 2, 2),
+            iasbx(OpCodeEnum::OpLOADI, 3, 0),
+            iabc(OpCodeEnum::OpCall, 1, 3, 1, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ]));
+        let err = state.pcall(0, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+        assert_eq!(*log.borrow(), [LuaValue::String("boom".into())]);
+        assert!(state.frames.is_empty());
+
+        // local x <close> = true
+        let (mut state, log) = state_with_closable();
+        let mut proto = closing_prototype(vec![iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false)]);
+        proto.code[0] = iabc(OpCodeEnum::OpLoadTrue, 0, 0, 0, false);
+        state.load_prototype(proto);
+        let err = state.call(0, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:-1: variable 'x' got a non-closable value"
+        );
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_close_error_replaces_error() {
+        let (mut state, log) = state_with_closable();
+        state.new_table();
+        state.new_table();
+        state.push_rust_function(|state| {
+            state.push_string("close failed");
+            Err(state.error())
+        });
+        state.set_field(-2, "__close").unwrap();
+        state.set_metatable(-2);
+        state.set_global("bad").unwrap();
+
+        // local x <close> = bad; local y <close> = obj; error("boom")
+        state.load_prototype(prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetTabUp, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpTbc, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpGetTabUp, 1, 0, 3, false),
+                iabc(OpCodeEnum::OpTbc, 1, 0, 0, false),
+                iabc(OpCodeEnum::OpGetTabUp, 2, 0, 1, false),
+                iabx(OpCodeEnum::OpLOADK, 3, 2),
+                iabc(OpCodeEnum::OpCall, 2, 2, 1, false),
+                iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+            ],
+            vec![
+                LuaValue::String("bad".into()),
+                LuaValue::String("error".into()),
+                LuaValue::String("boom".into()),
+                LuaValue::String("obj".into()),
+            ],
+            4,
+        ));
+        let err = state.pcall(0, 0, None).unwrap_err();
+        // y is closed first with the original error, then x replaces it
+        assert_eq!(err.to_string(), "close failed");
+        assert_eq!(*log.borrow(), [LuaValue::String("boom".into())]);
+        assert_eq!(state.get_top(), 1);
+    }
+
+    #[test]
+    fn test_close_suspended_coroutine() {
+        // local x <close> = obj; yield(); return
+        let (mut state, log) = state_with_closable();
+        state.load_prototype(closing_prototype(vec![
+            iabc(OpCodeEnum::OpGetTabUp, 1, 0, 3, false),
+            iabc(OpCodeEnum::OpCall, 1, 1, 1, false),
+            iabc(OpCodeEnum::OpReturn0, 0, 0, 0, false),
+        ]));
+        state.new_thread();
+        state.push_value(0);
+        assert_eq!(state.resume(0).unwrap(), Resumed::Yielded(0));
+        assert!(log.borrow().is_empty());
+
+        state.close_thread(0).unwrap();
+        assert_eq!(*log.borrow(), [LuaValue::Nil]);
+        assert_eq!(state.thread_status(0), Some(ThreadStatus::Dead));
+        assert!(state.is_main_thread());
+    }
+
+    /// Run a fixture main chunk with `args` and return all its results.
+    fn run_fixture(filename: &'static str, args: &[i64]) -> Vec<LuaValue> {
+        let mut state = LuaState::new();
+        state.load_prototype(read_prototype_fixture(filename));
+        args.iter().for_each(|&arg| state.push_integer(arg));
+        state.call(args.len(), LUA_MULTRET).unwrap();
+        (0..state.get_top() as i32)
+            .map(|idx| state.stack.get(idx))
+            .collect()
+    }
+
+    /// Array size the table in register 0 of `proto` was created with.
+    fn new_table_capacity(proto: Prototype) -> usize {
+        let mut state = new_main_state(proto);
+        let op_code = |state: &LuaState| {
+            OpCodeEnum::try_from(state.get_code(state.get_pc()).op_code()).unwrap()
+        };
+        while !matches!(op_code(&state), OpCodeEnum::OpNEWTABLE) {
+            step(&mut state).unwrap();
+        }
+        let a = state.get_code(state.get_pc()).abc().0;
+        step(&mut state).unwrap();
+        match state.stack.get(a) {
+            LuaValue::Table(table) => table.borrow().arr.capacity(),
+            val => panic!("NEWTABLE made a {} value", val.type_name()),
+        }
+    }
+
+    #[test]
+    fn test_table_constructor_with_call() {
+        // local function f() return 3, 4, 5 end
+        // local t = {1, 2, f()}
+        // return #t, t[5]
+        //
+        // 1       [1]     VARARGPREP      0
+        // 2       [1]     CLOSURE         0 0     ; 0x...
+        // 3       [2]     NEWTABLE        1 0 2   ; 2
+        // 4       [2]     EXTRAARG        0
+        // 5       [2]     LOADI           2 1
+        // 6       [2]     LOADI           3 2
+        // 7       [2]     MOVE            4 0
+        // 8       [2]     CALL            4 1 0   ; 0 in all out
+        // 9       [2]     SETLIST         1 0 0
+        // 10      [3]     LEN             2 1
+        // 11      [3]     GETI            3 1 5
+        // 12      [3]     RETURN          2 3 1   ; 2 out
+        // 13      [3]     RETURN          2 1 1   ; 0 out
+        use LuaValue::Integer;
+        assert_eq!(
+            run_fixture("table-multret.luac", &[]),
+            [Integer(5), Integer(5)]
+        );
+        let proto = read_prototype_fixture("table-multret.luac");
+        assert!(new_table_capacity(proto) >= 2);
+    }
+
+    #[test]
+    fn test_table_constructor_with_varargs() {
+        // local t = {1, 2, 3, ...}
+        // return #t, t[4], t[#t]
+        //
+        // 1       [1]     VARARGPREP      0
+        // 2       [1]     NEWTABLE        0 0 3   ; 3
+        // 3       [1]     EXTRAARG        0
+        // 4       [1]     LOADI           1 1
+        // 5       [1]     LOADI           2 2
+        // 6       [1]     LOADI           3 3
+        // 7       [1]     VARARG          4 0     ; all out
+        // 8       [1]     SETLIST         0 0 0
+        // 9       [2]     LEN             1 0
+        // 10      [2]     GETI            2 0 4
+        // 11      [2]     LEN             3 0
+        // 12      [2]     GETTABLE        3 0 3
+        // 13      [2]     RETURN          1 4 1   ; 3 out
+        // 14      [2]     RETURN          1 1 1   ; 0 out
+        use LuaValue::{Integer, Nil};
+        assert_eq!(
+            run_fixture("table-vararg.luac", &[10, 20]),
+            [Integer(5), Integer(10), Integer(20)]
+        );
+        assert_eq!(
+            run_fixture("table-vararg.luac", &[]),
+            [Integer(3), Nil, Integer(3)]
+        );
+        let proto = read_prototype_fixture("table-vararg.luac");
+        assert!(new_table_capacity(proto) >= 3);
+    }
+
+    #[test]
+    fn test_large_table_constructor() {
+        // local t = {1, 2, 3, ..., 310}
+        // return #t, t[200], t[#t]
+        //
+        // 1       [1]     VARARGPREP      0
+        // 2       [1]     NEWTABLE        0 0 54k ; 310
+        // 3       [1]     EXTRAARG        1
+        // 4       [1]     LOADI           1 1
+        // ...
+        // 54      [1]     SETLIST         0 50 0
+        // ...
+        // 319     [1]     SETLIST         0 10 44k
+        // 320     [1]     EXTRAARG        1
+        // 321     [2]     LEN             1 0
+        // ...
+        use LuaValue::Integer;
+        assert_eq!(
+            run_fixture("table-large.luac", &[]),
+            [Integer(310), Integer(200), Integer(310)]
+        );
+        let proto = read_prototype_fixture("table-large.luac");
+        assert!(new_table_capacity(proto) >= 310);
+    }
+
+    #[test]
+    fn test_method_call() {
+        // function Account:deposit(v) self.balance = self.balance + v end
+        let mut deposit = prototype(
+            vec![
+                iabc(OpCodeEnum::OpGetField, 2, 0, 0, false),
+                iabc(OpCodeEnum::OpAdd, 2, 2, 1, false),
+                iabc(OpCodeEnum::OpMmbin, 2, 1, 6, false),
+                iabc(OpCodeEnum::OpSetField, 0, 0, 2, false),
+                iabc(OpCodeEnum::OpReturn0, 0, 1, 0, false),
+            ],
+            vec![LuaValue::String("balance".into())],
+            3,
+        );
+        deposit.num_params = 2;
+        deposit.is_vararg = 0;
+        deposit.upvalues.clear();
+
+        // local Account = {balance = 0}
+        // function Account:deposit(v) ... end
+        // Account:deposit(10)
+        // Account:deposit(5)
+        // return Account.balance
+        let mut main = prototype(
+            vec![
+                iabc(OpCodeEnum::OpVarArgPrep, 0, 0, 0, false),
+                iabc(OpCodeEnum::OpNEWTABLE, 0, 1, 0, false),
+                iax(OpCodeEnum::OpExtraArg, 0),
+                iabc(OpCodeEnum::OpSetField, 0, 0, 1, true),
+                iabx(OpCodeEnum::OpClosure, 1, 0),
+                iabc(OpCodeEnum::OpSetField, 0, 2, 1, false),
+                iabc(OpCodeEnum::OpSELF, 1, 0, 2, true),
+                iasbx(OpCodeEnum::OpLOADI, 3, 10),
+                iabc(OpCodeEnum::OpCall, 1, 3, 1, false),
+                iabc(OpCodeEnum::OpSELF, 1, 0, 2, true),
+                iasbx(OpCodeEnum::OpLOADI, 3, 5),
+                iabc(OpCodeEnum::OpCall, 1, 3, 1, false),
+                iabc(OpCodeEnum::OpGetField, 1, 0, 0, false),
+                iabc(OpCodeEnum::OpReturn, 1, 2, 1, false),
+            ],
+            vec![
+                LuaValue::String("balance".into()),
+                LuaValue::Integer(0),
+                LuaValue::String("deposit".into()),
+            ],
+            4,
+        );
+        main.prototypes = vec![Rc::new(deposit)];
+
+        let mut state = LuaState::new();
+        state.load_prototype(main);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.stack.get(-1), LuaValue::Integer(15));
+    }
+
+    /// Compile and run `source` with the base library, returns its results.
+    fn run_source(source: &str) -> Vec<LuaValue> {
+        let mut state = LuaState::new();
+        state.open_base().unwrap();
+        state
+            .load(source.as_bytes().to_vec(), "=test", None)
+            .unwrap();
+        state.call(0, LUA_MULTRET).unwrap();
+        (0..state.get_top() as i32)
+            .map(|idx| state.stack.get(idx))
+            .collect()
+    }
+
+    #[test]
+    fn test_method_call_from_source() {
+        let results = run_source(
+            r#"
+            Account = {balance = 0}
+            function Account.new(balance)
+                return setmetatable({balance = balance}, {__index = Account})
+            end
+            function Account:deposit(v)
+                self.balance = self.balance + v
+            end
+            function Account:withdraw(v)
+                if v > self.balance then
+                    error("insufficient funds")
+                end
+                self.balance = self.balance - v
+            end
+
+            local acc = Account.new(100)
+            acc:deposit(10)
+            acc:withdraw(30)
+            return acc.balance, Account.balance, pcall(acc.withdraw, acc, 1000)
+            "#,
+        );
+        assert_eq!(
+            results,
+            [
+                LuaValue::Integer(80),
+                LuaValue::Integer(0),
+                LuaValue::Boolean(false),
+                LuaValue::String("insufficient funds".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compiled_chunk() {
+        use LuaValue::*;
+
+        // closures share the upvalues of a block, each iteration has its own
+        let results = run_source(
+            r#"
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end
+            end
+            local c1, c2 = counter(), counter()
+            c1() c1()
+
+            local fs, i = {}, 1
+            while true do
+                local j = i
+                fs[i] = function() return j end
+                if i == 3 then break end
+                i = i + 1
+            end
+            return c1(), c2(), fs[1]() + fs[2]() * 10 + fs[3]() * 100
+            "#,
+        );
+        assert_eq!(results, [Integer(3), Integer(1), Integer(321)]);
+
+        // multiple results, varargs and table constructors
+        let results = run_source(
+            r#"
+            local function f(...) return ... end
+            local a, b, c = f(1, 2)
+            local t = {f(1, 2, 3)}
+            local u = {f(1, 2, 3), 10, x = "x", [2 + 1] = 30}
+            return a, b, c, #t, #u, u[3], u.x, (f(7, 8))
+            "#,
+        );
+        assert_eq!(
+            results,
+            [
+                Integer(1),
+                Integer(2),
+                Nil,
+                Integer(3),
+                Integer(3),
+                Integer(30),
+                String("x".into()),
+                Integer(7),
+            ]
+        );
+
+        // operators and control flow
+        let results = run_source(
+            r#"
+            local n, s = 0, ""
+            repeat
+                n = n + 1
+                if n % 2 == 0 then s = s .. n elseif n > 4 then s = s .. "!" else s = s .. "-" end
+            until n >= 5
+            local x = nil
+            return s, x or "default", x and 1, 2 ^ -1, 7 // 2, -(3 - 5), not x, 1 < 2, "a" >= "b"
+            "#,
+        );
+        assert_eq!(
+            results,
+            [
+                String("-2-4!".into()),
+                String("default".into()),
+                Nil,
+                Number(0.5),
+                Integer(3),
+                Integer(2),
+                Boolean(true),
+                Boolean(true),
+                Boolean(false),
+            ]
+        );
     }
 }
@@ -1,10 +1,20 @@
 pub mod binary_chunk;
+pub mod codec;
+pub mod gc;
 pub mod instruction;
+pub mod lua_closure;
+pub mod lua_error;
+pub mod lua_string;
+pub mod lua_table;
+pub mod lua_thread;
 pub mod lua_value;
+pub mod number;
 pub mod op_code;
 pub mod reader;
+pub mod tag_method;
 pub mod undump;
 
+pub mod call_frame;
 pub mod lua_stack;
 pub mod lua_state;
 
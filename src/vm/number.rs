@@ -0,0 +1,457 @@
+use super::lua_value::LuaValue;
+
+/// Whitespace as accepted around numerals in strings.
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c')
+}
+
+fn trim(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&c| !is_space(c)).unwrap_or(s.len());
+    let end = s
+        .iter()
+        .rposition(|&c| !is_space(c))
+        .map_or(start, |i| i + 1);
+    &s[start..end]
+}
+
+/// Split an optional sign off, `true` when negative.
+fn sign(s: &[u8]) -> (bool, &[u8]) {
+    match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    }
+}
+
+/// Digits of a hexadecimal numeral, after its `0x` or `0X`.
+fn hex_digits(s: &[u8]) -> Option<&[u8]> {
+    match s {
+        [b'0', b'x' | b'X', rest @ ..] => Some(rest),
+        _ => None,
+    }
+}
+
+fn hex_value(c: u8) -> u32 {
+    (c as char).to_digit(16).unwrap_or(0)
+}
+
+/// Value of a numeral in a string, `lua_stringtonumber`: an integer when it
+/// has neither dot nor exponent and fits, a float otherwise. Surrounding
+/// whitespace and a sign are accepted, hexadecimal integers wrap around.
+pub fn str_to_number(s: &[u8]) -> Option<LuaValue> {
+    let s = trim(s);
+    if let Some(i) = str_to_integer(s) {
+        return Some(LuaValue::Integer(i));
+    }
+    str_to_float(s).map(LuaValue::Number)
+}
+
+fn str_to_integer(s: &[u8]) -> Option<i64> {
+    let (neg, s) = sign(s);
+    let mut a: i64 = 0;
+    if let Some(digits) = hex_digits(s) {
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        for &c in digits {
+            a = a.wrapping_mul(16).wrapping_add(hex_value(c) as i64);
+        }
+    } else {
+        if s.is_empty() || !s.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        // accumulate negatively, so that the minimum integer fits
+        for &c in s {
+            a = a.checked_mul(10)?.checked_sub((c - b'0') as i64)?;
+        }
+        if !neg {
+            a = a.checked_neg()?;
+        }
+        return Some(a);
+    }
+    Some(if neg { a.wrapping_neg() } else { a })
+}
+
+/// Length of the leading run of digits matching `is_digit`.
+fn digits_len(s: &[u8], is_digit: fn(&u8) -> bool) -> usize {
+    s.iter().take_while(|c| is_digit(c)).count()
+}
+
+fn str_to_float(s: &[u8]) -> Option<f64> {
+    let (neg, body) = sign(s);
+    let n = match hex_digits(body) {
+        Some(digits) => hex_to_float(digits)?,
+        None => {
+            // validate the grammar, rust would accept "inf" or "nan" too
+            let int_len = digits_len(body, u8::is_ascii_digit);
+            let mut i = int_len;
+            let mut frac_len = 0;
+            if body.get(i) == Some(&b'.') {
+                frac_len = digits_len(&body[i + 1..], u8::is_ascii_digit);
+                i += 1 + frac_len;
+            }
+            if int_len + frac_len == 0 {
+                return None;
+            }
+            if matches!(body.get(i), Some(b'e' | b'E')) {
+                let (_, exp) = sign(&body[i + 1..]);
+                let exp_len = digits_len(exp, u8::is_ascii_digit);
+                if exp_len == 0 {
+                    return None;
+                }
+                i = body.len() - exp.len() + exp_len;
+            }
+            if i != body.len() {
+                return None;
+            }
+            std::str::from_utf8(body).ok()?.parse::<f64>().ok()?
+        }
+    };
+    Some(if neg { -n } else { n })
+}
+
+/// Hexadecimal float, `lua_strx2number`: hex digits with an optional dot
+/// and an optional binary exponent `p`.
+fn hex_to_float(s: &[u8]) -> Option<f64> {
+    // more significant digits than a float can hold are only counted
+    const MAX_SIG_DIGITS: usize = 30;
+    let mut r = 0.0;
+    let mut e: i64 = 0;
+    let mut sig_digits = 0;
+    let mut any_digit = false;
+    let mut dot = false;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        if c == b'.' {
+            if dot {
+                break;
+            }
+            dot = true;
+        } else if c.is_ascii_hexdigit() {
+            any_digit = true;
+            if sig_digits == 0 && c == b'0' {
+                // leading zeros are not significant
+                if dot {
+                    e -= 4;
+                }
+            } else if sig_digits < MAX_SIG_DIGITS {
+                sig_digits += 1;
+                r = r * 16.0 + hex_value(c) as f64;
+                if dot {
+                    e -= 4;
+                }
+            } else if !dot {
+                e += 4;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if !any_digit {
+        return None;
+    }
+    if matches!(s.get(i), Some(b'p' | b'P')) {
+        let (neg, exp) = sign(&s[i + 1..]);
+        let exp_len = digits_len(exp, u8::is_ascii_digit);
+        if exp_len == 0 {
+            return None;
+        }
+        let mut exp_value: i64 = 0;
+        for &c in &exp[..exp_len] {
+            exp_value = exp_value
+                .saturating_mul(10)
+                .saturating_add((c - b'0') as i64);
+        }
+        e = e.saturating_add(if neg { -exp_value } else { exp_value });
+        i = s.len() - exp.len() + exp_len;
+    }
+    if i != s.len() {
+        return None;
+    }
+    Some(ldexp(r, e))
+}
+
+/// `x * 2^e` without overflowing the power on the way.
+fn ldexp(x: f64, e: i64) -> f64 {
+    let e = e.clamp(-2200, 2200) as i32;
+    x * 2f64.powi(e / 2) * 2f64.powi(e - e / 2)
+}
+
+/// Integer written in `base`, 2 to 36, as `tonumber(s, base)` reads it:
+/// letters are digits from 10 on, and the value wraps around.
+pub fn str_to_integer_base(s: &[u8], base: u32) -> Option<i64> {
+    let (neg, digits) = sign(trim(s));
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for &c in digits {
+        let d = (c as char).to_digit(36).filter(|&d| d < base)?;
+        n = n.wrapping_mul(base as i64).wrapping_add(d as i64);
+    }
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+/// String form of a number as Lua writes it, `None` for other values.
+pub fn number_to_str(val: &LuaValue) -> Option<String> {
+    match val {
+        LuaValue::Integer(i) => Some(i.to_string()),
+        LuaValue::Number(n) => Some(float_to_str(*n)),
+        _ => None,
+    }
+}
+
+/// A float with the `%.14g` format, floats looking like integers get a
+/// `.0` suffix to tell them apart.
+pub fn float_to_str(n: f64) -> String {
+    let mut s = format_g(n, 14, false);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+/// Spelling of infinities and NaN in C, `None` for finite floats.
+fn non_finite(n: f64) -> Option<String> {
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    if n.is_nan() {
+        Some(format!("{}nan", sign))
+    } else if n.is_infinite() {
+        Some(format!("{}inf", sign))
+    } else {
+        None
+    }
+}
+
+/// Mantissa and exponent of `n` in exponent notation with `precision`
+/// decimals.
+fn exp_parts(n: f64, precision: usize) -> (String, i32) {
+    let e = format!("{:.*e}", precision, n);
+    let (mantissa, exp) = e.split_once('e').unwrap();
+    (mantissa.to_string(), exp.parse().unwrap())
+}
+
+/// Exponent of a C exponent notation, signed and with at least two digits.
+fn exp_suffix(marker: char, exp: i32) -> String {
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}{}{:02}", marker, sign, exp.abs())
+}
+
+/// The C format `%.{precision}e`, with `alt` the `#` flag keeping the dot
+/// without decimals.
+pub fn format_e(n: f64, precision: usize, alt: bool) -> String {
+    if let Some(s) = non_finite(n) {
+        return s;
+    }
+    let (mut mantissa, exp) = exp_parts(n, precision);
+    if alt && precision == 0 {
+        mantissa.push('.');
+    }
+    mantissa + &exp_suffix('e', exp)
+}
+
+/// The C format `%.{precision}f`, with `alt` the `#` flag keeping the dot
+/// without decimals.
+pub fn format_f(n: f64, precision: usize, alt: bool) -> String {
+    if let Some(s) = non_finite(n) {
+        return s;
+    }
+    let mut s = format!("{:.*}", precision, n);
+    if alt && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// The C format `%.{precision}g`: the shorter of the fixed and exponent
+/// notations, without trailing zeros unless `alt`, the `#` flag.
+pub fn format_g(n: f64, precision: usize, alt: bool) -> String {
+    if let Some(s) = non_finite(n) {
+        return s;
+    }
+    let precision = precision.max(1);
+    // the exponent after rounding to the precision
+    let (mantissa, exp) = exp_parts(n, precision - 1);
+    let (digits, suffix) = if exp < -4 || exp >= precision as i32 {
+        (mantissa, exp_suffix('e', exp))
+    } else {
+        let decimals = (precision as i32 - 1 - exp) as usize;
+        (format!("{:.*}", decimals, n), String::new())
+    };
+    let digits = match (alt, digits.contains('.')) {
+        (false, _) => strip_zeros(&digits).to_string(),
+        (true, true) => digits,
+        (true, false) => digits + ".",
+    };
+    digits + &suffix
+}
+
+/// Trailing zeros of the fraction, and the dot if nothing is left of it.
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// The C format `%a`, a hexadecimal float with `precision` hex digits after
+/// the dot, by default as many as needed for an exact value. With `alt`,
+/// the `#` flag, the dot is kept without digits.
+pub fn format_a(n: f64, precision: Option<usize>, alt: bool) -> String {
+    if let Some(s) = non_finite(n) {
+        return s;
+    }
+    const FRAC_DIGITS: usize = 13;
+    let bits = n.to_bits();
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    let biased_exp = ((bits >> 52) & 0x7ff) as i32;
+    let mut frac = bits & ((1 << 52) - 1);
+    let (mut lead, exp) = match (biased_exp, frac) {
+        (0, 0) => (0, 0),
+        // subnormal
+        (0, _) => (0, -1022),
+        _ => (1, biased_exp - 1023),
+    };
+    let digits = match precision {
+        Some(p) if p < FRAC_DIGITS => {
+            // round half to even to `p` digits
+            let shift = (FRAC_DIGITS - p) * 4;
+            let rem = frac & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            frac >>= shift;
+            // without digits left the leading one decides the tie
+            let odd = if p == 0 { lead & 1 } else { frac & 1 } == 1;
+            if rem > half || (rem == half && odd) {
+                frac += 1;
+                if frac >> (p * 4) != 0 {
+                    frac &= (1 << (p * 4)) - 1;
+                    lead += 1;
+                }
+            }
+            if p == 0 {
+                String::new()
+            } else {
+                format!("{:0width$x}", frac, width = p)
+            }
+        }
+        Some(p) => format!("{:013x}{}", frac, "0".repeat(p - FRAC_DIGITS)),
+        None => format!("{:013x}", frac).trim_end_matches('0').to_string(),
+    };
+    let dot = if digits.is_empty() && !alt { "" } else { "." };
+    let exp_sign = if exp < 0 { '-' } else { '+' };
+    format!(
+        "{}0x{}{}{}p{}{}",
+        sign,
+        lead,
+        dot,
+        digits,
+        exp_sign,
+        exp.abs()
+    )
+}
+
+#[test]
+fn test_str_to_number() {
+    use LuaValue::{Integer, Number};
+    let num = |s: &str| str_to_number(s.as_bytes());
+
+    assert!(matches!(num("10"), Some(Integer(10))));
+    assert!(matches!(num(" 0x10 "), Some(Integer(16))));
+    assert!(matches!(num("-0XfF"), Some(Integer(-255))));
+    assert!(matches!(num("\t-7\n"), Some(Integer(-7))));
+    assert!(matches!(num("0xffffffffffffffff"), Some(Integer(-1))));
+    assert!(matches!(
+        num("9223372036854775807"),
+        Some(Integer(i64::MAX))
+    ));
+    assert!(matches!(
+        num("-9223372036854775808"),
+        Some(Integer(i64::MIN))
+    ));
+    // decimal integers that do not fit become floats
+    assert!(matches!(num("9223372036854775808"), Some(Number(n)) if n == 9223372036854775808.0));
+
+    assert!(matches!(num("1e2"), Some(Number(n)) if n == 100.0));
+    assert!(matches!(num("3."), Some(Number(n)) if n == 3.0));
+    assert!(matches!(num(".5"), Some(Number(n)) if n == 0.5));
+    assert!(matches!(num("314.25e-2"), Some(Number(n)) if n == 3.1425));
+    assert!(matches!(num("0x1p4"), Some(Number(n)) if n == 16.0));
+    assert!(matches!(num("0x.8"), Some(Number(n)) if n == 0.5));
+    assert!(matches!(num("0xA23p-4"), Some(Number(n)) if n == 162.1875));
+    assert!(matches!(num("0X1.921FB54442D18P+1"), Some(Number(n)) if n == std::f64::consts::PI));
+    assert!(matches!(num("1e400"), Some(Number(n)) if n == f64::INFINITY));
+
+    for bad in [
+        "", " ", "-", "0x", "1e", "1e+", ".", "1..2", "inf", "nan", "1 2", "0x1p", "1f", "--1",
+        "0x.p1", "1\0",
+    ] {
+        assert_eq!(num(bad), None, "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_str_to_integer_base() {
+    let int = |s: &str, base| str_to_integer_base(s.as_bytes(), base);
+
+    assert_eq!(int("ff", 16), Some(255));
+    assert_eq!(int(" -zz ", 36), Some(-1295));
+    assert_eq!(int("777", 8), Some(511));
+    assert_eq!(int("8", 8), None);
+    assert_eq!(int("1.0", 10), None);
+    assert_eq!(int("", 10), None);
+}
+
+#[test]
+fn test_number_to_str() {
+    use LuaValue::{Integer, Number};
+    let str = |val| number_to_str(&val).unwrap();
+
+    assert_eq!(str(Integer(10)), "10");
+    assert_eq!(str(Integer(i64::MIN)), "-9223372036854775808");
+    assert_eq!(str(Number(10.0)), "10.0");
+    assert_eq!(str(Number(-0.0)), "-0.0");
+    assert_eq!(str(Number(0.1)), "0.1");
+    assert_eq!(str(Number(1.0 / 3.0)), "0.33333333333333");
+    assert_eq!(str(Number(2.5e-5)), "2.5e-05");
+    assert_eq!(str(Number(1e15)), "1e+15");
+    assert_eq!(str(Number(123456789012345.0)), "1.2345678901234e+14");
+    assert_eq!(str(Number(12345678901234.0)), "12345678901234.0");
+    assert_eq!(str(Number(2f64.powi(63))), "9.2233720368548e+18");
+    assert_eq!(str(Number(1e300 * 1e10)), "inf");
+    assert_eq!(str(Number(-1e300 * 1e10)), "-inf");
+    assert_eq!(str(Number(f64::NAN)), "nan");
+    assert_eq!(str(Number(-f64::NAN)), "-nan");
+    assert_eq!(format_g(0.0001, 14, false), "0.0001");
+    assert_eq!(format_g(100.0, 3, false), "100");
+    assert_eq!(format_g(1000.0, 3, false), "1e+03");
+    assert_eq!(format_g(100.0, 6, true), "100.000");
+    assert_eq!(format_g(1000.0, 1, true), "1.e+03");
+}
+
+#[test]
+fn test_c_float_formats() {
+    assert_eq!(format_e(1234.5678, 6, false), "1.234568e+03");
+    assert_eq!(format_e(0.0, 2, false), "0.00e+00");
+    assert_eq!(format_e(1.75, 0, true), "2.e+00");
+    assert_eq!(format_f(2.5, 0, false), "2");
+    assert_eq!(format_f(2.5, 0, true), "2.");
+    assert_eq!(format_f(-1.0 / 3.0, 3, false), "-0.333");
+    assert_eq!(format_f(f64::INFINITY, 3, false), "inf");
+
+    assert_eq!(format_a(1.0, None, false), "0x1p+0");
+    assert_eq!(format_a(3.0, None, false), "0x1.8p+1");
+    assert_eq!(format_a(0.1, None, false), "0x1.999999999999ap-4");
+    assert_eq!(format_a(-0.0, None, false), "-0x0p+0");
+    assert_eq!(format_a(0.5, None, true), "0x1.p-1");
+    assert_eq!(
+        format_a(f64::MIN_POSITIVE / 4.0, None, false),
+        "0x0.4p-1022"
+    );
+    assert_eq!(format_a(1.5, Some(0), false), "0x2p+0");
+    assert_eq!(format_a(1.03125, Some(1), false), "0x1.0p+0");
+    assert_eq!(format_a(1.0, Some(15), false), "0x1.000000000000000p+0");
+    assert_eq!(format_a(f64::MAX, Some(2), false), "0x2.00p+1023");
+}
@@ -1,15 +1,23 @@
 use super::{
     instruction::{
         arith::{
-            add, add_k, b_and, b_and_k, b_or, b_or_k, b_xor, div, div_k, idiv, idiv_k, mod_, mod_k,
-            mul, mul_k, pow, pow_k, shl, shl_i, shr, shr_i, sub, sub_k,
+            add, add_i, add_k, b_and, b_and_k, b_not, b_or, b_or_k, b_xor, b_xor_k, div, div_k,
+            idiv, idiv_k, mm_bin, mm_bin_i, mm_bin_k, mod_, mod_k, mul, mul_k, pow, pow_k, shl,
+            shl_i, shr, shr_i, sub, sub_k, unm,
         },
-        compare::equal_i,
-        load::{load_i, load_k, load_kx, load_nil},
+        call::{call, closure, return0, return1, return_, self_, tail_call, vararg, vararg_prep},
+        compare::{
+            equal, equal_i, equal_k, great_equal_i, great_than_i, less_equal, less_equal_i,
+            less_than, less_than_i, not, test, test_set,
+        },
+        load::{load_f, load_false, load_false_skip, load_i, load_k, load_kx, load_nil, load_true},
         misc::{concat, jump, len, moving},
-        repeat::{for_loop, for_prep},
+        repeat::{for_loop, for_prep, tfor_call, tfor_loop, tfor_prep},
+        table::{get_field, get_i, get_table, new_table, set_field, set_i, set_list, set_table},
+        upvalue::{close, get_tab_up, get_upvalue, set_tab_up, set_upvalue, tbc},
         Instruction,
     },
+    lua_error::LuaError,
     lua_state::LuaVm,
 };
 
@@ -72,8 +80,8 @@ pub enum OpCodeEnum {
     OpBORK,
     OpBXORK,
 
-    OpSHLI,
     OpSHRI,
+    OpSHLI,
 
     OpAdd,
     OpSub,
@@ -126,9 +134,9 @@ pub enum OpCodeEnum {
 
     OpForLoop,
     OpForPrep,
-    OpForCall,
-    OpTForLoop,
+    OpTForPrep,
     OpTForCall,
+    OpTForLoop,
 
     OpSetList,
 
@@ -161,10 +169,12 @@ pub struct OpCode {
     pub arg_c_mode: OpArg,
     pub op_mode: OpMode,
     pub name: &'static str,
-    pub action: fn(i: Instruction, vm: &mut dyn LuaVm) -> (),
+    pub action: fn(i: Instruction, vm: &mut dyn LuaVm) -> Result<(), LuaError>,
 }
 
-fn noop(i: Instruction, vm: &mut dyn LuaVm) {}
+fn noop(_i: Instruction, _vm: &mut dyn LuaVm) -> Result<(), LuaError> {
+    Ok(())
+}
 
 pub const OP_CODE: [OpCode; 83] = [
     OpCode {
@@ -191,6 +201,15 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgK,
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IAsBx,
+        name: "LOADF",
+        action: load_f,
+    },
+    OpCode {
+        test_flag: 0,
+        set_a_flag: 1,
+        arg_b_mode: OpArg::OpArgK,
+        arg_c_mode: OpArg::OpArgN,
+        op_mode: OpMode::IABx,
         name: "LOADK",
         action: load_k,
     },
@@ -210,7 +229,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "LOADFALSE",
-        action: noop,
+        action: load_false,
     },
     OpCode {
         test_flag: 0,
@@ -219,7 +238,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "LFALSESKIP",
-        action: noop,
+        action: load_false_skip,
     },
     OpCode {
         test_flag: 0,
@@ -228,7 +247,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "LOADTRUE",
-        action: noop,
+        action: load_true,
     },
     OpCode {
         test_flag: 0,
@@ -246,7 +265,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "GETUPVAL",
-        action: noop,
+        action: get_upvalue,
     },
     OpCode {
         test_flag: 0,
@@ -255,7 +274,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "SETUPVAL",
-        action: noop,
+        action: set_upvalue,
     },
     OpCode {
         test_flag: 0,
@@ -264,7 +283,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GETTABUP",
-        action: noop,
+        action: get_tab_up,
     },
     OpCode {
         test_flag: 0,
@@ -273,7 +292,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GETTABLE",
-        action: noop,
+        action: get_table,
     },
     OpCode {
         test_flag: 0,
@@ -282,7 +301,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GETI",
-        action: noop,
+        action: get_i,
     },
     OpCode {
         test_flag: 0,
@@ -291,25 +310,16 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GETFIELD",
-        action: noop,
+        action: get_field,
     },
     OpCode {
         test_flag: 0,
-        set_a_flag: 1,
+        set_a_flag: 0,
         arg_b_mode: OpArg::OpArgK,
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
-        name: "GETTABUP",
-        action: noop,
-    },
-    OpCode {
-        test_flag: 0,
-        set_a_flag: 0,
-        arg_b_mode: OpArg::OpArgU,
-        arg_c_mode: OpArg::OpArgN,
-        op_mode: OpMode::IABC,
-        name: "SETUPVAL",
-        action: noop,
+        name: "SETTABUP",
+        action: set_tab_up,
     },
     OpCode {
         test_flag: 0,
@@ -318,7 +328,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "SETTABLE",
-        action: noop,
+        action: set_table,
     },
     OpCode {
         test_flag: 0,
@@ -327,7 +337,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "SETI",
-        action: noop,
+        action: set_i,
     },
     OpCode {
         test_flag: 0,
@@ -336,7 +346,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "SETFIELD",
-        action: noop,
+        action: set_field,
     },
     OpCode {
         test_flag: 0,
@@ -345,7 +355,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "NEWTABLE",
-        action: noop,
+        action: new_table,
     },
     OpCode {
         test_flag: 0,
@@ -354,7 +364,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "SELF",
-        action: noop,
+        action: self_,
     },
     OpCode {
         test_flag: 0,
@@ -363,7 +373,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "ADDI",
-        action: noop,
+        action: add_i,
     },
     OpCode {
         test_flag: 0,
@@ -453,7 +463,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "BXORK",
-        action: b_or_k,
+        action: b_xor_k,
     },
     OpCode {
         test_flag: 0,
@@ -461,8 +471,8 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgK,
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
-        name: "SHLI",
-        action: shl_i,
+        name: "SHRI",
+        action: shr_i,
     },
     OpCode {
         test_flag: 0,
@@ -470,8 +480,8 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgK,
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
-        name: "SHRI",
-        action: shr_i,
+        name: "SHLI",
+        action: shl_i,
     },
     OpCode {
         test_flag: 0,
@@ -588,7 +598,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "MMBIN",
-        action: noop,
+        action: mm_bin,
     },
     OpCode {
         test_flag: 0,
@@ -597,7 +607,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "MMBINI",
-        action: noop,
+        action: mm_bin_i,
     },
     OpCode {
         test_flag: 0,
@@ -606,7 +616,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "MMBINK",
-        action: noop,
+        action: mm_bin_k,
     },
     OpCode {
         test_flag: 0,
@@ -615,7 +625,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "UNM",
-        action: noop,
+        action: unm,
     },
     OpCode {
         test_flag: 0,
@@ -624,7 +634,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "BNOT",
-        action: noop,
+        action: b_not,
     },
     OpCode {
         test_flag: 0,
@@ -633,7 +643,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "NOT",
-        action: noop,
+        action: not,
     },
     OpCode {
         test_flag: 0,
@@ -660,7 +670,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgR,
         op_mode: OpMode::IABC,
         name: "CLOSE",
-        action: noop,
+        action: close,
     },
     OpCode {
         test_flag: 1,
@@ -669,7 +679,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IAsBx,
         name: "TBC",
-        action: noop,
+        action: tbc,
     },
     OpCode {
         test_flag: 1,
@@ -687,7 +697,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "EQ",
-        action: noop,
+        action: equal,
     },
     OpCode {
         test_flag: 1,
@@ -696,7 +706,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "LT",
-        action: noop,
+        action: less_than,
     },
     OpCode {
         test_flag: 0,
@@ -705,7 +715,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "LE",
-        action: noop,
+        action: less_equal,
     },
     OpCode {
         test_flag: 1,
@@ -714,7 +724,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "EQK",
-        action: noop,
+        action: equal_k,
     },
     OpCode {
         test_flag: 1,
@@ -732,7 +742,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "LTI",
-        action: noop,
+        action: less_than_i,
     },
     OpCode {
         test_flag: 0,
@@ -741,7 +751,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "LEI",
-        action: noop,
+        action: less_equal_i,
     },
     OpCode {
         test_flag: 1,
@@ -750,7 +760,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GTI",
-        action: noop,
+        action: great_than_i,
     },
     OpCode {
         test_flag: 0,
@@ -759,7 +769,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "GEI",
-        action: noop,
+        action: great_equal_i,
     },
     OpCode {
         test_flag: 1,
@@ -768,7 +778,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "TEST",
-        action: noop,
+        action: test,
     },
     OpCode {
         test_flag: 0,
@@ -777,7 +787,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "TESTSET",
-        action: noop,
+        action: test_set,
     },
     OpCode {
         test_flag: 0,
@@ -786,7 +796,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgK,
         op_mode: OpMode::IABC,
         name: "CALL",
-        action: noop,
+        action: call,
     },
     OpCode {
         test_flag: 0,
@@ -795,7 +805,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
         name: "TAILCALL",
-        action: noop,
+        action: tail_call,
     },
     OpCode {
         test_flag: 0,
@@ -804,7 +814,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "RETURN",
-        action: noop,
+        action: return_,
     },
     OpCode {
         test_flag: 0,
@@ -813,7 +823,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "RETURN0",
-        action: noop,
+        action: return0,
     },
     OpCode {
         test_flag: 0,
@@ -822,7 +832,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "RETURN1",
-        action: noop,
+        action: return1,
     },
     OpCode {
         test_flag: 0,
@@ -839,7 +849,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgR,
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IAsBx,
-        name: "FORREAP",
+        name: "FORPREP",
         action: for_prep,
     },
     OpCode {
@@ -848,8 +858,8 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgN,
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IABC,
-        name: "FORCALL",
-        action: noop,
+        name: "TFORPREP",
+        action: tfor_prep,
     },
     OpCode {
         test_flag: 0,
@@ -857,8 +867,8 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgR,
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
-        name: "TFORLOOP",
-        action: noop,
+        name: "TFORCALL",
+        action: tfor_call,
     },
     OpCode {
         test_flag: 0,
@@ -866,8 +876,8 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_b_mode: OpArg::OpArgN,
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IAsBx,
-        name: "TFORCALL",
-        action: noop,
+        name: "TFORLOOP",
+        action: tfor_loop,
     },
     OpCode {
         test_flag: 0,
@@ -876,7 +886,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgU,
         op_mode: OpMode::IAsBx,
         name: "SETLIST",
-        action: noop,
+        action: set_list,
     },
     OpCode {
         test_flag: 0,
@@ -885,7 +895,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABx,
         name: "CLOSURE",
-        action: noop,
+        action: closure,
     },
     OpCode {
         test_flag: 0,
@@ -894,7 +904,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "VARARG",
-        action: noop,
+        action: vararg,
     },
     OpCode {
         test_flag: 0,
@@ -903,7 +913,7 @@ pub const OP_CODE: [OpCode; 83] = [
         arg_c_mode: OpArg::OpArgN,
         op_mode: OpMode::IABC,
         name: "VARARGPREP",
-        action: noop,
+        action: vararg_prep,
     },
     OpCode {
         test_flag: 0,
@@ -918,8 +928,8 @@ pub const OP_CODE: [OpCode; 83] = [
 
 #[test]
 fn test_op_mode() {
-    fn op_mode(mm: u32, ot: u32, it: u32, t: u32, A: u32, m: u32) -> u32 {
-        ((mm) << 7) | ((ot) << 6) | ((it) << 5) | ((t) << 4) | ((A) << 3) | (m)
+    fn op_mode(mm: u32, ot: u32, it: u32, t: u32, a: u32, m: u32) -> u32 {
+        ((mm) << 7) | ((ot) << 6) | ((it) << 5) | ((t) << 4) | ((a) << 3) | (m)
     }
     println!("{:x}", op_mode(0, 0, 1, 0, 1, 0))
 }
@@ -927,16 +937,10 @@ fn test_op_mode() {
 #[test]
 fn test_u8_to_op_code_enum() {
     let op_code = OpCodeEnum::try_from(0).unwrap();
-    match op_code {
-        OpCodeEnum::OpMove => assert!(true),
-        _ => assert!(false),
-    }
+    assert!(matches!(op_code, OpCodeEnum::OpMove));
 
     let op_code_2 = OpCodeEnum::try_from(82).unwrap();
-    match op_code_2 {
-        OpCodeEnum::OpExtraArg => assert!(true),
-        _ => assert!(false),
-    }
+    assert!(matches!(op_code_2, OpCodeEnum::OpExtraArg));
 
     assert_eq!(OpCodeEnum::try_from(100).err().unwrap(), "range overflow")
 }
@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::rc::Rc;
 
 use super::{
     binary_chunk::{
@@ -6,11 +6,16 @@ use super::{
         LUAC_INT, LUAC_NUM, LUAC_VERSION, LUA_INTEGER_SIZE, LUA_NUMBER_SIZE, LUA_SIGNATURE,
         TAG_FALSE, TAG_FLOAT, TAG_INTEGER, TAG_LONG_STRING, TAG_NIL, TAG_SHORT_STRING, TAG_TRUE,
     },
+    codec::{unpack_float, unpack_int, Endian, INTEGER_SIZE},
+    lua_string::LuaString,
     lua_value::LuaValue,
 };
 
 pub type Unsigned = u64;
 
+/// Result of reading a binary chunk, the error tells why it is malformed.
+pub type ReadResult<T> = Result<T, &'static str>;
+
 pub struct LuaChunkReader {
     pub buffer: Vec<u8>,
     pub debug_byte: u8,
@@ -26,36 +31,41 @@ impl LuaChunkReader {
         }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) {
+    pub fn read(&mut self, buf: &mut [u8]) -> ReadResult<()> {
         self.buffer = buf.to_vec();
-        self.read_byte();
+        self.read_byte()?;
+        Ok(())
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let byte = self.buffer[self.index];
+    pub fn read_byte(&mut self) -> ReadResult<u8> {
+        let byte = *self
+            .buffer
+            .get(self.index)
+            .ok_or("truncated precompiled chunk")?;
         self.index += 1;
         self.debug_byte = byte;
-        byte
+        Ok(byte)
     }
 
-    fn read_bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for _ in 0..n {
-            bytes.push(self.read_byte());
+    fn read_bytes(&mut self, n: usize) -> ReadResult<Vec<u8>> {
+        // checked before allocating, `n` may come from a corrupt size
+        if self.buffer.len() - self.index < n {
+            return Err("truncated precompiled chunk");
         }
-
-        bytes
+        let bytes = self.buffer[self.index..self.index + n].to_vec();
+        self.index += n;
+        Ok(bytes)
     }
 
-    fn read_unsigned(&mut self, mut limit: usize) -> usize {
+    fn read_unsigned(&mut self, mut limit: usize) -> ReadResult<usize> {
         // let mut limit = 0xFFFFFFFF; // ~(size_t)0
-        let mut x = 0 as usize;
+        let mut x = 0_usize;
         let mut b;
         limit >>= 7;
         loop {
-            b = usize::try_from(self.read_byte()).unwrap();
+            b = usize::from(self.read_byte()?);
             if x >= limit {
-                panic!("integer overflow");
+                return Err("integer overflow");
             }
 
             // 0x7f === 0b0111_0000
@@ -66,202 +76,206 @@ impl LuaChunkReader {
                 break;
             }
         }
-        x
-    }
-
-    fn read_int(&mut self) -> i32 {
-        self.read_unsigned(std::i32::MAX as usize) as i32
+        Ok(x)
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let a1 = self.read_byte() as u32;
-        let a2 = self.read_byte() as u32;
-        let a3 = self.read_byte() as u32;
-        let a4 = self.read_byte() as u32;
-        a4 << 24 | a3 << 16 | a2 << 8 | a1
+    fn read_int(&mut self) -> ReadResult<i32> {
+        Ok(self.read_unsigned(i32::MAX as usize)? as i32)
     }
 
-    fn read_uint64(&mut self) -> u64 {
-        let a1 = self.read_u32() as u64;
-        let a2 = self.read_u32() as u64;
-        a2 << 32 | a1
+    fn read_u32(&mut self) -> ReadResult<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(unpack_int(&bytes, Endian::Little, false).unwrap_or_default() as u32)
     }
 
-    fn read_integer(&mut self) -> i64 {
-        let integer = self.read_uint64();
-        i64::try_from(integer).unwrap()
+    fn read_integer(&mut self) -> ReadResult<i64> {
+        let bytes = self.read_bytes(INTEGER_SIZE)?;
+        Ok(unpack_int(&bytes, Endian::Little, true).unwrap_or_default())
     }
 
-    fn read_number(&mut self) -> f64 {
-        f64::from_bits(self.read_uint64())
+    fn read_number(&mut self) -> ReadResult<f64> {
+        let bytes = self.read_bytes(8)?;
+        Ok(unpack_float(&bytes, Endian::Little))
     }
 
-    fn read_size(&mut self) -> usize {
+    fn read_size(&mut self) -> ReadResult<usize> {
         self.read_unsigned(0xFFFFFFFF)
     }
 
-    fn read_string(&mut self) -> String {
-        // let a = self.read_u32();
-        let size = self.read_size() as usize;
+    /// Raw bytes of a string, constants may hold any byte sequence.
+    fn read_lua_string(&mut self) -> ReadResult<LuaString> {
+        let size = self.read_size()?;
         if size == 0 {
-            String::new()
+            Ok(LuaString::from(""))
         } else {
-            let bytes = self.read_bytes(size - 1);
-
-            String::from_utf8(bytes).unwrap()
+            Ok(LuaString::from(self.read_bytes(size - 1)?))
         }
     }
 
-    pub fn check_header(&mut self) {
-        assert_eq!(
-            self.read_bytes(4).as_slice(),
-            LUA_SIGNATURE,
-            "Not a precompiled chunk!"
-        );
-        assert_eq!(self.read_byte(), LUAC_VERSION, "Version mismatch!");
-        assert_eq!(self.read_byte(), LUAC_FORMAT, "Format mismatch!");
-        assert_eq!(self.read_bytes(6).as_slice(), LUAC_DATA, "corrupted!");
+    /// A source or variable name, invalid UTF-8 is replaced.
+    fn read_string(&mut self) -> ReadResult<String> {
+        Ok(self.read_lua_string()?.to_str_lossy().into_owned())
+    }
+
+    /// Check the header of a binary chunk, on mismatch the error tells why
+    /// the chunk can not be loaded.
+    pub fn check_header(&mut self) -> ReadResult<()> {
+        if !self.buffer.starts_with(&LUA_SIGNATURE) {
+            return Err("not a binary chunk");
+        }
+        self.read_bytes(4)?;
+        if self.read_byte()? != LUAC_VERSION {
+            return Err("version mismatch");
+        }
+        if self.read_byte()? != LUAC_FORMAT {
+            return Err("format mismatch");
+        }
+        if self.read_bytes(6)?.as_slice() != LUAC_DATA {
+            return Err("corrupted chunk");
+        }
         // NOTE: lua 5.4 source code not check CINT_SIZE and CSIZET_SIEZE
-        // assert_eq!(self.read_byte(), CINT_SIZE, "Int size mismatch!");
-        // assert_eq!(self.read_byte(), CSIZET_SIEZE, "size_t size mismatch!");
-        assert_eq!(
-            self.read_byte(),
-            INSTRUCTION_SIZE,
-            "Instruction size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(),
-            LUA_INTEGER_SIZE,
-            "Lua Integer size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(),
-            LUA_NUMBER_SIZE,
-            "Lua Number size mismatch!"
-        );
-        assert_eq!(self.read_integer(), LUAC_INT, "endianness mismatch!");
-        assert_eq!(self.read_number(), LUAC_NUM, "float format mismatch!");
+        if self.read_byte()? != INSTRUCTION_SIZE {
+            return Err("Instruction size mismatch");
+        }
+        if self.read_byte()? != LUA_INTEGER_SIZE {
+            return Err("lua_Integer size mismatch");
+        }
+        if self.read_byte()? != LUA_NUMBER_SIZE {
+            return Err("lua_Number size mismatch");
+        }
+        if self.read_integer()? != LUAC_INT {
+            return Err("integer format mismatch");
+        }
+        if self.read_number()? != LUAC_NUM {
+            return Err("float format mismatch");
+        }
+        Ok(())
     }
 
-    pub fn read_function_prototype(&mut self, parent_source: String) -> Option<Prototype> {
-        let mut source = self.read_string();
-        if source == "" {
+    pub fn read_function_prototype(&mut self, parent_source: String) -> ReadResult<Prototype> {
+        let mut source = self.read_string()?;
+        if source.is_empty() {
             source = parent_source;
         }
 
-        Some(Prototype {
+        Ok(Prototype {
             source: source.clone(),
-            line_defined: self.read_int(),
-            last_line_defined: self.read_int(),
-            num_params: self.read_byte(),
-            is_vararg: self.read_byte(),
-            max_statck_size: self.read_byte(),
-            code: self.read_code(),
-            constants: self.read_constants(),
-            upvalues: self.read_upvalues(),
-            prototypes: self.read_function_prototypes(source),
-            line_info: self.read_line_info(),
-            abs_line_list: self.read_absolute_list(),
-            local_variable: self.read_local_variables(),
-            upvalue_names: self.read_upvalue_names(),
+            line_defined: self.read_int()?,
+            last_line_defined: self.read_int()?,
+            num_params: self.read_byte()?,
+            is_vararg: self.read_byte()?,
+            max_statck_size: self.read_byte()?,
+            code: self.read_code()?,
+            constants: self.read_constants()?,
+            upvalues: self.read_upvalues()?,
+            prototypes: self.read_function_prototypes(source)?,
+            line_info: self.read_line_info()?,
+            abs_line_list: self.read_absolute_list()?,
+            local_variable: self.read_local_variables()?,
+            upvalue_names: self.read_upvalue_names()?,
         })
     }
 
-    pub fn read_code(&mut self) -> Vec<u32> {
+    pub fn read_code(&mut self) -> ReadResult<Vec<u32>> {
         let mut codes = Vec::new();
-        let code_len = self.read_int();
+        let code_len = self.read_int()?;
         for _ in 0..code_len {
-            codes.push(self.read_u32())
+            codes.push(self.read_u32()?)
         }
-        codes
+        Ok(codes)
     }
 
-    pub fn read_constants(&mut self) -> Vec<LuaValue> {
+    pub fn read_constants(&mut self) -> ReadResult<Vec<LuaValue>> {
         let mut constants = Vec::new();
-        let const_len = self.read_int();
+        let const_len = self.read_int()?;
         for _ in 0..const_len {
-            constants.push(self.read_constant());
+            constants.push(self.read_constant()?);
         }
-        constants
+        Ok(constants)
     }
 
-    pub fn read_constant(&mut self) -> LuaValue {
-        match self.read_byte() {
+    pub fn read_constant(&mut self) -> ReadResult<LuaValue> {
+        let constant = match self.read_byte()? {
             TAG_NIL => LuaValue::Nil,
-            TAG_FALSE => LuaValue::Boolean(self.read_byte() != 0),
-            TAG_TRUE => LuaValue::Boolean(self.read_byte() != 0),
-            TAG_INTEGER => LuaValue::Integer(self.read_integer()),
-            TAG_FLOAT => LuaValue::Number(self.read_number()),
-            TAG_SHORT_STRING => LuaValue::String(self.read_string()),
-            TAG_LONG_STRING => LuaValue::String(self.read_string()),
-            v_tag => panic!("unknown value type: {}", v_tag),
-        }
+            TAG_FALSE => LuaValue::Boolean(false),
+            TAG_TRUE => LuaValue::Boolean(true),
+            TAG_INTEGER => LuaValue::Integer(self.read_integer()?),
+            TAG_FLOAT => LuaValue::Number(self.read_number()?),
+            TAG_SHORT_STRING | TAG_LONG_STRING => LuaValue::String(self.read_lua_string()?),
+            _ => return Err("unknown constant type"),
+        };
+        Ok(constant)
     }
 
-    pub fn read_upvalues(&mut self) -> Vec<Upvalue> {
+    pub fn read_upvalues(&mut self) -> ReadResult<Vec<Upvalue>> {
         let mut upvalues = Vec::new();
-        let upvalue_len = self.read_int();
+        let upvalue_len = self.read_int()?;
         for _ in 0..upvalue_len {
             upvalues.push(Upvalue {
-                instack: self.read_byte(),
-                index: self.read_byte(),
+                instack: self.read_byte()?,
+                index: self.read_byte()?,
+                kind: self.read_byte()?,
             });
         }
 
-        upvalues
+        Ok(upvalues)
     }
 
-    pub fn read_function_prototypes(&mut self, parent_source: String) -> Option<Vec<Prototype>> {
+    pub fn read_function_prototypes(
+        &mut self,
+        parent_source: String,
+    ) -> ReadResult<Vec<Rc<Prototype>>> {
         let mut prototypes = Vec::new();
-        let proto_len = self.read_int();
+        let proto_len = self.read_int()?;
         for _ in 0..proto_len {
-            prototypes.push(self.read_function_prototype(parent_source.clone()).unwrap());
+            let prototype = self.read_function_prototype(parent_source.clone())?;
+            prototypes.push(Rc::new(prototype));
         }
 
-        Some(prototypes)
+        Ok(prototypes)
     }
-    pub fn read_line_info(&mut self) -> Vec<u8> {
+    pub fn read_line_info(&mut self) -> ReadResult<Vec<u8>> {
         let mut line_infos = Vec::new();
-        let line_infos_len = self.read_int();
+        let line_infos_len = self.read_int()?;
         for _ in 0..line_infos_len {
-            line_infos.push(self.read_byte());
+            line_infos.push(self.read_byte()?);
         }
 
-        line_infos
+        Ok(line_infos)
     }
 
-    pub fn read_absolute_list(&mut self) -> Vec<AbsoluteLine> {
+    pub fn read_absolute_list(&mut self) -> ReadResult<Vec<AbsoluteLine>> {
         let mut abs_line_list = Vec::new();
-        let abs_line_len = self.read_int();
+        let abs_line_len = self.read_int()?;
         for _ in 0..abs_line_len {
             abs_line_list.push(AbsoluteLine {
-                pc: self.read_u32(),
-                line: self.read_u32(),
+                pc: self.read_int()? as u32,
+                line: self.read_int()? as u32,
             })
         }
 
-        abs_line_list
+        Ok(abs_line_list)
     }
 
-    pub fn read_local_variables(&mut self) -> Vec<LocalVariable> {
+    pub fn read_local_variables(&mut self) -> ReadResult<Vec<LocalVariable>> {
         let mut local_variables = Vec::new();
-        let local_variables_len = self.read_int();
+        let local_variables_len = self.read_int()?;
         for _ in 0..local_variables_len {
             local_variables.push(LocalVariable {
-                var_name: self.read_string(),
-                start_pc: self.read_int(),
-                end_pc: self.read_int(),
+                var_name: self.read_string()?,
+                start_pc: self.read_int()?,
+                end_pc: self.read_int()?,
             })
         }
-        local_variables
+        Ok(local_variables)
     }
-    pub fn read_upvalue_names(&mut self) -> Vec<String> {
+    pub fn read_upvalue_names(&mut self) -> ReadResult<Vec<String>> {
         let mut upvalue_names = Vec::new();
-        let upvalue_names_len = self.read_int();
+        let upvalue_names_len = self.read_int()?;
         for _ in 0..upvalue_names_len {
-            upvalue_names.push(self.read_string())
+            upvalue_names.push(self.read_string()?)
         }
-        upvalue_names
+        Ok(upvalue_names)
     }
 }
 
@@ -277,8 +291,8 @@ fn test_declare_a_variable() {
     ];
 
     let mut reader = LuaChunkReader::new(p);
-    reader.check_header();
-    reader.read_byte();
+    reader.check_header().unwrap();
+    reader.read_byte().unwrap();
     let proto = reader.read_function_prototype("".to_string()).unwrap();
     println!("{:#?}", proto);
 }
@@ -323,13 +337,13 @@ fn test_hello_word_program() {
     ];
 
     let mut reader = LuaChunkReader::new(hello_word_program);
-    reader.check_header();
-    reader.read_byte();
+    reader.check_header().unwrap();
+    reader.read_byte().unwrap();
     let proto = reader.read_function_prototype("".to_string()).unwrap();
     println!("{:?}", proto);
     assert_eq!(proto.source, "@./hello_word.lua");
     assert_eq!(proto.is_vararg, 1);
-    match proto.constants.get(0).unwrap() {
+    match proto.constants.first().unwrap() {
         LuaValue::String(str) => assert_eq!(str, "print"),
         _ => panic!("not print string"),
     }
@@ -374,8 +388,8 @@ fn test_echo_function() {
         0x56,
     ];
     let mut reader = LuaChunkReader::new(echo_function_program);
-    reader.check_header();
-    reader.read_byte();
+    reader.check_header().unwrap();
+    reader.read_byte().unwrap();
     let proto = reader.read_function_prototype("".to_string()).unwrap();
 
     println!("{:#?}", proto);
@@ -394,8 +408,8 @@ fn test_read_size() {
         0x4E, 0x56,
     ]);
 
-    reader.check_header();
-    reader.read_byte();
+    reader.check_header().unwrap();
+    reader.read_byte().unwrap();
     let proto = reader.read_function_prototype("".to_string()).unwrap();
 
     println!("{:#?}", proto);
@@ -403,14 +417,47 @@ fn test_read_size() {
 
 #[test]
 fn dump_chunk_file() {
-    let file = std::fs::File::open("/Users/yidafu/github/Language/crescent/loop.luac").unwrap();
-    let mut buf = Vec::new();
-    std::io::BufReader::new(file).read_to_end(&mut buf);
+    let buf = std::fs::read("fixtures/loop.luac").unwrap();
 
     let mut reader = LuaChunkReader::new(buf);
 
-    reader.check_header();
-    reader.read_byte();
+    reader.check_header().unwrap();
+    reader.read_byte().unwrap();
     let proto = reader.read_function_prototype("".to_string()).unwrap();
     println!("{:#?}", proto);
 }
+
+#[test]
+fn test_read_constants() {
+    let mut buf = vec![0x87, TAG_SHORT_STRING, 0x83, 0xff, 0x00];
+    buf.extend([TAG_LONG_STRING, 0x80 | 51]);
+    buf.extend([0xfe; 50]);
+    buf.extend([TAG_TRUE, TAG_FALSE, TAG_NIL, TAG_INTEGER]);
+    buf.extend([0xff; 8]);
+    buf.push(TAG_FLOAT);
+    buf.extend(1.5f64.to_le_bytes());
+    let mut reader = LuaChunkReader::new(buf);
+
+    let constants = reader.read_constants().unwrap();
+    assert_eq!(constants[0], LuaValue::String(LuaString::new(&[0xff, 0])));
+    assert_eq!(constants[1], LuaValue::String(LuaString::new(&[0xfe; 50])));
+    assert_eq!(constants[2], LuaValue::Boolean(true));
+    assert_eq!(constants[3], LuaValue::Boolean(false));
+    assert_eq!(constants[4], LuaValue::Nil);
+    assert_eq!(constants[5], LuaValue::Integer(-1));
+    assert_eq!(constants[6], LuaValue::Number(1.5));
+}
+
+#[test]
+fn test_read_errors() {
+    let mut reader = LuaChunkReader::new(vec![0x81, 0x42]);
+    assert_eq!(reader.read_constants(), Err("unknown constant type"));
+
+    // a size of more than 32 bits
+    let mut reader = LuaChunkReader::new(vec![0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x80]);
+    assert_eq!(reader.read_size(), Err("integer overflow"));
+
+    // strings are checked before their bytes are copied
+    let mut reader = LuaChunkReader::new(vec![0x0f, 0x7f, 0x7f, 0xff, 0x61]);
+    assert_eq!(reader.read_string(), Err("truncated precompiled chunk"));
+}
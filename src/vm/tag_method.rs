@@ -0,0 +1,392 @@
+use std::rc::Rc;
+
+use super::{
+    lua_error::LuaError,
+    lua_state::{LuaApi, LuaState},
+    lua_string::LuaString,
+    lua_table::LuaTableRef,
+    lua_value::LuaValue,
+    number::number_to_str,
+};
+
+/// Limit for chains of `__index` and `__newindex` tables.
+const MAXTAGLOOP: usize = 2000;
+
+/// Events that can be handled by a metamethod, in the order used by the C
+/// operand of the MMBIN instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMethod {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+    Lt,
+    Le,
+    Concat,
+    Call,
+    Close,
+}
+
+const TAG_METHODS: [TagMethod; 25] = [
+    TagMethod::Index,
+    TagMethod::NewIndex,
+    TagMethod::Gc,
+    TagMethod::Mode,
+    TagMethod::Len,
+    TagMethod::Eq,
+    TagMethod::Add,
+    TagMethod::Sub,
+    TagMethod::Mul,
+    TagMethod::Mod,
+    TagMethod::Pow,
+    TagMethod::Div,
+    TagMethod::IDiv,
+    TagMethod::BAnd,
+    TagMethod::BOr,
+    TagMethod::BXor,
+    TagMethod::Shl,
+    TagMethod::Shr,
+    TagMethod::Unm,
+    TagMethod::BNot,
+    TagMethod::Lt,
+    TagMethod::Le,
+    TagMethod::Concat,
+    TagMethod::Call,
+    TagMethod::Close,
+];
+
+impl TagMethod {
+    /// Key of the metamethod in a metatable.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TagMethod::Index => "__index",
+            TagMethod::NewIndex => "__newindex",
+            TagMethod::Gc => "__gc",
+            TagMethod::Mode => "__mode",
+            TagMethod::Len => "__len",
+            TagMethod::Eq => "__eq",
+            TagMethod::Add => "__add",
+            TagMethod::Sub => "__sub",
+            TagMethod::Mul => "__mul",
+            TagMethod::Mod => "__mod",
+            TagMethod::Pow => "__pow",
+            TagMethod::Div => "__div",
+            TagMethod::IDiv => "__idiv",
+            TagMethod::BAnd => "__band",
+            TagMethod::BOr => "__bor",
+            TagMethod::BXor => "__bxor",
+            TagMethod::Shl => "__shl",
+            TagMethod::Shr => "__shr",
+            TagMethod::Unm => "__unm",
+            TagMethod::BNot => "__bnot",
+            TagMethod::Lt => "__lt",
+            TagMethod::Le => "__le",
+            TagMethod::Concat => "__concat",
+            TagMethod::Call => "__call",
+            TagMethod::Close => "__close",
+        }
+    }
+
+    /// Bitwise operations need integer operands.
+    pub fn is_bitwise(&self) -> bool {
+        matches!(
+            self,
+            TagMethod::BAnd
+                | TagMethod::BOr
+                | TagMethod::BXor
+                | TagMethod::Shl
+                | TagMethod::Shr
+                | TagMethod::BNot
+        )
+    }
+}
+
+impl TryFrom<i32> for TagMethod {
+    type Error = &'static str;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        usize::try_from(value)
+            .ok()
+            .and_then(|idx| TAG_METHODS.get(idx).copied())
+            .ok_or("invalid tag method")
+    }
+}
+
+impl LuaState {
+    /// Metatable of a value, tables have their own, other types share one per
+    /// type.
+    pub fn get_metatable_of(&self, val: &LuaValue) -> Option<LuaTableRef> {
+        match val {
+            LuaValue::Table(table) => table.borrow().metatable.clone(),
+            _ => self.type_metatables.get(val.type_name()).cloned(),
+        }
+    }
+
+    /// Handler for `event` in the metatable of `val`, nil if there is none.
+    pub fn get_metamethod(&self, val: &LuaValue, event: TagMethod) -> LuaValue {
+        match self.get_metatable_of(val) {
+            Some(mt) => mt.borrow().get_str(event.name()),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// Call a metamethod with `args` and return its first result.
+    fn call_metamethod(&mut self, mm: LuaValue, args: &[LuaValue]) -> Result<LuaValue, LuaError> {
+        self.stack.push(mm);
+        for arg in args {
+            self.stack.push(arg.clone());
+        }
+        self.call(args.len(), 1)?;
+        Ok(self.stack.pop())
+    }
+
+    /// `t[k]`, following `__index` when the key is absent.
+    pub(crate) fn index(&mut self, mut t: LuaValue, k: LuaValue) -> Result<LuaValue, LuaError> {
+        for _ in 0..MAXTAGLOOP {
+            let mm = match &t {
+                LuaValue::Table(table) => {
+                    let val = table.borrow().get(&k);
+                    if !val.is_nil() {
+                        return Ok(val);
+                    }
+                    let mm = self.get_metamethod(&t, TagMethod::Index);
+                    if mm.is_nil() {
+                        return Ok(LuaValue::Nil);
+                    }
+                    mm
+                }
+                _ => {
+                    let mm = self.get_metamethod(&t, TagMethod::Index);
+                    if mm.is_nil() {
+                        return Err(self.operand_error("index", &t));
+                    }
+                    mm
+                }
+            };
+            if is_function(&mm) {
+                return self.call_metamethod(mm, &[t, k]);
+            }
+            t = mm;
+        }
+        Err(self.runtime_error("'__index' chain too long; possible loop"))
+    }
+
+    /// `t[k] = v`, following `__newindex` when the key is absent.
+    pub(crate) fn new_index(
+        &mut self,
+        mut t: LuaValue,
+        k: LuaValue,
+        v: LuaValue,
+    ) -> Result<(), LuaError> {
+        for _ in 0..MAXTAGLOOP {
+            let mm = match &t {
+                LuaValue::Table(table) => {
+                    let present = !table.borrow().get(&k).is_nil();
+                    let mm = if present {
+                        LuaValue::Nil
+                    } else {
+                        self.get_metamethod(&t, TagMethod::NewIndex)
+                    };
+                    if mm.is_nil() {
+                        let res = table.borrow_mut().put(k, v);
+                        return res.map_err(|msg| self.runtime_error(format!("table {}", msg)));
+                    }
+                    mm
+                }
+                _ => {
+                    let mm = self.get_metamethod(&t, TagMethod::NewIndex);
+                    if mm.is_nil() {
+                        return Err(self.operand_error("index", &t));
+                    }
+                    mm
+                }
+            };
+            if is_function(&mm) {
+                self.call_metamethod(mm, &[t, k, v])?;
+                return Ok(());
+            }
+            t = mm;
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possible loop"))
+    }
+
+    /// Binary operation on operands without a primitive meaning: call the
+    /// metamethod of the first operand having one, or raise the type error.
+    pub(crate) fn arith_metamethod(
+        &mut self,
+        a: LuaValue,
+        b: LuaValue,
+        event: TagMethod,
+    ) -> Result<LuaValue, LuaError> {
+        let mut mm = self.get_metamethod(&a, event);
+        if mm.is_nil() {
+            mm = self.get_metamethod(&b, event);
+        }
+        if !mm.is_nil() {
+            return self.call_metamethod(mm, &[a, b]);
+        }
+
+        let is_number = |v: &LuaValue| matches!(v, LuaValue::Integer(_) | LuaValue::Number(_));
+        // blame the first operand unless it is fine
+        let (operation, fine) = match event {
+            TagMethod::Concat => (
+                "concatenate",
+                is_number(&a) || matches!(a, LuaValue::String(_)),
+            ),
+            _ if event.is_bitwise() => {
+                if is_number(&a) && is_number(&b) {
+                    return Err(self.runtime_error("number has no integer representation"));
+                }
+                ("perform bitwise operation on", is_number(&a))
+            }
+            _ => ("perform arithmetic on", is_number(&a)),
+        };
+        Err(self.operand_error(operation, if fine { &b } else { &a }))
+    }
+
+    /// `a == b`, tables with different identities may be equal by `__eq`.
+    pub(crate) fn equal(&mut self, a: LuaValue, b: LuaValue) -> Result<bool, LuaError> {
+        if a == b {
+            return Ok(true);
+        }
+        if !matches!((&a, &b), (LuaValue::Table(_), LuaValue::Table(_))) {
+            return Ok(false);
+        }
+        let mut mm = self.get_metamethod(&a, TagMethod::Eq);
+        if mm.is_nil() {
+            mm = self.get_metamethod(&b, TagMethod::Eq);
+        }
+        if mm.is_nil() {
+            return Ok(false);
+        }
+        Ok(self.call_metamethod(mm, &[a, b])?.to_boolean())
+    }
+
+    /// `a < b` or `a <= b`, numbers and strings compare directly, other
+    /// values need `__lt` or `__le`.
+    pub(crate) fn order(
+        &mut self,
+        a: LuaValue,
+        b: LuaValue,
+        event: TagMethod,
+    ) -> Result<bool, LuaError> {
+        let numbers = |v: &LuaValue| matches!(v, LuaValue::Integer(_) | LuaValue::Number(_));
+        let strings = matches!((&a, &b), (LuaValue::String(_), LuaValue::String(_)));
+        if (numbers(&a) && numbers(&b)) || strings {
+            return Ok(if event == TagMethod::Lt {
+                a < b
+            } else {
+                a <= b
+            });
+        }
+
+        let mut mm = self.get_metamethod(&a, event);
+        if mm.is_nil() {
+            mm = self.get_metamethod(&b, event);
+        }
+        if !mm.is_nil() {
+            return Ok(self.call_metamethod(mm, &[a, b])?.to_boolean());
+        }
+        let (t1, t2) = (a.type_name(), b.type_name());
+        let msg = if t1 == t2 {
+            format!("attempt to compare two {} values", t1)
+        } else {
+            format!("attempt to compare {} with {}", t1, t2)
+        };
+        Err(self.runtime_error(msg))
+    }
+
+    /// `#v`, `__len` takes precedence over the length of a table.
+    pub(crate) fn length(&mut self, v: LuaValue) -> Result<LuaValue, LuaError> {
+        if let LuaValue::String(s) = &v {
+            return Ok(LuaValue::Integer(s.len() as i64));
+        }
+        let mm = self.get_metamethod(&v, TagMethod::Len);
+        if !mm.is_nil() {
+            return self.call_metamethod(mm, &[v.clone(), v]);
+        }
+        match &v {
+            LuaValue::Table(table) => Ok(LuaValue::Integer(table.borrow().len() as i64)),
+            _ => Err(self.operand_error("get length of", &v)),
+        }
+    }
+
+    /// The value at `idx` as a string, as `tostring` converts it: `__tostring`
+    /// takes precedence, then `__name` names the type of the value.
+    pub fn to_lstring(&mut self, idx: i32) -> Result<LuaString, LuaError> {
+        let val = self.stack.get(idx);
+        let mt = self.get_metatable_of(&val);
+        if let Some(mt) = &mt {
+            let mm = mt.borrow().get_str("__tostring");
+            if !mm.is_nil() {
+                return match self.call_metamethod(mm, &[val])? {
+                    LuaValue::String(s) => Ok(s),
+                    _ => Err(self.runtime_error("'__tostring' must return a string")),
+                };
+            }
+        }
+        let s = match &val {
+            LuaValue::String(s) => return Ok(s.clone()),
+            LuaValue::Nil => "nil".to_string(),
+            LuaValue::Boolean(b) => b.to_string(),
+            LuaValue::Integer(_) | LuaValue::Number(_) => number_to_str(&val).unwrap(),
+            _ => {
+                let name = match mt.map(|mt| mt.borrow().get_str("__name")) {
+                    Some(LuaValue::String(name)) => name.to_string(),
+                    _ => val.type_name().to_string(),
+                };
+                let ptr = match &val {
+                    LuaValue::Table(t) => Rc::as_ptr(t) as *const (),
+                    LuaValue::Function(c) => Rc::as_ptr(c) as *const (),
+                    LuaValue::RustFunction(f) => f.as_ptr(),
+                    LuaValue::Thread(t) => Rc::as_ptr(t) as *const (),
+                    _ => std::ptr::null(),
+                };
+                format!("{}: {:p}", name, ptr)
+            }
+        };
+        Ok(LuaString::from(s))
+    }
+
+    /// Make the value in slot `func` callable by inserting its `__call`
+    /// handler below it, the value becomes the first argument.
+    pub(crate) fn insert_call_metamethod(&mut self, func: usize) -> Result<(), LuaError> {
+        let val = self.stack.slots[func].clone();
+        let mm = self.get_metamethod(&val, TagMethod::Call);
+        if mm.is_nil() {
+            return Err(self.operand_error("call", &val));
+        }
+        self.stack.push(LuaValue::Nil);
+        self.stack.slots[func..self.stack.top].rotate_right(1);
+        self.stack.slots[func] = mm;
+        Ok(())
+    }
+}
+
+fn is_function(val: &LuaValue) -> bool {
+    matches!(val, LuaValue::Function(_) | LuaValue::RustFunction(_))
+}
+
+#[test]
+fn test_tag_method_order() {
+    assert_eq!(TagMethod::try_from(6), Ok(TagMethod::Add));
+    assert_eq!(TagMethod::try_from(17).unwrap().name(), "__shr");
+    assert_eq!(TagMethod::try_from(24), Ok(TagMethod::Close));
+    assert!(TagMethod::try_from(25).is_err());
+}
@@ -1 +1,13 @@
+use super::{
+    binary_chunk::Prototype,
+    reader::{LuaChunkReader, ReadResult},
+};
 
+/// Read the main function prototype of a binary chunk.
+pub fn undump(chunk: Vec<u8>) -> ReadResult<Prototype> {
+    let mut reader = LuaChunkReader::new(chunk);
+    reader.check_header()?;
+    // number of upvalues of the main function, repeated in its prototype
+    reader.read_byte()?;
+    reader.read_function_prototype(String::new())
+}